@@ -1,25 +1,532 @@
+-- stock is NULL for books whose stock isn't tracked
 CREATE TABLE Books (
     id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
     title TEXT NOT NULL,
     author TEXT NOT NULL,
-    price REAL NOT NULL
+    price REAL NOT NULL,
+    stock INTEGER CHECK (stock IS NULL OR stock >= 0),
+    -- grams and millimetres, books without a weight count as default_book_weight
+    weight INTEGER CHECK (weight IS NULL OR weight > 0),
+    length INTEGER CHECK (length IS NULL OR length > 0),
+    width INTEGER CHECK (width IS NULL OR width > 0),
+    height INTEGER CHECK (height IS NULL OR height > 0),
+    taxCategory TEXT NOT NULL DEFAULT 'book' CHECK (taxCategory IN ('book', 'ebook', 'general'))
 );
 
+-- mergedInto is set when the customer was merged into another one,
+-- lookups by the old id are redirected to it
+-- erasedAt is set when the customer's personal data was erased, the name is
+-- then a pseudonym and the address is empty
+-- country (ISO 3166 alpha-2) and postalCode pick the shipping zone, country and
+-- region (the state or province code, may be empty) the tax jurisdiction
 CREATE TABLE Customers (
     id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
     name TEXT NOT NULL,
     shippingAddress TEXT NOT NULL,
-    accountBalance REAL
+    country TEXT NOT NULL DEFAULT 'US',
+    region TEXT NOT NULL DEFAULT '',
+    postalCode TEXT NOT NULL DEFAULT '',
+    mergedInto INTEGER REFERENCES Customers(id),
+    erasedAt TEXT
 );
 
--- SQLITE has no boolean type, 1 is true, 0 false
-CREATE TABLE PurchaseOrders (
+-- other addresses known for a customer besides their shipping address
+CREATE TABLE CustomerAddresses (
     id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    address TEXT NOT NULL,
+    addedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- audit trail of customer merges
+CREATE TABLE CustomerMerges (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    sourceId INTEGER NOT NULL REFERENCES Customers(id),
+    targetId INTEGER NOT NULL REFERENCES Customers(id),
+    mergedBy TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    ordersMoved INTEGER NOT NULL,
+    balanceMoved REAL NOT NULL,
+    pointsMoved INTEGER NOT NULL,
+    mergedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- append-only, a customer's balance is the sum of their entries
+-- kind is one of: deposit, order_charge, refund, adjustment, store_credit
+CREATE TABLE LedgerEntries (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    kind TEXT NOT NULL,
+    amount REAL NOT NULL,
+    orderId INTEGER,
+    memo TEXT,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER LedgerEntriesNoUpdate BEFORE UPDATE ON LedgerEntries BEGIN SELECT RAISE(ABORT, 'ledger entries are append-only'); END;
+
+CREATE TRIGGER LedgerEntriesNoDelete BEFORE DELETE ON LedgerEntries BEGIN SELECT RAISE(ABORT, 'ledger entries are append-only'); END;
+
+-- only the sha256 hash of a card code is stored, remaining value is the
+-- initial value minus the card's redemptions
+CREATE TABLE GiftCards (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    codeHash TEXT NOT NULL UNIQUE,
+    codeLast4 TEXT NOT NULL,
+    initialValue REAL NOT NULL,
+    issuedBy TEXT NOT NULL,
+    issuedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expiresAt TEXT
+);
+
+CREATE TABLE GiftCardRedemptions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    giftCardId INTEGER NOT NULL REFERENCES GiftCards(id),
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    amount REAL NOT NULL,
+    ledgerEntryId INTEGER NOT NULL REFERENCES LedgerEntries(id),
+    redeemedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- points are earned in lots that expire, redemptions use the oldest lots first
+CREATE TABLE LoyaltyPointLots (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    orderId INTEGER,
+    points INTEGER NOT NULL,
+    remaining INTEGER NOT NULL,
+    earnedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expiresAt TEXT NOT NULL
+);
+
+-- kind is one of: earn, redeem, expire, restore, revoke
+CREATE TABLE LoyaltyTransactions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    kind TEXT NOT NULL,
+    points INTEGER NOT NULL,
+    orderId INTEGER,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE ShippingZones (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE
+);
+
+-- the zone of a destination is the one of the area for its country with the longest
+-- postalPrefix the postal code starts with, country '*' is the rest of the world
+CREATE TABLE ShippingZoneAreas (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    zoneId INTEGER NOT NULL REFERENCES ShippingZones(id),
+    country TEXT NOT NULL,
+    postalPrefix TEXT NOT NULL DEFAULT '',
+    UNIQUE (country, postalPrefix)
+);
+
+-- each carrier service of a zone has weight brackets, a parcel pays the price of the
+-- smallest bracket whose maxWeight (grams) it fits in, or nothing once the order's
+-- subtotal reaches freeOver
+CREATE TABLE ShippingRates (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    zoneId INTEGER NOT NULL REFERENCES ShippingZones(id),
+    carrier TEXT NOT NULL,
+    serviceLevel TEXT NOT NULL,
+    maxWeight INTEGER NOT NULL CHECK (maxWeight > 0),
+    price REAL NOT NULL CHECK (price >= 0),
+    freeOver REAL CHECK (freeOver IS NULL OR freeOver > 0),
+    UNIQUE (zoneId, carrier, serviceLevel, maxWeight)
+);
+
+-- total is what was charged: the sum of the lines minus the discount plus shipping,
+-- plus tax unless taxInclusive (the prices already contained it when the order was placed)
+-- shippingAddress is the customer's address when the order was placed
+-- state changes are recorded in OrderEvents, see db/order_state.rs for the allowed ones
+CREATE TABLE Orders (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    state TEXT NOT NULL DEFAULT 'pending' CHECK (state IN
+        ('pending', 'paid', 'picking', 'shipped', 'delivered', 'cancelled', 'returned')),
+    subtotal REAL NOT NULL,
+    -- discount is the coupon discount plus the loyalty points redeemed
+    discount REAL NOT NULL,
+    couponDiscount REAL NOT NULL DEFAULT 0,
+    shipping REAL NOT NULL DEFAULT 0,
+    tax REAL NOT NULL DEFAULT 0,
+    taxInclusive INTEGER NOT NULL DEFAULT 0,
+    total REAL NOT NULL,
+    shippingAddress TEXT NOT NULL,
+    shippingCarrier TEXT,
+    shippingService TEXT,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- fromState is NULL for the event that created the order
+CREATE TABLE OrderEvents (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    orderId INTEGER NOT NULL REFERENCES Orders(id),
+    fromState TEXT,
+    toState TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    note TEXT,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- cancelledBy is "customer <id>" or a staff member, overrideReason is set
+-- when staff cancelled the rest of an order that had partly shipped
+CREATE TABLE OrderCancellations (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    orderId INTEGER NOT NULL UNIQUE REFERENCES Orders(id),
+    reason TEXT NOT NULL,
+    cancelledBy TEXT NOT NULL,
+    overrideReason TEXT,
+    refunded REAL NOT NULL,
+    stockRestored INTEGER NOT NULL,
+    cancelledAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- unitPrice is the book's price when the order was placed,
+-- reserved is 1 when the quantity was taken from the book's stock
+-- tax is the line's share of OrderLineTaxes
+CREATE TABLE OrderLines (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    orderId INTEGER NOT NULL REFERENCES Orders(id),
+    bookId INTEGER NOT NULL REFERENCES Books(id),
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    unitPrice REAL NOT NULL,
+    reserved INTEGER NOT NULL DEFAULT 0,
+    tax REAL NOT NULL DEFAULT 0,
+    -- the line's share of the order's coupon and loyalty point discount, kept off refunds
+    discount REAL NOT NULL DEFAULT 0
+);
+
+-- region '' is a rate for the whole country, a destination pays the country rate
+-- and the rate of its region added together (e.g. GST and PST in Canada)
+CREATE TABLE TaxRates (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    country TEXT NOT NULL,
+    region TEXT NOT NULL DEFAULT '',
+    category TEXT NOT NULL CHECK (category IN ('book', 'ebook', 'general')),
+    rate REAL NOT NULL CHECK (rate >= 0 AND rate < 1),
+    UNIQUE (country, region, category)
+);
+
+-- the tax charged on an order line per jurisdiction, taxable is the line's value
+-- after its share of the discount, without tax
+CREATE TABLE OrderLineTaxes (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    orderLineId INTEGER NOT NULL REFERENCES OrderLines(id),
+    country TEXT NOT NULL,
+    region TEXT NOT NULL,
+    rate REAL NOT NULL,
+    taxable REAL NOT NULL,
+    amount REAL NOT NULL
+);
+
+-- kind 'percent' takes value percent off the eligible lines, 'fixed' takes value off
+-- them, eligible lines are all lines unless the coupon has CouponBooks or CouponAuthors
+-- startsOn and endsOn are inclusive dates, the limits count orders that weren't cancelled
+CREATE TABLE Coupons (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    kind TEXT NOT NULL CHECK (kind IN ('percent', 'fixed')),
+    value REAL NOT NULL CHECK (value > 0),
+    minOrder REAL,
+    usageLimit INTEGER,
+    perCustomerLimit INTEGER,
+    startsOn TEXT,
+    endsOn TEXT,
+    active INTEGER NOT NULL DEFAULT 1,
+    createdBy TEXT NOT NULL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE CouponBooks (
+    couponId INTEGER NOT NULL REFERENCES Coupons(id),
+    bookId INTEGER NOT NULL REFERENCES Books(id),
+    PRIMARY KEY (couponId, bookId)
+);
+
+CREATE TABLE CouponAuthors (
+    couponId INTEGER NOT NULL REFERENCES Coupons(id),
+    author TEXT NOT NULL,
+    PRIMARY KEY (couponId, author)
+);
+
+CREATE TABLE CouponRedemptions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    couponId INTEGER NOT NULL REFERENCES Coupons(id),
+    orderId INTEGER NOT NULL UNIQUE REFERENCES Orders(id),
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    amount REAL NOT NULL,
+    redeemedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- invoices and credit notes are numbered without gaps per series and fiscal year, the
+-- number is taken in the transaction that issues the document so a rollback gives it back
+CREATE TABLE InvoiceSequences (
+    series TEXT NOT NULL,
+    fiscalYear INTEGER NOT NULL,
+    lastNumber INTEGER NOT NULL,
+    PRIMARY KEY (series, fiscalYear)
+);
+
+-- frozen copies of what was billed, they don't change when the order, books or customer do.
+-- A credit note has creditedInvoiceId set and its amounts are what was given back, with
+-- total = subtotal - discount + shipping, plus tax unless taxInclusive.
+-- Invoices and their lines are kept for legal retention when a customer is erased,
+-- billing name and address included
+CREATE TABLE Invoices (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL CHECK (kind IN ('invoice', 'credit_note')),
+    number TEXT NOT NULL UNIQUE,
+    fiscalYear INTEGER NOT NULL,
+    orderId INTEGER NOT NULL REFERENCES Orders(id),
+    creditedInvoiceId INTEGER REFERENCES Invoices(id),
+    customerId INTEGER NOT NULL REFERENCES Customers(id),
+    billingName TEXT NOT NULL,
+    billingAddress TEXT NOT NULL,
+    country TEXT NOT NULL,
+    region TEXT NOT NULL,
+    postalCode TEXT NOT NULL,
+    subtotal REAL NOT NULL,
+    discount REAL NOT NULL,
+    shipping REAL NOT NULL,
+    tax REAL NOT NULL,
+    taxInclusive INTEGER NOT NULL,
+    total REAL NOT NULL,
+    reason TEXT,
+    issuedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK ((kind = 'credit_note') = (creditedInvoiceId IS NOT NULL))
+);
+CREATE TRIGGER InvoicesNoUpdate BEFORE UPDATE ON Invoices BEGIN SELECT RAISE(ABORT, 'invoices are immutable'); END;
+CREATE TRIGGER InvoicesNoDelete BEFORE DELETE ON Invoices BEGIN SELECT RAISE(ABORT, 'invoices are immutable'); END;
+
+CREATE TABLE InvoiceLines (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    invoiceId INTEGER NOT NULL REFERENCES Invoices(id),
+    bookId INTEGER NOT NULL REFERENCES Books(id),
+    description TEXT NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    unitPrice REAL NOT NULL,
+    tax REAL NOT NULL
+);
+CREATE TRIGGER InvoiceLinesNoUpdate BEFORE UPDATE ON InvoiceLines BEGIN SELECT RAISE(ABORT, 'invoices are immutable'); END;
+CREATE TRIGGER InvoiceLinesNoDelete BEFORE DELETE ON InvoiceLines BEGIN SELECT RAISE(ABORT, 'invoices are immutable'); END;
+
+-- a cart belongs to a customer or to an anonymous session, which is identified by the
+-- hash of its token like staff sessions are
+CREATE TABLE Carts (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    customerId INTEGER UNIQUE REFERENCES Customers(id),
+    tokenHash TEXT UNIQUE,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updatedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK ((customerId IS NULL) != (tokenHash IS NULL))
+);
+
+-- price is the book's price when the cart was last shown, checkout won't charge
+-- a different one before the customer has seen it
+CREATE TABLE CartItems (
+    cartId INTEGER NOT NULL REFERENCES Carts(id),
     bookId INTEGER NOT NULL REFERENCES Books(id),
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    price REAL NOT NULL,
+    addedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (cartId, bookId)
+);
+
+-- a batch of orders picked together, an order is only in one batch
+CREATE TABLE PickBatches (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    createdBy TEXT NOT NULL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE PickBatchOrders (
+    batchId INTEGER NOT NULL REFERENCES PickBatches(id),
+    orderId INTEGER NOT NULL UNIQUE REFERENCES Orders(id),
+    PRIMARY KEY (batchId, orderId)
+);
+
+-- one parcel of an order, an order can ship in several
+CREATE TABLE Shipments (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    orderId INTEGER NOT NULL REFERENCES Orders(id),
+    carrier TEXT NOT NULL,
+    serviceLevel TEXT NOT NULL,
+    trackingNumber TEXT,
+    shippedAt TEXT NOT NULL,
+    createdBy TEXT NOT NULL
+);
+
+CREATE TABLE ShipmentLines (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    shipmentId INTEGER NOT NULL REFERENCES Shipments(id),
+    orderLineId INTEGER NOT NULL REFERENCES OrderLines(id),
+    quantity INTEGER NOT NULL CHECK (quantity > 0)
+);
+
+-- status is one of: requested, approved, rejected, completed
+-- an RMA is completed when the items were received and the refund was posted
+CREATE TABLE Rmas (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    orderId INTEGER NOT NULL REFERENCES Orders(id),
     customerId INTEGER NOT NULL REFERENCES Customers(id),
-    shipped INTEGER NOT NULL
+    status TEXT NOT NULL DEFAULT 'requested',
+    reason TEXT NOT NULL,
+    decidedBy TEXT,
+    decisionNote TEXT,
+    receivedBy TEXT,
+    refunded REAL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updatedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- condition is one of: as_new, damaged, unsellable; disposition one of: restock, write_off
+-- both are set when the item is received
+CREATE TABLE RmaLines (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    rmaId INTEGER NOT NULL REFERENCES Rmas(id),
+    orderLineId INTEGER NOT NULL REFERENCES OrderLines(id),
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    condition TEXT,
+    disposition TEXT
+);
+
+-- role is one of: admin, catalog_manager, fulfillment_clerk, support
+CREATE TABLE Staff (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    passwordHash TEXT NOT NULL,
+    role TEXT NOT NULL,
+    totpSecret TEXT,
+    totpEnabled INTEGER NOT NULL DEFAULT 0,
+    totpLastStep INTEGER
+);
+
+-- only the sha256 hash of a session token is stored
+CREATE TABLE StaffSessions (
+    tokenHash TEXT NOT NULL PRIMARY KEY,
+    staffId INTEGER NOT NULL REFERENCES Staff(id),
+    expiresAt TEXT NOT NULL,
+    totpVerified INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE StaffRecoveryCodes (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    staffId INTEGER NOT NULL REFERENCES Staff(id),
+    codeHash TEXT NOT NULL,
+    usedAt TEXT
+);
+
+-- responses kept for the Idempotency-Key header, fingerprint is a hash of the request
+-- and status is NULL while the first request with the key is still being handled
+CREATE TABLE IdempotencyKeys (
+    key TEXT NOT NULL PRIMARY KEY,
+    fingerprint TEXT NOT NULL,
+    status INTEGER,
+    contentType TEXT,
+    body BLOB,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- scopes is a comma separated list of mounts (books, customers, orders)
+CREATE TABLE ApiKeys (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    keyPrefix TEXT NOT NULL,
+    keyHash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    lastUsedAt TEXT,
+    revokedAt TEXT
+);
+
+-- the secret signs deliveries, so it is kept as is rather than hashed
+CREATE TABLE WebhookSubscriptions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    active INTEGER NOT NULL DEFAULT 1,
+    createdBy TEXT NOT NULL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE WebhookSubscriptionEvents (
+    subscriptionId INTEGER NOT NULL REFERENCES WebhookSubscriptions(id),
+    eventType TEXT NOT NULL,
+    PRIMARY KEY (subscriptionId, eventType)
+);
+
+-- the outbox, written in the transaction of the change that raised the event
+CREATE TABLE WebhookEvents (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    eventType TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- an event to be sent to one subscription, status is one of: pending, delivered, failed
+CREATE TABLE WebhookDeliveries (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    eventId INTEGER NOT NULL REFERENCES WebhookEvents(id),
+    subscriptionId INTEGER NOT NULL REFERENCES WebhookSubscriptions(id),
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'delivered', 'failed')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    nextAttemptAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    deliveredAt TEXT,
+    UNIQUE (eventId, subscriptionId)
+);
+
+-- the delivery log, statusCode is missing when no response came back
+CREATE TABLE WebhookAttempts (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    deliveryId INTEGER NOT NULL REFERENCES WebhookDeliveries(id),
+    statusCode INTEGER,
+    error TEXT,
+    durationMs INTEGER NOT NULL,
+    attemptedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
 
+INSERT INTO ShippingZones (name) VALUES ('us');
+INSERT INTO ShippingZones (name) VALUES ('us-remote');
+INSERT INTO ShippingZones (name) VALUES ('canada');
+INSERT INTO ShippingZones (name) VALUES ('international');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (1, 'US', '');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '967');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '968');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '995');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '996');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '997');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '998');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (2, 'US', '999');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (3, 'CA', '');
+INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (4, '*', '');
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (1, 'usps', 'standard', 1000, 3.99, 35.0);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (1, 'usps', 'standard', 5000, 6.99, 35.0);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (1, 'usps', 'standard', 20000, 12.99, 35.0);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (1, 'ups', 'next_day', 5000, 19.99, NULL);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (1, 'ups', 'next_day', 20000, 34.99, NULL);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (2, 'usps', 'standard', 1000, 7.99, NULL);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (2, 'usps', 'standard', 20000, 19.99, NULL);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (3, 'canada_post', 'standard', 2000, 9.99, 75.0);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (3, 'canada_post', 'standard', 20000, 17.99, 75.0);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (4, 'dhl', 'international', 2000, 24.99, NULL);
+INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver) VALUES (4, 'dhl', 'international', 20000, 49.99, NULL);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('US', 'CA', 'book', 0.0725);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('US', 'CA', 'general', 0.0725);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('US', 'NY', 'book', 0.04);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('US', 'NY', 'ebook', 0.04);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('US', 'NY', 'general', 0.04);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('CA', '', 'book', 0.05);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('CA', '', 'ebook', 0.05);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('CA', '', 'general', 0.05);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('CA', 'BC', 'general', 0.07);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('GB', '', 'general', 0.2);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('DE', '', 'book', 0.07);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('DE', '', 'ebook', 0.07);
+INSERT INTO TaxRates (country, region, category, rate) VALUES ('DE', '', 'general', 0.19);
 INSERT INTO Books (title, author, price) VALUES ('The Hitchhikers Guide to the Galaxy', 'Douglas Adams', 12.99);
 INSERT INTO Books (title, author, price) VALUES ('Dune', 'Frank Herbert', 9.99);
 INSERT INTO Books (title, author, price) VALUES ('The Left Hand of Darkness', 'Ursula K. Le Guin', 8.99);
@@ -0,0 +1,521 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use log::warn;
+use rand::RngCore;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use sha2::{Digest, Sha256};
+use std::marker::PhantomData;
+
+use crate::db::api_keys::{self, ApiKey};
+use crate::db::staff::{self, StaffMember};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    CatalogManager,
+    FulfillmentClerk,
+    Support,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::CatalogManager => "catalog_manager",
+            Role::FulfillmentClerk => "fulfillment_clerk",
+            Role::Support => "support",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "admin" => Some(Role::Admin),
+            "catalog_manager" => Some(Role::CatalogManager),
+            "fulfillment_clerk" => Some(Role::FulfillmentClerk),
+            "support" => Some(Role::Support),
+            _ => None,
+        }
+    }
+}
+
+/// A staff operation that a handler can require through [`Authorized`].
+/// Admins are allowed everything, other roles only what `allows` grants.
+/// Sensitive permissions also need a session that was started with TOTP.
+/// Api keys are refused unless the permission opts in with `API_KEY_ALLOWED`.
+pub trait Permission {
+    const NAME: &'static str;
+    const REQUIRES_TOTP: bool;
+    const API_KEY_ALLOWED: bool = false;
+    fn allows(role: Role) -> bool;
+}
+
+/// adding or changing books
+pub struct CatalogWrite;
+/// managing discount codes
+pub struct Promotions;
+/// moving orders through fulfillment
+pub struct Shipping;
+/// recording that an order or a parcel of it has shipped
+pub struct RecordShipment;
+/// changing customer records on a customer's behalf
+pub struct CustomerAdmin;
+/// updating a customer's shipping address
+pub struct CustomerAddress;
+/// posting money to a customer's account ledger
+pub struct AccountLedger;
+/// merging duplicate customer accounts, admins only
+pub struct CustomerMerge;
+/// erasing a customer's personal data, admins only
+pub struct CustomerErasure;
+/// financial reports, admins only
+pub struct Reports;
+/// setting tax rates, admins only
+pub struct TaxAdmin;
+/// managing staff accounts
+pub struct StaffAdmin;
+/// managing webhook subscriptions, admins only
+pub struct Webhooks;
+
+impl Permission for CatalogWrite {
+    const NAME: &'static str = "catalog write";
+    const REQUIRES_TOTP: bool = true;
+    const API_KEY_ALLOWED: bool = true;
+    fn allows(role: Role) -> bool {
+        role == Role::CatalogManager
+    }
+}
+
+impl Permission for Promotions {
+    const NAME: &'static str = "promotions";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(role: Role) -> bool {
+        role == Role::CatalogManager
+    }
+}
+
+impl Permission for Shipping {
+    const NAME: &'static str = "shipping";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(role: Role) -> bool {
+        role == Role::FulfillmentClerk
+    }
+}
+
+impl Permission for RecordShipment {
+    const NAME: &'static str = "record shipment";
+    const REQUIRES_TOTP: bool = true;
+    const API_KEY_ALLOWED: bool = true;
+    fn allows(role: Role) -> bool {
+        role == Role::FulfillmentClerk
+    }
+}
+
+impl Permission for CustomerAdmin {
+    const NAME: &'static str = "customer administration";
+    const REQUIRES_TOTP: bool = false;
+    fn allows(role: Role) -> bool {
+        role == Role::Support
+    }
+}
+
+impl Permission for CustomerAddress {
+    const NAME: &'static str = "customer address";
+    const REQUIRES_TOTP: bool = false;
+    const API_KEY_ALLOWED: bool = true;
+    fn allows(role: Role) -> bool {
+        role == Role::Support
+    }
+}
+
+impl Permission for AccountLedger {
+    const NAME: &'static str = "account ledger";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(role: Role) -> bool {
+        role == Role::Support
+    }
+}
+
+impl Permission for CustomerMerge {
+    const NAME: &'static str = "customer merge";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(_role: Role) -> bool {
+        false
+    }
+}
+
+impl Permission for CustomerErasure {
+    const NAME: &'static str = "customer erasure";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(_role: Role) -> bool {
+        false
+    }
+}
+
+impl Permission for Reports {
+    const NAME: &'static str = "reports";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(_role: Role) -> bool {
+        false
+    }
+}
+
+impl Permission for TaxAdmin {
+    const NAME: &'static str = "tax administration";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(_role: Role) -> bool {
+        false
+    }
+}
+
+impl Permission for StaffAdmin {
+    const NAME: &'static str = "staff administration";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(_role: Role) -> bool {
+        false
+    }
+}
+
+impl Permission for Webhooks {
+    const NAME: &'static str = "webhooks";
+    const REQUIRES_TOTP: bool = true;
+    fn allows(_role: Role) -> bool {
+        false
+    }
+}
+
+/// Request guard for a logged in staff member, read from `Authorization: Bearer <token>`.
+/// Fails with 401 when the token is missing, unknown or expired.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StaffMember {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            Some(t) => t.trim().to_string(),
+            None => {
+                warn!(target: "warn", "unauthenticated request to {}", req.uri());
+                return Outcome::Error((Status::Unauthorized, "no session token".to_string()));
+            }
+        };
+        match staff::get_session_staff(token) {
+            Ok(member) => Outcome::Success(member),
+            Err(e) => {
+                warn!(target: "warn", "rejected session token for {}: {}", req.uri(), e);
+                Outcome::Error((Status::Unauthorized, e))
+            }
+        }
+    }
+}
+
+/// Request guard for service clients, read from the `X-API-Key` header.
+/// The key must carry the scope named after the mount of the matched route,
+/// so a key scoped to `orders` can only reach routes under `/orders`.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = match req.headers().get_one("X-API-Key") {
+            Some(k) => k.trim().to_string(),
+            None => return Outcome::Error((Status::Unauthorized, "no api key".to_string())),
+        };
+        let api_key = match api_keys::authenticate(key) {
+            Ok(k) => k,
+            Err(e) => {
+                warn!(target: "warn", "rejected api key for {}: {}", req.uri(), e);
+                return Outcome::Error((Status::Unauthorized, e));
+            }
+        };
+        let scope = req
+            .route()
+            .map(|r| r.uri.base().trim_start_matches('/').to_string())
+            .unwrap_or_default();
+        if api_key.has_scope(&scope) {
+            Outcome::Success(api_key)
+        } else {
+            warn!(target: "warn", "permission denied: api key {} ({}) lacks scope {} for {} {}",
+                api_key.id, api_key.name, scope, req.method(), req.uri());
+            Outcome::Error((Status::Forbidden, format!("{} scope required", scope)))
+        }
+    }
+}
+
+/// Who passed an [`Authorized`] guard.
+pub enum Principal {
+    Staff(StaffMember),
+    ApiKey(ApiKey),
+}
+
+impl Principal {
+    pub fn name(&self) -> String {
+        //! identifies the principal in logs
+        match self {
+            Principal::Staff(member) => member.username.clone(),
+            Principal::ApiKey(key) => format!("api key {} ({})", key.id, key.name),
+        }
+    }
+}
+
+/// Request guard that only succeeds when the staff member's role grants `P`,
+/// or when `P` admits api keys and one is sent whose scopes cover the route's mount.
+/// A valid session or key without the permission is answered with 403.
+pub struct Authorized<P: Permission> {
+    pub principal: Principal,
+    _permission: PhantomData<P>,
+}
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for Authorized<P> {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if req.headers().contains("X-API-Key") {
+            if !P::API_KEY_ALLOWED {
+                warn!(target: "warn", "permission denied: api keys can't use {} for {} {}",
+                    P::NAME, req.method(), req.uri());
+                return Outcome::Error((
+                    Status::Forbidden,
+                    format!("{} is not available to api keys", P::NAME),
+                ));
+            }
+            return ApiKey::from_request(req).await.map(|key| Authorized {
+                principal: Principal::ApiKey(key),
+                _permission: PhantomData,
+            });
+        }
+        let member = match StaffMember::from_request(req).await {
+            Outcome::Success(m) => m,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(s) => return Outcome::Forward(s),
+        };
+        if !(member.role == Role::Admin || P::allows(member.role)) {
+            warn!(target: "warn", "permission denied: {} ({}) lacks {} for {} {}",
+                member.username, member.role.as_str(), P::NAME, req.method(), req.uri());
+            Outcome::Error((
+                Status::Forbidden,
+                format!("{} permission required", P::NAME),
+            ))
+        } else if P::REQUIRES_TOTP && !member.totp_verified {
+            warn!(target: "warn", "permission denied: {} has no two-factor session for {} {}",
+                member.username, req.method(), req.uri());
+            Outcome::Error((Status::Forbidden, "two-factor login required".to_string()))
+        } else {
+            Outcome::Success(Authorized {
+                principal: Principal::Staff(member),
+                _permission: PhantomData,
+            })
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| format!("password hashing failed: {}", e))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn new_token() -> String {
+    //! random 256 bit token, hex encoded
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use rocket::local::blocking::Client;
+
+    #[get("/customers")]
+    fn customer_admin(_auth: Authorized<CustomerAdmin>) {}
+
+    #[get("/staff")]
+    fn staff_admin(_auth: Authorized<StaffAdmin>) {}
+
+    #[get("/")]
+    fn catalog_write(_auth: Authorized<CatalogWrite>) {}
+
+    fn client() -> Client {
+        let rocket = rocket::build()
+            .mount("/", routes![customer_admin, staff_admin])
+            .mount("/books", routes![catalog_write])
+            .mount("/customers", routes![customer_admin])
+            .mount("/staff", routes![crate::handlers::staff::logout]);
+        Client::untracked(rocket).expect("valid rocket")
+    }
+
+    fn session(username: &str, role: Role, totp_verified: bool) -> String {
+        let sid = staff::create_staff(username.to_string(), "correct horse".to_string(), role)
+            .expect("new staff member");
+        staff::start_session(sid, totp_verified)
+    }
+
+    fn status(client: &Client, path: &str, token: Option<&str>) -> Status {
+        let mut req = client.get(path);
+        if let Some(t) = token {
+            req = req.header(rocket::http::Header::new(
+                "Authorization",
+                format!("Bearer {}", t),
+            ));
+        }
+        req.dispatch().status()
+    }
+
+    #[test]
+    fn roles_get_only_their_permissions() {
+        let _db = db::test_db();
+        let client = client();
+        let support = session("sam", Role::Support, true);
+        let clerk = session("cleo", Role::FulfillmentClerk, true);
+        let admin = session("ada", Role::Admin, true);
+
+        assert_eq!(status(&client, "/customers", Some(&support)), Status::Ok);
+        assert_eq!(
+            status(&client, "/customers", Some(&clerk)),
+            Status::Forbidden
+        );
+        assert_eq!(status(&client, "/customers", Some(&admin)), Status::Ok);
+        // admin only permissions allow no other role
+        assert_eq!(status(&client, "/staff", Some(&support)), Status::Forbidden);
+        assert_eq!(status(&client, "/staff", Some(&admin)), Status::Ok);
+    }
+
+    #[test]
+    fn missing_or_unknown_tokens_are_unauthorized() {
+        let _db = db::test_db();
+        let client = client();
+
+        assert_eq!(status(&client, "/customers", None), Status::Unauthorized);
+        assert_eq!(
+            status(&client, "/customers", Some("not-a-session")),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    fn sensitive_permissions_need_a_two_factor_session() {
+        let _db = db::test_db();
+        let client = client();
+        let password_only = session("ada", Role::Admin, false);
+        let sid = staff::get_session_staff(password_only.clone()).unwrap().id;
+        let verified = staff::start_session(sid, true);
+
+        // customer administration doesn't need TOTP, staff administration does
+        assert_eq!(
+            status(&client, "/customers", Some(&password_only)),
+            Status::Ok
+        );
+        assert_eq!(
+            status(&client, "/staff", Some(&password_only)),
+            Status::Forbidden
+        );
+        assert_eq!(status(&client, "/staff", Some(&verified)), Status::Ok);
+    }
+
+    #[test]
+    fn logout_ends_only_the_callers_session() {
+        let _db = db::test_db();
+        let client = client();
+        let first = session("sam", Role::Support, false);
+        let sid = staff::get_session_staff(first.clone()).unwrap().id;
+        let second = staff::start_session(sid, false);
+
+        let res = client
+            .post("/staff/logout")
+            .header(rocket::http::Header::new(
+                "Authorization",
+                format!("Bearer {}", first),
+            ))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            status(&client, "/customers", Some(&first)),
+            Status::Unauthorized
+        );
+        assert_eq!(status(&client, "/customers", Some(&second)), Status::Ok);
+    }
+
+    fn key_status(client: &Client, path: &str, key: &str) -> Status {
+        client
+            .get(path)
+            .header(rocket::http::Header::new("X-API-Key", key.to_string()))
+            .dispatch()
+            .status()
+    }
+
+    fn key(scopes: &[&str]) -> (i64, String) {
+        api_keys::create_key(
+            "warehouse".to_string(),
+            scopes.iter().map(|s| s.to_string()).collect(),
+        )
+        .expect("new api key")
+    }
+
+    #[test]
+    fn api_keys_need_the_scope_of_the_mount() {
+        let _db = db::test_db();
+        let client = client();
+        let (_, books) = key(&["books"]);
+        let (_, orders) = key(&["orders"]);
+
+        assert_eq!(key_status(&client, "/books", &books), Status::Ok);
+        assert_eq!(key_status(&client, "/books", &orders), Status::Forbidden);
+        assert_eq!(
+            key_status(&client, "/books", "bsk_unknown"),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    fn api_keys_are_refused_on_permissions_that_did_not_opt_in() {
+        let _db = db::test_db();
+        let client = client();
+        let (_, customers) = key(&["customers"]);
+
+        // the scope covers the mount, but customer administration is staff only
+        assert_eq!(
+            key_status(&client, "/customers/customers", &customers),
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn rotated_and_revoked_keys_stop_working() {
+        let _db = db::test_db();
+        let client = client();
+        let (id, old) = key(&["books"]);
+
+        let new = api_keys::rotate_key(id).unwrap();
+        assert_eq!(key_status(&client, "/books", &old), Status::Unauthorized);
+        assert_eq!(key_status(&client, "/books", &new), Status::Ok);
+
+        api_keys::revoke_key(id).unwrap();
+        assert_eq!(key_status(&client, "/books", &new), Status::Unauthorized);
+        assert!(api_keys::rotate_key(id).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Settings read from `Rocket.toml` (or `ROCKET_*` environment variables),
+//! falling back to a default when a key is missing or malformed.
+use rocket::Config;
+use serde::de::DeserializeOwned;
+
+fn value<T: DeserializeOwned>(key: &str, default: T) -> T {
+    Config::figment().extract_inner(key).unwrap_or(default)
+}
+
+pub fn admin_username() -> String {
+    value("admin_username", "admin".to_string())
+}
+
+pub fn admin_password() -> Option<String> {
+    value("admin_password", None)
+}
+
+pub fn credit_limit() -> f64 {
+    //! how far below zero a customer's balance may go when ordering
+    value("credit_limit", 0.0_f64).max(0.0)
+}
+
+pub fn loyalty_points_per_unit() -> f64 {
+    //! points earned per 1.00 spent on a shipped order, before the tier multiplier
+    value("loyalty_points_per_unit", 1.0_f64).max(0.0)
+}
+
+pub fn loyalty_point_value() -> f64 {
+    //! discount given per point redeemed at order time
+    value("loyalty_point_value", 0.01_f64).max(0.0)
+}
+
+pub fn loyalty_points_expiry_days() -> i64 {
+    value("loyalty_points_expiry_days", 365_i64).max(1)
+}
+
+pub fn default_book_weight() -> i64 {
+    //! grams assumed for books without a weight when pricing shipping
+    value("default_book_weight", 400_i64).max(1)
+}
+
+pub fn prices_include_tax() -> bool {
+    //! book prices are gross (tax is taken out of them) rather than net (tax is added)
+    value("prices_include_tax", false)
+}
+
+pub fn idempotency_key_hours() -> i64 {
+    //! how long a stored response is replayed for its Idempotency-Key
+    value("idempotency_key_hours", 24_i64).max(1)
+}
+
+pub fn fiscal_year_start_month() -> i64 {
+    //! invoice numbers start again at 1 in this month, a fiscal year is named after
+    //! the calendar year it starts in
+    value("fiscal_year_start_month", 1_i64).clamp(1, 12)
+}
+
+pub fn webhook_max_attempts() -> i64 {
+    //! deliveries that failed this often are given up on
+    value("webhook_max_attempts", 8_i64).max(1)
+}
+
+pub fn webhook_backoff_seconds() -> i64 {
+    //! the wait after the first failed delivery, doubled after each further failure
+    value("webhook_backoff_seconds", 30_i64).max(1)
+}
+
+pub fn webhook_timeout_seconds() -> u64 {
+    value("webhook_timeout_seconds", 10_u64).max(1)
+}
+
+pub fn webhook_poll_seconds() -> u64 {
+    //! how often the delivery worker looks for due deliveries
+    value("webhook_poll_seconds", 5_u64).max(1)
+}
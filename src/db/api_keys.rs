@@ -0,0 +1,144 @@
+use super::db::connect;
+use crate::auth::{hash_token, new_token};
+use log::{error, info, warn};
+use serde::Serialize;
+
+/// scopes a key can be granted, one per mount
+pub const SCOPES: [&str; 3] = ["books", "customers", "orders"];
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+pub fn create_key(name: String, scopes: Vec<String>) -> Result<(i64, String), String> {
+    //! returns the id and the plaintext key, which is never stored
+    let db = connect();
+    let key = format!("bsk_{}", new_token());
+    db.execute(
+        "INSERT INTO ApiKeys (name, keyPrefix, keyHash, scopes) VALUES (?1, ?2, ?3, ?4)",
+        [&name, &key[..12], &hash_token(&key), &scopes.join(",")],
+    )
+    .expect("expected to be able to insert into ApiKeys table");
+    let id = db.last_insert_rowid();
+    info!(target: "info", "api key issued: {} ({}) scopes {}", id, name, scopes.join(","));
+    Ok((id, key))
+}
+
+pub fn rotate_key(id: i64) -> Result<String, String> {
+    //! replaces the secret of an active key, the old key stops working immediately
+    let db = connect();
+    let exist = exists_active(id).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if exist {
+        let key = format!("bsk_{}", new_token());
+        db.execute(
+            "UPDATE ApiKeys SET keyPrefix = ?1, keyHash = ?2, lastUsedAt = NULL WHERE id = ?3",
+            [&key[..12], &hash_token(&key), &id.to_string()],
+        )
+        .expect("expected to be able to update ApiKeys table");
+        info!(target: "info", "api key rotated: {}", id);
+        Ok(key)
+    } else {
+        warn!(target: "warn", "failed to rotate api key, not active: {}", id);
+        Err("api key does not exist or is revoked".to_string())
+    }
+}
+
+pub fn revoke_key(id: i64) -> Result<(), String> {
+    let db = connect();
+    let exist = exists_active(id).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if exist {
+        db.execute(
+            "UPDATE ApiKeys SET revokedAt = datetime('now') WHERE id = ?1",
+            [&id],
+        )
+        .expect("expected to be able to update ApiKeys table");
+        info!(target: "info", "api key revoked: {}", id);
+        Ok(())
+    } else {
+        warn!(target: "warn", "failed to revoke api key, not active: {}", id);
+        Err("api key does not exist or is revoked".to_string())
+    }
+}
+
+pub fn list_keys() -> Vec<ApiKey> {
+    let db = connect();
+    let mut stmt = db
+        .prepare(
+            "SELECT id, name, keyPrefix, scopes, createdAt, lastUsedAt, revokedAt
+             FROM ApiKeys ORDER BY id",
+        )
+        .expect("expected to be able to select from ApiKeys table");
+    let rows = stmt
+        .query_map([], row_to_key)
+        .expect("expected to be able to get keys from ApiKeys table");
+    rows.map(|r| r.expect("problem getting api key from database"))
+        .collect()
+}
+
+pub fn authenticate(key: String) -> Result<ApiKey, String> {
+    //! looks up an active key by its hash and records that it was used
+    let db = connect();
+    let mut stmt = db
+        .prepare(
+            "SELECT id, name, keyPrefix, scopes, createdAt, lastUsedAt, revokedAt
+             FROM ApiKeys WHERE keyHash = ?1 AND revokedAt IS NULL",
+        )
+        .expect("expected to be able to select from ApiKeys table");
+    let mut rows = stmt
+        .query_map([&hash_token(&key)], row_to_key)
+        .expect("expected to be able to get key from ApiKeys table");
+    match rows.next() {
+        Some(row) => {
+            let api_key = row.expect("problem getting api key from database");
+            db.execute(
+                "UPDATE ApiKeys SET lastUsedAt = datetime('now') WHERE id = ?1",
+                [&api_key.id],
+            )
+            .expect("expected to be able to update ApiKeys table");
+            Ok(api_key)
+        }
+        None => Err("api key is invalid or revoked".to_string()),
+    }
+}
+
+fn row_to_key(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    let scopes: String = row.get(3)?;
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        key_prefix: row.get(2)?,
+        scopes: scopes.split(',').map(|s| s.to_string()).collect(),
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+        revoked_at: row.get(6)?,
+    })
+}
+
+fn exists_active(id: i64) -> Result<bool, rusqlite::Error> {
+    //! checks that the key exists and has not been revoked
+    let conn = connect();
+    let check = conn
+        .prepare("SELECT id FROM ApiKeys WHERE id = ?1 AND revokedAt IS NULL")
+        .expect("expected to be able to select from ApiKeys table")
+        .exists([&id])?;
+    Ok(check)
+}
@@ -1,18 +1,35 @@
 use super::db::connect;
+use super::tax::TaxCategory;
+use super::webhooks::{self, EventType};
 use log::{error, info, warn};
+use rusqlite::{OptionalExtension, TransactionBehavior};
+use serde_json::json;
 
 pub fn create_book(title: String, author: String, price: f64) -> Result<(), String> {
-    let db = connect();
+    let mut db = connect();
     let exist = exists(title.clone(), author.clone()).unwrap_or_else(|e| {
         error!(target: "error", "statement exists check error: {}", e);
         panic!("connection with database failure")
     });
     if !exist {
-        db.execute(
+        let tx = db.transaction().unwrap_or_else(|e| {
+            error!(target: "error", "failed to start book transaction: {}", e);
+            panic!("connection with database failure")
+        });
+        tx.execute(
             "INSERT INTO books (title, author, price) VALUES (?1, ?2, ?3)",
             [&title, &author, &format!("{}", price)],
         )
         .expect("expected to be able to insert into Books table");
+        webhooks::emit(
+            &tx,
+            EventType::BookCreated,
+            json!({ "book_id": tx.last_insert_rowid(), "title": title, "author": author, "price": price }),
+        );
+        tx.commit().unwrap_or_else(|e| {
+            error!(target: "error", "failed to commit book transaction: {}", e);
+            panic!("connection with database failure")
+        });
         info!(target: "info", "book created: {} by {} for {}", title, author, price);
         Ok(())
     } else {
@@ -69,6 +86,116 @@ pub fn get_book_price(bid: i64) -> Result<f64, String> {
     }
 }
 
+pub fn set_price(bid: i64, price: f64) -> Result<(), String> {
+    //! carts show the new price from now on, orders already placed keep theirs
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start book transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let (title, author, old_price): (String, String, f64) = match tx
+        .query_row(
+            "SELECT title, author, price FROM books WHERE id = ?1",
+            [&bid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .expect("expected to be able to select from Books table")
+    {
+        Some(b) => b,
+        None => {
+            warn!(target: "warn", "failed to set book price: {}", bid);
+            return Err("bid does not exist in database".to_string());
+        }
+    };
+    if old_price == price {
+        return Ok(());
+    }
+    tx.execute(
+        "UPDATE books SET price = ?1 WHERE id = ?2",
+        rusqlite::params![price, bid],
+    )
+    .expect("expected to be able to update Books table");
+    webhooks::emit(
+        &tx,
+        EventType::BookPriceChanged,
+        json!({
+            "book_id": bid,
+            "title": title,
+            "author": author,
+            "old_price": old_price,
+            "price": price
+        }),
+    );
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit book transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "price of book {} changed from {} to {}", bid, old_price, price);
+    Ok(())
+}
+
+pub fn set_stock(bid: i64, stock: Option<i64>) -> Result<(), String> {
+    //! `None` stops tracking the book's stock
+    let db = connect();
+    let exist = exists_id(bid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if exist {
+        db.execute(
+            "UPDATE books SET stock = ?1 WHERE id = ?2",
+            rusqlite::params![stock, bid],
+        )
+        .expect("expected to be able to update Books table");
+        info!(target: "info", "stock of book {} set to {:?}", bid, stock);
+        Ok(())
+    } else {
+        warn!(target: "warn", "failed to set book stock: {}", bid);
+        Err("bid does not exist in database".to_string())
+    }
+}
+
+pub fn set_dimensions(bid: i64, dimensions: [Option<i64>; 4]) -> Result<(), String> {
+    //! weight in grams, then length, width and height in millimetres
+    let db = connect();
+    let exist = exists_id(bid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if exist {
+        let [weight, length, width, height] = dimensions;
+        db.execute(
+            "UPDATE books SET weight = ?1, length = ?2, width = ?3, height = ?4 WHERE id = ?5",
+            rusqlite::params![weight, length, width, height, bid],
+        )
+        .expect("expected to be able to update Books table");
+        info!(target: "info", "dimensions of book {} set to {:?}", bid, dimensions);
+        Ok(())
+    } else {
+        warn!(target: "warn", "failed to set book dimensions: {}", bid);
+        Err("bid does not exist in database".to_string())
+    }
+}
+
+pub fn set_tax_category(bid: i64, category: TaxCategory) -> Result<(), String> {
+    let db = connect();
+    let updated = db
+        .execute(
+            "UPDATE books SET taxCategory = ?1 WHERE id = ?2",
+            rusqlite::params![category.as_str(), bid],
+        )
+        .expect("expected to be able to update Books table");
+    if updated == 0 {
+        warn!(target: "warn", "failed to set book tax category: {}", bid);
+        return Err("bid does not exist in database".to_string());
+    }
+    info!(target: "info", "tax category of book {} set to {}", bid, category.as_str());
+    Ok(())
+}
+
 fn exists(title: String, author: String) -> Result<bool, rusqlite::Error> {
     //! checks if requested item exists in database
     let conn = connect();
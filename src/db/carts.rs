@@ -0,0 +1,587 @@
+use super::books;
+use super::db::connect;
+use super::purchaseOrders::{self, OrderError, OrderSummary, ShippingChoice};
+use crate::auth::{hash_token, new_token};
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+/// the same limits as an order placed directly
+const MAX_ITEMS: i64 = 50;
+const MAX_QUANTITY: i64 = 100;
+
+/// whose cart a request is for, anonymous sessions are known by their token
+pub enum Owner {
+    Customer(i64),
+    Session(String),
+}
+
+impl Owner {
+    fn describe(&self) -> String {
+        //! for logs, never includes the token
+        match self {
+            Owner::Customer(cid) => format!("customer {}", cid),
+            Owner::Session(_) => "anonymous session".to_string(),
+        }
+    }
+}
+
+pub enum CheckoutError {
+    /// the cart's prices or stock changed since the customer last saw it
+    Changed(String),
+    Order(OrderError),
+}
+
+impl From<OrderError> for CheckoutError {
+    fn from(e: OrderError) -> Self {
+        CheckoutError::Order(e)
+    }
+}
+
+impl From<String> for CheckoutError {
+    fn from(e: String) -> Self {
+        CheckoutError::Order(OrderError::Rejected(e))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CartItem {
+    pub book_id: i64,
+    pub title: String,
+    pub quantity: i64,
+    pub unit_price: f64,
+    pub line_total: f64,
+    /// the price the cart showed before, set when the book's price has changed since
+    pub previous_price: Option<f64>,
+    /// copies in stock, `None` when the book's stock isn't tracked
+    pub stock: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Cart {
+    pub customer_id: Option<i64>,
+    pub items: Vec<CartItem>,
+    pub subtotal: f64,
+}
+
+pub fn new_session() -> String {
+    //! starts an empty anonymous cart and returns its token, only a hash is stored
+    let db = connect();
+    let token = new_token();
+    db.execute(
+        "INSERT INTO Carts (tokenHash) VALUES (?1)",
+        [&hash_token(&token)],
+    )
+    .expect("expected to be able to insert into Carts table");
+    info!(target: "info", "anonymous cart {} started", db.last_insert_rowid());
+    token
+}
+
+pub fn view(owner: &Owner) -> Result<Cart, String> {
+    //! the cart at the books' current prices. Changed prices are flagged once and
+    //! then remembered as seen
+    let db = connect();
+    let (cart, rows) = match find(&db, owner)? {
+        Some(id) => (id, items(&db, id)),
+        None => (0, Vec::new()),
+    };
+    let mut view = Vec::with_capacity(rows.len());
+    for (bid, quantity, seen) in rows {
+        let price = books::get_book_price(bid)?;
+        let (title, stock): (String, Option<i64>) = db
+            .query_row(
+                "SELECT title, stock FROM Books WHERE id = ?1",
+                [&bid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("expected to be able to select from Books table");
+        let previous_price = if price != seen {
+            db.execute(
+                "UPDATE CartItems SET price = ?1 WHERE cartId = ?2 AND bookId = ?3",
+                params![price, cart, bid],
+            )
+            .expect("expected to be able to update CartItems table");
+            info!(target: "info", "price of book {} in cart {} changed from {:.2} to {:.2}",
+                bid, cart, seen, price);
+            Some(seen)
+        } else {
+            None
+        };
+        view.push(CartItem {
+            book_id: bid,
+            title,
+            quantity,
+            unit_price: price,
+            line_total: (price * quantity as f64 * 100.0).round() / 100.0,
+            previous_price,
+            stock,
+        });
+    }
+    let subtotal = (view.iter().fold(0.0, |t, i| t + i.line_total) * 100.0).round() / 100.0;
+    Ok(Cart {
+        customer_id: match owner {
+            Owner::Customer(cid) => Some(*cid),
+            Owner::Session(_) => None,
+        },
+        items: view,
+        subtotal,
+    })
+}
+
+pub fn add(owner: &Owner, bid: i64, quantity: i64) -> Result<Cart, String> {
+    //! adds copies of a book, on top of those already in the cart
+    let db = connect();
+    let cart = find_or_create(&db, owner)?;
+    let current: i64 = db
+        .query_row(
+            "SELECT quantity FROM CartItems WHERE cartId = ?1 AND bookId = ?2",
+            [&cart, &bid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from CartItems table")
+        .unwrap_or(0);
+    if current == 0 {
+        let count: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM CartItems WHERE cartId = ?1",
+                [&cart],
+                |row| row.get(0),
+            )
+            .expect("expected to be able to count CartItems table");
+        if count >= MAX_ITEMS {
+            warn!(target: "warn", "cart {} is full, book {} not added", cart, bid);
+            return Err(format!("a cart can have at most {} books", MAX_ITEMS));
+        }
+    }
+    store(&db, cart, bid, current + quantity)?;
+    info!(target: "info", "{} copies of book {} added to cart {} of {}",
+        quantity, bid, cart, owner.describe());
+    view(owner)
+}
+
+pub fn set_quantity(owner: &Owner, bid: i64, quantity: i64) -> Result<Cart, String> {
+    let db = connect();
+    let cart = match find(&db, owner)? {
+        Some(c) if in_cart(&db, c, bid) => c,
+        _ => return Err(format!("bid {} is not in the cart", bid)),
+    };
+    store(&db, cart, bid, quantity)?;
+    info!(target: "info", "quantity of book {} in cart {} set to {}", bid, cart, quantity);
+    view(owner)
+}
+
+pub fn remove(owner: &Owner, bid: i64) -> Result<Cart, String> {
+    let db = connect();
+    let cart = match find(&db, owner)? {
+        Some(c) if in_cart(&db, c, bid) => c,
+        _ => return Err(format!("bid {} is not in the cart", bid)),
+    };
+    db.execute(
+        "DELETE FROM CartItems WHERE cartId = ?1 AND bookId = ?2",
+        [&cart, &bid],
+    )
+    .expect("expected to be able to delete from CartItems table");
+    touch(&db, cart);
+    info!(target: "info", "book {} removed from cart {}", bid, cart);
+    view(owner)
+}
+
+pub fn checkout(
+    owner: &Owner,
+    cid: i64,
+    redeem_points: i64,
+    coupon: Option<String>,
+    choice: ShippingChoice,
+) -> Result<OrderSummary, CheckoutError> {
+    //! places the cart as an order for `cid` and empties it in one transaction. Prices and
+    //! stock are checked again first: when a price changed since the cart was last shown
+    //! nothing is ordered and the new price counts as seen, so checking out again charges it
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start checkout transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let cart = find(&tx, owner)?;
+    let rows = cart.map(|c| items(&tx, c)).unwrap_or_default();
+    let cart = match cart {
+        Some(c) if !rows.is_empty() => c,
+        _ => {
+            warn!(target: "warn", "checkout of an empty cart by {}", owner.describe());
+            return Err("cart is empty".to_string().into());
+        }
+    };
+    let mut changed = Vec::new();
+    let mut short = Vec::new();
+    for &(bid, quantity, seen) in &rows {
+        let (price, stock): (f64, Option<i64>) = tx
+            .query_row(
+                "SELECT price, stock FROM Books WHERE id = ?1",
+                [&bid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("expected to be able to select from Books table");
+        if price != seen {
+            tx.execute(
+                "UPDATE CartItems SET price = ?1 WHERE cartId = ?2 AND bookId = ?3",
+                params![price, cart, bid],
+            )
+            .expect("expected to be able to update CartItems table");
+            changed.push(format!("bid {} from {:.2} to {:.2}", bid, seen, price));
+        }
+        if let Some(s) = stock.filter(|s| *s < quantity) {
+            short.push(format!("bid {} has {} left", bid, s));
+        }
+    }
+    if !changed.is_empty() || !short.is_empty() {
+        tx.commit().unwrap_or_else(|e| {
+            error!(target: "error", "failed to commit checkout transaction: {}", e);
+            panic!("connection with database failure")
+        });
+        let reason = [
+            (!changed.is_empty()).then(|| format!("prices changed: {}", changed.join(", "))),
+            (!short.is_empty()).then(|| format!("not enough stock: {}", short.join(", "))),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("; ");
+        warn!(target: "warn", "checkout of cart {} stopped: {}", cart, reason);
+        return Err(CheckoutError::Changed(reason));
+    }
+    let lines: Vec<(i64, i64)> = rows.iter().map(|&(bid, q, _)| (bid, q)).collect();
+    let summary = purchaseOrders::insert_order(&tx, cid, &lines, redeem_points, coupon, choice)?;
+    tx.execute("DELETE FROM CartItems WHERE cartId = ?1", [&cart])
+        .expect("expected to be able to delete from CartItems table");
+    tx.execute("DELETE FROM Carts WHERE id = ?1", [&cart])
+        .expect("expected to be able to delete from Carts table");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit checkout transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "cart {} checked out as order {} for customer {}: {} lines, charged {:.2}",
+        cart, summary.id, cid, summary.lines.len(), summary.total);
+    Ok(summary)
+}
+
+fn find(conn: &Connection, owner: &Owner) -> Result<Option<i64>, String> {
+    //! the owner's cart if there is one yet, unknown tokens and customers are errors
+    match owner {
+        Owner::Customer(cid) => {
+            let exists = conn
+                .prepare("SELECT id FROM Customers WHERE id = ?1 AND erasedAt IS NULL")
+                .expect("expected to be able to select from Customers table")
+                .exists([cid])
+                .expect("expected to be able to select from Customers table");
+            if !exists {
+                warn!(target: "warn", "cart of unknown customer: {}", cid);
+                return Err("cid does not exist in database".to_string());
+            }
+            Ok(conn
+                .query_row("SELECT id FROM Carts WHERE customerId = ?1", [cid], |row| {
+                    row.get(0)
+                })
+                .optional()
+                .expect("expected to be able to select from Carts table"))
+        }
+        Owner::Session(token) => {
+            let cart: Option<i64> = conn
+                .query_row(
+                    "SELECT id FROM Carts WHERE tokenHash = ?1",
+                    [&hash_token(token)],
+                    |row| row.get(0),
+                )
+                .optional()
+                .expect("expected to be able to select from Carts table");
+            match cart {
+                Some(c) => Ok(Some(c)),
+                None => {
+                    warn!(target: "warn", "unknown cart token");
+                    Err("cart does not exist".to_string())
+                }
+            }
+        }
+    }
+}
+
+pub fn merge(conn: &Connection, source: i64, target: i64) {
+    //! moves the source customer's cart to the target on the caller's transaction,
+    //! adding its books to the target's cart when the target already has one
+    let cart_of = |cid: i64| -> Option<i64> {
+        conn.query_row(
+            "SELECT id FROM Carts WHERE customerId = ?1",
+            [&cid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from Carts table")
+    };
+    let source_cart = match cart_of(source) {
+        Some(c) => c,
+        None => return,
+    };
+    let target_cart = match cart_of(target) {
+        Some(c) => c,
+        None => {
+            conn.execute(
+                "UPDATE Carts SET customerId = ?1 WHERE id = ?2",
+                [&target, &source_cart],
+            )
+            .expect("expected to be able to update Carts table");
+            return;
+        }
+    };
+    conn.execute(
+        "INSERT INTO CartItems (cartId, bookId, quantity, price, addedAt)
+         SELECT ?1, bookId, quantity, price, addedAt FROM CartItems WHERE cartId = ?2
+         ON CONFLICT (cartId, bookId) DO UPDATE SET quantity = MIN(quantity + excluded.quantity, ?3)",
+        [&target_cart, &source_cart, &MAX_QUANTITY],
+    )
+    .expect("expected to be able to insert into CartItems table");
+    conn.execute("DELETE FROM CartItems WHERE cartId = ?1", [&source_cart])
+        .expect("expected to be able to delete from CartItems table");
+    conn.execute("DELETE FROM Carts WHERE id = ?1", [&source_cart])
+        .expect("expected to be able to delete from Carts table");
+    touch(conn, target_cart);
+}
+
+fn find_or_create(conn: &Connection, owner: &Owner) -> Result<i64, String> {
+    //! customers get a cart the first time they add a book
+    if let Some(cart) = find(conn, owner)? {
+        return Ok(cart);
+    }
+    let cid = match owner {
+        Owner::Customer(cid) => cid,
+        Owner::Session(_) => unreachable!("find fails for unknown tokens"),
+    };
+    conn.execute("INSERT INTO Carts (customerId) VALUES (?1)", [cid])
+        .expect("expected to be able to insert into Carts table");
+    Ok(conn.last_insert_rowid())
+}
+
+fn items(conn: &Connection, cart: i64) -> Vec<(i64, i64, f64)> {
+    //! (bid, quantity, price last shown), in the order they were added
+    let mut stmt = conn
+        .prepare(
+            "SELECT bookId, quantity, price FROM CartItems WHERE cartId = ?1
+             ORDER BY addedAt, bookId",
+        )
+        .expect("expected to be able to select from CartItems table");
+    let rows = stmt
+        .query_map([&cart], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .expect("expected to be able to get items from CartItems table");
+    rows.map(|r| r.expect("problem getting cart item from database"))
+        .collect()
+}
+
+fn in_cart(conn: &Connection, cart: i64, bid: i64) -> bool {
+    conn.prepare("SELECT 1 FROM CartItems WHERE cartId = ?1 AND bookId = ?2")
+        .expect("expected to be able to select from CartItems table")
+        .exists([&cart, &bid])
+        .expect("expected to be able to select from CartItems table")
+}
+
+fn store(conn: &Connection, cart: i64, bid: i64, quantity: i64) -> Result<(), String> {
+    //! writes the item's quantity at the book's current price, which the caller then shows
+    if quantity > MAX_QUANTITY {
+        return Err(format!("quantity must be between 1 and {}", MAX_QUANTITY));
+    }
+    let book: Option<(f64, Option<i64>)> = conn
+        .query_row(
+            "SELECT price, stock FROM Books WHERE id = ?1",
+            [&bid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .expect("expected to be able to select from Books table");
+    let (price, stock) = match book {
+        Some(b) => b,
+        None => {
+            warn!(target: "warn", "cart item for unknown book: {}", bid);
+            return Err(format!("bid {} does not exist in database", bid));
+        }
+    };
+    if let Some(s) = stock.filter(|s| *s < quantity) {
+        warn!(target: "warn", "not enough stock of book {} for cart {}: {} left, {} wanted",
+            bid, cart, s, quantity);
+        return Err(format!("not enough stock of bid {}: {} left", bid, s));
+    }
+    conn.execute(
+        "INSERT INTO CartItems (cartId, bookId, quantity, price) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (cartId, bookId) DO UPDATE SET quantity = excluded.quantity",
+        params![cart, bid, quantity, price],
+    )
+    .expect("expected to be able to insert into CartItems table");
+    touch(conn, cart);
+    Ok(())
+}
+
+fn touch(conn: &Connection, cart: i64) {
+    conn.execute(
+        "UPDATE Carts SET updatedAt = datetime('now') WHERE id = ?1",
+        [&cart],
+    )
+    .expect("expected to be able to update Carts table");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ledger;
+
+    fn customer(name: &str) -> i64 {
+        crate::db::test_customer(name, 100.0)
+    }
+
+    fn quantities(cart: &Cart) -> Vec<(i64, i64)> {
+        cart.items.iter().map(|i| (i.book_id, i.quantity)).collect()
+    }
+
+    fn checkout_of(owner: &Owner, cid: i64) -> Result<OrderSummary, String> {
+        match checkout(owner, cid, 0, None, ShippingChoice::default()) {
+            Ok(summary) => Ok(summary),
+            Err(CheckoutError::Changed(e)) => Err(format!("changed: {}", e)),
+            Err(CheckoutError::Order(OrderError::Rejected(e)))
+            | Err(CheckoutError::Order(OrderError::PaymentRequired(e))) => Err(e),
+        }
+    }
+
+    fn set_price(bid: i64, price: f64) {
+        connect()
+            .execute(
+                "UPDATE Books SET price = ?1 WHERE id = ?2",
+                params![price, bid],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn adding_a_book_again_adds_to_its_quantity() {
+        let _db = crate::db::test_db();
+        let owner = Owner::Customer(customer("Ann Reader"));
+
+        add(&owner, 2, 1).unwrap();
+        let cart = add(&owner, 2, 2).unwrap();
+        assert_eq!(quantities(&cart), vec![(2, 3)]);
+        assert_eq!(cart.subtotal, 29.97);
+
+        assert_eq!(
+            add(&owner, 2, 98).unwrap_err(),
+            "quantity must be between 1 and 100"
+        );
+        assert_eq!(
+            set_quantity(&owner, 1, 1).unwrap_err(),
+            "bid 1 is not in the cart"
+        );
+        assert_eq!(
+            add(&owner, 99, 1).unwrap_err(),
+            "bid 99 does not exist in database"
+        );
+        assert!(remove(&owner, 2).unwrap().items.is_empty());
+    }
+
+    #[test]
+    fn a_changed_price_is_flagged_once() {
+        let _db = crate::db::test_db();
+        let owner = Owner::Customer(customer("Ann Reader"));
+        add(&owner, 1, 1).unwrap();
+        set_price(1, 14.99);
+
+        let item = &view(&owner).unwrap().items[0];
+        assert_eq!((item.unit_price, item.previous_price), (14.99, Some(12.99)));
+        assert_eq!(view(&owner).unwrap().items[0].previous_price, None);
+    }
+
+    #[test]
+    fn checkout_stops_when_a_price_changed_unseen() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader");
+        let owner = Owner::Customer(cid);
+        add(&owner, 1, 1).unwrap();
+        set_price(1, 14.99);
+
+        assert_eq!(
+            checkout_of(&owner, cid).unwrap_err(),
+            "changed: prices changed: bid 1 from 12.99 to 14.99"
+        );
+        assert_eq!(ledger::balance(&connect(), cid), 100.0);
+
+        // the new price now counts as seen
+        let summary = checkout_of(&owner, cid).unwrap();
+        assert_eq!(summary.subtotal, 14.99);
+        assert!(view(&owner).unwrap().items.is_empty());
+        assert_eq!(checkout_of(&owner, cid).unwrap_err(), "cart is empty");
+    }
+
+    #[test]
+    fn checkout_stops_when_stock_ran_short() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader");
+        let owner = Owner::Customer(cid);
+        connect()
+            .execute("UPDATE Books SET stock = 5 WHERE id = 3", [])
+            .unwrap();
+        add(&owner, 3, 3).unwrap();
+        assert_eq!(
+            add(&owner, 3, 3).unwrap_err(),
+            "not enough stock of bid 3: 5 left"
+        );
+        connect()
+            .execute("UPDATE Books SET stock = 2 WHERE id = 3", [])
+            .unwrap();
+
+        assert_eq!(
+            checkout_of(&owner, cid).unwrap_err(),
+            "changed: not enough stock: bid 3 has 2 left"
+        );
+        assert_eq!(quantities(&view(&owner).unwrap()), vec![(3, 3)]);
+    }
+
+    #[test]
+    fn anonymous_carts_are_known_by_their_token() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader");
+        let token = new_session();
+        let owner = Owner::Session(token.clone());
+
+        let cart = add(&owner, 4, 2).unwrap();
+        assert_eq!(cart.customer_id, None);
+        assert_eq!(
+            view(&Owner::Session(format!("{}x", token))).unwrap_err(),
+            "cart does not exist"
+        );
+
+        let summary = checkout_of(&owner, cid).unwrap();
+        assert_eq!(summary.customer_id, cid);
+        assert_eq!(summary.subtotal, 15.98);
+    }
+
+    #[test]
+    fn merging_customers_combines_their_carts() {
+        let _db = crate::db::test_db();
+        let (source, target, lone) = (
+            customer("Ann Reader"),
+            customer("Bob Reader"),
+            customer("Cat Reader"),
+        );
+        add(&Owner::Customer(source), 1, 60).unwrap();
+        add(&Owner::Customer(source), 2, 1).unwrap();
+        add(&Owner::Customer(target), 1, 50).unwrap();
+
+        merge(&connect(), source, target);
+        // capped at the most a cart can hold
+        assert_eq!(
+            quantities(&view(&Owner::Customer(target)).unwrap()),
+            vec![(1, 100), (2, 1)]
+        );
+        assert!(view(&Owner::Customer(source)).unwrap().items.is_empty());
+
+        merge(&connect(), target, lone);
+        assert_eq!(
+            quantities(&view(&Owner::Customer(lone)).unwrap()),
+            vec![(1, 100), (2, 1)]
+        );
+    }
+}
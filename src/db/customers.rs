@@ -1,7 +1,21 @@
+use super::carts;
 use super::db::connect;
+use super::ledger::{self, EntryKind};
+use super::shipping::Destination;
 use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
 
-pub fn create_customer(name: String, address: String) -> Result<i64, String> {
+#[derive(Serialize, Debug, Clone)]
+pub struct MergeSummary {
+    pub source_id: i64,
+    pub target_id: i64,
+    pub orders_moved: i64,
+    pub balance_moved: f64,
+    pub points_moved: i64,
+}
+
+pub fn create_customer(name: String, address: String, dest: Destination) -> Result<i64, String> {
     let db = connect();
     let exist = exists(name.clone()).unwrap_or_else(|e| {
         error!(target: "error", "statement exists check error: {}", e);
@@ -9,14 +23,22 @@ pub fn create_customer(name: String, address: String) -> Result<i64, String> {
     });
     if !exist {
         db.execute(
-            "INSERT INTO customers (name, shippingAddress, accountBalance) VALUES (?1, ?2, 0.0)",
-            [&name, &address],
+            "INSERT INTO customers (name, shippingAddress, country, region, postalCode)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            [
+                &name,
+                &address,
+                &dest.country,
+                &dest.region,
+                &dest.postal_code,
+            ],
         )
         .expect("expected to be able to insert into Customers table");
-        info!(target: "info", "new customer added: {}", name);
-        get_customer_id(name, address)
+        let id = get_customer_id(name, address)?;
+        info!(target: "info", "new customer added: {}", id);
+        Ok(id)
     } else {
-        warn!(target: "warn", "customer already in database: {}", name);
+        warn!(target: "warn", "customer already in database");
         Err("customer already in database".to_string())
     }
 }
@@ -40,52 +62,50 @@ pub fn get_customer_id(name: String, address: String) -> Result<i64, String> {
             .expect("problem getting cid from database");
         Ok(id)
     } else {
-        warn!(target: "warn", "failed to get cid: {}", name);
+        warn!(target: "warn", "failed to get cid, no customer with that name");
         Err("customer does not exist in database".to_string())
     }
 }
 
-pub fn get_customer_address(cid: i64) -> Result<String, String> {
+pub fn update_customer_address(cid: i64, address: String) -> Result<(), String> {
     let db = connect();
     let exist = exists_id(cid).unwrap_or_else(|e| {
         error!(target: "error", "statement exists check error: {}", e);
         panic!("connection with database failure")
     });
     if exist {
-        let mut stmt = db
-            .prepare("SELECT shippingAddress FROM customers WHERE id = ?1")
-            .expect("expected to be able to select from Customers table");
-        let mut rows = stmt
-            .query_map([&cid], |row| row.get(0))
-            .expect("expected to be able to get shippingAddress from Customers table");
-        let address = rows
-            .next()
-            .expect("expected a value in the row")
-            .expect("problem getting address from database");
-        Ok(address)
+        let updated = db
+            .execute(
+                "UPDATE customers SET shippingAddress = ?1 WHERE id = ?2 AND erasedAt IS NULL",
+                [&address, &cid.to_string()],
+            )
+            .expect("expected to be able to update Customers table");
+        if updated == 0 {
+            warn!(target: "warn", "address update for erased customer: {}", cid);
+            return Err("customer has been erased".to_string());
+        }
+        Ok(())
     } else {
         warn!(target: "warn", "failed to get customer address: {}", cid);
         Err("cid does not exist in database".to_string())
     }
 }
 
-pub fn update_customer_address(cid: i64, address: String) -> Result<(), String> {
+pub fn set_destination(cid: i64, dest: Destination) -> Result<(), String> {
+    //! the country, region and postal code that pick the customer's shipping zone and taxes
     let db = connect();
-    let exist = exists_id(cid).unwrap_or_else(|e| {
-        error!(target: "error", "statement exists check error: {}", e);
-        panic!("connection with database failure")
-    });
-    if exist {
-        db.execute(
-            "UPDATE customers SET shippingAddress = ?1 WHERE id = ?2",
-            [&address, &cid.to_string()],
+    let updated = db
+        .execute(
+            "UPDATE customers SET country = ?1, region = ?2, postalCode = ?3
+             WHERE id = ?4 AND erasedAt IS NULL",
+            params![dest.country, dest.region, dest.postal_code, cid],
         )
         .expect("expected to be able to update Customers table");
-        Ok(())
-    } else {
-        warn!(target: "warn", "failed to get customer address: {}", cid);
-        Err("cid does not exist in database".to_string())
+    if updated == 0 {
+        warn!(target: "warn", "destination update for unknown or erased customer: {}", cid);
+        return Err("cid does not exist in database".to_string());
     }
+    Ok(())
 }
 
 pub fn customer_balance(cid: i64) -> Result<f64, String> {
@@ -95,23 +115,188 @@ pub fn customer_balance(cid: i64) -> Result<f64, String> {
         panic!("connection with database failure")
     });
     if exist {
-        let mut stmt = db
-            .prepare("SELECT accountBalance FROM customers WHERE id = ?1")
-            .expect("expected to be able to select from Customers table");
-        let mut rows = stmt
-            .query_map([&cid], |row| row.get(0))
-            .expect("expected to be able to get accountBalance from Customers table");
-        let balance = rows
-            .next()
-            .expect("expected a value in the row")
-            .expect("problem getting balance from database");
-        Ok(balance)
+        Ok(ledger::balance(&db, cid))
     } else {
         warn!(target: "warn", "failed to get customer balance: {}", cid);
         Err("cid does not exist in database".to_string())
     }
 }
 
+pub fn resolve_id(cid: i64) -> i64 {
+    //! follows merge redirects so ids of merged customers still resolve
+    let db = connect();
+    let current = resolve(&db, cid);
+    if current != cid {
+        info!(target: "info", "customer {} redirected to {}", cid, current);
+    }
+    current
+}
+
+fn resolve(conn: &Connection, cid: i64) -> i64 {
+    let mut current = cid;
+    // a merge never targets a merged customer, the bound only guards against bad data
+    for _ in 0..16 {
+        let next: Option<i64> = conn
+            .query_row(
+                "SELECT mergedInto FROM Customers WHERE id = ?1",
+                [&current],
+                |row| row.get(0),
+            )
+            .optional()
+            .expect("expected to be able to select from Customers table")
+            .flatten();
+        match next {
+            Some(target) => current = target,
+            None => break,
+        }
+    }
+    current
+}
+
+pub fn merge_customers(
+    source: i64,
+    target: i64,
+    merged_by: String,
+    reason: String,
+) -> Result<MergeSummary, String> {
+    //! moves orders, balance, loyalty points, redemptions, cart and addresses from source to
+    //! target in one transaction, records the merge and leaves source redirecting to target
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start merge transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    // checked on the transaction so a concurrent merge can't slip in between
+    if resolve(&tx, source) != source {
+        warn!(target: "warn", "customer merge of already merged customer rejected: {}", source);
+        return Err(format!("cid {} has already been merged", source));
+    }
+    let target = resolve(&tx, target);
+    if source == target {
+        warn!(target: "warn", "customer merge into itself rejected: {}", source);
+        return Err("source and target are the same customer".to_string());
+    }
+    for cid in [source, target] {
+        let erased_at: Option<Option<String>> = tx
+            .query_row(
+                "SELECT erasedAt FROM Customers WHERE id = ?1",
+                [&cid],
+                |row| row.get(0),
+            )
+            .optional()
+            .expect("expected to be able to select from Customers table");
+        match erased_at {
+            None => {
+                warn!(target: "warn", "customer merge with unknown cid: {}", cid);
+                return Err(format!("cid {} does not exist in database", cid));
+            }
+            // erased accounts keep only their ledger, nothing is merged into or out of them
+            Some(Some(_)) => {
+                warn!(target: "warn", "customer merge with erased cid: {}", cid);
+                return Err(format!("cid {} has been erased", cid));
+            }
+            Some(None) => {}
+        }
+    }
+    let orders_moved = tx
+        .execute(
+            "UPDATE Orders SET customerId = ?1 WHERE customerId = ?2",
+            [&target, &source],
+        )
+        .expect("expected to be able to update Orders table") as i64;
+    // the ledger is append-only, so the balance moves as a pair of adjustments
+    let balance = ledger::balance(&tx, source);
+    if balance.abs() >= 0.01 {
+        let memo = format!("merged customer {} into {}", source, target);
+        ledger::insert_entry(
+            &tx,
+            source,
+            EntryKind::Adjustment,
+            -balance,
+            None,
+            Some(memo.clone()),
+        );
+        ledger::insert_entry(
+            &tx,
+            target,
+            EntryKind::Adjustment,
+            balance,
+            None,
+            Some(memo),
+        );
+    }
+    let points_moved: i64 = tx
+        .query_row(
+            "SELECT COALESCE(SUM(remaining), 0) FROM LoyaltyPointLots
+             WHERE customerId = ?1 AND expiresAt > datetime('now')",
+            [&source],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LoyaltyPointLots table");
+    tx.execute(
+        "UPDATE LoyaltyPointLots SET customerId = ?1 WHERE customerId = ?2",
+        [&target, &source],
+    )
+    .expect("expected to be able to update LoyaltyPointLots table");
+    tx.execute(
+        "UPDATE LoyaltyTransactions SET customerId = ?1 WHERE customerId = ?2",
+        [&target, &source],
+    )
+    .expect("expected to be able to update LoyaltyTransactions table");
+    // per-customer coupon limits count redemptions, so they follow the customer
+    for table in ["GiftCardRedemptions", "CouponRedemptions", "Rmas"] {
+        tx.execute(
+            &format!("UPDATE {} SET customerId = ?1 WHERE customerId = ?2", table),
+            [&target, &source],
+        )
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to move {} of merged customer: {}", table, e);
+            panic!("connection with database failure")
+        });
+    }
+    carts::merge(&tx, source, target);
+    tx.execute(
+        "UPDATE CustomerAddresses SET customerId = ?1 WHERE customerId = ?2",
+        [&target, &source],
+    )
+    .expect("expected to be able to update CustomerAddresses table");
+    // keep the source's shipping address unless the target already knows it
+    tx.execute(
+        "INSERT INTO CustomerAddresses (customerId, address)
+         SELECT ?1, shippingAddress FROM Customers WHERE id = ?2
+         AND shippingAddress NOT IN (SELECT shippingAddress FROM Customers WHERE id = ?1)
+         AND shippingAddress NOT IN (SELECT address FROM CustomerAddresses WHERE customerId = ?1)",
+        [&target, &source],
+    )
+    .expect("expected to be able to insert into CustomerAddresses table");
+    tx.execute(
+        "UPDATE Customers SET mergedInto = ?1 WHERE id = ?2",
+        [&target, &source],
+    )
+    .expect("expected to be able to update Customers table");
+    tx.execute(
+        "INSERT INTO CustomerMerges (sourceId, targetId, mergedBy, reason, ordersMoved, balanceMoved, pointsMoved)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![source, target, merged_by, reason, orders_moved, balance, points_moved],
+    )
+    .expect("expected to be able to insert into CustomerMerges table");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit merge transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "customer {} merged into {} by {}: {} orders, {:.2} balance, {} points",
+        source, target, merged_by, orders_moved, balance, points_moved);
+    Ok(MergeSummary {
+        source_id: source,
+        target_id: target,
+        orders_moved,
+        balance_moved: balance,
+        points_moved,
+    })
+}
+
 fn exists(name: String) -> Result<bool, rusqlite::Error> {
     //! checks that customer exists
     let conn = connect();
@@ -122,7 +307,7 @@ fn exists(name: String) -> Result<bool, rusqlite::Error> {
     Ok(check)
 }
 
-fn exists_id(cid: i64) -> Result<bool, rusqlite::Error> {
+pub(crate) fn exists_id(cid: i64) -> Result<bool, rusqlite::Error> {
     //! same functionality as exists() but when only cid is provided
     let conn = connect();
     let check = conn
@@ -131,3 +316,102 @@ fn exists_id(cid: i64) -> Result<bool, rusqlite::Error> {
         .exists([&cid])?;
     Ok(check)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{create_order, ShippingChoice};
+
+    fn customer(name: &str, address: &str, deposit: f64) -> i64 {
+        let cid = crate::db::test_customer(name, deposit);
+        update_customer_address(cid, address.to_string()).unwrap();
+        cid
+    }
+
+    fn merge(source: i64, target: i64) -> Result<MergeSummary, String> {
+        merge_customers(source, target, "ada".to_string(), "duplicate".to_string())
+    }
+
+    fn count(sql: &str, cid: i64) -> i64 {
+        connect().query_row(sql, [&cid], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn merging_moves_orders_balance_and_addresses() {
+        let _db = crate::db::test_db();
+        let source = customer("Ann Reader", "1 Main St", 50.0);
+        let target = customer("Ann B. Reader", "2 Side St", 5.0);
+        create_order(source, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap();
+
+        let summary = merge(source, target).unwrap();
+        assert_eq!(summary.orders_moved, 1);
+        assert_eq!(summary.balance_moved, 32.08);
+        assert_eq!(customer_balance(source), Ok(0.0));
+        assert_eq!(customer_balance(target), Ok(37.08));
+        assert_eq!(
+            count("SELECT COUNT(*) FROM Orders WHERE customerId = ?1", target),
+            1
+        );
+        // the source's address is kept as one of the target's
+        assert_eq!(
+            count(
+                "SELECT COUNT(*) FROM CustomerAddresses WHERE customerId = ?1 AND address = '1 Main St'",
+                target
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn merged_ids_redirect_to_the_surviving_customer() {
+        let _db = crate::db::test_db();
+        let a = customer("Ann Reader", "1 Main St", 0.0);
+        let b = customer("Ann B. Reader", "2 Side St", 0.0);
+        let c = customer("A. Reader", "3 High St", 0.0);
+
+        merge(a, b).unwrap();
+        assert_eq!(resolve_id(a), b);
+        // a merged target is followed to where it went
+        assert_eq!(merge(c, a).unwrap().target_id, b);
+        assert_eq!(resolve_id(c), b);
+        assert_eq!(resolve_id(b), b);
+    }
+
+    #[test]
+    fn merges_that_make_no_sense_are_refused() {
+        let _db = crate::db::test_db();
+        let a = customer("Ann Reader", "1 Main St", 0.0);
+        let b = customer("Ann B. Reader", "2 Side St", 0.0);
+
+        assert!(merge(a, a).is_err());
+        assert!(merge(a, 99).is_err());
+        merge(a, b).unwrap();
+        assert!(merge(a, b).is_err());
+        assert!(merge(b, a).is_err());
+        assert_eq!(
+            count("SELECT COUNT(*) FROM CustomerMerges WHERE sourceId = ?1", a),
+            1
+        );
+    }
+
+    #[test]
+    fn redemptions_follow_the_merged_customer() {
+        let _db = crate::db::test_db();
+        let source = customer("Ann Reader", "1 Main St", 0.0);
+        let target = customer("Ann B. Reader", "2 Side St", 0.0);
+        let (_, code) = crate::db::gift_cards::issue_card(10.0, None, "ada".to_string()).unwrap();
+        crate::db::gift_cards::redeem_card(source, code, None).unwrap();
+
+        merge(source, target).unwrap();
+        assert_eq!(
+            count(
+                "SELECT COUNT(*) FROM GiftCardRedemptions WHERE customerId = ?1",
+                target
+            ),
+            1
+        );
+        assert_eq!(customer_balance(target), Ok(10.0));
+    }
+}
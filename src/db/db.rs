@@ -1,15 +1,91 @@
 // use rocket::log::private::info;
 use log::{error, info};
 use rusqlite::Connection;
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{env, fs, path::Path};
 
+#[cfg(test)]
+thread_local! {
+    /// the database of the test running on this thread
+    static TEST_DB: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[cfg(not(test))]
+fn db_path() -> String {
+    "dd.db".to_string()
+}
+
+#[cfg(test)]
+fn db_path() -> String {
+    TEST_DB
+        .with(|p| p.borrow().clone())
+        .expect("tests should open their database with db::test_db()")
+}
+
+/// a fresh database for one test, removed when dropped
+#[cfg(test)]
+pub struct TestDb(String);
+
+#[cfg(test)]
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        TEST_DB.with(|p| p.borrow_mut().take());
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+pub fn test_db() -> TestDb {
+    //! points `connect` on this thread at a new database built from init.sql
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir()
+        .join(format!(
+            "bookshop-test-{}-{}.db",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ))
+        .to_string_lossy()
+        .into_owned();
+    let _ = fs::remove_file(&path);
+    TEST_DB.with(|p| *p.borrow_mut() = Some(path.clone()));
+    TestDb(path)
+}
+
+#[cfg(test)]
+pub fn test_customer(name: &str, deposit: f64) -> i64 {
+    //! a customer at 1 Main St, San Francisco with `deposit` on their balance. Orders of a
+    //! few books ship there for 3.99 and are taxed 7.25%
+    use super::customers;
+    use super::ledger::{self, EntryKind};
+    use super::shipping::Destination;
+
+    let cid = customers::create_customer(
+        name.to_string(),
+        "1 Main St".to_string(),
+        Destination {
+            country: "US".to_string(),
+            region: "CA".to_string(),
+            postal_code: "94105".to_string(),
+        },
+    )
+    .expect("new customer");
+    if deposit > 0.0 {
+        ledger::post_entry(cid, EntryKind::Deposit, deposit, None, None).unwrap();
+    }
+    cid
+}
+
 pub fn connect() -> Connection {
+    let path = db_path();
     let mut must_initialize_db = false;
-    if !Path::new("dd.db").exists() {
+    if !Path::new(&path).exists() {
         must_initialize_db = true;
     }
 
-    let connection = Connection::open("dd.db").unwrap_or_else(|e| {
+    let connection = Connection::open(&path).unwrap_or_else(|e| {
         error!(target: "error", "failed to open database: {}", e);
         panic!("database access error")
     });
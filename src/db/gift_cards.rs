@@ -0,0 +1,275 @@
+use super::db::connect;
+use super::ledger::{self, EntryKind};
+use crate::auth::hash_token;
+use log::{error, info, warn};
+use rand::Rng;
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+/// Crockford base32, without the easily confused I, L, O and U
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// random characters in a code, followed by one check character
+const CODE_BODY_LEN: usize = 15;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LiabilityReport {
+    pub active_cards: i64,
+    pub outstanding_liability: f64,
+    pub expired_cards: i64,
+    pub expired_unredeemed: f64,
+}
+
+pub fn generate_code() -> String {
+    //! 15 random characters plus a Luhn mod 32 check character, grouped as XXXX-XXXX-XXXX-XXXX
+    let mut rng = rand::thread_rng();
+    let mut code: Vec<u8> = (0..CODE_BODY_LEN)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())])
+        .collect();
+    code.push(check_character(&code));
+    code.chunks(4)
+        .map(|c| String::from_utf8_lossy(c).to_string())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+pub fn normalize_code(code: &str) -> Result<String, String> {
+    //! strips separators, fixes case and common misreadings, then verifies the check character
+    let code: Vec<u8> = code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| match c.to_ascii_uppercase() {
+            'O' => b'0',
+            'I' | 'L' => b'1',
+            c => c as u8,
+        })
+        .collect();
+    if code.len() != CODE_BODY_LEN + 1 || !code.iter().all(|c| ALPHABET.contains(c)) {
+        return Err("gift card code is malformed".to_string());
+    }
+    if check_character(&code[..CODE_BODY_LEN]) != code[CODE_BODY_LEN] {
+        return Err("gift card code is invalid".to_string());
+    }
+    Ok(String::from_utf8_lossy(&code).to_string())
+}
+
+fn check_character(body: &[u8]) -> u8 {
+    //! Luhn mod N over the base32 alphabet
+    let n = ALPHABET.len();
+    let mut factor = 2;
+    let mut sum = 0;
+    for c in body.iter().rev() {
+        let index = ALPHABET
+            .iter()
+            .position(|a| a == c)
+            .expect("code characters come from the alphabet");
+        let addend = factor * index;
+        sum += addend / n + addend % n;
+        factor = if factor == 2 { 1 } else { 2 };
+    }
+    ALPHABET[(n - sum % n) % n]
+}
+
+pub fn issue_card(
+    value: f64,
+    expires_in_days: Option<i64>,
+    issued_by: String,
+) -> Result<(i64, String), String> {
+    //! returns the card id and its code, the code can't be recovered later
+    let db = connect();
+    let code = generate_code();
+    let normalized = normalize_code(&code)?;
+    let expiry = expires_in_days.map(|days| format!("+{} days", days));
+    db.execute(
+        "INSERT INTO GiftCards (codeHash, codeLast4, initialValue, issuedBy, expiresAt)
+         VALUES (?1, ?2, ?3, ?4, CASE WHEN ?5 IS NULL THEN NULL ELSE datetime('now', ?5) END)",
+        params![
+            hash_token(&normalized),
+            &normalized[CODE_BODY_LEN - 3..],
+            value,
+            issued_by,
+            expiry
+        ],
+    )
+    .expect("expected to be able to insert into GiftCards table");
+    let id = db.last_insert_rowid();
+    info!(target: "info", "gift card {} issued by {} for {:.2}", id, issued_by, value);
+    Ok((id, code))
+}
+
+pub fn redeem_card(cid: i64, code: String, amount: Option<f64>) -> Result<f64, String> {
+    //! moves up to `amount` (default: everything left) from the card into the
+    //! customer's balance as store credit, returns the amount redeemed
+    let code = normalize_code(&code).inspect_err(|e| {
+        warn!(target: "warn", "gift card redemption for customer {} rejected: {}", cid, e);
+    })?;
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start redemption transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let customer = tx
+        .query_row("SELECT id FROM Customers WHERE id = ?1", [&cid], |row| {
+            row.get::<_, i64>(0)
+        })
+        .optional()
+        .expect("expected to be able to select from Customers table");
+    if customer.is_none() {
+        warn!(target: "warn", "gift card redemption for unknown customer: {}", cid);
+        return Err("cid does not exist in database".to_string());
+    }
+    let card: Option<(i64, String, f64, bool)> = tx
+        .query_row(
+            "SELECT id, codeLast4, initialValue - COALESCE(
+                 (SELECT SUM(amount) FROM GiftCardRedemptions WHERE giftCardId = GiftCards.id), 0.0),
+                 expiresAt IS NOT NULL AND expiresAt <= datetime('now')
+             FROM GiftCards WHERE codeHash = ?1",
+            [&hash_token(&code)],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .expect("expected to be able to select from GiftCards table");
+    let (gid, last4, remaining, expired) = match card {
+        Some(c) => c,
+        None => {
+            warn!(target: "warn", "unknown gift card code redeemed for customer {}", cid);
+            return Err("gift card does not exist".to_string());
+        }
+    };
+    if expired {
+        warn!(target: "warn", "expired gift card {} redeemed for customer {}", gid, cid);
+        return Err("gift card has expired".to_string());
+    }
+    let remaining = (remaining * 100.0).round() / 100.0;
+    if remaining < 0.01 {
+        return Err("gift card has no value left".to_string());
+    }
+    let amount = amount.unwrap_or(remaining).min(remaining);
+    let eid = ledger::insert_entry(
+        &tx,
+        cid,
+        EntryKind::StoreCredit,
+        amount,
+        None,
+        Some(format!("gift card ending {}", last4)),
+    );
+    tx.execute(
+        "INSERT INTO GiftCardRedemptions (giftCardId, customerId, amount, ledgerEntryId) VALUES (?1, ?2, ?3, ?4)",
+        params![gid, cid, amount, eid],
+    )
+    .expect("expected to be able to insert into GiftCardRedemptions table");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit redemption transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "gift card {} redeemed by customer {} for {:.2}", gid, cid, amount);
+    Ok(amount)
+}
+
+pub fn liability_report() -> LiabilityReport {
+    //! value left on cards that can still be redeemed, and on cards that expired unused
+    let db = connect();
+    let totals = |expired: bool| -> (i64, f64) {
+        db.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(remaining), 0.0) FROM (
+                 SELECT initialValue - COALESCE(
+                     (SELECT SUM(amount) FROM GiftCardRedemptions WHERE giftCardId = GiftCards.id), 0.0) AS remaining
+                 FROM GiftCards
+                 WHERE (expiresAt IS NOT NULL AND expiresAt <= datetime('now')) = ?1
+             ) WHERE remaining >= 0.005",
+            [&expired],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("expected to be able to sum GiftCards table")
+    };
+    let (active_cards, outstanding) = totals(false);
+    let (expired_cards, expired_unredeemed) = totals(true);
+    LiabilityReport {
+        active_cards,
+        outstanding_liability: (outstanding * 100.0).round() / 100.0,
+        expired_cards,
+        expired_unredeemed: (expired_unredeemed * 100.0).round() / 100.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer() -> i64 {
+        crate::db::test_customer("Ann Reader", 0.0)
+    }
+
+    #[test]
+    fn check_character_is_luhn_mod_32() {
+        // 1 doubled is 2, 32 - 2 = 30 is Y
+        assert_eq!(check_character(b"000000000000001"), b'Y');
+        // Z (31) doubled is 62, its base 32 digits add up to 1 + 30 = 31, 32 - 31 = 1
+        assert_eq!(check_character(b"00000000000000Z"), b'1');
+        // characters in odd positions from the right aren't doubled
+        assert_eq!(check_character(b"0000000000000Z0"), b'1');
+        assert_eq!(check_character(b"000000000000000"), b'0');
+    }
+
+    #[test]
+    fn generated_codes_are_grouped_and_valid() {
+        for _ in 0..50 {
+            let code = generate_code();
+            assert_eq!(code.len(), 19);
+            assert!(code.split('-').all(|group| group.len() == 4));
+            assert_eq!(normalize_code(&code).unwrap(), code.replace('-', ""));
+        }
+    }
+
+    #[test]
+    fn every_single_character_error_is_caught() {
+        let code = normalize_code(&generate_code()).unwrap().into_bytes();
+        for i in 0..code.len() {
+            for c in ALPHABET.iter().filter(|c| **c != code[i]) {
+                let mut typo = code.clone();
+                typo[i] = *c;
+                assert!(normalize_code(&String::from_utf8(typo).unwrap()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn codes_are_normalized_before_checking() {
+        let body = "0O1IL0000000000";
+        let check = check_character(b"001110000000000") as char;
+        assert_eq!(
+            normalize_code(&format!("{} {}", body.to_lowercase(), check)).unwrap(),
+            format!("001110000000000{}", check)
+        );
+        assert!(normalize_code("0000-0000").is_err());
+        assert!(normalize_code("0000-0000-0000-000U").is_err());
+    }
+
+    #[test]
+    fn redemptions_move_what_is_left_into_the_balance() {
+        let _db = crate::db::test_db();
+        let cid = customer();
+        let (_, code) = issue_card(25.0, None, "ada".to_string()).unwrap();
+
+        assert_eq!(redeem_card(cid, code.clone(), Some(10.0)), Ok(10.0));
+        assert_eq!(liability_report().outstanding_liability, 15.0);
+        // more than is left takes what is left
+        assert_eq!(redeem_card(cid, code.to_lowercase(), Some(40.0)), Ok(15.0));
+        assert!(redeem_card(cid, code, None).is_err());
+        assert_eq!(ledger::balance(&connect(), cid), 25.0);
+        assert_eq!(liability_report().active_cards, 0);
+    }
+
+    #[test]
+    fn expired_cards_are_refused_and_reported() {
+        let _db = crate::db::test_db();
+        let cid = customer();
+        let (_, code) = issue_card(25.0, Some(0), "ada".to_string()).unwrap();
+
+        assert!(redeem_card(cid, code, None).is_err());
+        let report = liability_report();
+        assert_eq!(report.expired_cards, 1);
+        assert_eq!(report.expired_unredeemed, 25.0);
+    }
+}
@@ -0,0 +1,164 @@
+use super::db::connect;
+use crate::config;
+use log::{error, info, warn};
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+
+/// a response stored for replay
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// what to do with a request that carries an Idempotency-Key
+pub enum Claim {
+    /// first use of the key, the response is to be stored with [`complete`]
+    New,
+    Replay(StoredResponse),
+    /// the first request with the key hasn't finished yet
+    InProgress,
+    /// the key was used before for a different request
+    Mismatch,
+}
+
+pub fn claim(key: &str, fingerprint: &str) -> Claim {
+    //! looks the key up and reserves it when it is new, expired keys are removed first
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start idempotency transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    tx.execute(
+        "DELETE FROM IdempotencyKeys WHERE createdAt < datetime('now', ?1)",
+        [&format!("-{} hours", config::idempotency_key_hours())],
+    )
+    .expect("expected to be able to delete from IdempotencyKeys table");
+    // the response is missing while the first request is in progress
+    let stored: Option<(String, Option<StoredResponse>)> = tx
+        .query_row(
+            "SELECT fingerprint, status, contentType, body FROM IdempotencyKeys WHERE key = ?1",
+            [key],
+            |row| {
+                let status: Option<u16> = row.get(1)?;
+                Ok((
+                    row.get(0)?,
+                    match status {
+                        Some(status) => Some(StoredResponse {
+                            status,
+                            content_type: row.get(2)?,
+                            body: row.get::<_, Option<Vec<u8>>>(3)?.unwrap_or_default(),
+                        }),
+                        None => None,
+                    },
+                ))
+            },
+        )
+        .optional()
+        .expect("expected to be able to select from IdempotencyKeys table");
+    let claim = match stored {
+        None => {
+            tx.execute(
+                "INSERT INTO IdempotencyKeys (key, fingerprint) VALUES (?1, ?2)",
+                [key, fingerprint],
+            )
+            .expect("expected to be able to insert into IdempotencyKeys table");
+            Claim::New
+        }
+        Some((f, _)) if f != fingerprint => {
+            warn!(target: "warn", "idempotency key {} reused for a different request", key);
+            Claim::Mismatch
+        }
+        Some((_, None)) => {
+            warn!(target: "warn", "idempotency key {} retried while in progress", key);
+            Claim::InProgress
+        }
+        Some((_, Some(response))) => {
+            info!(target: "info", "idempotency key {} replayed", key);
+            Claim::Replay(response)
+        }
+    };
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit idempotency transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    claim
+}
+
+pub fn complete(key: &str, response: &StoredResponse) {
+    let db = connect();
+    db.execute(
+        "UPDATE IdempotencyKeys SET status = ?1, contentType = ?2, body = ?3 WHERE key = ?4",
+        params![response.status, response.content_type, response.body, key],
+    )
+    .expect("expected to be able to update IdempotencyKeys table");
+}
+
+pub fn release(key: &str) {
+    //! forgets a key whose request failed, so the client can retry it
+    let db = connect();
+    db.execute("DELETE FROM IdempotencyKeys WHERE key = ?1", [key])
+        .expect("expected to be able to delete from IdempotencyKeys table");
+    info!(target: "info", "idempotency key {} released after a failed request", key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &str) -> StoredResponse {
+        StoredResponse {
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn a_completed_key_is_replayed_for_the_same_request() {
+        let _db = crate::db::test_db();
+
+        assert!(matches!(claim("k1", "order a"), Claim::New));
+        assert!(matches!(claim("k1", "order a"), Claim::InProgress));
+        complete("k1", &response("{\"id\":1}"));
+
+        match claim("k1", "order a") {
+            Claim::Replay(stored) => {
+                assert_eq!(stored.status, 200);
+                assert_eq!(stored.content_type.as_deref(), Some("application/json"));
+                assert_eq!(stored.body, b"{\"id\":1}");
+            }
+            _ => panic!("expected a replay"),
+        }
+    }
+
+    #[test]
+    fn a_key_reused_for_another_request_is_a_mismatch() {
+        let _db = crate::db::test_db();
+
+        assert!(matches!(claim("k1", "order a"), Claim::New));
+        assert!(matches!(claim("k1", "order b"), Claim::Mismatch));
+        complete("k1", &response("{}"));
+        assert!(matches!(claim("k1", "order b"), Claim::Mismatch));
+        assert!(matches!(claim("k2", "order b"), Claim::New));
+    }
+
+    #[test]
+    fn released_and_expired_keys_can_be_used_again() {
+        let _db = crate::db::test_db();
+
+        assert!(matches!(claim("k1", "order a"), Claim::New));
+        release("k1");
+        assert!(matches!(claim("k1", "order b"), Claim::New));
+
+        complete("k1", &response("{}"));
+        connect()
+            .execute(
+                "UPDATE IdempotencyKeys SET createdAt = datetime('now', '-1000 hours')",
+                [],
+            )
+            .unwrap();
+        assert!(matches!(claim("k1", "order c"), Claim::New));
+    }
+}
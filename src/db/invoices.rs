@@ -0,0 +1,512 @@
+use super::db::connect;
+use crate::config;
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentKind {
+    Invoice,
+    CreditNote,
+}
+
+impl DocumentKind {
+    pub fn parse(kind: &str) -> Option<DocumentKind> {
+        match kind {
+            "invoice" => Some(DocumentKind::Invoice),
+            "credit_note" => Some(DocumentKind::CreditNote),
+            _ => None,
+        }
+    }
+
+    fn prefix(&self) -> &'static str {
+        //! the numbering series
+        match self {
+            DocumentKind::Invoice => "INV",
+            DocumentKind::CreditNote => "CN",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InvoiceLine {
+    pub book_id: i64,
+    pub description: String,
+    pub quantity: i64,
+    pub unit_price: f64,
+    pub line_total: f64,
+    pub tax: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Invoice {
+    pub kind: DocumentKind,
+    pub number: String,
+    pub fiscal_year: i64,
+    pub order_id: i64,
+    /// the number of the invoice a credit note refers to
+    pub credited_invoice: Option<String>,
+    pub customer_id: i64,
+    pub billing_name: String,
+    pub billing_address: String,
+    pub country: String,
+    pub region: String,
+    pub postal_code: String,
+    pub lines: Vec<InvoiceLine>,
+    pub subtotal: f64,
+    pub discount: f64,
+    pub shipping: f64,
+    pub tax: f64,
+    pub tax_inclusive: bool,
+    pub total: f64,
+    pub reason: Option<String>,
+    pub issued_at: String,
+}
+
+pub fn issue(conn: &Connection, oid: i64) -> String {
+    //! invoices a paid order on the caller's transaction, copying its lines, amounts and
+    //! the customer's billing details as they are now. Returns the invoice number
+    let (fiscal_year, number) = next_number(conn, DocumentKind::Invoice);
+    conn.execute(
+        "INSERT INTO Invoices (kind, number, fiscalYear, orderId, customerId, billingName,
+             billingAddress, country, region, postalCode, subtotal, discount, shipping, tax,
+             taxInclusive, total)
+         SELECT 'invoice', ?1, ?2, o.id, o.customerId, c.name, o.shippingAddress, c.country,
+             c.region, c.postalCode, o.subtotal, o.discount, o.shipping, o.tax, o.taxInclusive,
+             o.total
+         FROM Orders o JOIN Customers c ON c.id = o.customerId WHERE o.id = ?3",
+        params![number, fiscal_year, oid],
+    )
+    .expect("expected to be able to insert into Invoices table");
+    let id = conn.last_insert_rowid();
+    conn.execute(
+        "INSERT INTO InvoiceLines (invoiceId, bookId, description, quantity, unitPrice, tax)
+         SELECT ?1, l.bookId, b.title || ' by ' || b.author, l.quantity, l.unitPrice, l.tax
+         FROM OrderLines l JOIN Books b ON b.id = l.bookId WHERE l.orderId = ?2 ORDER BY l.id",
+        [&id, &oid],
+    )
+    .expect("expected to be able to insert into InvoiceLines table");
+    info!(target: "info", "invoice {} issued for order {}", number, oid);
+    number
+}
+
+pub fn credit(
+    conn: &Connection,
+    oid: i64,
+    refunded: f64,
+    reason: &str,
+    returned: Option<&[(i64, i64)]>,
+) -> Option<String> {
+    //! issues a credit note against the order's invoice for a refund, on the caller's
+    //! transaction. `returned` (bid, quantity) copies are credited, or everything not yet
+    //! credited, shipping included, when the whole order is refunded. What the refund
+    //! doesn't cover of the lines, like their share of the order's discount, shows as discount
+    if refunded < 0.01 {
+        return None;
+    }
+    let invoice = match find(conn, "kind = 'invoice' AND orderId = ?1", [&oid]) {
+        Some(i) => i,
+        None => {
+            warn!(target: "warn", "refund on order {} without an invoice, no credit note issued", oid);
+            return None;
+        }
+    };
+    let invoice_id: i64 = conn
+        .query_row(
+            "SELECT id FROM Invoices WHERE number = ?1",
+            [&invoice.number],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to select from Invoices table");
+    let mut lines = Vec::new();
+    let mut line_discount = 0.0;
+    for line in &invoice.lines {
+        let credited: i64 = conn
+            .query_row(
+                "SELECT COALESCE(SUM(l.quantity), 0) FROM InvoiceLines l
+                 JOIN Invoices i ON i.id = l.invoiceId
+                 WHERE i.creditedInvoiceId = ?1 AND l.bookId = ?2",
+                [&invoice_id, &line.book_id],
+                |row| row.get(0),
+            )
+            .expect("expected to be able to sum InvoiceLines table");
+        let left = line.quantity - credited;
+        let quantity = match returned {
+            Some(r) => r
+                .iter()
+                .find(|(bid, _)| *bid == line.book_id)
+                .map_or(0, |(_, q)| *q)
+                .min(left),
+            None => left,
+        };
+        if quantity > 0 {
+            line_discount += conn
+                .query_row(
+                    "SELECT COALESCE(SUM(discount * ?1 / quantity), 0.0) FROM OrderLines
+                     WHERE orderId = ?2 AND bookId = ?3",
+                    params![quantity, oid, line.book_id],
+                    |row| row.get::<_, f64>(0),
+                )
+                .expect("expected to be able to select from OrderLines table");
+            lines.push(InvoiceLine {
+                book_id: line.book_id,
+                description: line.description.clone(),
+                quantity,
+                unit_price: line.unit_price,
+                line_total: (line.unit_price * quantity as f64 * 100.0).round() / 100.0,
+                tax: (line.tax * quantity as f64 / line.quantity as f64 * 100.0).round() / 100.0,
+            });
+        }
+    }
+    let shipping = match returned {
+        Some(_) => 0.0,
+        None => {
+            let credited: f64 = conn
+                .query_row(
+                    "SELECT COALESCE(SUM(shipping), 0.0) FROM Invoices WHERE creditedInvoiceId = ?1",
+                    [&invoice_id],
+                    |row| row.get(0),
+                )
+                .expect("expected to be able to sum Invoices table");
+            (invoice.shipping - credited).max(0.0)
+        }
+    };
+    let subtotal = (lines.iter().fold(0.0, |t, l| t + l.line_total) * 100.0).round() / 100.0;
+    let tax = (lines.iter().fold(0.0, |t, l| t + l.tax).min(refunded) * 100.0).round() / 100.0;
+    let added_tax = if invoice.tax_inclusive { 0.0 } else { tax };
+    // the credited copies' share of the order's discount, or more when the refund was capped
+    // at what was left of the charge
+    let uncovered = subtotal + shipping + added_tax - refunded;
+    let discount = (line_discount.max(uncovered).max(0.0) * 100.0).round() / 100.0;
+
+    let (fiscal_year, number) = next_number(conn, DocumentKind::CreditNote);
+    conn.execute(
+        "INSERT INTO Invoices (kind, number, fiscalYear, orderId, creditedInvoiceId, customerId,
+             billingName, billingAddress, country, region, postalCode, subtotal, discount,
+             shipping, tax, taxInclusive, total, reason)
+         SELECT 'credit_note', ?1, ?2, orderId, id, customerId, billingName, billingAddress,
+             country, region, postalCode, ?3, ?4, ?5, ?6, taxInclusive, ?7, ?8
+         FROM Invoices WHERE id = ?9",
+        params![
+            number,
+            fiscal_year,
+            subtotal,
+            discount,
+            shipping,
+            tax,
+            refunded,
+            reason,
+            invoice_id
+        ],
+    )
+    .expect("expected to be able to insert into Invoices table");
+    let id = conn.last_insert_rowid();
+    for line in &lines {
+        conn.execute(
+            "INSERT INTO InvoiceLines (invoiceId, bookId, description, quantity, unitPrice, tax)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id,
+                line.book_id,
+                line.description,
+                line.quantity,
+                line.unit_price,
+                line.tax
+            ],
+        )
+        .expect("expected to be able to insert into InvoiceLines table");
+    }
+    info!(target: "info", "credit note {} for {:.2} issued against invoice {}",
+        number, refunded, invoice.number);
+    Some(number)
+}
+
+pub fn get(number: &str, cid: i64) -> Result<Invoice, String> {
+    //! `cid` has to be the customer of the invoiced order
+    let db = connect();
+    match find(
+        &db,
+        "number = ?1 AND orderId IN (SELECT id FROM Orders WHERE customerId = ?2)",
+        params![number, cid],
+    ) {
+        Some(i) => Ok(i),
+        None => {
+            warn!(target: "warn", "unknown invoice {} for customer {}", number, cid);
+            Err("invoice does not exist in database".to_string())
+        }
+    }
+}
+
+pub fn for_order(oid: i64, cid: i64) -> Result<Vec<Invoice>, String> {
+    //! the order's invoice followed by its credit notes
+    let db = connect();
+    let exists = db
+        .prepare("SELECT id FROM Orders WHERE id = ?1 AND customerId = ?2")
+        .expect("expected to be able to select from Orders table")
+        .exists([&oid, &cid])
+        .expect("expected to be able to select from Orders table");
+    if !exists {
+        warn!(target: "warn", "invoices of unknown order {} for customer {}", oid, cid);
+        return Err("order does not exist in database".to_string());
+    }
+    Ok(select(&db, "orderId = ?1", params![oid]))
+}
+
+pub fn of_customers(conn: &Connection, cids: &[i64]) -> Vec<Invoice> {
+    //! the invoices and credit notes billed to any of the customers or for their orders,
+    //! which moved to another customer when they were merged
+    let ids = cids
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    select(
+        conn,
+        &format!(
+            "i.customerId IN ({0}) OR i.orderId IN (SELECT id FROM Orders WHERE customerId IN ({0}))",
+            ids
+        ),
+        [],
+    )
+}
+
+pub fn list(from: &str, to: &str) -> Result<Vec<Invoice>, String> {
+    //! every invoice and credit note issued from `from` to `to` (inclusive dates), by number
+    if from > to {
+        warn!(target: "warn", "invoice listing period rejected: {} to {}", from, to);
+        return Err("from must not be after to".to_string());
+    }
+    let db = connect();
+    Ok(select(
+        &db,
+        "issuedAt >= ?1 AND issuedAt < date(?2, '+1 day')",
+        params![from, to],
+    ))
+}
+
+fn next_number(conn: &Connection, kind: DocumentKind) -> (i64, String) {
+    //! the next number in the series for the current fiscal year, e.g. INV-2024-000001
+    let fiscal_year: i64 = conn
+        .query_row(
+            "SELECT CAST(strftime('%Y', 'now') AS INTEGER)
+                 - (CAST(strftime('%m', 'now') AS INTEGER) < ?1)",
+            [&config::fiscal_year_start_month()],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to compute the fiscal year");
+    let last: i64 = conn
+        .query_row(
+            "INSERT INTO InvoiceSequences (series, fiscalYear, lastNumber) VALUES (?1, ?2, 1)
+             ON CONFLICT (series, fiscalYear) DO UPDATE SET lastNumber = lastNumber + 1
+             RETURNING lastNumber",
+            params![kind.prefix(), fiscal_year],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to update InvoiceSequences table");
+    (
+        fiscal_year,
+        format!("{}-{}-{:06}", kind.prefix(), fiscal_year, last),
+    )
+}
+
+fn find<P: rusqlite::Params>(conn: &Connection, condition: &str, params: P) -> Option<Invoice> {
+    select(conn, condition, params).pop()
+}
+
+fn select<P: rusqlite::Params>(conn: &Connection, condition: &str, params: P) -> Vec<Invoice> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT i.id, i.kind, i.number, i.fiscalYear, i.orderId,
+                 (SELECT number FROM Invoices WHERE id = i.creditedInvoiceId), i.customerId,
+                 i.billingName, i.billingAddress, i.country, i.region, i.postalCode, i.subtotal,
+                 i.discount, i.shipping, i.tax, i.taxInclusive, i.total, i.reason, i.issuedAt
+             FROM Invoices i WHERE {} ORDER BY i.id",
+            condition
+        ))
+        .expect("expected to be able to select from Invoices table");
+    let rows = stmt
+        .query_map(params, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                Invoice {
+                    kind: DocumentKind::parse(&row.get::<_, String>(1)?)
+                        .expect("invoice kind is constrained by the table"),
+                    number: row.get(2)?,
+                    fiscal_year: row.get(3)?,
+                    order_id: row.get(4)?,
+                    credited_invoice: row.get(5)?,
+                    customer_id: row.get(6)?,
+                    billing_name: row.get(7)?,
+                    billing_address: row.get(8)?,
+                    country: row.get(9)?,
+                    region: row.get(10)?,
+                    postal_code: row.get(11)?,
+                    lines: Vec::new(),
+                    subtotal: row.get(12)?,
+                    discount: row.get(13)?,
+                    shipping: row.get(14)?,
+                    tax: row.get(15)?,
+                    tax_inclusive: row.get(16)?,
+                    total: row.get(17)?,
+                    reason: row.get(18)?,
+                    issued_at: row.get(19)?,
+                },
+            ))
+        })
+        .expect("expected to be able to get invoices from Invoices table");
+    let invoices: Vec<(i64, Invoice)> = rows
+        .map(|r| r.expect("problem getting invoice from database"))
+        .collect();
+    invoices
+        .into_iter()
+        .map(|(id, mut invoice)| {
+            invoice.lines = lines(conn, id);
+            invoice
+        })
+        .collect()
+}
+
+fn lines(conn: &Connection, id: i64) -> Vec<InvoiceLine> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT bookId, description, quantity, unitPrice, tax FROM InvoiceLines
+             WHERE invoiceId = ?1 ORDER BY id",
+        )
+        .expect("expected to be able to select from InvoiceLines table");
+    let rows = stmt
+        .query_map([&id], |row| {
+            let quantity: i64 = row.get(2)?;
+            let unit_price: f64 = row.get(3)?;
+            Ok(InvoiceLine {
+                book_id: row.get(0)?,
+                description: row.get(1)?,
+                quantity,
+                unit_price,
+                line_total: (unit_price * quantity as f64 * 100.0).round() / 100.0,
+                tax: row.get(4)?,
+            })
+        })
+        .expect("expected to be able to get lines from InvoiceLines table");
+    rows.map(|r| r.expect("problem getting invoice line from database"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::customers;
+    use crate::db::ledger::{self, EntryKind};
+    use crate::db::purchaseOrders::{cancel_order, create_order, ShippingChoice};
+    use crate::db::test_customer as customer;
+
+    fn order(cid: i64, lines: &[(i64, i64)]) -> Option<(i64, String)> {
+        create_order(cid, lines, 0, None, ShippingChoice::default())
+            .ok()
+            .map(|s| (s.id, s.invoice))
+    }
+
+    fn number(kind: DocumentKind, fiscal_year: i64, n: i64) -> String {
+        format!("{}-{}-{:06}", kind.prefix(), fiscal_year, n)
+    }
+
+    #[test]
+    fn invoice_numbers_have_no_gaps() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 20.0);
+
+        let (_, first) = order(cid, &[(1, 1)]).expect("order placed");
+        // neither a refused order nor a rolled back one takes a number
+        assert!(order(cid, &[(1, 1)]).is_none());
+        {
+            let mut db = connect();
+            let tx = db.transaction().unwrap();
+            issue(&tx, 1);
+        }
+        ledger::post_entry(cid, EntryKind::Deposit, 20.0, None, None).unwrap();
+        let (_, second) = order(cid, &[(1, 1)]).expect("order placed");
+
+        let fiscal_year = get(&first, cid).unwrap().fiscal_year;
+        assert_eq!(first, number(DocumentKind::Invoice, fiscal_year, 1));
+        assert_eq!(second, number(DocumentKind::Invoice, fiscal_year, 2));
+    }
+
+    #[test]
+    fn a_cancelled_order_is_credited_in_full() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 20.0);
+        let (oid, invoice) = order(cid, &[(1, 1)]).unwrap();
+        cancel_order(oid, None, "clerk", "changed mind".to_string(), None).unwrap();
+
+        let documents = for_order(oid, cid).unwrap();
+        assert_eq!(documents.len(), 2);
+        let note = &documents[1];
+        assert_eq!(note.kind, DocumentKind::CreditNote);
+        assert_eq!(
+            note.number,
+            number(DocumentKind::CreditNote, note.fiscal_year, 1)
+        );
+        assert_eq!(note.credited_invoice.as_deref(), Some(invoice.as_str()));
+        assert_eq!(
+            (
+                note.subtotal,
+                note.shipping,
+                note.tax,
+                note.discount,
+                note.total
+            ),
+            (12.99, 3.99, 0.94, 0.0, 17.92)
+        );
+        assert_eq!(get(&invoice, cid).unwrap().total, 17.92);
+        assert!(get(&invoice, cid + 1).is_err());
+    }
+
+    #[test]
+    fn copies_are_only_credited_once() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 50.0);
+        // 32.97 of books, 6.99 shipping and 2.39 tax
+        let (oid, _) = order(cid, &[(2, 2), (1, 1)]).unwrap();
+        let db = connect();
+
+        let returned = credit(&db, oid, 13.93, "returned", Some(&[(1, 1)])).unwrap();
+        let rest = credit(&db, oid, 28.42, "cancelled", None).unwrap();
+        assert!(credit(&db, oid, 0.0, "nothing", None).is_none());
+
+        let documents = for_order(oid, cid).unwrap();
+        let returned = documents.iter().find(|d| d.number == returned).unwrap();
+        assert_eq!(returned.lines.len(), 1);
+        assert_eq!(
+            (
+                returned.subtotal,
+                returned.shipping,
+                returned.tax,
+                returned.total
+            ),
+            (12.99, 0.0, 0.94, 13.93)
+        );
+        let rest = documents.iter().find(|d| d.number == rest).unwrap();
+        assert_eq!(rest.lines.len(), 1);
+        assert_eq!(rest.lines[0].book_id, 2);
+        assert_eq!(
+            (rest.subtotal, rest.shipping, rest.tax, rest.total),
+            (19.98, 6.99, 1.45, 28.42)
+        );
+    }
+
+    #[test]
+    fn merged_customers_keep_their_invoices() {
+        let _db = crate::db::test_db();
+        let source = customer("Ann Reader", 20.0);
+        let target = customer("Ann B Reader", 20.0);
+        let (_, invoice) = order(source, &[(1, 1)]).unwrap();
+        customers::merge_customers(source, target, "admin".to_string(), "duplicate".to_string())
+            .unwrap();
+
+        let numbers: Vec<String> = of_customers(&connect(), &[target])
+            .into_iter()
+            .map(|i| i.number)
+            .collect();
+        assert_eq!(numbers, vec![invoice]);
+    }
+}
@@ -0,0 +1,233 @@
+use super::customers;
+use super::db::connect;
+use log::{error, info, warn};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Deposit,
+    OrderCharge,
+    Refund,
+    Adjustment,
+    StoreCredit,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Deposit => "deposit",
+            EntryKind::OrderCharge => "order_charge",
+            EntryKind::Refund => "refund",
+            EntryKind::Adjustment => "adjustment",
+            EntryKind::StoreCredit => "store_credit",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<EntryKind> {
+        match kind {
+            "deposit" => Some(EntryKind::Deposit),
+            "order_charge" => Some(EntryKind::OrderCharge),
+            "refund" => Some(EntryKind::Refund),
+            "adjustment" => Some(EntryKind::Adjustment),
+            "store_credit" => Some(EntryKind::StoreCredit),
+            _ => None,
+        }
+    }
+
+    pub fn signed(&self, amount: f64) -> f64 {
+        //! charges take money out of the account, adjustments keep the sign they were given
+        match self {
+            EntryKind::OrderCharge => -amount.abs(),
+            EntryKind::Adjustment => amount,
+            _ => amount.abs(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LedgerEntry {
+    pub id: i64,
+    pub kind: String,
+    pub amount: f64,
+    pub order_id: Option<i64>,
+    pub memo: Option<String>,
+    pub created_at: String,
+}
+
+pub fn post_entry(
+    cid: i64,
+    kind: EntryKind,
+    amount: f64,
+    order_id: Option<i64>,
+    memo: Option<String>,
+) -> Result<i64, String> {
+    let db = connect();
+    let exist = customers::exists_id(cid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if exist {
+        Ok(insert_entry(&db, cid, kind, amount, order_id, memo))
+    } else {
+        warn!(target: "warn", "failed to post ledger entry, cid not in database: {}", cid);
+        Err("cid does not exist in database".to_string())
+    }
+}
+
+pub fn insert_entry(
+    conn: &Connection,
+    cid: i64,
+    kind: EntryKind,
+    amount: f64,
+    order_id: Option<i64>,
+    memo: Option<String>,
+) -> i64 {
+    //! appends an entry on the given connection, so it can be part of a caller's transaction
+    let amount = kind.signed(amount);
+    conn.execute(
+        "INSERT INTO LedgerEntries (customerId, kind, amount, orderId, memo) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![cid, kind.as_str(), amount, order_id, memo],
+    )
+    .expect("expected to be able to insert into LedgerEntries table");
+    let id = conn.last_insert_rowid();
+    info!(target: "info", "ledger entry {} posted for customer {}: {} {:.2}", id, cid, kind.as_str(), amount);
+    id
+}
+
+pub fn balance(conn: &Connection, cid: i64) -> f64 {
+    //! the balance is derived from the ledger, rounded to cents
+    let sum: f64 = conn
+        .query_row(
+            "SELECT COALESCE(SUM(amount), 0.0) FROM LedgerEntries WHERE customerId = ?1",
+            [&cid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LedgerEntries table");
+    (sum * 100.0).round() / 100.0
+}
+
+pub fn list_entries(cid: i64, page: i64, per_page: i64) -> Result<(Vec<LedgerEntry>, i64), String> {
+    //! newest first, returns the page of entries and the total number of entries
+    let db = connect();
+    let exist = customers::exists_id(cid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if !exist {
+        warn!(target: "warn", "failed to list ledger entries, cid not in database: {}", cid);
+        return Err("cid does not exist in database".to_string());
+    }
+    let total: i64 = db
+        .query_row(
+            "SELECT COUNT(*) FROM LedgerEntries WHERE customerId = ?1",
+            [&cid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count LedgerEntries table");
+    let mut stmt = db
+        .prepare(
+            "SELECT id, kind, amount, orderId, memo, createdAt FROM LedgerEntries
+             WHERE customerId = ?1 ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+        )
+        .expect("expected to be able to select from LedgerEntries table");
+    let rows = stmt
+        .query_map(params![cid, per_page, (page - 1) * per_page], |row| {
+            Ok(LedgerEntry {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                amount: row.get(2)?,
+                order_id: row.get(3)?,
+                memo: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })
+        .expect("expected to be able to get entries from LedgerEntries table");
+    let entries = rows
+        .map(|r| r.expect("problem getting ledger entry from database"))
+        .collect();
+    Ok((entries, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer() -> i64 {
+        crate::db::test_customer("Ann Reader", 0.0)
+    }
+
+    #[test]
+    fn charges_are_negative_and_adjustments_keep_their_sign() {
+        assert_eq!(EntryKind::OrderCharge.signed(12.5), -12.5);
+        assert_eq!(EntryKind::OrderCharge.signed(-12.5), -12.5);
+        assert_eq!(EntryKind::Refund.signed(-3.0), 3.0);
+        assert_eq!(EntryKind::Deposit.signed(20.0), 20.0);
+        assert_eq!(EntryKind::Adjustment.signed(-4.25), -4.25);
+    }
+
+    #[test]
+    fn balance_is_the_rounded_sum_of_the_entries() {
+        let _db = crate::db::test_db();
+        let cid = customer();
+        let db = connect();
+        assert_eq!(balance(&db, cid), 0.0);
+
+        post_entry(cid, EntryKind::Deposit, 50.0, None, None).unwrap();
+        post_entry(cid, EntryKind::OrderCharge, 17.92, Some(1), None).unwrap();
+        post_entry(cid, EntryKind::Refund, 5.1, Some(1), None).unwrap();
+        post_entry(
+            cid,
+            EntryKind::Adjustment,
+            -0.1,
+            None,
+            Some("rounding".to_string()),
+        )
+        .unwrap();
+        assert_eq!(balance(&db, cid), 37.08);
+    }
+
+    #[test]
+    fn entries_are_append_only() {
+        let _db = crate::db::test_db();
+        let cid = customer();
+        let id = post_entry(cid, EntryKind::Deposit, 10.0, None, None).unwrap();
+        let db = connect();
+
+        assert!(db
+            .execute(
+                "UPDATE LedgerEntries SET amount = 1000 WHERE id = ?1",
+                [&id]
+            )
+            .is_err());
+        assert!(db
+            .execute("DELETE FROM LedgerEntries WHERE id = ?1", [&id])
+            .is_err());
+        assert_eq!(balance(&db, cid), 10.0);
+    }
+
+    #[test]
+    fn entries_are_listed_newest_first_by_page() {
+        let _db = crate::db::test_db();
+        let cid = customer();
+        for amount in [1.0, 2.0, 3.0] {
+            post_entry(cid, EntryKind::Deposit, amount, None, None).unwrap();
+        }
+
+        let (page, total) = list_entries(cid, 1, 2).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(
+            page.iter().map(|e| e.amount).collect::<Vec<f64>>(),
+            [3.0, 2.0]
+        );
+        let (page, _) = list_entries(cid, 2, 2).unwrap();
+        assert_eq!(page[0].amount, 1.0);
+    }
+
+    #[test]
+    fn unknown_customers_are_rejected() {
+        let _db = crate::db::test_db();
+        assert!(post_entry(42, EntryKind::Deposit, 10.0, None, None).is_err());
+        assert!(list_entries(42, 1, 20).is_err());
+    }
+}
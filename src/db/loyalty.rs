@@ -0,0 +1,398 @@
+use super::customers;
+use super::db::connect;
+use crate::config;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Bronze,
+    Silver,
+    Gold,
+}
+
+impl Tier {
+    pub fn for_spend(spend: f64) -> Tier {
+        //! tiers follow the net spend of the last 12 months
+        if spend >= 500.0 {
+            Tier::Gold
+        } else if spend >= 100.0 {
+            Tier::Silver
+        } else {
+            Tier::Bronze
+        }
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        match self {
+            Tier::Bronze => 1.0,
+            Tier::Silver => 1.25,
+            Tier::Gold => 1.5,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LoyaltySummary {
+    pub points: i64,
+    pub point_value: f64,
+    pub tier: Tier,
+    pub spend_12_months: f64,
+    pub expiring_in_30_days: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LoyaltyTransaction {
+    pub id: i64,
+    pub kind: String,
+    pub points: i64,
+    pub order_id: Option<i64>,
+    pub created_at: String,
+}
+
+pub fn earn_for_order(conn: &Connection, cid: i64, poid: i64) -> i64 {
+    //! credits points for a shipped order based on what was charged for it,
+    //! called from `ship_po` on its connection
+    let charged: f64 = conn
+        .query_row(
+            "SELECT COALESCE(-SUM(amount), 0.0) FROM LedgerEntries
+             WHERE orderId = ?1 AND kind IN ('order_charge', 'refund')",
+            [&poid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LedgerEntries table");
+    let tier = Tier::for_spend(spend_12_months(conn, cid));
+    let points =
+        (charged.max(0.0) * config::loyalty_points_per_unit() * tier.multiplier()).floor() as i64;
+    if points <= 0 {
+        return 0;
+    }
+    let expiry = format!("+{} days", config::loyalty_points_expiry_days());
+    conn.execute(
+        "INSERT INTO LoyaltyPointLots (customerId, orderId, points, remaining, expiresAt)
+         VALUES (?1, ?2, ?3, ?3, datetime('now', ?4))",
+        params![cid, poid, points, expiry],
+    )
+    .expect("expected to be able to insert into LoyaltyPointLots table");
+    record(conn, cid, "earn", points, Some(poid));
+    info!(target: "info", "customer {} earned {} points for order {}", cid, points, poid);
+    points
+}
+
+pub fn redeem(conn: &Connection, cid: i64, points: i64, poid: i64) -> Result<(), String> {
+    //! takes points from the oldest unexpired lots first, on the caller's transaction
+    expire_points(conn, cid);
+    if points > available_points(conn, cid) {
+        warn!(target: "warn", "customer {} tried to redeem more points than available: {}", cid, points);
+        return Err("not enough loyalty points".to_string());
+    }
+    let mut left = points;
+    while left > 0 {
+        let (lot, remaining): (i64, i64) = conn
+            .query_row(
+                "SELECT id, remaining FROM LoyaltyPointLots
+                 WHERE customerId = ?1 AND remaining > 0 ORDER BY expiresAt, id LIMIT 1",
+                [&cid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("expected to be able to select from LoyaltyPointLots table");
+        let used = remaining.min(left);
+        conn.execute(
+            "UPDATE LoyaltyPointLots SET remaining = remaining - ?1 WHERE id = ?2",
+            [used, lot],
+        )
+        .expect("expected to be able to update LoyaltyPointLots table");
+        left -= used;
+    }
+    record(conn, cid, "redeem", -points, Some(poid));
+    info!(target: "info", "customer {} redeemed {} points on order {}", cid, points, poid);
+    Ok(())
+}
+
+pub fn reverse_order(conn: &Connection, cid: i64, oid: i64) -> i64 {
+    //! for a cancelled order: takes back what is left of the points it earned and
+    //! gives back the points redeemed on it as a new lot, returns the points given back
+    // earned points that were already spent can't be taken back
+    let earned: i64 = conn
+        .query_row(
+            "SELECT COALESCE(SUM(remaining), 0) FROM LoyaltyPointLots
+             WHERE customerId = ?1 AND orderId = ?2",
+            [&cid, &oid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LoyaltyPointLots table");
+    if earned > 0 {
+        conn.execute(
+            "UPDATE LoyaltyPointLots SET remaining = 0 WHERE customerId = ?1 AND orderId = ?2",
+            [&cid, &oid],
+        )
+        .expect("expected to be able to update LoyaltyPointLots table");
+        record(conn, cid, "revoke", -earned, Some(oid));
+    }
+    let redeemed: i64 = conn
+        .query_row(
+            "SELECT COALESCE(-SUM(points), 0) FROM LoyaltyTransactions
+             WHERE customerId = ?1 AND orderId = ?2 AND kind = 'redeem'",
+            [&cid, &oid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LoyaltyTransactions table");
+    if redeemed > 0 {
+        let expiry = format!("+{} days", config::loyalty_points_expiry_days());
+        conn.execute(
+            "INSERT INTO LoyaltyPointLots (customerId, orderId, points, remaining, expiresAt)
+             VALUES (?1, NULL, ?2, ?2, datetime('now', ?3))",
+            params![cid, redeemed, expiry],
+        )
+        .expect("expected to be able to insert into LoyaltyPointLots table");
+        record(conn, cid, "restore", redeemed, Some(oid));
+    }
+    if earned > 0 || redeemed > 0 {
+        info!(target: "info", "order {} reversed for customer {}: {} points revoked, {} restored",
+            oid, cid, earned, redeemed);
+    }
+    redeemed
+}
+
+pub fn summary(cid: i64) -> Result<LoyaltySummary, String> {
+    let db = connect();
+    let exist = customers::exists_id(cid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if !exist {
+        warn!(target: "warn", "failed to get loyalty summary, cid not in database: {}", cid);
+        return Err("cid does not exist in database".to_string());
+    }
+    expire_points(&db, cid);
+    let spend = spend_12_months(&db, cid);
+    let expiring: i64 = db
+        .query_row(
+            "SELECT COALESCE(SUM(remaining), 0) FROM LoyaltyPointLots
+             WHERE customerId = ?1 AND expiresAt <= datetime('now', '+30 days')",
+            [&cid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LoyaltyPointLots table");
+    Ok(LoyaltySummary {
+        points: available_points(&db, cid),
+        point_value: config::loyalty_point_value(),
+        tier: Tier::for_spend(spend),
+        spend_12_months: spend,
+        expiring_in_30_days: expiring,
+    })
+}
+
+pub fn history(
+    cid: i64,
+    page: i64,
+    per_page: i64,
+) -> Result<(Vec<LoyaltyTransaction>, i64), String> {
+    //! newest first, returns the page of transactions and the total number of transactions
+    let db = connect();
+    let exist = customers::exists_id(cid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if !exist {
+        warn!(target: "warn", "failed to get loyalty history, cid not in database: {}", cid);
+        return Err("cid does not exist in database".to_string());
+    }
+    expire_points(&db, cid);
+    let total: i64 = db
+        .query_row(
+            "SELECT COUNT(*) FROM LoyaltyTransactions WHERE customerId = ?1",
+            [&cid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count LoyaltyTransactions table");
+    let mut stmt = db
+        .prepare(
+            "SELECT id, kind, points, orderId, createdAt FROM LoyaltyTransactions
+             WHERE customerId = ?1 ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+        )
+        .expect("expected to be able to select from LoyaltyTransactions table");
+    let rows = stmt
+        .query_map(params![cid, per_page, (page - 1) * per_page], |row| {
+            Ok(LoyaltyTransaction {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                points: row.get(2)?,
+                order_id: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })
+        .expect("expected to be able to get transactions from LoyaltyTransactions table");
+    let transactions = rows
+        .map(|r| r.expect("problem getting loyalty transaction from database"))
+        .collect();
+    Ok((transactions, total))
+}
+
+fn available_points(conn: &Connection, cid: i64) -> i64 {
+    conn.query_row(
+        "SELECT COALESCE(SUM(remaining), 0) FROM LoyaltyPointLots
+         WHERE customerId = ?1 AND expiresAt > datetime('now')",
+        [&cid],
+        |row| row.get(0),
+    )
+    .expect("expected to be able to sum LoyaltyPointLots table")
+}
+
+fn expire_points(conn: &Connection, cid: i64) {
+    //! zeroes lots past their expiry and records what was lost in the history
+    let expired: Option<i64> = conn
+        .query_row(
+            "SELECT SUM(remaining) FROM LoyaltyPointLots
+             WHERE customerId = ?1 AND remaining > 0 AND expiresAt <= datetime('now')",
+            [&cid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to sum LoyaltyPointLots table")
+        .flatten();
+    if let Some(points) = expired.filter(|p| *p > 0) {
+        conn.execute(
+            "UPDATE LoyaltyPointLots SET remaining = 0
+             WHERE customerId = ?1 AND remaining > 0 AND expiresAt <= datetime('now')",
+            [&cid],
+        )
+        .expect("expected to be able to update LoyaltyPointLots table");
+        record(conn, cid, "expire", -points, None);
+        info!(target: "info", "{} loyalty points expired for customer {}", points, cid);
+    }
+}
+
+fn spend_12_months(conn: &Connection, cid: i64) -> f64 {
+    //! order charges net of refunds over the last 12 months, on orders that belong
+    //! to the customer now, including those of customers merged into it
+    let spend: f64 = conn
+        .query_row(
+            "SELECT COALESCE(-SUM(amount), 0.0) FROM LedgerEntries
+             WHERE orderId IN (SELECT id FROM Orders WHERE customerId = ?1)
+             AND kind IN ('order_charge', 'refund') AND createdAt >= datetime('now', '-12 months')",
+            [&cid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LedgerEntries table");
+    (spend.max(0.0) * 100.0).round() / 100.0
+}
+
+fn record(conn: &Connection, cid: i64, kind: &str, points: i64, poid: Option<i64>) {
+    conn.execute(
+        "INSERT INTO LoyaltyTransactions (customerId, kind, points, orderId) VALUES (?1, ?2, ?3, ?4)",
+        params![cid, kind, points, poid],
+    )
+    .expect("expected to be able to insert into LoyaltyTransactions table");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{create_order, ShippingChoice};
+
+    fn customer(deposit: f64) -> i64 {
+        crate::db::test_customer("Ann Reader", deposit)
+    }
+
+    fn lot(conn: &Connection, cid: i64, points: i64, expiry: &str) {
+        conn.execute(
+            "INSERT INTO LoyaltyPointLots (customerId, orderId, points, remaining, expiresAt)
+             VALUES (?1, NULL, ?2, ?2, datetime('now', ?3))",
+            params![cid, points, expiry],
+        )
+        .unwrap();
+    }
+
+    fn remaining(conn: &Connection, cid: i64) -> Vec<i64> {
+        let mut stmt = conn
+            .prepare("SELECT remaining FROM LoyaltyPointLots WHERE customerId = ?1 ORDER BY id")
+            .unwrap();
+        let rows = stmt.query_map([&cid], |row| row.get(0)).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn tiers_follow_the_spend() {
+        assert_eq!(Tier::for_spend(99.99), Tier::Bronze);
+        assert_eq!(Tier::for_spend(100.0), Tier::Silver);
+        assert_eq!(Tier::for_spend(499.99), Tier::Silver);
+        assert_eq!(Tier::for_spend(500.0), Tier::Gold);
+        assert_eq!(Tier::Silver.multiplier(), 1.25);
+    }
+
+    #[test]
+    fn shipped_orders_earn_a_point_per_unit_charged_by_tier() {
+        let _db = crate::db::test_db();
+        let cid = customer(1000.0);
+        let db = connect();
+        let small = create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id;
+        // 17.92 charged, bronze
+        assert_eq!(earn_for_order(&db, cid, small), 17);
+
+        let large = create_order(cid, &[(1, 10)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap();
+        // 129.90 + 9.42 tax, free shipping over 35, silver with 157.24 spent
+        assert_eq!(large.total, 139.32);
+        assert_eq!(earn_for_order(&db, cid, large.id), 174);
+        assert_eq!(summary(cid).unwrap().points, 191);
+        assert_eq!(summary(cid).unwrap().tier, Tier::Silver);
+    }
+
+    #[test]
+    fn redemptions_use_the_oldest_lots_first() {
+        let _db = crate::db::test_db();
+        let cid = customer(0.01);
+        let db = connect();
+        lot(&db, cid, 50, "+300 days");
+        lot(&db, cid, 30, "+10 days");
+
+        redeem(&db, cid, 40, 1).unwrap();
+        assert_eq!(remaining(&db, cid), [40, 0]);
+        assert!(redeem(&db, cid, 41, 1).is_err());
+        assert_eq!(remaining(&db, cid), [40, 0]);
+    }
+
+    #[test]
+    fn expired_points_are_written_off() {
+        let _db = crate::db::test_db();
+        let cid = customer(0.01);
+        let db = connect();
+        lot(&db, cid, 25, "-1 days");
+        lot(&db, cid, 10, "+20 days");
+
+        let summary = summary(cid).unwrap();
+        assert_eq!(summary.points, 10);
+        assert_eq!(summary.expiring_in_30_days, 10);
+        let (history, _) = history(cid, 1, 20).unwrap();
+        assert_eq!(history[0].kind, "expire");
+        assert_eq!(history[0].points, -25);
+        assert!(redeem(&db, cid, 11, 1).is_err());
+    }
+
+    #[test]
+    fn points_redeemed_on_an_order_take_money_off_and_come_back_on_reversal() {
+        let _db = crate::db::test_db();
+        let cid = customer(100.0);
+        let db = connect();
+        lot(&db, cid, 150, "+300 days");
+
+        let order = create_order(cid, &[(1, 1)], 150, None, ShippingChoice::default())
+            .ok()
+            .unwrap();
+        // 1.50 off the book, 0.83 tax on the 11.49 left
+        assert_eq!(order.discount, 1.5);
+        assert_eq!(order.total, 16.31);
+        assert_eq!(available_points(&db, cid), 0);
+
+        earn_for_order(&db, cid, order.id);
+        assert_eq!(reverse_order(&db, cid, order.id), 150);
+        assert_eq!(available_points(&db, cid), 150);
+    }
+}
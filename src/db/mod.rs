@@ -1,4 +1,26 @@
+pub mod api_keys;
 pub mod books;
+pub mod carts;
 pub mod customers;
+// db/db.rs and purchaseOrders predate the lint gate and keep their names
+#[allow(clippy::module_inception)]
 mod db;
+#[cfg(test)]
+pub use db::{connect, test_customer, test_db};
+pub mod gift_cards;
+pub mod idempotency;
+pub mod invoices;
+pub mod ledger;
+pub mod loyalty;
+pub mod order_state;
+pub mod picking;
+pub mod privacy;
+pub mod promotions;
+#[allow(non_snake_case)]
 pub mod purchaseOrders;
+pub mod returns;
+pub mod shipments;
+pub mod shipping;
+pub mod staff;
+pub mod tax;
+pub mod webhooks;
@@ -0,0 +1,296 @@
+use super::db::connect;
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderState {
+    Pending,
+    Paid,
+    Picking,
+    Shipped,
+    Delivered,
+    Cancelled,
+    Returned,
+}
+
+impl OrderState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderState::Pending => "pending",
+            OrderState::Paid => "paid",
+            OrderState::Picking => "picking",
+            OrderState::Shipped => "shipped",
+            OrderState::Delivered => "delivered",
+            OrderState::Cancelled => "cancelled",
+            OrderState::Returned => "returned",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<OrderState> {
+        match state {
+            "pending" => Some(OrderState::Pending),
+            "paid" => Some(OrderState::Paid),
+            "picking" => Some(OrderState::Picking),
+            "shipped" => Some(OrderState::Shipped),
+            "delivered" => Some(OrderState::Delivered),
+            "cancelled" => Some(OrderState::Cancelled),
+            "returned" => Some(OrderState::Returned),
+            _ => None,
+        }
+    }
+
+    pub fn can_become(&self, next: OrderState) -> bool {
+        //! orders can be cancelled until they ship and returned once they have
+        use OrderState::*;
+        matches!(
+            (self, next),
+            (Pending, Paid)
+                | (Pending, Cancelled)
+                | (Paid, Picking)
+                | (Paid, Cancelled)
+                | (Picking, Shipped)
+                | (Picking, Cancelled)
+                | (Shipped, Delivered)
+                | (Shipped, Returned)
+                | (Delivered, Returned)
+        )
+    }
+
+    pub fn has_shipped(&self) -> bool {
+        matches!(
+            self,
+            OrderState::Shipped | OrderState::Delivered | OrderState::Returned
+        )
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderEvent {
+    pub from_state: Option<OrderState>,
+    pub to_state: OrderState,
+    pub actor: String,
+    pub note: Option<String>,
+    pub created_at: String,
+}
+
+pub fn current(conn: &Connection, oid: i64) -> Option<OrderState> {
+    conn.query_row("SELECT state FROM Orders WHERE id = ?1", [&oid], |row| {
+        row.get::<_, String>(0)
+    })
+    .optional()
+    .expect("expected to be able to select from Orders table")
+    .map(|s| OrderState::parse(&s).expect("order state is constrained by the table"))
+}
+
+pub fn record_created(conn: &Connection, oid: i64, actor: &str) {
+    //! the first event of every order, written when the order row is inserted
+    conn.execute(
+        "INSERT INTO OrderEvents (orderId, fromState, toState, actor) VALUES (?1, NULL, 'pending', ?2)",
+        params![oid, actor],
+    )
+    .expect("expected to be able to insert into OrderEvents table");
+}
+
+pub fn transition(
+    conn: &Connection,
+    oid: i64,
+    next: OrderState,
+    actor: &str,
+    note: Option<String>,
+) -> Result<OrderState, String> {
+    //! moves the order to `next` if the state machine allows it and records the event,
+    //! on the caller's connection so it can be part of a transaction; returns the old state
+    let state = match current(conn, oid) {
+        Some(s) => s,
+        None => {
+            warn!(target: "warn", "state change for unknown order: {}", oid);
+            return Err("order does not exist in database".to_string());
+        }
+    };
+    if !state.can_become(next) {
+        warn!(target: "warn", "illegal state change for order {}: {} to {}",
+            oid, state.as_str(), next.as_str());
+        return Err(format!(
+            "order is {}, it can't become {}",
+            state.as_str(),
+            next.as_str()
+        ));
+    }
+    apply(conn, oid, state, next, actor, note);
+    Ok(state)
+}
+
+pub fn force(
+    conn: &Connection,
+    oid: i64,
+    next: OrderState,
+    actor: &str,
+    note: Option<String>,
+) -> Result<OrderState, String> {
+    //! staff override of the state machine, only cancelled and returned orders can't be changed
+    let state = match current(conn, oid) {
+        Some(s) => s,
+        None => {
+            warn!(target: "warn", "state change for unknown order: {}", oid);
+            return Err("order does not exist in database".to_string());
+        }
+    };
+    if matches!(state, OrderState::Cancelled | OrderState::Returned) {
+        return Err(format!("order is {}, it can't be changed", state.as_str()));
+    }
+    warn!(target: "warn", "state machine overridden for order {}: {} to {} by {}",
+        oid, state.as_str(), next.as_str(), actor);
+    apply(conn, oid, state, next, actor, note);
+    Ok(state)
+}
+
+fn apply(
+    conn: &Connection,
+    oid: i64,
+    state: OrderState,
+    next: OrderState,
+    actor: &str,
+    note: Option<String>,
+) {
+    conn.execute(
+        "UPDATE Orders SET state = ?1 WHERE id = ?2 AND state = ?3",
+        params![next.as_str(), oid, state.as_str()],
+    )
+    .expect("expected to be able to update Orders table");
+    conn.execute(
+        "INSERT INTO OrderEvents (orderId, fromState, toState, actor, note) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![oid, state.as_str(), next.as_str(), actor, note],
+    )
+    .expect("expected to be able to insert into OrderEvents table");
+    info!(target: "info", "order {} changed from {} to {} by {}",
+        oid, state.as_str(), next.as_str(), actor);
+}
+
+pub fn events(oid: i64) -> Result<Vec<OrderEvent>, String> {
+    //! oldest first
+    let db = connect();
+    if current(&db, oid).is_none() {
+        warn!(target: "warn", "events requested for unknown order: {}", oid);
+        return Err("order does not exist in database".to_string());
+    }
+    let mut stmt = db
+        .prepare(
+            "SELECT fromState, toState, actor, note, createdAt FROM OrderEvents
+             WHERE orderId = ?1 ORDER BY id",
+        )
+        .expect("expected to be able to select from OrderEvents table");
+    let rows = stmt
+        .query_map([&oid], |row| {
+            let from: Option<String> = row.get(0)?;
+            let to: String = row.get(1)?;
+            Ok(OrderEvent {
+                from_state: from.and_then(|s| OrderState::parse(&s)),
+                to_state: OrderState::parse(&to).expect("order state is constrained by the table"),
+                actor: row.get(2)?,
+                note: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })
+        .expect("expected to be able to get events from OrderEvents table");
+    Ok(rows
+        .map(|r| r.expect("problem getting order event from database"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{create_order, ShippingChoice};
+    use OrderState::*;
+
+    const ALL: [OrderState; 7] = [
+        Pending, Paid, Picking, Shipped, Delivered, Cancelled, Returned,
+    ];
+
+    fn paid_order() -> i64 {
+        let cid = crate::db::test_customer("Ann Reader", 50.0);
+        create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id
+    }
+
+    #[test]
+    fn only_the_lifecycle_transitions_are_allowed() {
+        let allowed = [
+            (Pending, Paid),
+            (Pending, Cancelled),
+            (Paid, Picking),
+            (Paid, Cancelled),
+            (Picking, Shipped),
+            (Picking, Cancelled),
+            (Shipped, Delivered),
+            (Shipped, Returned),
+            (Delivered, Returned),
+        ];
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    from.can_become(to),
+                    allowed.contains(&(from, to)),
+                    "{} to {}",
+                    from.as_str(),
+                    to.as_str()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn states_round_trip_through_their_names() {
+        for state in ALL {
+            assert_eq!(OrderState::parse(state.as_str()), Some(state));
+        }
+        assert_eq!(OrderState::parse("lost"), None);
+        assert!(Delivered.has_shipped() && Returned.has_shipped());
+        assert!(!Picking.has_shipped() && !Cancelled.has_shipped());
+    }
+
+    #[test]
+    fn transitions_are_recorded_in_the_history() {
+        let _db = crate::db::test_db();
+        let oid = paid_order();
+        let db = connect();
+
+        assert_eq!(transition(&db, oid, Picking, "cleo", None), Ok(Paid));
+        assert!(transition(&db, oid, Delivered, "cleo", None).is_err());
+        assert_eq!(current(&db, oid), Some(Picking));
+
+        let history = events(oid).unwrap();
+        let steps: Vec<(Option<OrderState>, OrderState)> =
+            history.iter().map(|e| (e.from_state, e.to_state)).collect();
+        assert_eq!(
+            steps,
+            [
+                (None, Pending),
+                (Some(Pending), Paid),
+                (Some(Paid), Picking)
+            ]
+        );
+        assert_eq!(history[2].actor, "cleo");
+        assert!(transition(&db, 99, Picking, "cleo", None).is_err());
+    }
+
+    #[test]
+    fn overrides_skip_steps_but_not_final_states() {
+        let _db = crate::db::test_db();
+        let oid = paid_order();
+        let db = connect();
+
+        assert_eq!(
+            force(&db, oid, Delivered, "ada", Some("lost scan".to_string())),
+            Ok(Paid)
+        );
+        assert_eq!(current(&db, oid), Some(Delivered));
+        transition(&db, oid, Returned, "ada", None).unwrap();
+        assert!(force(&db, oid, Paid, "ada", None).is_err());
+        assert_eq!(events(oid).unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,337 @@
+use super::db::connect;
+use super::order_state::{self, OrderState};
+use super::shipments::ParcelLine;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+/// every copy of a book needed for a batch
+#[derive(Serialize, Debug, Clone)]
+pub struct PickItem {
+    pub book_id: i64,
+    pub title: String,
+    pub author: String,
+    pub quantity: i64,
+    pub order_ids: Vec<i64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PickBatch {
+    pub id: i64,
+    pub created_by: String,
+    pub created_at: String,
+    pub order_ids: Vec<i64>,
+    pub items: Vec<PickItem>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PackingSlip {
+    pub order_id: i64,
+    pub customer_id: i64,
+    pub name: String,
+    pub address: String,
+    pub postal_code: String,
+    pub region: String,
+    pub country: String,
+    pub carrier: Option<String>,
+    pub service_level: Option<String>,
+    pub lines: Vec<ParcelLine>,
+}
+
+pub fn create_batch(order_ids: Option<&[i64]>, actor: &str) -> Result<PickBatch, String> {
+    //! batches the given orders, or every paid order not yet batched, and moves them to
+    //! picking in one transaction. Only paid orders can be batched
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start pick batch transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let oids = match order_ids {
+        Some(oids) => {
+            for &oid in oids {
+                match order_state::current(&tx, oid) {
+                    Some(OrderState::Paid) => {}
+                    Some(s) => {
+                        warn!(target: "warn", "order {} in state {} left out of pick batch", oid, s.as_str());
+                        return Err(format!(
+                            "order {} is {}, only paid orders can be picked",
+                            oid,
+                            s.as_str()
+                        ));
+                    }
+                    None => {
+                        return Err(format!("order {} does not exist in database", oid));
+                    }
+                }
+            }
+            oids.to_vec()
+        }
+        None => {
+            let mut stmt = tx
+                .prepare("SELECT id FROM Orders WHERE state = 'paid' ORDER BY id")
+                .expect("expected to be able to select from Orders table");
+            let rows = stmt
+                .query_map([], |row| row.get(0))
+                .expect("expected to be able to get ids from Orders table");
+            rows.map(|r| r.expect("problem getting order id from database"))
+                .collect()
+        }
+    };
+    if oids.is_empty() {
+        return Err("no paid orders to pick".to_string());
+    }
+    tx.execute("INSERT INTO PickBatches (createdBy) VALUES (?1)", [actor])
+        .expect("expected to be able to insert into PickBatches table");
+    let bid = tx.last_insert_rowid();
+    for oid in &oids {
+        tx.execute(
+            "INSERT INTO PickBatchOrders (batchId, orderId) VALUES (?1, ?2)",
+            [&bid, oid],
+        )
+        .expect("expected to be able to insert into PickBatchOrders table");
+        order_state::transition(
+            &tx,
+            *oid,
+            OrderState::Picking,
+            actor,
+            Some(format!("pick batch {}", bid)),
+        )?;
+    }
+    let batch = select_batch(&tx, bid).expect("expected the pick batch to exist");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit pick batch transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "pick batch {} of {} orders created by {}", bid, oids.len(), actor);
+    Ok(batch)
+}
+
+pub fn get_batch(bid: i64) -> Result<PickBatch, String> {
+    let db = connect();
+    select_batch(&db, bid).ok_or_else(|| {
+        warn!(target: "warn", "unknown pick batch: {}", bid);
+        "pick batch does not exist in database".to_string()
+    })
+}
+
+pub fn packing_slips(bid: i64) -> Result<Vec<PackingSlip>, String> {
+    //! one slip per order of the batch that wasn't cancelled since, by order id
+    let db = connect();
+    if select_batch(&db, bid).is_none() {
+        warn!(target: "warn", "packing slips of unknown pick batch: {}", bid);
+        return Err("pick batch does not exist in database".to_string());
+    }
+    let mut stmt = db
+        .prepare(
+            "SELECT o.id, o.customerId, c.name, o.shippingAddress, c.postalCode, c.region,
+                 c.country, o.shippingCarrier, o.shippingService
+             FROM PickBatchOrders p JOIN Orders o ON o.id = p.orderId
+             JOIN Customers c ON c.id = o.customerId
+             WHERE p.batchId = ?1 AND o.state != 'cancelled' ORDER BY o.id",
+        )
+        .expect("expected to be able to select from PickBatchOrders table");
+    let rows = stmt
+        .query_map([&bid], |row| {
+            Ok(PackingSlip {
+                order_id: row.get(0)?,
+                customer_id: row.get(1)?,
+                name: row.get(2)?,
+                address: row.get(3)?,
+                postal_code: row.get(4)?,
+                region: row.get(5)?,
+                country: row.get(6)?,
+                carrier: row.get(7)?,
+                service_level: row.get(8)?,
+                lines: Vec::new(),
+            })
+        })
+        .expect("expected to be able to get packing slips from Orders table");
+    let slips: Vec<PackingSlip> = rows
+        .map(|r| r.expect("problem getting packing slip from database"))
+        .collect();
+    Ok(slips
+        .into_iter()
+        .map(|mut slip| {
+            slip.lines = order_lines(&db, slip.order_id);
+            slip
+        })
+        .collect())
+}
+
+fn select_batch(conn: &Connection, bid: i64) -> Option<PickBatch> {
+    let (created_by, created_at): (String, String) = conn
+        .query_row(
+            "SELECT createdBy, createdAt FROM PickBatches WHERE id = ?1",
+            [&bid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .expect("expected to be able to select from PickBatches table")?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT p.orderId FROM PickBatchOrders p JOIN Orders o ON o.id = p.orderId
+             WHERE p.batchId = ?1 AND o.state != 'cancelled' ORDER BY p.orderId",
+        )
+        .expect("expected to be able to select from PickBatchOrders table");
+    let order_ids: Vec<i64> = stmt
+        .query_map([&bid], |row| row.get(0))
+        .expect("expected to be able to get orders from PickBatchOrders table")
+        .map(|r| r.expect("problem getting order id from database"))
+        .collect();
+    Some(PickBatch {
+        id: bid,
+        created_by,
+        created_at,
+        order_ids,
+        items: pick_items(conn, bid),
+    })
+}
+
+fn pick_items(conn: &Connection, bid: i64) -> Vec<PickItem> {
+    //! the batch's lines added up per book, by title
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.bookId, b.title, b.author, SUM(l.quantity), GROUP_CONCAT(DISTINCT l.orderId)
+             FROM PickBatchOrders p JOIN Orders o ON o.id = p.orderId
+             JOIN OrderLines l ON l.orderId = o.id JOIN Books b ON b.id = l.bookId
+             WHERE p.batchId = ?1 AND o.state != 'cancelled'
+             GROUP BY l.bookId ORDER BY b.title, l.bookId",
+        )
+        .expect("expected to be able to select from OrderLines table");
+    let rows = stmt
+        .query_map(params![bid], |row| {
+            let orders: String = row.get(4)?;
+            let mut order_ids: Vec<i64> =
+                orders.split(',').filter_map(|o| o.parse().ok()).collect();
+            order_ids.sort_unstable();
+            Ok(PickItem {
+                book_id: row.get(0)?,
+                title: row.get(1)?,
+                author: row.get(2)?,
+                quantity: row.get(3)?,
+                order_ids,
+            })
+        })
+        .expect("expected to be able to get pick items from OrderLines table");
+    rows.map(|r| r.expect("problem getting pick item from database"))
+        .collect()
+}
+
+fn order_lines(conn: &Connection, oid: i64) -> Vec<ParcelLine> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.bookId, b.title, l.quantity FROM OrderLines l
+             JOIN Books b ON b.id = l.bookId WHERE l.orderId = ?1 ORDER BY l.id",
+        )
+        .expect("expected to be able to select from OrderLines table");
+    let rows = stmt
+        .query_map([&oid], |row| {
+            Ok(ParcelLine {
+                book_id: row.get(0)?,
+                title: row.get(1)?,
+                quantity: row.get(2)?,
+            })
+        })
+        .expect("expected to be able to get lines from OrderLines table");
+    rows.map(|r| r.expect("problem getting order line from database"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{cancel_order, create_order, ShippingChoice};
+
+    fn order(name: &str, lines: &[(i64, i64)]) -> i64 {
+        let cid = crate::db::test_customer(name, 100.0);
+        create_order(cid, lines, 0, None, ShippingChoice::default())
+            .ok()
+            .expect("order placed")
+            .id
+    }
+
+    fn state(oid: i64) -> Option<OrderState> {
+        order_state::current(&connect(), oid)
+    }
+
+    #[test]
+    fn a_batch_adds_up_the_copies_of_every_paid_order() {
+        let _db = crate::db::test_db();
+        let ann = order("Ann Reader", &[(2, 2), (1, 1)]);
+        let bob = order("Bob Reader", &[(2, 1)]);
+
+        let batch = create_batch(None, "clerk").unwrap();
+        assert_eq!(batch.order_ids, vec![ann, bob]);
+        let items: Vec<(&str, i64, Vec<i64>)> = batch
+            .items
+            .iter()
+            .map(|i| (i.title.as_str(), i.quantity, i.order_ids.clone()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                ("Dune", 3, vec![ann, bob]),
+                ("The Hitchhikers Guide to the Galaxy", 1, vec![ann]),
+            ]
+        );
+        assert_eq!(state(ann), Some(OrderState::Picking));
+        assert_eq!(state(bob), Some(OrderState::Picking));
+
+        assert_eq!(
+            create_batch(None, "clerk").unwrap_err(),
+            "no paid orders to pick"
+        );
+    }
+
+    #[test]
+    fn only_paid_orders_can_be_batched() {
+        let _db = crate::db::test_db();
+        let paid = order("Ann Reader", &[(1, 1)]);
+        let cancelled = order("Bob Reader", &[(1, 1)]);
+        cancel_order(cancelled, None, "clerk", "changed mind".to_string(), None).unwrap();
+
+        assert_eq!(
+            create_batch(Some(&[paid, cancelled]), "clerk").unwrap_err(),
+            format!(
+                "order {} is cancelled, only paid orders can be picked",
+                cancelled
+            )
+        );
+        assert_eq!(
+            create_batch(Some(&[paid, 99]), "clerk").unwrap_err(),
+            "order 99 does not exist in database"
+        );
+        assert_eq!(state(paid), Some(OrderState::Paid));
+        assert!(get_batch(1).is_err());
+    }
+
+    #[test]
+    fn packing_slips_leave_out_orders_cancelled_since() {
+        let _db = crate::db::test_db();
+        let ann = order("Ann Reader", &[(2, 2), (1, 1)]);
+        let bob = order("Bob Reader", &[(3, 1)]);
+        let batch = create_batch(Some(&[ann, bob]), "clerk").unwrap();
+        cancel_order(bob, None, "clerk", "changed mind".to_string(), None).unwrap();
+
+        let slips = packing_slips(batch.id).unwrap();
+        assert_eq!(slips.len(), 1);
+        let slip = &slips[0];
+        assert_eq!(
+            (slip.order_id, slip.name.as_str(), slip.address.as_str()),
+            (ann, "Ann Reader", "1 Main St")
+        );
+        assert_eq!(slip.carrier.as_deref(), Some("usps"));
+        let lines: Vec<(i64, i64)> = slip.lines.iter().map(|l| (l.book_id, l.quantity)).collect();
+        assert_eq!(lines, vec![(2, 2), (1, 1)]);
+
+        let batch = get_batch(batch.id).unwrap();
+        assert_eq!(batch.order_ids, vec![ann]);
+        assert_eq!(batch.items.len(), 2);
+        assert_eq!(
+            packing_slips(batch.id + 1).unwrap_err(),
+            "pick batch does not exist in database"
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use super::db::connect;
+use super::invoices::{self, Invoice};
+use super::ledger::LedgerEntry;
+use super::order_state::OrderState;
+use super::purchaseOrders::{order_lines, OrderLine};
+use crate::auth::new_token;
+use crate::logging;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+/// every customer row holding this customer's data: the customer and the
+/// duplicates merged into it, directly or through other merges
+const CUSTOMER_ROWS: &str = "WITH RECURSIVE merged(id) AS (
+         SELECT ?1 UNION SELECT c.id FROM Customers c JOIN merged m ON c.mergedInto = m.id
+     )";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Profile {
+    pub id: i64,
+    pub name: String,
+    pub shipping_address: String,
+    pub country: String,
+    pub region: String,
+    pub postal_code: String,
+    pub merged_into: Option<i64>,
+    pub erased_at: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Address {
+    pub customer_id: i64,
+    pub address: String,
+    pub added_at: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderRecord {
+    pub id: i64,
+    pub customer_id: i64,
+    pub state: OrderState,
+    pub shipping: f64,
+    pub tax: f64,
+    pub total: f64,
+    pub shipping_address: String,
+    pub created_at: String,
+    pub lines: Vec<OrderLine>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Redemption {
+    pub card_ending: String,
+    pub amount: f64,
+    pub redeemed_at: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PointsRecord {
+    pub kind: String,
+    pub points: i64,
+    pub order_id: Option<i64>,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Export {
+    pub exported_at: String,
+    pub profile: Profile,
+    pub merged_accounts: Vec<Profile>,
+    pub addresses: Vec<Address>,
+    pub orders: Vec<OrderRecord>,
+    pub balance: f64,
+    pub ledger: Vec<LedgerEntry>,
+    pub gift_card_redemptions: Vec<Redemption>,
+    pub loyalty: Vec<PointsRecord>,
+    pub invoices: Vec<Invoice>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Erasure {
+    pub customer_id: i64,
+    pub pseudonym: String,
+    pub accounts_erased: i64,
+    pub log_files_scrubbed: usize,
+}
+
+pub fn export(cid: i64) -> Result<Export, String> {
+    //! everything stored about a customer, including accounts merged into it
+    let db = connect();
+    let profile = match profile(&db, cid) {
+        Some(p) => p,
+        None => {
+            warn!(target: "warn", "failed to export customer data, cid not in database: {}", cid);
+            return Err("cid does not exist in database".to_string());
+        }
+    };
+    let merged_accounts = select(
+        &db,
+        &format!(
+            "{} SELECT id, name, shippingAddress, country, region, postalCode, mergedInto, erasedAt FROM Customers
+             WHERE id IN merged AND id != ?1 ORDER BY id",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| {
+            Ok(Profile {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                shipping_address: row.get(2)?,
+                country: row.get(3)?,
+                region: row.get(4)?,
+                postal_code: row.get(5)?,
+                merged_into: row.get(6)?,
+                erased_at: row.get(7)?,
+            })
+        },
+    );
+    let addresses = select(
+        &db,
+        &format!(
+            "{} SELECT customerId, address, addedAt FROM CustomerAddresses
+             WHERE customerId IN merged ORDER BY id",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| {
+            Ok(Address {
+                customer_id: row.get(0)?,
+                address: row.get(1)?,
+                added_at: row.get(2)?,
+            })
+        },
+    );
+    let mut orders = select(
+        &db,
+        &format!(
+            "{} SELECT id, customerId, state, shipping, tax, total, shippingAddress, createdAt FROM Orders
+             WHERE customerId IN merged ORDER BY id",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| {
+            Ok(OrderRecord {
+                id: row.get(0)?,
+                customer_id: row.get(1)?,
+                state: OrderState::parse(&row.get::<_, String>(2)?)
+                    .expect("order state is constrained by the table"),
+                shipping: row.get(3)?,
+                tax: row.get(4)?,
+                total: row.get(5)?,
+                shipping_address: row.get(6)?,
+                created_at: row.get(7)?,
+                lines: Vec::new(),
+            })
+        },
+    );
+    for order in orders.iter_mut() {
+        order.lines = order_lines(&db, order.id);
+    }
+    let ledger = select(
+        &db,
+        &format!(
+            "{} SELECT id, kind, amount, orderId, memo, createdAt FROM LedgerEntries
+             WHERE customerId IN merged ORDER BY id",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| {
+            Ok(LedgerEntry {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                amount: row.get(2)?,
+                order_id: row.get(3)?,
+                memo: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        },
+    );
+    let balance = (ledger.iter().fold(0.0, |t, e| t + e.amount) * 100.0).round() / 100.0;
+    let gift_card_redemptions = select(
+        &db,
+        &format!(
+            "{} SELECT g.codeLast4, r.amount, r.redeemedAt FROM GiftCardRedemptions r
+             JOIN GiftCards g ON g.id = r.giftCardId WHERE r.customerId IN merged ORDER BY r.id",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| {
+            Ok(Redemption {
+                card_ending: row.get(0)?,
+                amount: row.get(1)?,
+                redeemed_at: row.get(2)?,
+            })
+        },
+    );
+    let loyalty = select(
+        &db,
+        &format!(
+            "{} SELECT kind, points, orderId, createdAt FROM LoyaltyTransactions
+             WHERE customerId IN merged ORDER BY id",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| {
+            Ok(PointsRecord {
+                kind: row.get(0)?,
+                points: row.get(1)?,
+                order_id: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        },
+    );
+    let cids: Vec<i64> = std::iter::once(cid)
+        .chain(merged_accounts.iter().map(|p| p.id))
+        .collect();
+    let invoices = invoices::of_customers(&db, &cids);
+    let exported_at: String = db
+        .query_row("SELECT datetime('now')", [], |row| row.get(0))
+        .expect("expected to be able to read the database clock");
+    info!(target: "info", "personal data of customer {} exported", cid);
+    Ok(Export {
+        exported_at,
+        profile,
+        merged_accounts,
+        addresses,
+        orders,
+        balance,
+        ledger,
+        gift_card_redemptions,
+        loyalty,
+        invoices,
+    })
+}
+
+pub fn erase(cid: i64) -> Result<Erasure, String> {
+    //! replaces the name with a pseudonym and drops every address and postal code of the customer
+    //! and the accounts merged into it, orders and ledger entries are kept for accounting
+    //! under the same customer id and invoices unchanged for legal retention, then removes
+    //! the old values from the log files
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start erasure transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    match profile(&tx, cid) {
+        None => {
+            warn!(target: "warn", "failed to erase customer, cid not in database: {}", cid);
+            return Err("cid does not exist in database".to_string());
+        }
+        Some(p) if p.erased_at.is_some() => {
+            return Err("customer has already been erased".to_string());
+        }
+        Some(_) => {}
+    }
+    let unshipped: i64 = tx
+        .query_row(
+            &format!(
+                "{} SELECT COUNT(*) FROM Orders
+                 WHERE customerId IN merged AND state IN ('pending', 'paid', 'picking')",
+                CUSTOMER_ROWS
+            ),
+            [&cid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count Orders table");
+    if unshipped > 0 {
+        warn!(target: "warn", "erasure of customer {} refused, {} orders not shipped", cid, unshipped);
+        return Err("customer has orders that have not shipped yet".to_string());
+    }
+    // the values to remove from the logs, collected before they are overwritten
+    let mut terms = select(
+        &tx,
+        &format!(
+            "{} SELECT name FROM Customers WHERE id IN merged
+             UNION SELECT shippingAddress FROM Customers WHERE id IN merged
+             UNION SELECT address FROM CustomerAddresses WHERE customerId IN merged
+             UNION SELECT shippingAddress FROM Orders WHERE customerId IN merged",
+            CUSTOMER_ROWS
+        ),
+        cid,
+        |row| row.get::<_, String>(0),
+    );
+    terms.retain(|t| !t.trim().is_empty());
+    let ids = select(
+        &tx,
+        &format!("{} SELECT id FROM merged", CUSTOMER_ROWS),
+        cid,
+        |row| row.get::<_, i64>(0),
+    );
+    let pseudonym = format!("Erased {}", &new_token()[..16]);
+    for id in &ids {
+        // merged accounts get their own pseudonym so names stay unique
+        let name = if *id == cid {
+            pseudonym.clone()
+        } else {
+            format!("Erased {}", &new_token()[..16])
+        };
+        tx.execute(
+            "UPDATE Customers SET name = ?1, shippingAddress = '', postalCode = '', erasedAt = datetime('now')
+             WHERE id = ?2",
+            params![name, id],
+        )
+        .expect("expected to be able to update Customers table");
+    }
+    tx.execute(
+        &format!(
+            "{} UPDATE Orders SET shippingAddress = '' WHERE customerId IN merged",
+            CUSTOMER_ROWS
+        ),
+        [&cid],
+    )
+    .expect("expected to be able to update Orders table");
+    tx.execute(
+        &format!(
+            "{} DELETE FROM CustomerAddresses WHERE customerId IN merged",
+            CUSTOMER_ROWS
+        ),
+        [&cid],
+    )
+    .expect("expected to be able to delete from CustomerAddresses table");
+    tx.execute(
+        &format!(
+            "{} DELETE FROM CartItems WHERE cartId IN
+                 (SELECT id FROM Carts WHERE customerId IN merged)",
+            CUSTOMER_ROWS
+        ),
+        [&cid],
+    )
+    .expect("expected to be able to delete from CartItems table");
+    tx.execute(
+        &format!(
+            "{} DELETE FROM Carts WHERE customerId IN merged",
+            CUSTOMER_ROWS
+        ),
+        [&cid],
+    )
+    .expect("expected to be able to delete from Carts table");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit erasure transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    let log_files_scrubbed = logging::scrub(&terms, &format!("[customer {}]", cid));
+    info!(target: "info", "customer {} erased, {} accounts, {} log files scrubbed",
+        cid, ids.len(), log_files_scrubbed);
+    Ok(Erasure {
+        customer_id: cid,
+        pseudonym,
+        accounts_erased: ids.len() as i64,
+        log_files_scrubbed,
+    })
+}
+
+fn profile(conn: &Connection, cid: i64) -> Option<Profile> {
+    conn.query_row(
+        "SELECT id, name, shippingAddress, country, region, postalCode, mergedInto, erasedAt FROM Customers WHERE id = ?1",
+        [&cid],
+        |row| {
+            Ok(Profile {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                shipping_address: row.get(2)?,
+                country: row.get(3)?,
+                region: row.get(4)?,
+                postal_code: row.get(5)?,
+                merged_into: row.get(6)?,
+                erased_at: row.get(7)?,
+            })
+        },
+    )
+    .optional()
+    .expect("expected to be able to select from Customers table")
+}
+
+fn select<T, F>(conn: &Connection, sql: &str, cid: i64, map: F) -> Vec<T>
+where
+    F: FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+{
+    //! runs a query over the customer's rows and collects every result
+    let mut stmt = conn
+        .prepare(sql)
+        .expect("expected to be able to prepare customer data query");
+    let rows = stmt
+        .query_map([&cid], map)
+        .expect("expected to be able to query customer data");
+    rows.map(|r| r.expect("problem getting customer data from database"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::customers;
+    use crate::db::ledger::{self, EntryKind};
+    use crate::db::purchaseOrders::{create_order, ship_po, ShippingChoice};
+
+    fn shipped_order(cid: i64) -> i64 {
+        ledger::post_entry(cid, EntryKind::Deposit, 50.0, None, None).unwrap();
+        let oid = create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id;
+        ship_po(oid, "cleo").unwrap();
+        oid
+    }
+
+    #[test]
+    fn export_holds_the_customer_and_merged_accounts() {
+        let _db = crate::db::test_db();
+        let source = crate::db::test_customer("Quillon Exportsource", 0.0);
+        customers::update_customer_address(source, "17 Zzyzx Testlane".to_string()).unwrap();
+        let target = crate::db::test_customer("Quillon Exporttarget", 0.0);
+        shipped_order(source);
+        customers::merge_customers(source, target, "ada".to_string(), "dupe".to_string()).unwrap();
+
+        let export = export(target).unwrap();
+        assert_eq!(export.profile.id, target);
+        assert_eq!(export.merged_accounts.len(), 1);
+        assert_eq!(export.merged_accounts[0].merged_into, Some(target));
+        assert_eq!(export.orders.len(), 1);
+        assert_eq!(export.orders[0].lines.len(), 1);
+        assert_eq!(export.balance, 32.08);
+        // the deposit, the charge and the pair of merge adjustments
+        assert_eq!(export.ledger.len(), 4);
+        assert!(export
+            .addresses
+            .iter()
+            .any(|a| a.address == "17 Zzyzx Testlane"));
+    }
+
+    #[test]
+    fn a_customer_without_entries_exports_a_zero_balance() {
+        let _db = crate::db::test_db();
+        let cid = crate::db::test_customer("Quillon Emptyledger", 0.0);
+
+        let json = rocket::serde::json::to_string(&export(cid).unwrap()).unwrap();
+        assert!(json.contains("\"balance\":0.0"));
+        assert!(export(99).is_err());
+    }
+
+    #[test]
+    fn erasure_replaces_personal_data_and_keeps_the_accounts() {
+        let _db = crate::db::test_db();
+        let cid = crate::db::test_customer("Quillon Erasetest", 0.0);
+        let oid = shipped_order(cid);
+
+        let erasure = erase(cid).unwrap();
+        assert!(erasure.pseudonym.starts_with("Erased "));
+        let profile = profile(&connect(), cid).unwrap();
+        assert_eq!(profile.name, erasure.pseudonym);
+        assert_eq!(profile.shipping_address, "");
+        assert_eq!(profile.postal_code, "");
+        assert!(profile.erased_at.is_some());
+        let address: String = connect()
+            .query_row(
+                "SELECT shippingAddress FROM Orders WHERE id = ?1",
+                [&oid],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(address, "");
+        assert_eq!(ledger::balance(&connect(), cid), 32.08);
+        assert!(erase(cid).is_err());
+    }
+
+    #[test]
+    fn customers_with_unshipped_orders_are_not_erased() {
+        let _db = crate::db::test_db();
+        let cid = crate::db::test_customer("Quillon Waitingorder", 0.0);
+        ledger::post_entry(cid, EntryKind::Deposit, 50.0, None, None).unwrap();
+        create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap();
+
+        assert!(erase(cid).is_err());
+        assert_eq!(
+            profile(&connect(), cid).unwrap().name,
+            "Quillon Waitingorder"
+        );
+    }
+
+    #[test]
+    fn erased_customers_are_not_merged() {
+        let _db = crate::db::test_db();
+        let erased = crate::db::test_customer("Quillon Mergeerased", 0.0);
+        let kept = crate::db::test_customer("Quillon Mergekept", 0.0);
+        let merged = crate::db::test_customer("Quillon Mergemerged", 0.0);
+        customers::merge_customers(merged, erased, "ada".to_string(), "dupe".to_string()).unwrap();
+        erase(erased).unwrap();
+        let merge = |source, target| {
+            customers::merge_customers(source, target, "ada".to_string(), "dupe".to_string())
+        };
+
+        assert_eq!(
+            merge(erased, kept).err(),
+            Some(format!("cid {} has been erased", erased))
+        );
+        assert_eq!(
+            merge(kept, erased).err(),
+            Some(format!("cid {} has been erased", erased))
+        );
+        // merged ids resolve to the erased account
+        assert!(merge(kept, merged).is_err());
+        assert_eq!(customers::resolve_id(kept), kept);
+    }
+}
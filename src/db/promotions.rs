@@ -0,0 +1,641 @@
+use super::db::connect;
+use super::purchaseOrders::OrderLine;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CouponKind {
+    Percent,
+    Fixed,
+}
+
+impl CouponKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CouponKind::Percent => "percent",
+            CouponKind::Fixed => "fixed",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<CouponKind> {
+        match kind {
+            "percent" => Some(CouponKind::Percent),
+            "fixed" => Some(CouponKind::Fixed),
+            _ => None,
+        }
+    }
+}
+
+/// the rules of a discount code, empty `book_ids` and `authors` mean every book
+pub struct Rules {
+    pub code: String,
+    pub kind: CouponKind,
+    pub value: f64,
+    pub min_order: Option<f64>,
+    pub usage_limit: Option<i64>,
+    pub per_customer_limit: Option<i64>,
+    pub starts_on: Option<String>,
+    pub ends_on: Option<String>,
+    pub active: bool,
+    pub book_ids: Vec<i64>,
+    pub authors: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Promotion {
+    pub id: i64,
+    pub code: String,
+    pub kind: CouponKind,
+    pub value: f64,
+    pub min_order: Option<f64>,
+    pub usage_limit: Option<i64>,
+    pub per_customer_limit: Option<i64>,
+    pub starts_on: Option<String>,
+    pub ends_on: Option<String>,
+    pub active: bool,
+    pub book_ids: Vec<i64>,
+    pub authors: Vec<String>,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PromotionStats {
+    pub promotion_id: i64,
+    pub code: String,
+    /// orders that used the code and weren't cancelled
+    pub redemptions: i64,
+    pub customers: i64,
+    pub discount_given: f64,
+    pub order_revenue: f64,
+    pub cancelled_orders: i64,
+}
+
+/// the coupon discount on an order
+#[derive(Serialize, Debug, Clone)]
+pub struct AppliedCoupon {
+    pub code: String,
+    pub amount: f64,
+    /// books of the order the coupon applies to
+    #[serde(skip)]
+    pub book_ids: Vec<i64>,
+}
+
+pub fn create(rules: Rules, actor: &str) -> Result<Promotion, String> {
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start promotion transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    if find_code(&tx, &rules.code).is_some() {
+        warn!(target: "warn", "promotion code already in use: {}", rules.code);
+        return Err("code is already in use".to_string());
+    }
+    tx.execute(
+        "INSERT INTO Coupons (code, kind, value, createdBy) VALUES (?1, ?2, ?3, ?4)",
+        params![rules.code, rules.kind.as_str(), rules.value, actor],
+    )
+    .expect("expected to be able to insert into Coupons table");
+    let id = tx.last_insert_rowid();
+    write_rules(&tx, id, &rules)?;
+    let promotion = promotion(&tx, id).expect("expected the promotion to exist");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit promotion transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "promotion {} ({}) created by {}", id, promotion.code, actor);
+    Ok(promotion)
+}
+
+pub fn update(id: i64, rules: Rules, actor: &str) -> Result<Promotion, String> {
+    //! replaces every rule of the promotion, past redemptions are kept
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start promotion transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    if promotion(&tx, id).is_none() {
+        warn!(target: "warn", "update of unknown promotion: {}", id);
+        return Err("promotion does not exist in database".to_string());
+    }
+    if find_code(&tx, &rules.code).is_some_and(|other| other != id) {
+        warn!(target: "warn", "promotion code already in use: {}", rules.code);
+        return Err("code is already in use".to_string());
+    }
+    tx.execute(
+        "UPDATE Coupons SET code = ?1, kind = ?2, value = ?3 WHERE id = ?4",
+        params![rules.code, rules.kind.as_str(), rules.value, id],
+    )
+    .expect("expected to be able to update Coupons table");
+    write_rules(&tx, id, &rules)?;
+    let promotion = promotion(&tx, id).expect("expected the promotion to exist");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit promotion transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "promotion {} ({}) updated by {}", id, promotion.code, actor);
+    Ok(promotion)
+}
+
+pub fn delete(id: i64, actor: &str) -> Result<bool, String> {
+    //! promotions that were never redeemed are deleted, the others are deactivated so their
+    //! redemptions keep their code; returns whether it was deleted
+    let db = connect();
+    if promotion(&db, id).is_none() {
+        warn!(target: "warn", "deletion of unknown promotion: {}", id);
+        return Err("promotion does not exist in database".to_string());
+    }
+    let redeemed = db
+        .prepare("SELECT id FROM CouponRedemptions WHERE couponId = ?1")
+        .expect("expected to be able to select from CouponRedemptions table")
+        .exists([&id])
+        .expect("expected to be able to select from CouponRedemptions table");
+    if redeemed {
+        db.execute("UPDATE Coupons SET active = 0 WHERE id = ?1", [&id])
+            .expect("expected to be able to update Coupons table");
+        info!(target: "info", "promotion {} deactivated by {}", id, actor);
+    } else {
+        db.execute("DELETE FROM CouponBooks WHERE couponId = ?1", [&id])
+            .expect("expected to be able to delete from CouponBooks table");
+        db.execute("DELETE FROM CouponAuthors WHERE couponId = ?1", [&id])
+            .expect("expected to be able to delete from CouponAuthors table");
+        db.execute("DELETE FROM Coupons WHERE id = ?1", [&id])
+            .expect("expected to be able to delete from Coupons table");
+        info!(target: "info", "promotion {} deleted by {}", id, actor);
+    }
+    Ok(!redeemed)
+}
+
+pub fn get(id: i64) -> Result<Promotion, String> {
+    match promotion(&connect(), id) {
+        Some(p) => Ok(p),
+        None => {
+            warn!(target: "warn", "unknown promotion requested: {}", id);
+            Err("promotion does not exist in database".to_string())
+        }
+    }
+}
+
+pub fn list() -> Vec<Promotion> {
+    //! newest first
+    let db = connect();
+    let ids: Vec<i64> = db
+        .prepare("SELECT id FROM Coupons ORDER BY id DESC")
+        .expect("expected to be able to select from Coupons table")
+        .query_map([], |row| row.get(0))
+        .expect("expected to be able to get ids from Coupons table")
+        .map(|r| r.expect("problem getting promotion from database"))
+        .collect();
+    ids.into_iter()
+        .map(|id| promotion(&db, id).expect("expected the promotion to exist"))
+        .collect()
+}
+
+pub fn stats(id: i64) -> Result<PromotionStats, String> {
+    let db = connect();
+    let code = match promotion(&db, id) {
+        Some(p) => p.code,
+        None => {
+            warn!(target: "warn", "stats of unknown promotion: {}", id);
+            return Err("promotion does not exist in database".to_string());
+        }
+    };
+    let (redemptions, customers, discount_given, order_revenue, cancelled_orders) = db
+        .query_row(
+            "SELECT COUNT(*) FILTER (WHERE o.state != 'cancelled'),
+                 COUNT(DISTINCT r.customerId) FILTER (WHERE o.state != 'cancelled'),
+                 COALESCE(SUM(r.amount) FILTER (WHERE o.state != 'cancelled'), 0.0),
+                 COALESCE(SUM(o.total) FILTER (WHERE o.state != 'cancelled'), 0.0),
+                 COUNT(*) FILTER (WHERE o.state = 'cancelled')
+             FROM CouponRedemptions r JOIN Orders o ON o.id = r.orderId WHERE r.couponId = ?1",
+            [&id],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, f64>(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )
+        .expect("expected to be able to sum CouponRedemptions table");
+    Ok(PromotionStats {
+        promotion_id: id,
+        code,
+        redemptions,
+        customers,
+        discount_given: (discount_given * 100.0).round() / 100.0,
+        order_revenue: (order_revenue * 100.0).round() / 100.0,
+        cancelled_orders,
+    })
+}
+
+pub fn apply(
+    conn: &Connection,
+    code: &str,
+    cid: i64,
+    lines: &[OrderLine],
+    subtotal: f64,
+) -> Result<(i64, AppliedCoupon), String> {
+    //! checks the code against its rules for this customer and order,
+    //! returns the coupon id and the discount on the order's eligible lines
+    let rejected = |reason: &str| {
+        warn!(target: "warn", "coupon {} rejected for customer {}: {}", code, cid, reason);
+        Err(reason.to_string())
+    };
+    let id = match find_code(conn, code) {
+        Some(id) => id,
+        None => return rejected("coupon code is not valid"),
+    };
+    let p = promotion(conn, id).expect("expected the promotion to exist");
+    let current: bool = conn
+        .query_row(
+            "SELECT (startsOn IS NULL OR startsOn <= date('now'))
+                 AND (endsOn IS NULL OR endsOn >= date('now')) FROM Coupons WHERE id = ?1",
+            [&id],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to select from Coupons table");
+    if !p.active || !current {
+        return rejected("coupon code is not valid");
+    }
+    if p.min_order.is_some_and(|m| subtotal < m) {
+        return rejected(&format!(
+            "coupon needs an order of at least {:.2}",
+            p.min_order.unwrap_or_default()
+        ));
+    }
+    let used = |customer: Option<i64>| -> i64 {
+        conn.query_row(
+            "SELECT COUNT(*) FROM CouponRedemptions r JOIN Orders o ON o.id = r.orderId
+             WHERE r.couponId = ?1 AND o.state != 'cancelled' AND (?2 IS NULL OR r.customerId = ?2)",
+            params![id, customer],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count CouponRedemptions table")
+    };
+    if p.usage_limit.is_some_and(|l| used(None) >= l) {
+        return rejected("coupon has been used up");
+    }
+    if p.per_customer_limit.is_some_and(|l| used(Some(cid)) >= l) {
+        return rejected("coupon has already been used by the customer");
+    }
+    let restricted = !p.book_ids.is_empty() || !p.authors.is_empty();
+    let mut eligible = 0.0;
+    let mut book_ids = Vec::new();
+    for line in lines {
+        let author: String = conn
+            .query_row(
+                "SELECT author FROM Books WHERE id = ?1",
+                [&line.book_id],
+                |row| row.get(0),
+            )
+            .expect("expected to be able to select from Books table");
+        if !restricted
+            || p.book_ids.contains(&line.book_id)
+            || p.authors.iter().any(|a| a.eq_ignore_ascii_case(&author))
+        {
+            eligible += line.line_total;
+            book_ids.push(line.book_id);
+        }
+    }
+    if eligible <= 0.0 {
+        return rejected("coupon doesn't apply to any book in the order");
+    }
+    let amount = match p.kind {
+        CouponKind::Percent => eligible * p.value / 100.0,
+        CouponKind::Fixed => p.value.min(eligible),
+    };
+    Ok((
+        id,
+        AppliedCoupon {
+            code: p.code,
+            amount: (amount * 100.0).round() / 100.0,
+            book_ids,
+        },
+    ))
+}
+
+pub fn record(conn: &Connection, coupon_id: i64, oid: i64, cid: i64, amount: f64) {
+    conn.execute(
+        "INSERT INTO CouponRedemptions (couponId, orderId, customerId, amount) VALUES (?1, ?2, ?3, ?4)",
+        params![coupon_id, oid, cid, amount],
+    )
+    .expect("expected to be able to insert into CouponRedemptions table");
+    info!(target: "info", "coupon {} redeemed by customer {} on order {}: {:.2}",
+        coupon_id, cid, oid, amount);
+}
+
+fn find_code(conn: &Connection, code: &str) -> Option<i64> {
+    conn.query_row("SELECT id FROM Coupons WHERE code = ?1", [code], |row| {
+        row.get(0)
+    })
+    .optional()
+    .expect("expected to be able to select from Coupons table")
+}
+
+fn write_rules(conn: &Connection, id: i64, rules: &Rules) -> Result<(), String> {
+    conn.execute(
+        "UPDATE Coupons SET minOrder = ?1, usageLimit = ?2, perCustomerLimit = ?3,
+             startsOn = ?4, endsOn = ?5, active = ?6 WHERE id = ?7",
+        params![
+            rules.min_order,
+            rules.usage_limit,
+            rules.per_customer_limit,
+            rules.starts_on,
+            rules.ends_on,
+            rules.active,
+            id
+        ],
+    )
+    .expect("expected to be able to update Coupons table");
+    conn.execute("DELETE FROM CouponBooks WHERE couponId = ?1", [&id])
+        .expect("expected to be able to delete from CouponBooks table");
+    conn.execute("DELETE FROM CouponAuthors WHERE couponId = ?1", [&id])
+        .expect("expected to be able to delete from CouponAuthors table");
+    for bid in &rules.book_ids {
+        let book = conn
+            .prepare("SELECT id FROM Books WHERE id = ?1")
+            .expect("expected to be able to select from Books table")
+            .exists([bid])
+            .expect("expected to be able to select from Books table");
+        if !book {
+            return Err(format!("bid {} does not exist in database", bid));
+        }
+        conn.execute(
+            "INSERT OR IGNORE INTO CouponBooks (couponId, bookId) VALUES (?1, ?2)",
+            [&id, bid],
+        )
+        .expect("expected to be able to insert into CouponBooks table");
+    }
+    for author in &rules.authors {
+        conn.execute(
+            "INSERT OR IGNORE INTO CouponAuthors (couponId, author) VALUES (?1, ?2)",
+            params![id, author],
+        )
+        .expect("expected to be able to insert into CouponAuthors table");
+    }
+    Ok(())
+}
+
+fn promotion(conn: &Connection, id: i64) -> Option<Promotion> {
+    let mut p = conn
+        .query_row(
+            "SELECT id, code, kind, value, minOrder, usageLimit, perCustomerLimit, startsOn,
+                 endsOn, active, createdBy, createdAt FROM Coupons WHERE id = ?1",
+            [&id],
+            |row| {
+                Ok(Promotion {
+                    id: row.get(0)?,
+                    code: row.get(1)?,
+                    kind: CouponKind::parse(&row.get::<_, String>(2)?)
+                        .expect("coupon kind is constrained by the table"),
+                    value: row.get(3)?,
+                    min_order: row.get(4)?,
+                    usage_limit: row.get(5)?,
+                    per_customer_limit: row.get(6)?,
+                    starts_on: row.get(7)?,
+                    ends_on: row.get(8)?,
+                    active: row.get(9)?,
+                    book_ids: Vec::new(),
+                    authors: Vec::new(),
+                    created_by: row.get(10)?,
+                    created_at: row.get(11)?,
+                })
+            },
+        )
+        .optional()
+        .expect("expected to be able to select from Coupons table")?;
+    p.book_ids = conn
+        .prepare("SELECT bookId FROM CouponBooks WHERE couponId = ?1 ORDER BY bookId")
+        .expect("expected to be able to select from CouponBooks table")
+        .query_map([&id], |row| row.get(0))
+        .expect("expected to be able to get books from CouponBooks table")
+        .map(|r| r.expect("problem getting coupon book from database"))
+        .collect();
+    p.authors = conn
+        .prepare("SELECT author FROM CouponAuthors WHERE couponId = ?1 ORDER BY author")
+        .expect("expected to be able to select from CouponAuthors table")
+        .query_map([&id], |row| row.get(0))
+        .expect("expected to be able to get authors from CouponAuthors table")
+        .map(|r| r.expect("problem getting coupon author from database"))
+        .collect();
+    Some(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{
+        cancel_order, create_order, OrderError, OrderSummary, ShippingChoice,
+    };
+
+    fn rules(code: &str, kind: CouponKind, value: f64) -> Rules {
+        Rules {
+            code: code.to_string(),
+            kind,
+            value,
+            min_order: None,
+            usage_limit: None,
+            per_customer_limit: None,
+            starts_on: None,
+            ends_on: None,
+            active: true,
+            book_ids: Vec::new(),
+            authors: Vec::new(),
+        }
+    }
+
+    fn customer(name: &str) -> i64 {
+        //! a California customer, taxed 7.25%
+        crate::db::test_customer(name, 200.0)
+    }
+
+    fn order(cid: i64, lines: &[(i64, i64)], code: &str) -> Result<OrderSummary, String> {
+        match create_order(
+            cid,
+            lines,
+            0,
+            Some(code.to_string()),
+            ShippingChoice::default(),
+        ) {
+            Ok(summary) => Ok(summary),
+            Err(OrderError::Rejected(e)) | Err(OrderError::PaymentRequired(e)) => Err(e),
+        }
+    }
+
+    #[test]
+    fn the_discount_is_taken_off_before_tax() {
+        let _db = crate::db::test_db();
+        create(rules("TENOFF", CouponKind::Percent, 10.0), "admin").unwrap();
+        let cid = customer("Ann Reader");
+
+        let summary = order(cid, &[(1, 1)], "TENOFF").unwrap();
+        assert_eq!(summary.discount, 1.3);
+        assert_eq!(summary.coupon.unwrap().amount, 1.3);
+        // 7.25% of 11.69
+        assert_eq!(summary.tax, 0.85);
+        assert_eq!(summary.total, 16.53);
+    }
+
+    #[test]
+    fn restricted_coupons_only_discount_their_books() {
+        let _db = crate::db::test_db();
+        let mut herbert = rules("DUNE20", CouponKind::Percent, 20.0);
+        herbert.authors = vec!["frank herbert".to_string()];
+        create(herbert, "admin").unwrap();
+        let cid = customer("Ann Reader");
+
+        let summary = order(cid, &[(1, 1), (2, 1)], "DUNE20").unwrap();
+        // 20% of Dune's 9.99
+        assert_eq!(summary.discount, 2.0);
+        let taxes: Vec<f64> = summary.lines.iter().map(|l| l.tax).collect();
+        // the other book is taxed on its full price, Dune on 7.99
+        assert_eq!(taxes, vec![0.94, 0.58]);
+
+        assert_eq!(
+            order(cid, &[(1, 1)], "DUNE20").unwrap_err(),
+            "coupon doesn't apply to any book in the order"
+        );
+    }
+
+    #[test]
+    fn fixed_discounts_never_exceed_the_eligible_books() {
+        let _db = crate::db::test_db();
+        let mut fixed = rules("FIFTEEN", CouponKind::Fixed, 15.0);
+        fixed.book_ids = vec![2];
+        create(fixed, "admin").unwrap();
+        let cid = customer("Ann Reader");
+
+        let summary = order(cid, &[(1, 1), (2, 1)], "FIFTEEN").unwrap();
+        assert_eq!(summary.discount, 9.99);
+        assert_eq!(summary.lines[1].tax, 0.0);
+    }
+
+    #[test]
+    fn refunds_keep_a_restricted_discount_on_its_line() {
+        use crate::db::purchaseOrders::ship_po;
+        use crate::db::returns::{self, Condition, Disposition};
+        use crate::db::shipments::{self, Parcel};
+
+        let _db = crate::db::test_db();
+        let mut fixed = rules("DUNEFIVE", CouponKind::Fixed, 5.0);
+        fixed.book_ids = vec![2];
+        create(fixed, "admin").unwrap();
+        let cid = customer("Ann Reader");
+        let parcel = || Parcel {
+            carrier: "usps".to_string(),
+            service_level: "standard".to_string(),
+            tracking_number: None,
+            shipped_at: None,
+        };
+
+        // the other book ships, Dune is cancelled at 4.99 and 0.36 tax
+        let summary = order(cid, &[(1, 1), (2, 1)], "DUNEFIVE").unwrap();
+        let discounts: Vec<f64> = summary.lines.iter().map(|l| l.discount).collect();
+        assert_eq!(discounts, vec![0.0, 5.0]);
+        shipments::create_shipment(summary.id, parcel(), Some(&[(1, 1)]), "cleo").unwrap();
+        let cancellation = cancel_order(
+            summary.id,
+            None,
+            "ada",
+            "out of print".to_string(),
+            Some("supplier".to_string()),
+        )
+        .unwrap();
+        assert_eq!(cancellation.refunded, 5.35);
+
+        // returning the other book refunds its full price and tax
+        let oid = order(cid, &[(1, 1), (2, 1)], "DUNEFIVE").unwrap().id;
+        ship_po(oid, "cleo").unwrap();
+        let rid = returns::open_rma(oid, cid, "wrong book".to_string(), &[(1, 1)]).unwrap();
+        returns::decide(rid, true, "sam", None).unwrap();
+        let rma =
+            returns::receive(rid, "cleo", &[(1, Condition::AsNew, Disposition::Restock)]).unwrap();
+        assert_eq!(rma.refunded, Some(13.93));
+    }
+
+    #[test]
+    fn coupons_outside_their_rules_are_refused() {
+        let _db = crate::db::test_db();
+        let mut minimum = rules("BIG", CouponKind::Percent, 10.0);
+        minimum.min_order = Some(20.0);
+        create(minimum, "admin").unwrap();
+        let mut expired = rules("OLD", CouponKind::Percent, 10.0);
+        expired.ends_on = Some("2000-01-01".to_string());
+        create(expired, "admin").unwrap();
+        let mut paused = rules("PAUSED", CouponKind::Percent, 10.0);
+        paused.active = false;
+        create(paused, "admin").unwrap();
+        let cid = customer("Ann Reader");
+
+        assert_eq!(
+            order(cid, &[(1, 1)], "BIG").unwrap_err(),
+            "coupon needs an order of at least 20.00"
+        );
+        assert!(order(cid, &[(1, 2)], "BIG").is_ok());
+        for code in ["OLD", "PAUSED", "NOPE"] {
+            assert_eq!(
+                order(cid, &[(1, 1)], code).unwrap_err(),
+                "coupon code is not valid"
+            );
+        }
+    }
+
+    #[test]
+    fn usage_limits_do_not_count_cancelled_orders() {
+        let _db = crate::db::test_db();
+        let mut limited = rules("ONCE", CouponKind::Percent, 10.0);
+        limited.usage_limit = Some(2);
+        limited.per_customer_limit = Some(1);
+        create(limited, "admin").unwrap();
+        let (ann, bob, cat) = (
+            customer("Ann Reader"),
+            customer("Bob Reader"),
+            customer("Cat Reader"),
+        );
+
+        let first = order(ann, &[(1, 1)], "ONCE").unwrap();
+        assert_eq!(
+            order(ann, &[(1, 1)], "ONCE").unwrap_err(),
+            "coupon has already been used by the customer"
+        );
+        order(bob, &[(1, 1)], "ONCE").unwrap();
+        assert_eq!(
+            order(cat, &[(1, 1)], "ONCE").unwrap_err(),
+            "coupon has been used up"
+        );
+
+        cancel_order(first.id, None, "clerk", "changed mind".to_string(), None).unwrap();
+        order(cat, &[(1, 1)], "ONCE").unwrap();
+        let stats = stats(1).unwrap();
+        assert_eq!(
+            (stats.redemptions, stats.customers, stats.cancelled_orders),
+            (2, 2, 1)
+        );
+    }
+
+    #[test]
+    fn redeemed_promotions_are_deactivated_rather_than_deleted() {
+        let _db = crate::db::test_db();
+        let used = create(rules("USED", CouponKind::Percent, 10.0), "admin").unwrap();
+        let unused = create(rules("UNUSED", CouponKind::Percent, 10.0), "admin").unwrap();
+        order(customer("Ann Reader"), &[(1, 1)], "USED").unwrap();
+
+        assert_eq!(delete(used.id, "admin"), Ok(false));
+        assert!(!get(used.id).unwrap().active);
+        assert_eq!(delete(unused.id, "admin"), Ok(true));
+        assert!(get(unused.id).is_err());
+        assert_eq!(
+            create(rules("USED", CouponKind::Fixed, 1.0), "admin").unwrap_err(),
+            "code is already in use"
+        );
+    }
+}
@@ -1,24 +1,688 @@
 use super::db::connect;
+use super::invoices;
+use super::ledger::{self, EntryKind};
+use super::loyalty;
+use super::order_state::{self, OrderState};
+use super::promotions::{self, AppliedCoupon};
+use super::shipments::{self, Parcel};
+use super::shipping::{self, RateOption};
+use super::tax;
+use super::webhooks::{self, EventType};
+use crate::config;
 use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
 
-pub fn create_purchase_order(cid: i64, bid: i64) -> Result<i64, String> {
-    let db = connect();
-    let exist = exists_id(cid, bid).unwrap_or_else(|e| {
-        error!(target: "error", "statement exists check error: {}", e);
+pub enum OrderError {
+    /// the customer's balance plus credit limit doesn't cover the order
+    PaymentRequired(String),
+    Rejected(String),
+}
+
+impl From<String> for OrderError {
+    fn from(e: String) -> Self {
+        OrderError::Rejected(e)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderLine {
+    pub book_id: i64,
+    pub title: String,
+    pub quantity: i64,
+    pub unit_price: f64,
+    pub line_total: f64,
+    /// included in `line_total` when the order's prices included tax
+    pub tax: f64,
+    /// the line's share of the order's coupon and loyalty point discount
+    pub discount: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderSummary {
+    pub id: i64,
+    /// the number of the order's invoice
+    pub invoice: String,
+    pub customer_id: i64,
+    pub lines: Vec<OrderLine>,
+    pub subtotal: f64,
+    /// the coupon's part of `discount`, the rest is loyalty points
+    pub coupon: Option<AppliedCoupon>,
+    pub discount: f64,
+    pub shipping: RateOption,
+    pub tax: f64,
+    pub tax_inclusive: bool,
+    pub total: f64,
+}
+
+/// the carrier service a customer picked from a quote, the cheapest one is used
+/// for fields left out
+#[derive(Default)]
+pub struct ShippingChoice {
+    pub carrier: Option<String>,
+    pub service_level: Option<String>,
+}
+
+pub fn create_purchase_order(
+    cid: i64,
+    bid: i64,
+    redeem_points: i64,
+    coupon: Option<String>,
+) -> Result<i64, OrderError> {
+    //! single book orders, kept for the original `/orders/new` endpoint, shipped the cheapest way
+    create_order(
+        cid,
+        &[(bid, 1)],
+        redeem_points,
+        coupon,
+        ShippingChoice::default(),
+    )
+    .map(|o| o.id)
+}
+
+pub fn create_order(
+    cid: i64,
+    lines: &[(i64, i64)],
+    redeem_points: i64,
+    coupon: Option<String>,
+    choice: ShippingChoice,
+) -> Result<OrderSummary, OrderError> {
+    //! places the order in its own transaction, see [`insert_order`]
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start order transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let summary = insert_order(&tx, cid, lines, redeem_points, coupon, choice)?;
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit order transaction: {}", e);
         panic!("connection with database failure")
     });
-    if !exist {
-        db.execute(
-            "INSERT INTO PurchaseOrders (customerId, bookId, shipped) VALUES (?1, ?2, 0)",
-            [&cid, &bid],
+    info!(target: "info", "new order {} created for customer {}: {} lines, {} {} shipping, charged {:.2}",
+        summary.id, cid, summary.lines.len(), summary.shipping.carrier,
+        summary.shipping.service_level, summary.total);
+    Ok(summary)
+}
+
+pub fn insert_order(
+    tx: &Connection,
+    cid: i64,
+    lines: &[(i64, i64)],
+    redeem_points: i64,
+    coupon: Option<String>,
+    choice: ShippingChoice,
+) -> Result<OrderSummary, OrderError> {
+    //! takes (bid, quantity) lines, snapshots each book's price, applies the coupon, prices
+    //! shipping to the customer's zone, taxes each line for the customer's jurisdiction,
+    //! charges the customer and inserts the order on the caller's transaction. The coupon
+    //! and then loyalty points redeemed are taken off the books but not off shipping
+    let address: String = match tx
+        .query_row(
+            "SELECT shippingAddress FROM Customers WHERE id = ?1 AND erasedAt IS NULL",
+            [&cid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from Customers table")
+    {
+        Some(a) => a,
+        None => {
+            warn!(target: "warn", "order for unknown customer: {}", cid);
+            return Err("cid does not exist in database".to_string().into());
+        }
+    };
+    let mut priced = Vec::with_capacity(lines.len());
+    for &(bid, quantity) in lines {
+        let (title, price): (String, f64) = match tx
+            .query_row(
+                "SELECT title, price FROM Books WHERE id = ?1",
+                [&bid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .expect("expected to be able to select from Books table")
+        {
+            Some(b) => b,
+            None => {
+                warn!(target: "warn", "order for unknown book: {}", bid);
+                return Err(format!("bid {} does not exist in database", bid).into());
+            }
+        };
+        priced.push(OrderLine {
+            book_id: bid,
+            title,
+            quantity,
+            unit_price: price,
+            line_total: (price * quantity as f64 * 100.0).round() / 100.0,
+            tax: 0.0,
+            discount: 0.0,
+        });
+    }
+    let subtotal = (priced.iter().map(|l| l.line_total).sum::<f64>() * 100.0).round() / 100.0;
+    let coupon = match &coupon {
+        Some(code) => Some(promotions::apply(tx, code, cid, &priced, subtotal)?),
+        None => None,
+    };
+    let coupon_discount = coupon.as_ref().map_or(0.0, |(_, c)| c.amount);
+    // never redeem more points than it takes to cover what the coupon left
+    let payable = subtotal - coupon_discount;
+    let point_value = config::loyalty_point_value();
+    let redeem_points = if point_value > 0.0 {
+        redeem_points.min((payable / point_value).ceil() as i64)
+    } else {
+        0
+    };
+    let points_discount = (redeem_points as f64 * point_value).min(payable);
+    let discount = ((coupon_discount + points_discount) * 100.0).round() / 100.0;
+    let dest = shipping::destination(tx, cid).expect("expected the customer to exist");
+    let quote = shipping::quote_for(tx, &dest, lines, subtotal)?;
+    let rate = shipping::choose(
+        &quote,
+        choice.carrier.as_deref(),
+        choice.service_level.as_deref(),
+    )?;
+    // lines are taxed after their share of the discount, shipping isn't taxed. The coupon
+    // is shared among the lines it applies to, loyalty points among all of them
+    let inclusive = config::prices_include_tax();
+    let eligible: f64 = match &coupon {
+        Some((_, c)) => priced
+            .iter()
+            .filter(|l| c.book_ids.contains(&l.book_id))
+            .map(|l| l.line_total)
+            .sum(),
+        None => 0.0,
+    };
+    let mut line_taxes = Vec::with_capacity(priced.len());
+    for line in priced.iter_mut() {
+        let coupon_share = match &coupon {
+            Some((_, c)) if eligible > 0.0 && c.book_ids.contains(&line.book_id) => {
+                coupon_discount * line.line_total / eligible
+            }
+            _ => 0.0,
+        };
+        let points_share = if subtotal > 0.0 {
+            points_discount * line.line_total / subtotal
+        } else {
+            0.0
+        };
+        line.discount = ((coupon_share + points_share) * 100.0).round() / 100.0;
+        let taxable = (line.line_total - coupon_share - points_share).max(0.0);
+        let parts = tax::line_tax(tx, &dest, line.book_id, taxable, inclusive);
+        // folded from 0.0 since an empty f64 sum is -0.0, untaxed lines would show tax -0.0
+        line.tax = (parts.iter().fold(0.0, |t, p| t + p.amount) * 100.0).round() / 100.0;
+        line_taxes.push(parts);
+    }
+    // the shares are rounded to cents, the last discounted line takes up the rounding so
+    // refunding every line gives back exactly what was paid
+    let rounding = discount - priced.iter().fold(0.0, |t, l| t + l.discount);
+    if let Some(last) = priced.iter_mut().rev().find(|l| l.discount > 0.0) {
+        last.discount = ((last.discount + rounding) * 100.0).round() / 100.0;
+    }
+    let order_tax = (priced.iter().fold(0.0, |t, l| t + l.tax) * 100.0).round() / 100.0;
+    let added_tax = if inclusive { 0.0 } else { order_tax };
+    let charge = ((subtotal - discount + rate.price + added_tax) * 100.0).round() / 100.0;
+    let balance = ledger::balance(tx, cid);
+    let credit_limit = config::credit_limit();
+    if balance + credit_limit < charge {
+        warn!(target: "warn", "insufficient funds for order by customer {}: balance {:.2} total {:.2}",
+            cid, balance, charge);
+        return Err(OrderError::PaymentRequired(format!(
+            "insufficient funds: balance {:.2}, credit limit {:.2}, total {:.2}",
+            balance, credit_limit, charge
+        )));
+    }
+    tx.execute(
+        "INSERT INTO Orders (customerId, subtotal, discount, couponDiscount, shipping, tax,
+             taxInclusive, total, shippingAddress, shippingCarrier, shippingService)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            cid,
+            subtotal,
+            discount,
+            coupon_discount,
+            rate.price,
+            order_tax,
+            inclusive,
+            charge,
+            address,
+            rate.carrier,
+            rate.service_level
+        ],
+    )
+    .expect("expected to be able to insert into Orders table");
+    let oid = tx.last_insert_rowid();
+    let actor = format!("customer {}", cid);
+    order_state::record_created(tx, oid, &actor);
+    for (line, parts) in priced.iter().zip(&line_taxes) {
+        let reserved = reserve_stock(tx, line.book_id, line.quantity)?;
+        tx.execute(
+            "INSERT INTO OrderLines (orderId, bookId, quantity, unitPrice, reserved, tax, discount)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                oid,
+                line.book_id,
+                line.quantity,
+                line.unit_price,
+                reserved,
+                line.tax,
+                line.discount
+            ],
+        )
+        .expect("expected to be able to insert into OrderLines table");
+        tax::record(tx, tx.last_insert_rowid(), parts);
+    }
+    if let Some((coupon_id, applied)) = &coupon {
+        promotions::record(tx, *coupon_id, oid, cid, applied.amount);
+    }
+    if redeem_points > 0 {
+        loyalty::redeem(tx, cid, redeem_points, oid)?;
+    }
+    if charge >= 0.01 {
+        ledger::insert_entry(tx, cid, EntryKind::OrderCharge, charge, Some(oid), None);
+    }
+    // the total comes out of the balance straight away, so the order is paid on creation
+    order_state::transition(tx, oid, OrderState::Paid, &actor, None)?;
+    let invoice = invoices::issue(tx, oid);
+    let summary = OrderSummary {
+        id: oid,
+        invoice,
+        customer_id: cid,
+        lines: priced,
+        subtotal,
+        coupon: coupon.map(|(_, c)| c),
+        discount,
+        shipping: rate,
+        tax: order_tax,
+        tax_inclusive: inclusive,
+        total: charge,
+    };
+    webhooks::emit(
+        tx,
+        EventType::OrderCreated,
+        serde_json::to_value(&summary).expect("an order summary serializes to JSON"),
+    );
+    Ok(summary)
+}
+
+fn reserve_stock(conn: &Connection, bid: i64, quantity: i64) -> Result<bool, OrderError> {
+    //! takes the quantity from the book's stock if it is tracked, returns whether it was
+    let stock: Option<i64> = conn
+        .query_row("SELECT stock FROM Books WHERE id = ?1", [&bid], |row| {
+            row.get(0)
+        })
+        .expect("expected to be able to select from Books table");
+    match stock {
+        None => Ok(false),
+        Some(s) if s < quantity => {
+            warn!(target: "warn", "not enough stock of book {}: {} left, {} ordered", bid, s, quantity);
+            Err(format!("not enough stock of bid {}: {} left", bid, s).into())
+        }
+        Some(_) => {
+            conn.execute(
+                "UPDATE Books SET stock = stock - ?1 WHERE id = ?2",
+                [&quantity, &bid],
+            )
+            .expect("expected to be able to update Books table");
+            Ok(true)
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Cancellation {
+    pub order_id: i64,
+    pub previous_state: OrderState,
+    pub refunded: f64,
+    /// the number of the credit note issued for the refund
+    pub credit_note: Option<String>,
+    pub stock_restored: i64,
+    pub points_restored: i64,
+}
+
+pub fn cancel_order(
+    oid: i64,
+    customer: Option<i64>,
+    actor: &str,
+    reason: String,
+    override_reason: Option<String>,
+) -> Result<Cancellation, String> {
+    //! cancels the order, puts reserved stock back and refunds what was charged for it
+    //! to the customer's balance in one transaction. Without an `override_reason` only
+    //! orders without any parcel can be cancelled; with one, a partly shipped order has
+    //! what hasn't shipped cancelled and refunded. Shipped orders go through a return.
+    //! `customer` is checked against the order's customer when the customer cancels
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start cancellation transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let cid: i64 = match tx
+        .query_row(
+            "SELECT customerId FROM Orders WHERE id = ?1",
+            [&oid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from Orders table")
+    {
+        Some(c) => c,
+        None => {
+            warn!(target: "warn", "cancellation of unknown order: {}", oid);
+            return Err("order does not exist in database".to_string());
+        }
+    };
+    if customer.is_some_and(|c| c != cid) {
+        warn!(target: "warn", "customer {:?} tried to cancel order {} of customer {}", customer, oid, cid);
+        return Err("order does not exist in database".to_string());
+    }
+    let parcels: i64 = tx
+        .query_row(
+            "SELECT COUNT(*) FROM Shipments WHERE orderId = ?1",
+            [&oid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count Shipments table");
+    if parcels > 0 && override_reason.is_none() {
+        warn!(target: "warn", "cancellation of partly shipped order {} by {}", oid, actor);
+        return Err("order has partly shipped, it can't be cancelled".to_string());
+    }
+    if order_state::current(&tx, oid).is_some_and(|s| s.has_shipped()) {
+        warn!(target: "warn", "cancellation of shipped order {} by {}", oid, actor);
+        return Err(
+            "order has shipped, its copies can only come back through a return".to_string(),
+        );
+    }
+    let previous_state = match &override_reason {
+        Some(o) => order_state::force(
+            &tx,
+            oid,
+            OrderState::Cancelled,
+            actor,
+            Some(format!("{} (override: {})", reason, o)),
+        )?,
+        None => {
+            order_state::transition(&tx, oid, OrderState::Cancelled, actor, Some(reason.clone()))?
+        }
+    };
+    // copies already sent in a parcel don't come back into stock
+    let unshipped = "OrderLines.quantity - COALESCE(
+                         (SELECT SUM(quantity) FROM ShipmentLines WHERE orderLineId = OrderLines.id), 0)";
+    tx.execute(
+        &format!(
+            "UPDATE Books SET stock = stock + (
+                 SELECT SUM({}) FROM OrderLines
+                 WHERE orderId = ?1 AND bookId = Books.id AND reserved = 1)
+             WHERE stock IS NOT NULL AND id IN
+                 (SELECT bookId FROM OrderLines WHERE orderId = ?1 AND reserved = 1)",
+            unshipped
+        ),
+        [&oid],
+    )
+    .expect("expected to be able to update Books table");
+    let stock_restored: i64 = tx
+        .query_row(
+            &format!(
+                "SELECT COALESCE(SUM({}), 0) FROM OrderLines WHERE orderId = ?1 AND reserved = 1",
+                unshipped
+            ),
+            [&oid],
+            |row| row.get(0),
         )
-        .expect("expected to be able to insert into PurchaseOrders table");
-        info!(target: "info", "new order created (cid, bid): {}, {}", cid, bid);
-        get_purchase_order_id(cid, bid)
+        .expect("expected to be able to sum OrderLines table");
+    // net of any earlier refunds, including those booked to a customer merged away since
+    let charged: f64 = tx
+        .query_row(
+            "SELECT COALESCE(-SUM(amount), 0.0) FROM LedgerEntries
+             WHERE orderId = ?1 AND kind IN ('order_charge', 'refund')",
+            [&oid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LedgerEntries table");
+    let refunded = if parcels == 0 {
+        (charged.max(0.0) * 100.0).round() / 100.0
     } else {
-        warn!(target: "warn", "order already in database (cid, bid): {}, {}", cid, bid);
-        Err("order already in database".to_string())
+        // only the copies that haven't shipped, priced like a return: net of their line's
+        // share of the discount, with the tax added on them and without shipping
+        let unshipped_value: f64 = tx
+            .query_row(
+                &format!(
+                    "SELECT COALESCE(SUM(({0}) * (OrderLines.unitPrice
+                         - OrderLines.discount / OrderLines.quantity + CASE WHEN o.taxInclusive = 0
+                         THEN OrderLines.tax / OrderLines.quantity ELSE 0 END)), 0.0)
+                     FROM OrderLines JOIN Orders o ON o.id = OrderLines.orderId
+                     WHERE OrderLines.orderId = ?1",
+                    unshipped
+                ),
+                [&oid],
+                |row| row.get(0),
+            )
+            .expect("expected to be able to sum OrderLines table");
+        (unshipped_value.min(charged).max(0.0) * 100.0).round() / 100.0
+    };
+    if refunded >= 0.01 {
+        ledger::insert_entry(
+            &tx,
+            cid,
+            EntryKind::Refund,
+            refunded,
+            Some(oid),
+            Some(format!("order {} cancelled", oid)),
+        );
+    }
+    let credited: Option<Vec<(i64, i64)>> = (parcels > 0).then(|| {
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT bookId, SUM({0}) FROM OrderLines WHERE orderId = ?1
+                 GROUP BY bookId HAVING SUM({0}) > 0",
+                unshipped
+            ))
+            .expect("expected to be able to select from OrderLines table");
+        let rows = stmt
+            .query_map([&oid], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("expected to be able to get lines from OrderLines table");
+        rows.map(|r| r.expect("problem getting order line from database"))
+            .collect()
+    });
+    let credit_note = invoices::credit(&tx, oid, refunded, &reason, credited.as_deref());
+    let points_restored = loyalty::reverse_order(&tx, cid, oid);
+    tx.execute(
+        "INSERT INTO OrderCancellations (orderId, reason, cancelledBy, overrideReason, refunded, stockRestored)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![oid, reason, actor, override_reason, refunded, stock_restored],
+    )
+    .expect("expected to be able to insert into OrderCancellations table");
+    let cancellation = Cancellation {
+        order_id: oid,
+        previous_state,
+        refunded,
+        credit_note,
+        stock_restored,
+        points_restored,
+    };
+    webhooks::emit(
+        &tx,
+        EventType::OrderCancelled,
+        serde_json::to_value(&cancellation).expect("a cancellation serializes to JSON"),
+    );
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit cancellation transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "order {} cancelled by {}: refunded {:.2}, {} copies back in stock",
+        oid, actor, refunded, stock_restored);
+    Ok(cancellation)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderStatus {
+    pub id: i64,
+    pub customer_id: i64,
+    pub customer_name: String,
+    pub state: OrderState,
+    pub shipping_address: String,
+    pub subtotal: f64,
+    pub discount: f64,
+    pub shipping: f64,
+    pub shipping_carrier: Option<String>,
+    pub shipping_service: Option<String>,
+    pub tax: f64,
+    pub tax_inclusive: bool,
+    pub total: f64,
+    pub created_at: String,
+    pub lines: Vec<OrderLine>,
+}
+
+/// the columns [`status_row`] reads
+const STATUS_COLUMNS: &str = "o.id, o.customerId, c.name, o.state, o.shippingAddress, o.subtotal,
+     o.discount, o.shipping, o.shippingCarrier, o.shippingService, o.tax, o.taxInclusive,
+     o.total, o.createdAt
+     FROM Orders o JOIN Customers c ON c.id = o.customerId";
+
+fn status_row(row: &rusqlite::Row) -> rusqlite::Result<OrderStatus> {
+    Ok(OrderStatus {
+        id: row.get(0)?,
+        customer_id: row.get(1)?,
+        customer_name: row.get(2)?,
+        state: OrderState::parse(&row.get::<_, String>(3)?)
+            .expect("order state is constrained by the table"),
+        shipping_address: row.get(4)?,
+        subtotal: row.get(5)?,
+        discount: row.get(6)?,
+        shipping: row.get(7)?,
+        shipping_carrier: row.get(8)?,
+        shipping_service: row.get(9)?,
+        tax: row.get(10)?,
+        tax_inclusive: row.get(11)?,
+        total: row.get(12)?,
+        created_at: row.get(13)?,
+        lines: Vec::new(),
+    })
+}
+
+pub fn order_status(oid: i64, cid: i64) -> Result<OrderStatus, String> {
+    //! the order as placed, `cid` has to be the order's customer
+    let db = connect();
+    let status = db
+        .query_row(
+            &format!(
+                "SELECT {} WHERE o.id = ?1 AND o.customerId = ?2",
+                STATUS_COLUMNS
+            ),
+            [&oid, &cid],
+            status_row,
+        )
+        .optional()
+        .expect("expected to be able to select from Orders table");
+    match status {
+        Some(mut s) => {
+            s.lines = order_lines(&db, oid);
+            Ok(s)
+        }
+        None => {
+            warn!(target: "warn", "status of unknown order {} for customer {}", oid, cid);
+            Err("order does not exist in database".to_string())
+        }
+    }
+}
+
+/// narrows an order listing, `from` and `to` are inclusive dates
+pub struct OrderFilter {
+    pub customer_id: Option<i64>,
+    pub state: Option<OrderState>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+pub fn list_orders(
+    filter: &OrderFilter,
+    page: i64,
+    per_page: i64,
+) -> Result<(Vec<OrderStatus>, i64), String> {
+    //! newest first with their lines, returns the page of orders and the number of
+    //! orders matching the filter
+    let db = connect();
+    if let Some(cid) = filter.customer_id {
+        let exists = db
+            .prepare("SELECT id FROM Customers WHERE id = ?1")
+            .expect("expected to be able to select from Customers table")
+            .exists([&cid])
+            .expect("expected to be able to select from Customers table");
+        if !exists {
+            warn!(target: "warn", "failed to list orders, cid not in database: {}", cid);
+            return Err("cid does not exist in database".to_string());
+        }
+    }
+    if let (Some(from), Some(to)) = (&filter.from, &filter.to) {
+        if from > to {
+            return Err("from must not be after to".to_string());
+        }
     }
+    let conditions = "WHERE (?1 IS NULL OR o.customerId = ?1) AND (?2 IS NULL OR o.state = ?2)
+         AND (?3 IS NULL OR o.createdAt >= ?3) AND (?4 IS NULL OR o.createdAt < date(?4, '+1 day'))";
+    let state = filter.state.map(|s| s.as_str());
+    let total: i64 = db
+        .query_row(
+            &format!("SELECT COUNT(*) FROM Orders o {}", conditions),
+            params![filter.customer_id, state, filter.from, filter.to],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count Orders table");
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {} {} ORDER BY o.id DESC LIMIT ?5 OFFSET ?6",
+            STATUS_COLUMNS, conditions
+        ))
+        .expect("expected to be able to select from Orders table");
+    let rows = stmt
+        .query_map(
+            params![
+                filter.customer_id,
+                state,
+                filter.from,
+                filter.to,
+                per_page,
+                (page - 1) * per_page
+            ],
+            status_row,
+        )
+        .expect("expected to be able to get orders from Orders table");
+    let mut orders: Vec<OrderStatus> = rows
+        .map(|r| r.expect("problem getting order from database"))
+        .collect();
+    for order in orders.iter_mut() {
+        order.lines = order_lines(&db, order.id);
+    }
+    Ok((orders, total))
+}
+
+pub fn order_lines(conn: &Connection, oid: i64) -> Vec<OrderLine> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.bookId, b.title, l.quantity, l.unitPrice, l.tax, l.discount
+             FROM OrderLines l JOIN Books b ON b.id = l.bookId WHERE l.orderId = ?1 ORDER BY l.id",
+        )
+        .expect("expected to be able to select from OrderLines table");
+    let rows = stmt
+        .query_map([&oid], |row| {
+            let quantity: i64 = row.get(2)?;
+            let unit_price: f64 = row.get(3)?;
+            Ok(OrderLine {
+                book_id: row.get(0)?,
+                title: row.get(1)?,
+                quantity,
+                unit_price,
+                line_total: (unit_price * quantity as f64 * 100.0).round() / 100.0,
+                tax: row.get(4)?,
+                discount: row.get(5)?,
+            })
+        })
+        .expect("expected to be able to get lines from OrderLines table");
+    rows.map(|r| r.expect("problem getting order line from database"))
+        .collect()
 }
 
 pub fn get_purchase_order_id(cid: i64, bid: i64) -> Result<i64, String> {
@@ -29,11 +693,14 @@ pub fn get_purchase_order_id(cid: i64, bid: i64) -> Result<i64, String> {
     });
     if exist {
         let mut stmt = db
-            .prepare("SELECT id FROM PurchaseOrders WHERE customerId = ?1 AND bookId = ?2")
-            .expect("expected to be able to select from PurchaseOrders table");
+            .prepare(
+                "SELECT o.id FROM Orders o JOIN OrderLines l ON l.orderId = o.id
+                 WHERE o.customerId = ?1 AND l.bookId = ?2 ORDER BY o.id DESC",
+            )
+            .expect("expected to be able to select from Orders table");
         let mut rows = stmt
             .query_map([&cid, &bid], |row| row.get(0))
-            .expect("expected to be able to get id from PurchaseOrders table");
+            .expect("expected to be able to get id from Orders table");
         let id = rows
             .next()
             .expect("expected a value in the row")
@@ -46,41 +713,43 @@ pub fn get_purchase_order_id(cid: i64, bid: i64) -> Result<i64, String> {
 }
 
 pub fn is_po_shipped(poid: i64) -> Result<i64, String> {
+    //! 1 once the order has left the warehouse, derived from its state
     let db = connect();
     let exist = exists_shipped(poid).unwrap_or_else(|e| {
         error!(target: "error", "statement exists check error: {}", e);
         panic!("connection with database failure")
     });
     if exist {
-        let mut stmt = db
-            .prepare("SELECT shipped FROM PurchaseOrders WHERE id = ?1")
-            .expect("expected to be able to select from PurchaseOrders table");
-        let mut rows = stmt
-            .query_map([&poid], |row| row.get(0))
-            .expect("expected to be able to get shipped from PurchaseOrders table");
-        let shipped: i64 = rows
-            .next()
-            .expect("expected a value in the row")
-            .expect("problem getting shipped from database");
-        Ok(shipped)
+        let state = order_state::current(&db, poid).expect("expected the order to exist");
+        Ok(state.has_shipped() as i64)
     } else {
         warn!(target: "warn", "poid not in database: {}", poid);
         Err("purchase order does not exist in database".to_string())
     }
 }
 
-pub fn ship_po(poid: i64) -> Result<(), String> {
-    let db = connect();
+pub fn ship_po(poid: i64, actor: &str) -> Result<(), String> {
+    //! ships everything left on the order in one parcel with the carrier service it was
+    //! priced with, see `shipments::create_shipment` for parcels with tracking
     let exist = exists_shipped(poid).unwrap_or_else(|e| {
         error!(target: "error", "statement exists check error: {}", e);
         panic!("connection with database failure")
     });
     if exist {
-        db.execute(
-            "UPDATE PurchaseOrders SET shipped = 1 WHERE id = ?1",
-            [&poid],
-        )
-        .expect("expected to be able to update PurchaseOrders table");
+        let (carrier, service_level): (Option<String>, Option<String>) = connect()
+            .query_row(
+                "SELECT shippingCarrier, shippingService FROM Orders WHERE id = ?1",
+                [&poid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("expected to be able to select from Orders table");
+        let parcel = Parcel {
+            carrier: carrier.unwrap_or_else(|| "unspecified".to_string()),
+            service_level: service_level.unwrap_or_else(|| "standard".to_string()),
+            tracking_number: None,
+            shipped_at: None,
+        };
+        shipments::create_shipment(poid, parcel, None, actor)?;
         Ok(())
     } else {
         warn!(target: "warn", "poid not in database: {}", poid);
@@ -88,12 +757,35 @@ pub fn ship_po(poid: i64) -> Result<(), String> {
     }
 }
 
+pub fn set_state(
+    poid: i64,
+    next: OrderState,
+    actor: &str,
+    note: Option<String>,
+) -> Result<(), String> {
+    //! fulfilment steps other than shipping, which also credits loyalty points
+    if next == OrderState::Shipped {
+        return ship_po(poid, actor);
+    }
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .expect("expected to be able to start a transaction");
+    order_state::transition(&tx, poid, next, actor, note)?;
+    tx.commit()
+        .expect("expected to be able to commit order state transaction");
+    Ok(())
+}
+
 fn exists_id(cid: i64, bid: i64) -> Result<bool, rusqlite::Error> {
-    //! checks that the cid and bid exist in database
+    //! checks that the customer has an order with the book in it
     let conn = connect();
     let check = conn
-        .prepare("SELECT id FROM PurchaseOrders WHERE customerId = ?1 AND bookId = ?2")
-        .expect("expected to be able to select from Books table")
+        .prepare(
+            "SELECT o.id FROM Orders o JOIN OrderLines l ON l.orderId = o.id
+             WHERE o.customerId = ?1 AND l.bookId = ?2",
+        )
+        .expect("expected to be able to select from Orders table")
         .exists([&cid, &bid])?;
     Ok(check)
 }
@@ -102,8 +794,230 @@ fn exists_shipped(poid: i64) -> Result<bool, rusqlite::Error> {
     //! same functionality as exists_id() but when only poid is provided
     let conn = connect();
     let check = conn
-        .prepare("SELECT shipped FROM PurchaseOrders WHERE id = ?1")
-        .expect("expected to be able to select from Books table")
+        .prepare("SELECT id FROM Orders WHERE id = ?1")
+        .expect("expected to be able to select from Orders table")
         .exists([&poid])?;
     Ok(check)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::customers;
+    use crate::db::test_customer as customer;
+
+    fn balance(cid: i64) -> f64 {
+        ledger::balance(&connect(), cid)
+    }
+
+    fn order(cid: i64, lines: &[(i64, i64)]) -> Result<OrderSummary, OrderError> {
+        create_order(cid, lines, 0, None, ShippingChoice::default())
+    }
+
+    #[test]
+    fn placing_an_order_charges_the_balance() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 50.0);
+
+        // 12.99 book, 3.99 shipping and 0.94 tax
+        let summary = order(cid, &[(1, 1)]).ok().expect("order placed");
+        assert_eq!(summary.total, 17.92);
+        assert_eq!(balance(cid), 32.08);
+        let charged: f64 = connect()
+            .query_row(
+                "SELECT amount FROM LedgerEntries WHERE orderId = ?1 AND kind = 'order_charge'",
+                [&summary.id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(charged, -17.92);
+        assert_eq!(
+            order_state::current(&connect(), summary.id),
+            Some(OrderState::Paid)
+        );
+    }
+
+    #[test]
+    fn orders_the_balance_does_not_cover_are_refused() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 10.0);
+
+        match order(cid, &[(1, 1)]) {
+            Err(OrderError::PaymentRequired(_)) => {}
+            _ => panic!("expected payment to be required"),
+        }
+        assert_eq!(balance(cid), 10.0);
+        let orders: i64 = connect()
+            .query_row("SELECT COUNT(*) FROM Orders", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(orders, 0);
+    }
+
+    fn stock(bid: i64) -> Option<i64> {
+        connect()
+            .query_row("SELECT stock FROM Books WHERE id = ?1", [&bid], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn lines_are_priced_by_quantity_at_the_price_of_the_day() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 100.0);
+
+        let summary = order(cid, &[(2, 3), (4, 1)]).ok().unwrap();
+        assert_eq!(summary.lines.len(), 2);
+        assert_eq!(summary.lines[0].unit_price, 9.99);
+        assert_eq!(summary.lines[0].line_total, 29.97);
+        assert_eq!(summary.lines[1].line_total, 7.99);
+        assert_eq!(summary.subtotal, 37.96);
+
+        // later price changes don't change the order
+        crate::db::books::set_price(2, 19.99).unwrap();
+        let lines = order_lines(&connect(), summary.id);
+        assert_eq!(lines[0].unit_price, 9.99);
+        assert_eq!(lines[0].quantity, 3);
+    }
+
+    #[test]
+    fn tracked_stock_is_reserved_and_shortages_place_nothing() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 200.0);
+        crate::db::books::set_stock(2, Some(5)).unwrap();
+
+        order(cid, &[(2, 3), (1, 1)]).ok().unwrap();
+        assert_eq!(stock(2), Some(2));
+        // untracked books aren't limited
+        assert_eq!(stock(1), None);
+
+        let before = balance(cid);
+        match order(cid, &[(1, 1), (2, 3)]) {
+            Err(OrderError::Rejected(e)) => assert!(e.contains("not enough stock")),
+            _ => panic!("expected the order to be rejected"),
+        }
+        assert_eq!(stock(2), Some(2));
+        assert_eq!(balance(cid), before);
+    }
+
+    #[test]
+    fn unknown_books_and_customers_are_rejected() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 100.0);
+
+        assert!(matches!(
+            order(cid, &[(1, 1), (99, 1)]),
+            Err(OrderError::Rejected(_))
+        ));
+        assert!(matches!(order(99, &[(1, 1)]), Err(OrderError::Rejected(_))));
+    }
+
+    fn refunds(oid: i64) -> Vec<(i64, f64)> {
+        let db = connect();
+        let mut stmt = db
+            .prepare("SELECT customerId, amount FROM LedgerEntries WHERE orderId = ?1 AND kind = 'refund'")
+            .unwrap();
+        let rows = stmt
+            .query_map([&oid], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    fn cancel(
+        oid: i64,
+        customer: Option<i64>,
+        override_reason: Option<&str>,
+    ) -> Result<Cancellation, String> {
+        cancel_order(
+            oid,
+            customer,
+            "ada",
+            "changed their mind".to_string(),
+            override_reason.map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn cancelling_refunds_the_charge_and_restocks() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 50.0);
+        crate::db::books::set_stock(1, Some(3)).unwrap();
+        let oid = order(cid, &[(1, 2)]).ok().unwrap().id;
+        assert_eq!(balance(cid), 18.15);
+
+        let cancellation = cancel(oid, Some(cid), None).unwrap();
+        assert_eq!(cancellation.previous_state, OrderState::Paid);
+        assert_eq!(cancellation.refunded, 31.85);
+        assert_eq!(cancellation.stock_restored, 2);
+        assert!(cancellation.credit_note.is_some());
+        assert_eq!(balance(cid), 50.0);
+        assert_eq!(stock(1), Some(3));
+        // only once
+        assert!(cancel(oid, Some(cid), None).is_err());
+        assert_eq!(refunds(oid).len(), 1);
+    }
+
+    #[test]
+    fn customers_only_cancel_their_own_orders() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 50.0);
+        let other = customer("Bob Reader", 0.0);
+        let oid = order(cid, &[(1, 1)]).ok().unwrap().id;
+
+        assert!(cancel(oid, Some(other), None).is_err());
+        assert_eq!(
+            order_state::current(&connect(), oid),
+            Some(OrderState::Paid)
+        );
+    }
+
+    #[test]
+    fn orders_of_a_merged_customer_are_refunded_to_the_surviving_one() {
+        let _db = crate::db::test_db();
+        let source = customer("Ann Reader", 50.0);
+        let target = customer("Ann B. Reader", 0.0);
+        let oid = order(source, &[(1, 1)]).ok().unwrap().id;
+        customers::merge_customers(source, target, "ada".to_string(), "dupe".to_string()).unwrap();
+
+        // the charge was booked to the source, the refund goes to the order's customer now
+        assert_eq!(cancel(oid, Some(target), None).unwrap().refunded, 17.92);
+        assert_eq!(refunds(oid), [(target, 17.92)]);
+        assert_eq!(balance(target), 50.0);
+        assert_eq!(balance(source), 0.0);
+    }
+
+    #[test]
+    fn shipped_orders_are_never_cancelled() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 50.0);
+        let oid = order(cid, &[(1, 1)]).ok().unwrap().id;
+        ship_po(oid, "cleo").unwrap();
+
+        assert!(cancel(oid, None, None).is_err());
+        assert!(cancel(oid, None, Some("customer called")).is_err());
+        assert!(refunds(oid).is_empty());
+    }
+
+    #[test]
+    fn an_override_refunds_only_what_has_not_shipped() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 100.0);
+        crate::db::books::set_stock(2, Some(2)).unwrap();
+        // 19.98 + 1.45 tax and 12.99 + 0.94 tax, with 6.99 shipping for 1.2 kg
+        let oid = order(cid, &[(2, 2), (1, 1)]).ok().unwrap().id;
+        let parcel = Parcel {
+            carrier: "usps".to_string(),
+            service_level: "standard".to_string(),
+            tracking_number: None,
+            shipped_at: None,
+        };
+        shipments::create_shipment(oid, parcel, Some(&[(1, 1)]), "cleo").unwrap();
+
+        assert!(cancel(oid, None, None).is_err());
+        let cancellation = cancel(oid, None, Some("out of print")).unwrap();
+        // the two unshipped copies with their tax, not the shipping or the shipped book
+        assert_eq!(cancellation.refunded, 21.43);
+        assert_eq!(cancellation.stock_restored, 2);
+        assert_eq!(stock(2), Some(2));
+    }
+}
@@ -0,0 +1,520 @@
+use super::db::connect;
+use super::invoices;
+use super::ledger::{self, EntryKind};
+use super::loyalty;
+use super::order_state::{self, OrderState};
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    AsNew,
+    Damaged,
+    Unsellable,
+}
+
+impl Condition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Condition::AsNew => "as_new",
+            Condition::Damaged => "damaged",
+            Condition::Unsellable => "unsellable",
+        }
+    }
+
+    pub fn parse(condition: &str) -> Option<Condition> {
+        match condition {
+            "as_new" => Some(Condition::AsNew),
+            "damaged" => Some(Condition::Damaged),
+            "unsellable" => Some(Condition::Unsellable),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Restock,
+    WriteOff,
+}
+
+impl Disposition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Disposition::Restock => "restock",
+            Disposition::WriteOff => "write_off",
+        }
+    }
+
+    pub fn parse(disposition: &str) -> Option<Disposition> {
+        match disposition {
+            "restock" => Some(Disposition::Restock),
+            "write_off" => Some(Disposition::WriteOff),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RmaLine {
+    pub book_id: i64,
+    pub title: String,
+    pub quantity: i64,
+    pub unit_price: f64,
+    pub condition: Option<String>,
+    pub disposition: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Rma {
+    pub id: i64,
+    pub order_id: i64,
+    pub customer_id: i64,
+    pub status: String,
+    pub reason: String,
+    pub decided_by: Option<String>,
+    pub decision_note: Option<String>,
+    pub received_by: Option<String>,
+    pub refunded: Option<f64>,
+    pub created_at: String,
+    pub updated_at: String,
+    pub lines: Vec<RmaLine>,
+}
+
+pub fn open_rma(oid: i64, cid: i64, reason: String, lines: &[(i64, i64)]) -> Result<i64, String> {
+    //! takes (bid, quantity) lines of a shipped order, a line can't be returned
+    //! more often than it was ordered, counting every RMA that wasn't rejected
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start rma transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let owner: Option<i64> = tx
+        .query_row(
+            "SELECT customerId FROM Orders WHERE id = ?1",
+            [&oid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from Orders table");
+    if owner != Some(cid) {
+        warn!(target: "warn", "rma for unknown order {} by customer {}", oid, cid);
+        return Err("order does not exist in database".to_string());
+    }
+    let state = order_state::current(&tx, oid).expect("expected the order to exist");
+    if !matches!(state, OrderState::Shipped | OrderState::Delivered) {
+        return Err(format!(
+            "order is {}, only shipped orders can be returned",
+            state.as_str()
+        ));
+    }
+    let mut rma_lines = Vec::with_capacity(lines.len());
+    for &(bid, quantity) in lines {
+        let line: Option<(i64, i64)> = tx
+            .query_row(
+                "SELECT id, quantity - COALESCE((
+                     SELECT SUM(r.quantity) FROM RmaLines r JOIN Rmas m ON m.id = r.rmaId
+                     WHERE r.orderLineId = OrderLines.id AND m.status != 'rejected'), 0)
+                 FROM OrderLines WHERE orderId = ?1 AND bookId = ?2",
+                [&oid, &bid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .expect("expected to be able to select from OrderLines table");
+        match line {
+            None => return Err(format!("bid {} is not part of the order", bid)),
+            Some((_, returnable)) if quantity > returnable => {
+                warn!(target: "warn", "rma for order {} asks {} of book {}, {} returnable",
+                    oid, quantity, bid, returnable);
+                return Err(format!(
+                    "only {} of bid {} can be returned",
+                    returnable, bid
+                ));
+            }
+            Some((olid, _)) => rma_lines.push((olid, quantity)),
+        }
+    }
+    tx.execute(
+        "INSERT INTO Rmas (orderId, customerId, reason) VALUES (?1, ?2, ?3)",
+        params![oid, cid, reason],
+    )
+    .expect("expected to be able to insert into Rmas table");
+    let rid = tx.last_insert_rowid();
+    for (olid, quantity) in rma_lines {
+        tx.execute(
+            "INSERT INTO RmaLines (rmaId, orderLineId, quantity) VALUES (?1, ?2, ?3)",
+            [&rid, &olid, &quantity],
+        )
+        .expect("expected to be able to insert into RmaLines table");
+    }
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit rma transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "rma {} opened for order {} by customer {}", rid, oid, cid);
+    Ok(rid)
+}
+
+pub fn decide(rid: i64, approve: bool, actor: &str, note: Option<String>) -> Result<(), String> {
+    let db = connect();
+    let status = if approve { "approved" } else { "rejected" };
+    let updated = db
+        .execute(
+            "UPDATE Rmas SET status = ?1, decidedBy = ?2, decisionNote = ?3, updatedAt = datetime('now')
+             WHERE id = ?4 AND status = 'requested'",
+            params![status, actor, note, rid],
+        )
+        .expect("expected to be able to update Rmas table");
+    if updated == 0 {
+        return Err(not_in_status(&db, rid, "requested"));
+    }
+    info!(target: "info", "rma {} {} by {}", rid, status, actor);
+    Ok(())
+}
+
+pub fn receive(
+    rid: i64,
+    actor: &str,
+    items: &[(i64, Condition, Disposition)],
+) -> Result<Rma, String> {
+    //! records the condition and disposition of each returned book, puts restocked copies
+    //! back into tracked stock and refunds the lines at the price paid, net of their share
+    //! of the order's discount and with the tax charged on them, shipping isn't refunded.
+    //! The order becomes returned once all of it has come back
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start rma transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let (oid, cid, status): (i64, i64, String) = match tx
+        .query_row(
+            "SELECT r.orderId, o.customerId, r.status FROM Rmas r
+             JOIN Orders o ON o.id = r.orderId WHERE r.id = ?1",
+            [&rid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .expect("expected to be able to select from Rmas table")
+    {
+        Some(r) => r,
+        None => return Err("rma does not exist in database".to_string()),
+    };
+    if status != "approved" {
+        return Err(format!(
+            "rma is {}, only approved rmas can be received",
+            status
+        ));
+    }
+    let lines = rma_lines(&tx, rid);
+    for line in &lines {
+        if !items.iter().any(|(bid, _, _)| *bid == line.book_id) {
+            return Err(format!("no condition given for bid {}", line.book_id));
+        }
+    }
+    let mut value = 0.0;
+    let mut added_tax = 0.0;
+    for &(bid, condition, disposition) in items {
+        let line = match lines.iter().find(|l| l.book_id == bid) {
+            Some(l) => l,
+            None => return Err(format!("bid {} is not part of the rma", bid)),
+        };
+        tx.execute(
+            "UPDATE RmaLines SET condition = ?1, disposition = ?2
+             WHERE rmaId = ?3 AND orderLineId IN (SELECT id FROM OrderLines WHERE bookId = ?4)",
+            params![condition.as_str(), disposition.as_str(), rid, bid],
+        )
+        .expect("expected to be able to update RmaLines table");
+        if disposition == Disposition::Restock {
+            tx.execute(
+                "UPDATE Books SET stock = stock + ?1 WHERE id = ?2 AND stock IS NOT NULL",
+                [&line.quantity, &bid],
+            )
+            .expect("expected to be able to update Books table");
+        }
+        // the line's share of the discount stays with the order, tax that was added on top
+        // of the price comes back with the copies
+        let (discount, tax): (f64, f64) = tx
+            .query_row(
+                "SELECT l.discount * ?1 / l.quantity,
+                     CASE WHEN o.taxInclusive = 0 THEN l.tax * ?1 / l.quantity ELSE 0 END
+                 FROM OrderLines l JOIN Orders o ON o.id = l.orderId
+                 WHERE l.orderId = ?2 AND l.bookId = ?3",
+                params![line.quantity, oid, bid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .expect("expected to be able to select from OrderLines table")
+            .unwrap_or((0.0, 0.0));
+        value += line.unit_price * line.quantity as f64 - discount;
+        added_tax += tax;
+    }
+    // never refund more than is left of what was charged for the order
+    let charged: f64 = tx
+        .query_row(
+            "SELECT COALESCE(-SUM(amount), 0.0) FROM LedgerEntries
+             WHERE orderId = ?1 AND kind IN ('order_charge', 'refund')",
+            [&oid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum LedgerEntries table");
+    let refund = ((value + added_tax).min(charged).max(0.0) * 100.0).round() / 100.0;
+    if refund >= 0.01 {
+        ledger::insert_entry(
+            &tx,
+            cid,
+            EntryKind::Refund,
+            refund,
+            Some(oid),
+            Some(format!("return {}", rid)),
+        );
+    }
+    let returned: Vec<(i64, i64)> = lines.iter().map(|l| (l.book_id, l.quantity)).collect();
+    invoices::credit(
+        &tx,
+        oid,
+        refund,
+        &format!("return {}", rid),
+        Some(&returned),
+    );
+    tx.execute(
+        "UPDATE Rmas SET status = 'completed', receivedBy = ?1, refunded = ?2, updatedAt = datetime('now')
+         WHERE id = ?3",
+        params![actor, refund, rid],
+    )
+    .expect("expected to be able to update Rmas table");
+    let outstanding: i64 = tx
+        .query_row(
+            "SELECT COALESCE(SUM(quantity), 0) - COALESCE((
+                 SELECT SUM(r.quantity) FROM RmaLines r JOIN Rmas m ON m.id = r.rmaId
+                 WHERE m.orderId = ?1 AND m.status = 'completed'), 0)
+             FROM OrderLines WHERE orderId = ?1",
+            [&oid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to sum OrderLines table");
+    if outstanding <= 0 {
+        order_state::transition(
+            &tx,
+            oid,
+            OrderState::Returned,
+            actor,
+            Some(format!("return {}", rid)),
+        )?;
+        loyalty::reverse_order(&tx, cid, oid);
+    }
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit rma transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "rma {} received by {}, refunded {:.2}", rid, actor, refund);
+    get_rma(rid)
+}
+
+pub fn get_rma(rid: i64) -> Result<Rma, String> {
+    let db = connect();
+    let mut rmas = select_rmas(&db, "WHERE id = ?1", params![rid]);
+    rmas.pop().ok_or_else(|| {
+        warn!(target: "warn", "rma not in database: {}", rid);
+        "rma does not exist in database".to_string()
+    })
+}
+
+pub fn queue(statuses: &[&str]) -> Vec<Rma> {
+    //! oldest first, so the longest waiting customers are handled first
+    let db = connect();
+    let placeholders = vec!["?"; statuses.len()].join(", ");
+    select_rmas(
+        &db,
+        &format!("WHERE status IN ({}) ORDER BY createdAt, id", placeholders),
+        rusqlite::params_from_iter(statuses),
+    )
+}
+
+fn select_rmas<P: rusqlite::Params>(conn: &Connection, filter: &str, params: P) -> Vec<Rma> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, orderId, customerId, status, reason, decidedBy, decisionNote,
+             receivedBy, refunded, createdAt, updatedAt FROM Rmas {}",
+            filter
+        ))
+        .expect("expected to be able to select from Rmas table");
+    let rows = stmt
+        .query_map(params, |row| {
+            Ok(Rma {
+                id: row.get(0)?,
+                order_id: row.get(1)?,
+                customer_id: row.get(2)?,
+                status: row.get(3)?,
+                reason: row.get(4)?,
+                decided_by: row.get(5)?,
+                decision_note: row.get(6)?,
+                received_by: row.get(7)?,
+                refunded: row.get(8)?,
+                created_at: row.get(9)?,
+                updated_at: row.get(10)?,
+                lines: Vec::new(),
+            })
+        })
+        .expect("expected to be able to get rmas from Rmas table");
+    rows.map(|r| {
+        let mut rma = r.expect("problem getting rma from database");
+        rma.lines = rma_lines(conn, rma.id);
+        rma
+    })
+    .collect()
+}
+
+fn rma_lines(conn: &Connection, rid: i64) -> Vec<RmaLine> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.bookId, b.title, r.quantity, l.unitPrice, r.condition, r.disposition
+             FROM RmaLines r JOIN OrderLines l ON l.id = r.orderLineId
+             JOIN Books b ON b.id = l.bookId WHERE r.rmaId = ?1 ORDER BY r.id",
+        )
+        .expect("expected to be able to select from RmaLines table");
+    let rows = stmt
+        .query_map([&rid], |row| {
+            Ok(RmaLine {
+                book_id: row.get(0)?,
+                title: row.get(1)?,
+                quantity: row.get(2)?,
+                unit_price: row.get(3)?,
+                condition: row.get(4)?,
+                disposition: row.get(5)?,
+            })
+        })
+        .expect("expected to be able to get lines from RmaLines table");
+    rows.map(|r| r.expect("problem getting rma line from database"))
+        .collect()
+}
+
+fn not_in_status(conn: &Connection, rid: i64, expected: &str) -> String {
+    let status: Option<String> = conn
+        .query_row("SELECT status FROM Rmas WHERE id = ?1", [&rid], |row| {
+            row.get(0)
+        })
+        .optional()
+        .expect("expected to be able to select from Rmas table");
+    match status {
+        Some(s) => format!("rma is {}, it must be {}", s, expected),
+        None => "rma does not exist in database".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::customers;
+    use crate::db::purchaseOrders::{create_order, ship_po, ShippingChoice};
+    use crate::db::test_customer as customer;
+
+    fn shipped_order(cid: i64) -> i64 {
+        //! two copies of Dune (19.98 + 1.45 tax) and one Hitchhiker's Guide (12.99 + 0.94 tax),
+        //! 1.2 kg shipped for 6.99
+        let oid = create_order(cid, &[(2, 2), (1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id;
+        ship_po(oid, "cleo").unwrap();
+        oid
+    }
+
+    fn balance(cid: i64) -> f64 {
+        ledger::balance(&connect(), cid)
+    }
+
+    fn stock(bid: i64) -> Option<i64> {
+        connect()
+            .query_row("SELECT stock FROM Books WHERE id = ?1", [&bid], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn only_shipped_copies_can_be_returned_once() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 100.0);
+        let unshipped = create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id;
+        assert!(open_rma(unshipped, cid, "late".to_string(), &[(1, 1)]).is_err());
+
+        let oid = shipped_order(cid);
+        assert!(open_rma(oid, cid, "torn".to_string(), &[(2, 3)]).is_err());
+        assert!(open_rma(oid, cid, "torn".to_string(), &[(5, 1)]).is_err());
+        let first = open_rma(oid, cid, "torn".to_string(), &[(2, 2)]).unwrap();
+        assert!(open_rma(oid, cid, "torn".to_string(), &[(2, 1)]).is_err());
+        // a rejected rma gives its copies back
+        decide(first, false, "sam", None).unwrap();
+        assert!(open_rma(oid, cid, "torn".to_string(), &[(2, 1)]).is_ok());
+        assert!(decide(first, true, "sam", None).is_err());
+    }
+
+    #[test]
+    fn received_copies_are_refunded_with_their_tax_and_restocked() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 100.0);
+        crate::db::books::set_stock(1, Some(1)).unwrap();
+        let oid = shipped_order(cid);
+
+        let rid = open_rma(oid, cid, "wrong book".to_string(), &[(1, 1)]).unwrap();
+        assert!(receive(rid, "cleo", &[(1, Condition::AsNew, Disposition::Restock)]).is_err());
+        decide(rid, true, "sam", None).unwrap();
+        let rma = receive(rid, "cleo", &[(1, Condition::AsNew, Disposition::Restock)]).unwrap();
+        assert_eq!(rma.status, "completed");
+        assert_eq!(rma.refunded, Some(13.93));
+        assert_eq!(balance(cid), 71.58);
+        assert_eq!(stock(1), Some(1));
+        // the rest of the order hasn't come back
+        assert_eq!(
+            order_state::current(&connect(), oid),
+            Some(OrderState::Shipped)
+        );
+    }
+
+    #[test]
+    fn written_off_copies_are_refunded_but_not_restocked() {
+        let _db = crate::db::test_db();
+        let cid = customer("Ann Reader", 100.0);
+        crate::db::books::set_stock(2, Some(2)).unwrap();
+        let oid = shipped_order(cid);
+
+        let rid = open_rma(oid, cid, "water damage".to_string(), &[(2, 2), (1, 1)]).unwrap();
+        decide(rid, true, "sam", None).unwrap();
+        let items = [
+            (2, Condition::Unsellable, Disposition::WriteOff),
+            (1, Condition::Damaged, Disposition::WriteOff),
+        ];
+        assert_eq!(receive(rid, "cleo", &items).unwrap().refunded, Some(35.36));
+        assert_eq!(stock(2), Some(0));
+        // everything came back, shipping isn't refunded
+        assert_eq!(
+            order_state::current(&connect(), oid),
+            Some(OrderState::Returned)
+        );
+        assert_eq!(balance(cid), 93.01);
+    }
+
+    #[test]
+    fn returns_of_a_merged_customer_are_refunded_to_the_surviving_one() {
+        let _db = crate::db::test_db();
+        let source = customer("Ann Reader", 100.0);
+        let target = customer("Ann B. Reader", 0.0);
+        let oid = shipped_order(source);
+        customers::merge_customers(source, target, "ada".to_string(), "dupe".to_string()).unwrap();
+
+        let rid = open_rma(oid, target, "wrong book".to_string(), &[(1, 1)]).unwrap();
+        decide(rid, true, "sam", None).unwrap();
+        receive(rid, "cleo", &[(1, Condition::AsNew, Disposition::WriteOff)]).unwrap();
+        // 100.00 - 42.35 charged + 13.93 refunded
+        assert_eq!(balance(target), 71.58);
+        assert_eq!(balance(source), 0.0);
+    }
+}
@@ -0,0 +1,684 @@
+use super::db::connect;
+use super::loyalty;
+use super::order_state::{self, OrderState};
+use super::webhooks::{self, EventType};
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+use serde_json::json;
+
+/// carrier details of a parcel, `shipped_at` defaults to now
+pub struct Parcel {
+    pub carrier: String,
+    pub service_level: String,
+    pub tracking_number: Option<String>,
+    pub shipped_at: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ParcelLine {
+    pub book_id: i64,
+    pub title: String,
+    pub quantity: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Shipment {
+    pub id: i64,
+    pub carrier: String,
+    pub service_level: String,
+    pub tracking_number: Option<String>,
+    pub shipped_at: String,
+    pub lines: Vec<ParcelLine>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Tracking {
+    pub order_id: i64,
+    pub state: OrderState,
+    pub shipments: Vec<Shipment>,
+    pub unshipped: Vec<ParcelLine>,
+}
+
+pub fn create_shipment(
+    oid: i64,
+    parcel: Parcel,
+    lines: Option<&[(i64, i64)]>,
+    actor: &str,
+) -> Result<Shipment, String> {
+    //! records a parcel with the given (bid, quantity) lines, or everything not yet shipped.
+    //! A paid order is moved to picking by its first parcel and becomes shipped, earning
+    //! its loyalty points, once every line is covered
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start shipment transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let shipment = insert_shipment(&tx, oid, parcel, lines, actor)?;
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit shipment transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "shipment {} of order {} sent by {} with {} {}",
+        shipment.id, oid, actor, shipment.carrier, shipment.service_level);
+    Ok(shipment)
+}
+
+fn insert_shipment(
+    tx: &Connection,
+    oid: i64,
+    parcel: Parcel,
+    lines: Option<&[(i64, i64)]>,
+    actor: &str,
+) -> Result<Shipment, String> {
+    //! [`create_shipment`] on the caller's transaction
+    match order_state::current(tx, oid) {
+        None => {
+            warn!(target: "warn", "shipment for unknown order: {}", oid);
+            return Err("order does not exist in database".to_string());
+        }
+        Some(OrderState::Paid) => {
+            order_state::transition(tx, oid, OrderState::Picking, actor, None)?;
+        }
+        Some(OrderState::Picking) => {}
+        Some(s) => {
+            warn!(target: "warn", "shipment for order {} in state {}", oid, s.as_str());
+            return Err(format!("order is {}, it can't be shipped", s.as_str()));
+        }
+    }
+    let remaining = unshipped_lines(tx, oid);
+    let included: Vec<(i64, i64, i64)> = match lines {
+        None => remaining
+            .iter()
+            .map(|(olid, l)| (*olid, l.book_id, l.quantity))
+            .collect(),
+        Some(lines) => {
+            let mut included = Vec::with_capacity(lines.len());
+            for &(bid, quantity) in lines {
+                match remaining.iter().find(|(_, l)| l.book_id == bid) {
+                    Some((olid, l)) if quantity <= l.quantity => {
+                        included.push((*olid, bid, quantity))
+                    }
+                    Some((_, l)) => {
+                        return Err(format!("only {} of bid {} left to ship", l.quantity, bid))
+                    }
+                    None => {
+                        return Err(format!("bid {} has nothing left to ship in the order", bid))
+                    }
+                }
+            }
+            included
+        }
+    };
+    if included.is_empty() {
+        return Err("order has nothing left to ship".to_string());
+    }
+    tx.execute(
+        "INSERT INTO Shipments (orderId, carrier, serviceLevel, trackingNumber, shippedAt, createdBy)
+         VALUES (?1, ?2, ?3, ?4, COALESCE(?5, datetime('now')), ?6)",
+        params![
+            oid,
+            parcel.carrier,
+            parcel.service_level,
+            parcel.tracking_number,
+            parcel.shipped_at,
+            actor
+        ],
+    )
+    .expect("expected to be able to insert into Shipments table");
+    let sid = tx.last_insert_rowid();
+    for (olid, _, quantity) in &included {
+        tx.execute(
+            "INSERT INTO ShipmentLines (shipmentId, orderLineId, quantity) VALUES (?1, ?2, ?3)",
+            [&sid, olid, quantity],
+        )
+        .expect("expected to be able to insert into ShipmentLines table");
+    }
+    if unshipped_lines(tx, oid).is_empty() {
+        order_state::transition(
+            tx,
+            oid,
+            OrderState::Shipped,
+            actor,
+            Some(format!("shipment {}", sid)),
+        )?;
+        let cid: i64 = tx
+            .query_row(
+                "SELECT customerId FROM Orders WHERE id = ?1",
+                [&oid],
+                |row| row.get(0),
+            )
+            .expect("expected to be able to select from Orders table");
+        loyalty::earn_for_order(tx, cid, oid);
+        webhooks::emit(
+            tx,
+            EventType::OrderShipped,
+            json!({ "order_id": oid, "customer_id": cid, "shipments": shipments(tx, oid) }),
+        );
+    }
+    Ok(shipments(tx, oid)
+        .into_iter()
+        .find(|s| s.id == sid)
+        .expect("expected the shipment to exist"))
+}
+
+/// a row of a bulk shipping file that could be read, `line` is its line in the file
+pub struct BulkRow {
+    pub line: u64,
+    pub order_id: i64,
+    pub carrier: String,
+    pub tracking_number: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RowOutcome {
+    Shipped,
+    AlreadyShipped,
+    Unknown,
+    Malformed,
+    /// the order can't ship, e.g. it was cancelled
+    Rejected,
+    /// would have shipped, but another row failed in a file applied as a whole
+    NotApplied,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RowResult {
+    pub line: u64,
+    pub order_id: Option<i64>,
+    pub outcome: RowOutcome,
+    pub shipment_id: Option<i64>,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BulkReport {
+    /// whether the file was applied as a whole
+    pub atomic: bool,
+    pub shipped: usize,
+    pub already_shipped: usize,
+    pub unknown: usize,
+    pub malformed: usize,
+    pub rejected: usize,
+    pub not_applied: usize,
+    pub rows: Vec<RowResult>,
+}
+
+pub fn ship_bulk(
+    rows: &[BulkRow],
+    malformed: Vec<RowResult>,
+    atomic: bool,
+    actor: &str,
+) -> BulkReport {
+    //! ships everything left on each row's order as one parcel. `atomic` applies the file in
+    //! one transaction that is rolled back when any row, including a malformed one, fails;
+    //! otherwise each row is its own transaction. Orders that have already shipped are
+    //! reported and left alone, so a file can be uploaded again
+    let mut db = connect();
+    let mut results = malformed;
+    if atomic {
+        let tx = begin_bulk(&mut db);
+        results.extend(rows.iter().map(|row| ship_row(&tx, row, actor)));
+        let failed = results.iter().any(|r| {
+            matches!(
+                r.outcome,
+                RowOutcome::Unknown | RowOutcome::Malformed | RowOutcome::Rejected
+            )
+        });
+        if failed {
+            // dropping the transaction rolls it back
+            for r in results
+                .iter_mut()
+                .filter(|r| r.outcome == RowOutcome::Shipped)
+            {
+                r.outcome = RowOutcome::NotApplied;
+                r.shipment_id = None;
+            }
+        } else {
+            commit_bulk(tx);
+        }
+    } else {
+        for row in rows {
+            let tx = begin_bulk(&mut db);
+            let result = ship_row(&tx, row, actor);
+            // a row that failed part way leaves nothing behind
+            if result.outcome == RowOutcome::Shipped {
+                commit_bulk(tx);
+            }
+            results.push(result);
+        }
+    }
+    results.sort_by_key(|r| r.line);
+    let count = |outcome: RowOutcome| results.iter().filter(|r| r.outcome == outcome).count();
+    let report = BulkReport {
+        atomic,
+        shipped: count(RowOutcome::Shipped),
+        already_shipped: count(RowOutcome::AlreadyShipped),
+        unknown: count(RowOutcome::Unknown),
+        malformed: count(RowOutcome::Malformed),
+        rejected: count(RowOutcome::Rejected),
+        not_applied: count(RowOutcome::NotApplied),
+        rows: Vec::new(),
+    };
+    info!(target: "info", "bulk shipping file of {} rows applied by {}: {} shipped, {} already shipped, {} failed",
+        results.len(), actor, report.shipped, report.already_shipped,
+        report.unknown + report.malformed + report.rejected);
+    BulkReport {
+        rows: results,
+        ..report
+    }
+}
+
+fn ship_row(conn: &Connection, row: &BulkRow, actor: &str) -> RowResult {
+    //! everything left on the order in one parcel with the service it was priced with
+    let result = |outcome, shipment_id, message| RowResult {
+        line: row.line,
+        order_id: Some(row.order_id),
+        outcome,
+        shipment_id,
+        message,
+    };
+    let service_level: Option<Option<String>> = conn
+        .query_row(
+            "SELECT shippingService FROM Orders WHERE id = ?1",
+            [&row.order_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from Orders table");
+    let service_level = match service_level {
+        Some(s) => s.unwrap_or_else(|| "standard".to_string()),
+        None => {
+            warn!(target: "warn", "bulk shipping row {} names unknown order {}", row.line, row.order_id);
+            return result(
+                RowOutcome::Unknown,
+                None,
+                Some("order does not exist in database".to_string()),
+            );
+        }
+    };
+    match order_state::current(conn, row.order_id) {
+        Some(s) if s.has_shipped() => return result(RowOutcome::AlreadyShipped, None, None),
+        Some(OrderState::Paid) | Some(OrderState::Picking) => {}
+        Some(s) => {
+            warn!(target: "warn", "bulk shipping row {} names order {} in state {}", row.line, row.order_id, s.as_str());
+            return result(
+                RowOutcome::Rejected,
+                None,
+                Some(format!("order is {}, it can't be shipped", s.as_str())),
+            );
+        }
+        None => unreachable!("the order was just found"),
+    }
+    let parcel = Parcel {
+        carrier: row.carrier.clone(),
+        service_level,
+        tracking_number: row.tracking_number.clone(),
+        shipped_at: None,
+    };
+    match insert_shipment(conn, row.order_id, parcel, None, actor) {
+        Ok(shipment) => result(RowOutcome::Shipped, Some(shipment.id), None),
+        Err(e) => result(RowOutcome::Rejected, None, Some(e)),
+    }
+}
+
+fn begin_bulk(db: &mut Connection) -> rusqlite::Transaction<'_> {
+    db.transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start bulk shipping transaction: {}", e);
+            panic!("connection with database failure")
+        })
+}
+
+fn commit_bulk(tx: rusqlite::Transaction<'_>) {
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit bulk shipping transaction: {}", e);
+        panic!("connection with database failure")
+    });
+}
+
+pub fn tracking(oid: i64, cid: i64) -> Result<Tracking, String> {
+    //! the order's parcels and what hasn't shipped yet, `cid` has to be the order's customer
+    let db = connect();
+    let owner: Option<i64> = db
+        .query_row(
+            "SELECT customerId FROM Orders WHERE id = ?1",
+            [&oid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from Orders table");
+    if owner != Some(cid) {
+        warn!(target: "warn", "tracking of unknown order {} for customer {}", oid, cid);
+        return Err("order does not exist in database".to_string());
+    }
+    Ok(Tracking {
+        order_id: oid,
+        state: order_state::current(&db, oid).expect("expected the order to exist"),
+        shipments: shipments(&db, oid),
+        unshipped: unshipped_lines(&db, oid)
+            .into_iter()
+            .map(|(_, l)| l)
+            .collect(),
+    })
+}
+
+fn shipments(conn: &Connection, oid: i64) -> Vec<Shipment> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, carrier, serviceLevel, trackingNumber, shippedAt FROM Shipments
+             WHERE orderId = ?1 ORDER BY id",
+        )
+        .expect("expected to be able to select from Shipments table");
+    let rows = stmt
+        .query_map([&oid], |row| {
+            Ok(Shipment {
+                id: row.get(0)?,
+                carrier: row.get(1)?,
+                service_level: row.get(2)?,
+                tracking_number: row.get(3)?,
+                shipped_at: row.get(4)?,
+                lines: Vec::new(),
+            })
+        })
+        .expect("expected to be able to get shipments from Shipments table");
+    rows.map(|r| {
+        let mut shipment = r.expect("problem getting shipment from database");
+        shipment.lines = shipment_lines(conn, shipment.id);
+        shipment
+    })
+    .collect()
+}
+
+fn shipment_lines(conn: &Connection, sid: i64) -> Vec<ParcelLine> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.bookId, b.title, s.quantity FROM ShipmentLines s
+             JOIN OrderLines l ON l.id = s.orderLineId JOIN Books b ON b.id = l.bookId
+             WHERE s.shipmentId = ?1 ORDER BY s.id",
+        )
+        .expect("expected to be able to select from ShipmentLines table");
+    let rows = stmt
+        .query_map([&sid], |row| {
+            Ok(ParcelLine {
+                book_id: row.get(0)?,
+                title: row.get(1)?,
+                quantity: row.get(2)?,
+            })
+        })
+        .expect("expected to be able to get lines from ShipmentLines table");
+    rows.map(|r| r.expect("problem getting shipment line from database"))
+        .collect()
+}
+
+fn unshipped_lines(conn: &Connection, oid: i64) -> Vec<(i64, ParcelLine)> {
+    //! order line ids with the quantity not yet in a parcel
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.id, l.bookId, b.title, l.quantity - COALESCE(
+                 (SELECT SUM(quantity) FROM ShipmentLines WHERE orderLineId = l.id), 0) AS left
+             FROM OrderLines l JOIN Books b ON b.id = l.bookId
+             WHERE l.orderId = ?1 AND left > 0 ORDER BY l.id",
+        )
+        .expect("expected to be able to select from OrderLines table");
+    let rows = stmt
+        .query_map([&oid], |row| {
+            Ok((
+                row.get(0)?,
+                ParcelLine {
+                    book_id: row.get(1)?,
+                    title: row.get(2)?,
+                    quantity: row.get(3)?,
+                },
+            ))
+        })
+        .expect("expected to be able to get lines from OrderLines table");
+    rows.map(|r| r.expect("problem getting order line from database"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{cancel_order, create_order, ShippingChoice};
+
+    fn order(lines: &[(i64, i64)]) -> (i64, i64) {
+        let cid = crate::db::test_customer("Ann Reader", 100.0);
+        let oid = create_order(cid, lines, 0, None, ShippingChoice::default())
+            .ok()
+            .expect("order placed")
+            .id;
+        (oid, cid)
+    }
+
+    fn parcel() -> Parcel {
+        Parcel {
+            carrier: "ups".to_string(),
+            service_level: "standard".to_string(),
+            tracking_number: Some("1Z999".to_string()),
+            shipped_at: None,
+        }
+    }
+
+    fn state(oid: i64) -> Option<OrderState> {
+        order_state::current(&connect(), oid)
+    }
+
+    #[test]
+    fn an_order_ships_once_every_line_is_in_a_parcel() {
+        let _db = crate::db::test_db();
+        let (oid, cid) = order(&[(2, 2), (1, 1)]);
+
+        let first = create_shipment(oid, parcel(), Some(&[(2, 1)]), "clerk").unwrap();
+        assert_eq!(first.lines.len(), 1);
+        assert_eq!(first.lines[0].quantity, 1);
+        assert_eq!(state(oid), Some(OrderState::Picking));
+        assert_eq!(loyalty::summary(cid).unwrap().points, 0);
+
+        let tracked = tracking(oid, cid).unwrap();
+        let unshipped: Vec<(i64, i64)> = tracked
+            .unshipped
+            .iter()
+            .map(|l| (l.book_id, l.quantity))
+            .collect();
+        assert_eq!(unshipped, vec![(2, 1), (1, 1)]);
+
+        let rest = create_shipment(oid, parcel(), None, "clerk").unwrap();
+        assert_eq!(rest.lines.len(), 2);
+        assert_eq!(state(oid), Some(OrderState::Shipped));
+        assert!(loyalty::summary(cid).unwrap().points > 0);
+        let tracked = tracking(oid, cid).unwrap();
+        assert_eq!(tracked.shipments.len(), 2);
+        assert!(tracked.unshipped.is_empty());
+    }
+
+    #[test]
+    fn parcels_cannot_hold_more_than_is_left_to_ship() {
+        let _db = crate::db::test_db();
+        let (oid, _) = order(&[(2, 2)]);
+
+        assert_eq!(
+            create_shipment(oid, parcel(), Some(&[(2, 3)]), "clerk").unwrap_err(),
+            "only 2 of bid 2 left to ship"
+        );
+        assert_eq!(
+            create_shipment(oid, parcel(), Some(&[(1, 1)]), "clerk").unwrap_err(),
+            "bid 1 has nothing left to ship in the order"
+        );
+        // the failed parcels left the order as it was
+        assert_eq!(state(oid), Some(OrderState::Paid));
+
+        create_shipment(oid, parcel(), None, "clerk").unwrap();
+        assert_eq!(
+            create_shipment(oid, parcel(), None, "clerk").unwrap_err(),
+            "order is shipped, it can't be shipped"
+        );
+    }
+
+    #[test]
+    fn cancelled_orders_cannot_be_shipped() {
+        let _db = crate::db::test_db();
+        let (oid, _) = order(&[(1, 1)]);
+        cancel_order(oid, None, "clerk", "changed mind".to_string(), None).unwrap();
+
+        assert_eq!(
+            create_shipment(oid, parcel(), None, "clerk").unwrap_err(),
+            "order is cancelled, it can't be shipped"
+        );
+    }
+
+    #[test]
+    fn tracking_is_only_shown_to_the_orders_customer() {
+        let _db = crate::db::test_db();
+        let (oid, cid) = order(&[(1, 1)]);
+        create_shipment(oid, parcel(), None, "clerk").unwrap();
+
+        let tracked = tracking(oid, cid).unwrap();
+        assert_eq!(tracked.state, OrderState::Shipped);
+        assert_eq!(
+            tracked.shipments[0].tracking_number.as_deref(),
+            Some("1Z999")
+        );
+        assert_eq!(
+            tracking(oid, cid + 1).unwrap_err(),
+            "order does not exist in database"
+        );
+    }
+
+    fn row(line: u64, order_id: i64) -> BulkRow {
+        BulkRow {
+            line,
+            order_id,
+            carrier: "ups".to_string(),
+            tracking_number: Some(format!("1Z{}", line)),
+        }
+    }
+
+    fn outcomes(report: &BulkReport) -> Vec<RowOutcome> {
+        report.rows.iter().map(|r| r.outcome).collect()
+    }
+
+    fn placed(names: &[&str]) -> Vec<i64> {
+        names
+            .iter()
+            .map(|name| {
+                let cid = crate::db::test_customer(name, 100.0);
+                create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+                    .ok()
+                    .expect("order placed")
+                    .id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn an_atomic_file_with_a_bad_row_ships_nothing() {
+        let _db = crate::db::test_db();
+        let oids = placed(&["Ann Reader", "Bob Reader"]);
+        let rows = [row(2, oids[0]), row(3, 99), row(4, oids[1])];
+
+        let report = ship_bulk(&rows, Vec::new(), true, "clerk");
+        assert_eq!(
+            outcomes(&report),
+            vec![
+                RowOutcome::NotApplied,
+                RowOutcome::Unknown,
+                RowOutcome::NotApplied
+            ]
+        );
+        assert_eq!(
+            (report.shipped, report.unknown, report.not_applied),
+            (0, 1, 2)
+        );
+        assert!(report.rows.iter().all(|r| r.shipment_id.is_none()));
+        for oid in oids {
+            assert_eq!(state(oid), Some(OrderState::Paid));
+        }
+    }
+
+    #[test]
+    fn a_malformed_row_stops_an_atomic_file_too() {
+        let _db = crate::db::test_db();
+        let oids = placed(&["Ann Reader"]);
+        let malformed = vec![RowResult {
+            line: 2,
+            order_id: None,
+            outcome: RowOutcome::Malformed,
+            shipment_id: None,
+            message: Some("order_id should be a number".to_string()),
+        }];
+
+        let report = ship_bulk(&[row(3, oids[0])], malformed, true, "clerk");
+        assert_eq!(
+            outcomes(&report),
+            vec![RowOutcome::Malformed, RowOutcome::NotApplied]
+        );
+        assert_eq!(state(oids[0]), Some(OrderState::Paid));
+    }
+
+    #[test]
+    fn rows_on_their_own_ship_around_the_bad_ones() {
+        let _db = crate::db::test_db();
+        let oids = placed(&["Ann Reader", "Bob Reader", "Cat Reader"]);
+        cancel_order(oids[1], None, "clerk", "changed mind".to_string(), None).unwrap();
+        let rows = [
+            row(2, oids[0]),
+            row(3, oids[1]),
+            row(4, 99),
+            row(5, oids[2]),
+        ];
+
+        let report = ship_bulk(&rows, Vec::new(), false, "clerk");
+        assert_eq!(
+            outcomes(&report),
+            vec![
+                RowOutcome::Shipped,
+                RowOutcome::Rejected,
+                RowOutcome::Unknown,
+                RowOutcome::Shipped
+            ]
+        );
+        assert_eq!(state(oids[0]), Some(OrderState::Shipped));
+        assert_eq!(state(oids[1]), Some(OrderState::Cancelled));
+        assert_eq!(state(oids[2]), Some(OrderState::Shipped));
+        let shipment = &tracking_of(oids[0]).shipments[0];
+        assert_eq!(Some(shipment.id), report.rows[0].shipment_id);
+        assert_eq!(
+            (
+                shipment.service_level.as_str(),
+                shipment.tracking_number.as_deref()
+            ),
+            ("standard", Some("1Z2"))
+        );
+    }
+
+    #[test]
+    fn a_file_can_be_uploaded_again() {
+        let _db = crate::db::test_db();
+        let oids = placed(&["Ann Reader", "Bob Reader"]);
+        let rows = [row(2, oids[0]), row(3, oids[1])];
+        ship_bulk(&rows[..1], Vec::new(), true, "clerk");
+
+        let report = ship_bulk(&rows, Vec::new(), true, "clerk");
+        assert_eq!(
+            outcomes(&report),
+            vec![RowOutcome::AlreadyShipped, RowOutcome::Shipped]
+        );
+        assert_eq!(tracking_of(oids[0]).shipments.len(), 1);
+        assert_eq!(state(oids[1]), Some(OrderState::Shipped));
+    }
+
+    fn tracking_of(oid: i64) -> Tracking {
+        let cid: i64 = connect()
+            .query_row(
+                "SELECT customerId FROM Orders WHERE id = ?1",
+                [&oid],
+                |row| row.get(0),
+            )
+            .unwrap();
+        tracking(oid, cid).unwrap()
+    }
+}
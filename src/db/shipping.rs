@@ -0,0 +1,475 @@
+use super::db::connect;
+use crate::config;
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/// cubic millimetres per gram of dimensional weight, the common 5000 cm³ per kg
+const VOLUMETRIC_DIVISOR: i64 = 5000;
+
+/// where a parcel goes, `country` is an ISO 3166 alpha-2 code and `region` the
+/// state or province, which only matters for tax
+pub struct Destination {
+    pub country: String,
+    pub region: String,
+    pub postal_code: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RateOption {
+    pub carrier: String,
+    pub service_level: String,
+    pub price: f64,
+    /// the order's subtotal reached the free-shipping threshold
+    pub free: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Quote {
+    pub zone: String,
+    pub weight: i64,
+    pub subtotal: f64,
+    /// cheapest first
+    pub options: Vec<RateOption>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Rate {
+    pub id: i64,
+    pub carrier: String,
+    pub service_level: String,
+    pub max_weight: i64,
+    pub price: f64,
+    pub free_over: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Area {
+    pub country: String,
+    pub postal_prefix: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Zone {
+    pub id: i64,
+    pub name: String,
+    pub areas: Vec<Area>,
+    pub rates: Vec<Rate>,
+}
+
+pub fn destination(conn: &Connection, cid: i64) -> Option<Destination> {
+    conn.query_row(
+        "SELECT country, region, postalCode FROM Customers WHERE id = ?1",
+        [&cid],
+        |row| {
+            Ok(Destination {
+                country: row.get(0)?,
+                region: row.get(1)?,
+                postal_code: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+    .expect("expected to be able to select from Customers table")
+}
+
+pub fn customer_destination(cid: i64) -> Result<Destination, String> {
+    match destination(&connect(), cid) {
+        Some(d) => Ok(d),
+        None => {
+            warn!(target: "warn", "shipping destination of unknown customer: {}", cid);
+            Err("cid does not exist in database".to_string())
+        }
+    }
+}
+
+pub fn quote(dest: &Destination, lines: &[(i64, i64)]) -> Result<Quote, String> {
+    //! prices shipping of a prospective order of (bid, quantity) lines
+    let db = connect();
+    let mut subtotal = 0.0;
+    for &(bid, quantity) in lines {
+        let price: f64 = match db
+            .query_row("SELECT price FROM Books WHERE id = ?1", [&bid], |row| {
+                row.get(0)
+            })
+            .optional()
+            .expect("expected to be able to select from Books table")
+        {
+            Some(p) => p,
+            None => {
+                warn!(target: "warn", "shipping quote for unknown book: {}", bid);
+                return Err(format!("bid {} does not exist in database", bid));
+            }
+        };
+        subtotal += (price * quantity as f64 * 100.0).round() / 100.0;
+    }
+    quote_for(&db, dest, lines, (subtotal * 100.0).round() / 100.0)
+}
+
+pub fn quote_for(
+    conn: &Connection,
+    dest: &Destination,
+    lines: &[(i64, i64)],
+    subtotal: f64,
+) -> Result<Quote, String> {
+    //! on the caller's connection so orders are priced inside their transaction
+    let (zid, zone) = match zone_for(conn, dest) {
+        Some(z) => z,
+        None => {
+            warn!(target: "warn", "no shipping zone for {} {}", dest.country, dest.postal_code);
+            return Err(format!("no shipping to {}", dest.country));
+        }
+    };
+    let weight = weight(conn, lines);
+    let mut stmt = conn
+        .prepare(
+            "SELECT carrier, serviceLevel, price, freeOver FROM ShippingRates r
+             WHERE zoneId = ?1 AND maxWeight = (SELECT MIN(maxWeight) FROM ShippingRates
+                 WHERE zoneId = r.zoneId AND carrier = r.carrier
+                 AND serviceLevel = r.serviceLevel AND maxWeight >= ?2)",
+        )
+        .expect("expected to be able to select from ShippingRates table");
+    let rows = stmt
+        .query_map(params![zid, weight], |row| {
+            let price: f64 = row.get(2)?;
+            let free_over: Option<f64> = row.get(3)?;
+            let free = free_over.is_some_and(|f| subtotal >= f);
+            Ok(RateOption {
+                carrier: row.get(0)?,
+                service_level: row.get(1)?,
+                price: if free { 0.0 } else { price },
+                free,
+            })
+        })
+        .expect("expected to be able to get rates from ShippingRates table");
+    let mut options: Vec<RateOption> = rows
+        .map(|r| r.expect("problem getting rate from database"))
+        .collect();
+    if options.is_empty() {
+        warn!(target: "warn", "no shipping rate in zone {} for {}g", zone, weight);
+        return Err(format!(
+            "no shipping rate for a {}g parcel to {}",
+            weight, dest.country
+        ));
+    }
+    options.sort_by(|a, b| a.price.total_cmp(&b.price));
+    Ok(Quote {
+        zone,
+        weight,
+        subtotal,
+        options,
+    })
+}
+
+pub fn choose(
+    quote: &Quote,
+    carrier: Option<&str>,
+    service_level: Option<&str>,
+) -> Result<RateOption, String> {
+    //! the requested carrier service, or the cheapest one when none was asked for
+    quote
+        .options
+        .iter()
+        .find(|o| {
+            carrier.is_none_or(|c| c == o.carrier)
+                && service_level.is_none_or(|s| s == o.service_level)
+        })
+        .cloned()
+        .ok_or_else(|| format!("no such shipping option to zone {}", quote.zone))
+}
+
+fn zone_for(conn: &Connection, dest: &Destination) -> Option<(i64, String)> {
+    //! the area with the longest matching prefix in the country, else the rest of the world
+    conn.query_row(
+        "SELECT z.id, z.name FROM ShippingZoneAreas a JOIN ShippingZones z ON z.id = a.zoneId
+         WHERE (a.country = ?1 AND substr(?2, 1, length(a.postalPrefix)) = a.postalPrefix)
+            OR a.country = '*'
+         ORDER BY a.country = '*', length(a.postalPrefix) DESC LIMIT 1",
+        params![dest.country, dest.postal_code],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .expect("expected to be able to select from ShippingZoneAreas table")
+}
+
+fn weight(conn: &Connection, lines: &[(i64, i64)]) -> i64 {
+    //! billable grams, each book counts with its weight or its dimensional weight if that is more
+    let default = config::default_book_weight();
+    let mut total = 0;
+    for &(bid, quantity) in lines {
+        let (weight, volume): (Option<i64>, Option<i64>) = conn
+            .query_row(
+                "SELECT weight, length * width * height FROM Books WHERE id = ?1",
+                [&bid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .expect("expected to be able to select from Books table")
+            .unwrap_or((None, None));
+        let grams = weight
+            .unwrap_or(default)
+            .max(volume.unwrap_or(0) / VOLUMETRIC_DIVISOR);
+        total += grams * quantity;
+    }
+    total
+}
+
+pub fn zones() -> Vec<Zone> {
+    //! the whole rate table, for staff
+    let db = connect();
+    let mut stmt = db
+        .prepare("SELECT id, name FROM ShippingZones ORDER BY id")
+        .expect("expected to be able to select from ShippingZones table");
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(Zone {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                areas: Vec::new(),
+                rates: Vec::new(),
+            })
+        })
+        .expect("expected to be able to get zones from ShippingZones table");
+    let mut zones: Vec<Zone> = rows
+        .map(|r| r.expect("problem getting zone from database"))
+        .collect();
+    for zone in zones.iter_mut() {
+        let mut stmt = db
+            .prepare(
+                "SELECT country, postalPrefix FROM ShippingZoneAreas
+                 WHERE zoneId = ?1 ORDER BY country, postalPrefix",
+            )
+            .expect("expected to be able to select from ShippingZoneAreas table");
+        zone.areas = stmt
+            .query_map([&zone.id], |row| {
+                Ok(Area {
+                    country: row.get(0)?,
+                    postal_prefix: row.get(1)?,
+                })
+            })
+            .expect("expected to be able to get areas from ShippingZoneAreas table")
+            .map(|r| r.expect("problem getting area from database"))
+            .collect();
+        let mut stmt = db
+            .prepare(
+                "SELECT id, carrier, serviceLevel, maxWeight, price, freeOver FROM ShippingRates
+                 WHERE zoneId = ?1 ORDER BY carrier, serviceLevel, maxWeight",
+            )
+            .expect("expected to be able to select from ShippingRates table");
+        zone.rates = stmt
+            .query_map([&zone.id], |row| {
+                Ok(Rate {
+                    id: row.get(0)?,
+                    carrier: row.get(1)?,
+                    service_level: row.get(2)?,
+                    max_weight: row.get(3)?,
+                    price: row.get(4)?,
+                    free_over: row.get(5)?,
+                })
+            })
+            .expect("expected to be able to get rates from ShippingRates table")
+            .map(|r| r.expect("problem getting rate from database"))
+            .collect();
+    }
+    zones
+}
+
+pub fn add_area(zone: &str, area: Area) -> Result<i64, String> {
+    //! creates the zone if it is new, returns its id
+    let db = connect();
+    let taken: Option<String> = db
+        .query_row(
+            "SELECT z.name FROM ShippingZoneAreas a JOIN ShippingZones z ON z.id = a.zoneId
+             WHERE a.country = ?1 AND a.postalPrefix = ?2",
+            params![area.country, area.postal_prefix],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from ShippingZoneAreas table");
+    if let Some(z) = taken {
+        warn!(target: "warn", "shipping area {} {} already in zone {}", area.country, area.postal_prefix, z);
+        return Err(format!("area is already in zone {}", z));
+    }
+    db.execute(
+        "INSERT OR IGNORE INTO ShippingZones (name) VALUES (?1)",
+        [zone],
+    )
+    .expect("expected to be able to insert into ShippingZones table");
+    let zid: i64 = db
+        .query_row(
+            "SELECT id FROM ShippingZones WHERE name = ?1",
+            [zone],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to select from ShippingZones table");
+    db.execute(
+        "INSERT INTO ShippingZoneAreas (zoneId, country, postalPrefix) VALUES (?1, ?2, ?3)",
+        params![zid, area.country, area.postal_prefix],
+    )
+    .expect("expected to be able to insert into ShippingZoneAreas table");
+    info!(target: "info", "shipping area {} {} added to zone {}", area.country, area.postal_prefix, zone);
+    Ok(zid)
+}
+
+pub fn set_rate(
+    zid: i64,
+    carrier: &str,
+    service_level: &str,
+    max_weight: i64,
+    price: f64,
+    free_over: Option<f64>,
+) -> Result<i64, String> {
+    //! adds a weight bracket to a zone, or replaces the bracket with the same max weight
+    let db = connect();
+    let exists = db
+        .prepare("SELECT id FROM ShippingZones WHERE id = ?1")
+        .expect("expected to be able to select from ShippingZones table")
+        .exists([&zid])
+        .expect("expected to be able to select from ShippingZones table");
+    if !exists {
+        warn!(target: "warn", "rate for unknown shipping zone: {}", zid);
+        return Err("zone does not exist in database".to_string());
+    }
+    db.execute(
+        "INSERT INTO ShippingRates (zoneId, carrier, serviceLevel, maxWeight, price, freeOver)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (zoneId, carrier, serviceLevel, maxWeight)
+         DO UPDATE SET price = excluded.price, freeOver = excluded.freeOver",
+        params![zid, carrier, service_level, max_weight, price, free_over],
+    )
+    .expect("expected to be able to insert into ShippingRates table");
+    let id: i64 = db
+        .query_row(
+            "SELECT id FROM ShippingRates
+             WHERE zoneId = ?1 AND carrier = ?2 AND serviceLevel = ?3 AND maxWeight = ?4",
+            params![zid, carrier, service_level, max_weight],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to select from ShippingRates table");
+    info!(target: "info", "shipping rate {} of zone {} set: {} {} up to {}g for {:.2}",
+        id, zid, carrier, service_level, max_weight, price);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to(country: &str, postal_code: &str) -> Destination {
+        Destination {
+            country: country.to_string(),
+            region: String::new(),
+            postal_code: postal_code.to_string(),
+        }
+    }
+
+    fn prices(quote: &Quote) -> Vec<(&str, f64)> {
+        quote
+            .options
+            .iter()
+            .map(|o| (o.service_level.as_str(), o.price))
+            .collect()
+    }
+
+    #[test]
+    fn parcels_pay_the_smallest_bracket_they_fit_in() {
+        let _db = crate::db::test_db();
+        connect()
+            .execute("UPDATE Books SET weight = 500 WHERE id = 5", [])
+            .unwrap();
+
+        let exactly = quote(&to("US", "94105"), &[(5, 2)]).unwrap();
+        assert_eq!(exactly.zone, "us");
+        assert_eq!(exactly.weight, 1000);
+        assert_eq!(
+            prices(&exactly),
+            vec![("standard", 3.99), ("next_day", 19.99)]
+        );
+
+        let over = quote(&to("US", "94105"), &[(5, 3)]).unwrap();
+        assert_eq!(over.weight, 1500);
+        assert_eq!(prices(&over), vec![("standard", 6.99), ("next_day", 19.99)]);
+
+        // books without a weight count with the configured 400g
+        assert_eq!(quote(&to("US", "94105"), &[(4, 3)]).unwrap().weight, 1200);
+    }
+
+    #[test]
+    fn bulky_books_count_with_their_dimensional_weight() {
+        let _db = crate::db::test_db();
+        connect()
+            .execute(
+                "UPDATE Books SET weight = 300, length = 300, width = 200, height = 50 WHERE id = 5",
+                [],
+            )
+            .unwrap();
+
+        // 3,000,000 mm³ weigh 600g rather than 300g
+        assert_eq!(quote(&to("US", "94105"), &[(5, 2)]).unwrap().weight, 1200);
+    }
+
+    #[test]
+    fn standard_shipping_is_free_from_the_threshold() {
+        let _db = crate::db::test_db();
+
+        let under = quote(&to("US", "94105"), &[(1, 2)]).unwrap();
+        assert_eq!(under.subtotal, 25.98);
+        assert!(!under.options[0].free);
+
+        let free = quote(&to("US", "94105"), &[(1, 2), (2, 1)]).unwrap();
+        assert_eq!(free.subtotal, 35.97);
+        assert_eq!(prices(&free), vec![("standard", 0.0), ("next_day", 19.99)]);
+        assert!(free.options[0].free);
+        assert!(!free.options[1].free);
+    }
+
+    #[test]
+    fn the_longest_postal_prefix_picks_the_zone() {
+        let _db = crate::db::test_db();
+        let lines = [(1, 3)];
+
+        let remote = quote(&to("US", "96701"), &lines).unwrap();
+        assert_eq!(remote.zone, "us-remote");
+        // no free shipping to remote areas
+        assert_eq!(prices(&remote), vec![("standard", 19.99)]);
+
+        assert_eq!(quote(&to("US", "10001"), &lines).unwrap().zone, "us");
+        let canada = quote(&to("CA", "M5V 2T6"), &lines).unwrap();
+        assert_eq!(canada.zone, "canada");
+        assert_eq!(prices(&canada), vec![("standard", 9.99)]);
+        let abroad = quote(&to("GB", "SW1A 1AA"), &lines).unwrap();
+        assert_eq!(abroad.zone, "international");
+        assert_eq!(prices(&abroad), vec![("international", 24.99)]);
+    }
+
+    #[test]
+    fn parcels_heavier_than_every_bracket_cannot_ship() {
+        let _db = crate::db::test_db();
+
+        assert_eq!(
+            quote(&to("US", "94105"), &[(5, 51)]).unwrap_err(),
+            "no shipping rate for a 20400g parcel to US"
+        );
+        assert_eq!(
+            quote(&to("US", "94105"), &[(99, 1)]).unwrap_err(),
+            "bid 99 does not exist in database"
+        );
+    }
+
+    #[test]
+    fn the_cheapest_option_is_chosen_unless_one_is_asked_for() {
+        let _db = crate::db::test_db();
+        let quote = quote(&to("US", "94105"), &[(1, 1)]).unwrap();
+
+        assert_eq!(choose(&quote, None, None).unwrap().carrier, "usps");
+        let next_day = choose(&quote, Some("ups"), None).unwrap();
+        assert_eq!(
+            (next_day.service_level.as_str(), next_day.price),
+            ("next_day", 19.99)
+        );
+        assert_eq!(
+            choose(&quote, Some("usps"), Some("next_day")).unwrap_err(),
+            "no such shipping option to zone us"
+        );
+    }
+}
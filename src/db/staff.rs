@@ -0,0 +1,373 @@
+use super::db::connect;
+use crate::auth::{hash_password, hash_token, new_token, verify_password, Role};
+use crate::config;
+use crate::totp::{self, Clock};
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+
+const TOTP_ISSUER: &str = "Bookshop";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct StaffMember {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    /// the session was started with a TOTP or recovery code
+    pub totp_verified: bool,
+    /// hash of the session token the request was made with
+    pub session: String,
+}
+
+/// Second factor supplied at login by staff with TOTP enabled.
+pub enum SecondFactor {
+    Totp(String),
+    RecoveryCode(String),
+}
+
+pub fn create_staff(username: String, password: String, role: Role) -> Result<i64, String> {
+    let db = connect();
+    let exist = exists(username.clone()).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if !exist {
+        let hash = hash_password(&password)?;
+        db.execute(
+            "INSERT INTO Staff (username, passwordHash, role) VALUES (?1, ?2, ?3)",
+            [&username, &hash, role.as_str()],
+        )
+        .expect("expected to be able to insert into Staff table");
+        info!(target: "info", "staff account created: {} ({})", username, role.as_str());
+        Ok(db.last_insert_rowid())
+    } else {
+        warn!(target: "warn", "staff username already in use: {}", username);
+        Err("staff username already in use".to_string())
+    }
+}
+
+pub fn bootstrap_admin() {
+    //! creates the first admin from the `admin_username`/`admin_password` config
+    //! values (e.g. `ROCKET_ADMIN_PASSWORD`) when no staff accounts exist yet
+    let db = connect();
+    let count: i64 = db
+        .query_row("SELECT COUNT(*) FROM Staff", [], |row| row.get(0))
+        .expect("expected to be able to count Staff table");
+    if count > 0 {
+        return;
+    }
+    match config::admin_password() {
+        Some(password) => {
+            if let Err(e) = create_staff(config::admin_username(), password, Role::Admin) {
+                error!(target: "error", "failed to create initial admin: {}", e);
+            }
+        }
+        None => {
+            warn!(target: "warn", "no staff accounts exist and no admin_password is configured")
+        }
+    }
+}
+
+pub fn login(
+    username: String,
+    password: String,
+    second_factor: Option<SecondFactor>,
+    clock: &dyn Clock,
+) -> Result<String, String> {
+    //! checks the credentials (and the second factor when TOTP is enabled)
+    //! and returns a new session token
+    let db = connect();
+    let mut stmt = db
+        .prepare("SELECT id, passwordHash, totpEnabled FROM Staff WHERE username = ?1")
+        .expect("expected to be able to select from Staff table");
+    let found: Option<(i64, String, bool)> = stmt
+        .query_map([&username], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .expect("expected to be able to get passwordHash from Staff table")
+        .next()
+        .map(|r| r.expect("problem getting staff from database"));
+    let (sid, totp_enabled) = match found {
+        Some((sid, hash, totp_enabled)) if verify_password(&password, &hash) => (sid, totp_enabled),
+        _ => {
+            warn!(target: "warn", "failed staff login: {}", username);
+            return Err("invalid username or password".to_string());
+        }
+    };
+    if !totp_enabled {
+        return Ok(start_session(sid, false));
+    }
+    let verified = match second_factor {
+        Some(SecondFactor::Totp(code)) => verify_totp(sid, &code, clock),
+        Some(SecondFactor::RecoveryCode(code)) => use_recovery_code(sid, &code),
+        None => {
+            warn!(target: "warn", "staff login without second factor: {}", username);
+            return Err("two-factor code required".to_string());
+        }
+    };
+    if verified {
+        Ok(start_session(sid, true))
+    } else {
+        warn!(target: "warn", "failed staff second factor: {}", username);
+        Err("invalid two-factor code".to_string())
+    }
+}
+
+pub fn start_session(sid: i64, totp_verified: bool) -> String {
+    //! issues a session token that is valid for eight hours
+    let db = connect();
+    let token = new_token();
+    db.execute(
+        "INSERT INTO StaffSessions (tokenHash, staffId, expiresAt, totpVerified) VALUES (?1, ?2, datetime('now', '+8 hours'), ?3)",
+        [&hash_token(&token), &sid.to_string(), &(totp_verified as i64).to_string()],
+    )
+    .expect("expected to be able to insert into StaffSessions table");
+    info!(target: "info", "staff session started: {}", sid);
+    token
+}
+
+pub fn end_session(member: &StaffMember) -> Result<(), String> {
+    //! ends the session the staff member is authenticated with
+    let db = connect();
+    let removed = db
+        .execute(
+            "DELETE FROM StaffSessions WHERE tokenHash = ?1 AND staffId = ?2",
+            [&member.session, &member.id.to_string()],
+        )
+        .expect("expected to be able to delete from StaffSessions table");
+    if removed == 1 {
+        info!(target: "info", "staff session ended: {}", member.id);
+        Ok(())
+    } else {
+        Err("session does not exist".to_string())
+    }
+}
+
+pub fn get_session_staff(token: String) -> Result<StaffMember, String> {
+    let db = connect();
+    let session = hash_token(&token);
+    let mut stmt = db
+        .prepare(
+            "SELECT Staff.id, Staff.username, Staff.role, StaffSessions.totpVerified FROM StaffSessions
+             JOIN Staff ON Staff.id = StaffSessions.staffId
+             WHERE StaffSessions.tokenHash = ?1 AND StaffSessions.expiresAt > datetime('now')",
+        )
+        .expect("expected to be able to select from StaffSessions table");
+    let mut rows = stmt
+        .query_map([&session], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get::<_, String>(2)?,
+                row.get(3)?,
+            ))
+        })
+        .expect("expected to be able to get staff from StaffSessions table");
+    match rows.next() {
+        Some(row) => {
+            let (id, username, role, totp_verified) =
+                row.expect("problem getting staff from database");
+            let role = Role::parse(&role).unwrap_or_else(|| {
+                error!(target: "error", "unknown role stored for staff {}: {}", id, role);
+                panic!("invalid role in database")
+            });
+            Ok(StaffMember {
+                id,
+                username,
+                role,
+                totp_verified,
+                session,
+            })
+        }
+        None => Err("session is invalid or expired".to_string()),
+    }
+}
+
+pub fn set_role(sid: i64, role: Role) -> Result<(), String> {
+    let db = connect();
+    let updated = db
+        .execute(
+            "UPDATE Staff SET role = ?1 WHERE id = ?2",
+            [role.as_str(), &sid.to_string()],
+        )
+        .expect("expected to be able to update Staff table");
+    if updated == 1 {
+        info!(target: "info", "staff {} role changed to {}", sid, role.as_str());
+        Ok(())
+    } else {
+        warn!(target: "warn", "failed to change role, sid not in database: {}", sid);
+        Err("sid does not exist in database".to_string())
+    }
+}
+
+pub fn begin_totp_enrollment(member: &StaffMember) -> Result<(String, String), String> {
+    //! stores a new, not yet enabled secret and returns it with its provisioning uri
+    let db = connect();
+    let enabled: bool = db
+        .query_row(
+            "SELECT totpEnabled FROM Staff WHERE id = ?1",
+            [&member.id],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to select from Staff table");
+    if enabled {
+        warn!(target: "warn", "totp enrollment while already enabled: {}", member.username);
+        return Err("two-factor authentication is already enabled".to_string());
+    }
+    let secret = totp::generate_secret();
+    db.execute(
+        "UPDATE Staff SET totpSecret = ?1, totpLastStep = NULL WHERE id = ?2",
+        [&secret, &member.id.to_string()],
+    )
+    .expect("expected to be able to update Staff table");
+    info!(target: "info", "totp enrollment started: {}", member.username);
+    let uri = totp::provisioning_uri(&secret, &member.username, TOTP_ISSUER);
+    Ok((secret, uri))
+}
+
+pub fn confirm_totp_enrollment(
+    member: &StaffMember,
+    code: String,
+    clock: &dyn Clock,
+) -> Result<Vec<String>, String> {
+    //! enables TOTP once the first code checks out and returns fresh recovery codes
+    let db = connect();
+    let (secret, enabled): (Option<String>, bool) = db
+        .query_row(
+            "SELECT totpSecret, totpEnabled FROM Staff WHERE id = ?1",
+            [&member.id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("expected to be able to select from Staff table");
+    if enabled || secret.is_none() {
+        return Err("no two-factor enrollment in progress".to_string());
+    }
+    if !verify_totp(member.id, &code, clock) {
+        warn!(target: "warn", "totp enrollment code rejected: {}", member.username);
+        return Err("invalid two-factor code".to_string());
+    }
+    db.execute(
+        "UPDATE Staff SET totpEnabled = 1 WHERE id = ?1",
+        [&member.id],
+    )
+    .expect("expected to be able to update Staff table");
+    info!(target: "info", "totp enabled: {}", member.username);
+    Ok(new_recovery_codes(member.id))
+}
+
+pub fn reset_totp(sid: i64) -> Result<(), String> {
+    //! admin reset for lost devices, also ends the staff member's sessions
+    let mut db = connect();
+    let exist = exists_id(sid).unwrap_or_else(|e| {
+        error!(target: "error", "statement exists check error: {}", e);
+        panic!("connection with database failure")
+    });
+    if !exist {
+        warn!(target: "warn", "failed to reset totp, sid not in database: {}", sid);
+        return Err("sid does not exist in database".to_string());
+    }
+    let tx = db
+        .transaction()
+        .expect("expected to be able to start a transaction");
+    tx.execute(
+        "UPDATE Staff SET totpSecret = NULL, totpEnabled = 0, totpLastStep = NULL WHERE id = ?1",
+        [&sid],
+    )
+    .expect("expected to be able to update Staff table");
+    tx.execute("DELETE FROM StaffRecoveryCodes WHERE staffId = ?1", [&sid])
+        .expect("expected to be able to delete from StaffRecoveryCodes table");
+    tx.execute("DELETE FROM StaffSessions WHERE staffId = ?1", [&sid])
+        .expect("expected to be able to delete from StaffSessions table");
+    tx.commit()
+        .expect("expected to be able to commit totp reset");
+    info!(target: "info", "totp reset for staff: {}", sid);
+    Ok(())
+}
+
+fn verify_totp(sid: i64, code: &str, clock: &dyn Clock) -> bool {
+    //! checks a code against the stored secret and remembers its step to block replays
+    let db = connect();
+    let (secret, last_step): (Option<String>, Option<i64>) = db
+        .query_row(
+            "SELECT totpSecret, totpLastStep FROM Staff WHERE id = ?1",
+            [&sid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("expected to be able to select from Staff table");
+    let secret = match secret {
+        Some(s) => s,
+        None => return false,
+    };
+    match totp::verify(&secret, code, clock, last_step.map(|s| s as u64)) {
+        Some(step) => {
+            db.execute(
+                "UPDATE Staff SET totpLastStep = ?1 WHERE id = ?2",
+                [step as i64, sid],
+            )
+            .expect("expected to be able to update Staff table");
+            true
+        }
+        None => false,
+    }
+}
+
+fn new_recovery_codes(sid: i64) -> Vec<String> {
+    //! replaces any previous recovery codes, only their hashes are stored
+    let db = connect();
+    db.execute("DELETE FROM StaffRecoveryCodes WHERE staffId = ?1", [&sid])
+        .expect("expected to be able to delete from StaffRecoveryCodes table");
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let raw: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| (c as char).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &raw[..5], &raw[5..])
+        })
+        .collect();
+    for code in &codes {
+        db.execute(
+            "INSERT INTO StaffRecoveryCodes (staffId, codeHash) VALUES (?1, ?2)",
+            [&sid.to_string(), &hash_token(code)],
+        )
+        .expect("expected to be able to insert into StaffRecoveryCodes table");
+    }
+    codes
+}
+
+fn use_recovery_code(sid: i64, code: &str) -> bool {
+    //! recovery codes work once
+    let db = connect();
+    let used = db
+        .execute(
+            "UPDATE StaffRecoveryCodes SET usedAt = datetime('now')
+             WHERE staffId = ?1 AND codeHash = ?2 AND usedAt IS NULL",
+            [&sid.to_string(), &hash_token(code.trim())],
+        )
+        .expect("expected to be able to update StaffRecoveryCodes table");
+    if used > 0 {
+        info!(target: "info", "recovery code used by staff: {}", sid);
+    }
+    used > 0
+}
+
+fn exists(username: String) -> Result<bool, rusqlite::Error> {
+    //! checks that the staff username is taken
+    let conn = connect();
+    let check = conn
+        .prepare("SELECT id FROM Staff WHERE username = ?1")
+        .expect("expected to be able to select from Staff table")
+        .exists([&username])?;
+    Ok(check)
+}
+
+fn exists_id(sid: i64) -> Result<bool, rusqlite::Error> {
+    //! same functionality as exists() but when only sid is provided
+    let conn = connect();
+    let check = conn
+        .prepare("SELECT username FROM Staff WHERE id = ?1")
+        .expect("expected to be able to select from Staff table")
+        .exists([&sid])?;
+    Ok(check)
+}
@@ -0,0 +1,315 @@
+use super::db::connect;
+use super::shipping::Destination;
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaxCategory {
+    Book,
+    Ebook,
+    General,
+}
+
+impl TaxCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaxCategory::Book => "book",
+            TaxCategory::Ebook => "ebook",
+            TaxCategory::General => "general",
+        }
+    }
+
+    pub fn parse(category: &str) -> Option<TaxCategory> {
+        match category {
+            "book" => Some(TaxCategory::Book),
+            "ebook" => Some(TaxCategory::Ebook),
+            "general" => Some(TaxCategory::General),
+            _ => None,
+        }
+    }
+}
+
+/// the tax of one jurisdiction on an order line, `region` is empty for a country-wide tax
+#[derive(Serialize, Debug, Clone)]
+pub struct TaxPart {
+    pub country: String,
+    pub region: String,
+    pub rate: f64,
+    pub taxable: f64,
+    pub amount: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaxRate {
+    pub country: String,
+    pub region: String,
+    pub category: TaxCategory,
+    pub rate: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JurisdictionLiability {
+    pub country: String,
+    pub region: String,
+    pub orders: i64,
+    pub taxable: f64,
+    pub tax: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaxReport {
+    pub from: String,
+    pub to: String,
+    pub jurisdictions: Vec<JurisdictionLiability>,
+    pub total: f64,
+}
+
+pub fn line_tax(
+    conn: &Connection,
+    dest: &Destination,
+    bid: i64,
+    value: f64,
+    inclusive: bool,
+) -> Vec<TaxPart> {
+    //! the tax on `value` of the book shipped to `dest`, one part per jurisdiction with a rate.
+    //! With `inclusive` prices the tax is taken out of `value`, otherwise it comes on top
+    let category: String = conn
+        .query_row(
+            "SELECT taxCategory FROM Books WHERE id = ?1",
+            [&bid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to select from Books table");
+    let mut stmt = conn
+        .prepare(
+            "SELECT region, rate FROM TaxRates
+             WHERE country = ?1 AND region IN ('', ?2) AND category = ?3 AND rate > 0
+             ORDER BY region",
+        )
+        .expect("expected to be able to select from TaxRates table");
+    let rates: Vec<(String, f64)> = stmt
+        .query_map(params![dest.country, dest.region, category], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .expect("expected to be able to get rates from TaxRates table")
+        .map(|r| r.expect("problem getting tax rate from database"))
+        .collect();
+    let combined: f64 = rates.iter().map(|(_, r)| r).sum();
+    let taxable = if inclusive {
+        value / (1.0 + combined)
+    } else {
+        value
+    };
+    let taxable = (taxable * 100.0).round() / 100.0;
+    rates
+        .into_iter()
+        .map(|(region, rate)| TaxPart {
+            country: dest.country.clone(),
+            region,
+            rate,
+            taxable,
+            amount: (taxable * rate * 100.0).round() / 100.0,
+        })
+        .collect()
+}
+
+pub fn record(conn: &Connection, olid: i64, parts: &[TaxPart]) {
+    //! stores the parts of an order line for the liability report
+    for part in parts {
+        conn.execute(
+            "INSERT INTO OrderLineTaxes (orderLineId, country, region, rate, taxable, amount)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                olid,
+                part.country,
+                part.region,
+                part.rate,
+                part.taxable,
+                part.amount
+            ],
+        )
+        .expect("expected to be able to insert into OrderLineTaxes table");
+    }
+}
+
+pub fn rates() -> Vec<TaxRate> {
+    let db = connect();
+    let mut stmt = db
+        .prepare(
+            "SELECT country, region, category, rate FROM TaxRates
+             ORDER BY country, region, category",
+        )
+        .expect("expected to be able to select from TaxRates table");
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(TaxRate {
+                country: row.get(0)?,
+                region: row.get(1)?,
+                category: TaxCategory::parse(&row.get::<_, String>(2)?)
+                    .expect("tax category is constrained by the table"),
+                rate: row.get(3)?,
+            })
+        })
+        .expect("expected to be able to get rates from TaxRates table");
+    rows.map(|r| r.expect("problem getting tax rate from database"))
+        .collect()
+}
+
+pub fn set_rate(rate: TaxRate) {
+    //! adds or replaces the rate, a rate of 0 stops taxing the category there
+    let db = connect();
+    db.execute(
+        "INSERT INTO TaxRates (country, region, category, rate) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (country, region, category) DO UPDATE SET rate = excluded.rate",
+        params![rate.country, rate.region, rate.category.as_str(), rate.rate],
+    )
+    .expect("expected to be able to insert into TaxRates table");
+    info!(target: "info", "tax rate for {} {} {} set to {}",
+        rate.category.as_str(), rate.country, rate.region, rate.rate);
+}
+
+pub fn liability_report(from: &str, to: &str) -> Result<TaxReport, String> {
+    //! tax owed per jurisdiction on orders placed from `from` to `to` (inclusive dates),
+    //! cancelled orders owe nothing and returned copies are taken off
+    let db = connect();
+    if from > to {
+        warn!(target: "warn", "tax report period rejected: {} to {}", from, to);
+        return Err("from must not be after to".to_string());
+    }
+    let mut stmt = db
+        .prepare(
+            "SELECT t.country, t.region, COUNT(DISTINCT o.id),
+                 SUM(t.taxable * kept), SUM(t.amount * kept)
+             FROM OrderLineTaxes t
+             JOIN (SELECT id, orderId, (quantity - COALESCE(
+                     (SELECT SUM(r.quantity) FROM RmaLines r JOIN Rmas m ON m.id = r.rmaId
+                      WHERE r.orderLineId = OrderLines.id AND m.status = 'completed'), 0)
+                 ) * 1.0 / quantity AS kept FROM OrderLines) l ON l.id = t.orderLineId
+             JOIN Orders o ON o.id = l.orderId
+             WHERE o.state != 'cancelled'
+                 AND o.createdAt >= ?1 AND o.createdAt < date(?2, '+1 day')
+             GROUP BY t.country, t.region ORDER BY t.country, t.region",
+        )
+        .expect("expected to be able to select from OrderLineTaxes table");
+    let rows = stmt
+        .query_map([from, to], |row| {
+            let taxable: f64 = row.get(3)?;
+            let tax: f64 = row.get(4)?;
+            Ok(JurisdictionLiability {
+                country: row.get(0)?,
+                region: row.get(1)?,
+                orders: row.get(2)?,
+                taxable: (taxable * 100.0).round() / 100.0,
+                tax: (tax * 100.0).round() / 100.0,
+            })
+        })
+        .expect("expected to be able to get taxes from OrderLineTaxes table");
+    let jurisdictions: Vec<JurisdictionLiability> = rows
+        .map(|r| r.expect("problem getting tax liability from database"))
+        .collect();
+    let total = (jurisdictions.iter().fold(0.0, |t, j| t + j.tax) * 100.0).round() / 100.0;
+    Ok(TaxReport {
+        from: from.to_string(),
+        to: to.to_string(),
+        jurisdictions,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::purchaseOrders::{cancel_order, create_order, ShippingChoice};
+
+    fn to(country: &str, region: &str) -> Destination {
+        Destination {
+            country: country.to_string(),
+            region: region.to_string(),
+            postal_code: String::new(),
+        }
+    }
+
+    fn amounts(parts: &[TaxPart]) -> Vec<(&str, f64, f64)> {
+        parts
+            .iter()
+            .map(|p| (p.region.as_str(), p.taxable, p.amount))
+            .collect()
+    }
+
+    #[test]
+    fn exclusive_tax_comes_on_top_of_the_price() {
+        let _db = crate::db::test_db();
+        let parts = line_tax(&connect(), &to("US", "CA"), 1, 12.99, false);
+
+        assert_eq!(amounts(&parts), vec![("CA", 12.99, 0.94)]);
+    }
+
+    #[test]
+    fn inclusive_tax_is_taken_out_of_the_price() {
+        let _db = crate::db::test_db();
+        let parts = line_tax(&connect(), &to("US", "CA"), 1, 12.99, true);
+
+        // 12.99 / 1.0725
+        assert_eq!(amounts(&parts), vec![("CA", 12.11, 0.88)]);
+    }
+
+    #[test]
+    fn country_and_region_rates_are_separate_parts() {
+        let _db = crate::db::test_db();
+        let db = connect();
+        db.execute("UPDATE Books SET taxCategory = 'general' WHERE id = 3", [])
+            .unwrap();
+
+        let on_top = line_tax(&db, &to("CA", "BC"), 3, 10.0, false);
+        assert_eq!(amounts(&on_top), vec![("", 10.0, 0.5), ("BC", 10.0, 0.7)]);
+        let inside = line_tax(&db, &to("CA", "BC"), 3, 11.2, true);
+        assert_eq!(amounts(&inside), vec![("", 10.0, 0.5), ("BC", 10.0, 0.7)]);
+        // books only pay the federal rate in BC
+        let book = line_tax(&db, &to("CA", "BC"), 1, 10.0, false);
+        assert_eq!(amounts(&book), vec![("", 10.0, 0.5)]);
+    }
+
+    #[test]
+    fn places_and_categories_without_a_rate_are_not_taxed() {
+        let _db = crate::db::test_db();
+        let db = connect();
+
+        assert!(line_tax(&db, &to("US", "TX"), 1, 12.99, false).is_empty());
+        db.execute("UPDATE Books SET taxCategory = 'ebook' WHERE id = 1", [])
+            .unwrap();
+        assert!(line_tax(&db, &to("US", "CA"), 1, 12.99, false).is_empty());
+        set_rate(TaxRate {
+            country: "US".to_string(),
+            region: "NY".to_string(),
+            category: TaxCategory::Ebook,
+            rate: 0.0,
+        });
+        assert!(line_tax(&db, &to("US", "NY"), 1, 12.99, false).is_empty());
+    }
+
+    #[test]
+    fn cancelled_orders_owe_no_tax() {
+        let _db = crate::db::test_db();
+        let cid = crate::db::test_customer("Ann Reader", 100.0);
+        let kept = create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default());
+        let cancelled = create_order(cid, &[(2, 1)], 0, None, ShippingChoice::default());
+        assert!(kept.is_ok());
+        let cancelled = cancelled.ok().unwrap().id;
+        cancel_order(cancelled, None, "clerk", "changed mind".to_string(), None).unwrap();
+
+        let report = liability_report("2000-01-01", "2999-12-31").unwrap();
+        assert_eq!(report.jurisdictions.len(), 1);
+        let ca = &report.jurisdictions[0];
+        assert_eq!(
+            (ca.region.as_str(), ca.orders, ca.taxable, ca.tax),
+            ("CA", 1, 12.99, 0.94)
+        );
+        assert_eq!(report.total, 0.94);
+        assert_eq!(
+            liability_report("2999-12-31", "2000-01-01").unwrap_err(),
+            "from must not be after to"
+        );
+    }
+}
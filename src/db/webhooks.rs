@@ -0,0 +1,444 @@
+use super::db::connect;
+use crate::config;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    OrderCreated,
+    OrderShipped,
+    OrderCancelled,
+    BookCreated,
+    BookPriceChanged,
+    /// only sent on request, to test a subscription
+    Ping,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::OrderCreated => "order.created",
+            EventType::OrderShipped => "order.shipped",
+            EventType::OrderCancelled => "order.cancelled",
+            EventType::BookCreated => "book.created",
+            EventType::BookPriceChanged => "book.price_changed",
+            EventType::Ping => "ping",
+        }
+    }
+
+    pub fn parse(event: &str) -> Option<EventType> {
+        //! the events a subscription can ask for, which doesn't include ping
+        match event {
+            "order.created" => Some(EventType::OrderCreated),
+            "order.shipped" => Some(EventType::OrderShipped),
+            "order.cancelled" => Some(EventType::OrderCancelled),
+            "book.created" => Some(EventType::BookCreated),
+            "book.price_changed" => Some(EventType::BookPriceChanged),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Subscription {
+    pub id: i64,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+/// a new subscription, the only time its secret is shown
+#[derive(Serialize, Debug, Clone)]
+pub struct NewSubscription {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    pub secret: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Attempt {
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub attempted_at: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Delivery {
+    pub id: i64,
+    pub event_id: i64,
+    pub event_type: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: Option<String>,
+    pub delivered_at: Option<String>,
+    pub log: Vec<Attempt>,
+}
+
+/// a delivery the worker is to send now
+pub struct DueDelivery {
+    pub id: i64,
+    pub event_id: i64,
+    pub event_type: String,
+    pub payload: String,
+    pub created_at: String,
+    pub url: String,
+    pub secret: String,
+}
+
+pub fn emit(conn: &Connection, event: EventType, data: Value) {
+    //! writes the event to the outbox on the caller's transaction, with a delivery for
+    //! every active subscription to it, so it is only sent if the change is committed
+    conn.execute(
+        "INSERT INTO WebhookEvents (eventType, payload) VALUES (?1, ?2)",
+        [event.as_str(), &data.to_string()],
+    )
+    .expect("expected to be able to insert into WebhookEvents table");
+    let eid = conn.last_insert_rowid();
+    conn.execute(
+        "INSERT INTO WebhookDeliveries (eventId, subscriptionId)
+         SELECT ?1, s.id FROM WebhookSubscriptions s
+         JOIN WebhookSubscriptionEvents e ON e.subscriptionId = s.id
+         WHERE s.active = 1 AND e.eventType = ?2",
+        params![eid, event.as_str()],
+    )
+    .expect("expected to be able to insert into WebhookDeliveries table");
+}
+
+pub fn create_subscription(url: &str, events: &[EventType], actor: &str) -> NewSubscription {
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start webhook transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let secret = format!("whsec_{}", crate::auth::new_token());
+    tx.execute(
+        "INSERT INTO WebhookSubscriptions (url, secret, createdBy) VALUES (?1, ?2, ?3)",
+        [url, &secret, actor],
+    )
+    .expect("expected to be able to insert into WebhookSubscriptions table");
+    let sid = tx.last_insert_rowid();
+    for event in events {
+        tx.execute(
+            "INSERT INTO WebhookSubscriptionEvents (subscriptionId, eventType) VALUES (?1, ?2)",
+            params![sid, event.as_str()],
+        )
+        .expect("expected to be able to insert into WebhookSubscriptionEvents table");
+    }
+    let subscription = select_subscriptions(&tx, "id = ?1", [&sid])
+        .pop()
+        .expect("expected the subscription to exist");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit webhook transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "webhook subscription {} to {} created by {}", sid, url, actor);
+    NewSubscription {
+        subscription,
+        secret,
+    }
+}
+
+pub fn subscriptions() -> Vec<Subscription> {
+    let db = connect();
+    select_subscriptions(&db, "1 = 1", [])
+}
+
+pub fn deactivate(sid: i64, actor: &str) -> Result<(), String> {
+    //! stops new deliveries and gives up on pending ones, the log is kept
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start webhook transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let changed = tx
+        .execute(
+            "UPDATE WebhookSubscriptions SET active = 0 WHERE id = ?1 AND active = 1",
+            [&sid],
+        )
+        .expect("expected to be able to update WebhookSubscriptions table");
+    if changed == 0 {
+        warn!(target: "warn", "deactivation of unknown or inactive webhook subscription {}", sid);
+        return Err("active subscription does not exist in database".to_string());
+    }
+    tx.execute(
+        "UPDATE WebhookDeliveries SET status = 'failed' WHERE subscriptionId = ?1 AND status = 'pending'",
+        [&sid],
+    )
+    .expect("expected to be able to update WebhookDeliveries table");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit webhook transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    info!(target: "info", "webhook subscription {} deactivated by {}", sid, actor);
+    Ok(())
+}
+
+pub fn ping(sid: i64) -> Result<i64, String> {
+    //! queues a ping event for the subscription alone, returns the event id
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start webhook transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    let active: Option<bool> = tx
+        .query_row(
+            "SELECT active FROM WebhookSubscriptions WHERE id = ?1",
+            [&sid],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("expected to be able to select from WebhookSubscriptions table");
+    if active != Some(true) {
+        warn!(target: "warn", "ping of unknown or inactive webhook subscription {}", sid);
+        return Err("active subscription does not exist in database".to_string());
+    }
+    tx.execute(
+        "INSERT INTO WebhookEvents (eventType, payload) VALUES (?1, ?2)",
+        [
+            EventType::Ping.as_str(),
+            &serde_json::json!({ "subscription_id": sid }).to_string(),
+        ],
+    )
+    .expect("expected to be able to insert into WebhookEvents table");
+    let eid = tx.last_insert_rowid();
+    tx.execute(
+        "INSERT INTO WebhookDeliveries (eventId, subscriptionId) VALUES (?1, ?2)",
+        [&eid, &sid],
+    )
+    .expect("expected to be able to insert into WebhookDeliveries table");
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit webhook transaction: {}", e);
+        panic!("connection with database failure")
+    });
+    Ok(eid)
+}
+
+pub fn deliveries(sid: i64, page: i64, per_page: i64) -> Result<(Vec<Delivery>, i64), String> {
+    //! the subscription's delivery log, newest first, with the total number of deliveries
+    let db = connect();
+    let exists = db
+        .prepare("SELECT id FROM WebhookSubscriptions WHERE id = ?1")
+        .expect("expected to be able to select from WebhookSubscriptions table")
+        .exists([&sid])
+        .expect("expected to be able to select from WebhookSubscriptions table");
+    if !exists {
+        warn!(target: "warn", "deliveries of unknown webhook subscription {}", sid);
+        return Err("subscription does not exist in database".to_string());
+    }
+    let total: i64 = db
+        .query_row(
+            "SELECT COUNT(*) FROM WebhookDeliveries WHERE subscriptionId = ?1",
+            [&sid],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to count WebhookDeliveries table");
+    let mut stmt = db
+        .prepare(
+            "SELECT d.id, d.eventId, e.eventType, d.status, d.attempts, d.nextAttemptAt,
+                 d.deliveredAt
+             FROM WebhookDeliveries d JOIN WebhookEvents e ON e.id = d.eventId
+             WHERE d.subscriptionId = ?1 ORDER BY d.id DESC LIMIT ?2 OFFSET ?3",
+        )
+        .expect("expected to be able to select from WebhookDeliveries table");
+    let rows = stmt
+        .query_map(params![sid, per_page, (page - 1) * per_page], |row| {
+            let status: String = row.get(3)?;
+            let next_attempt_at: String = row.get(5)?;
+            Ok(Delivery {
+                id: row.get(0)?,
+                event_id: row.get(1)?,
+                event_type: row.get(2)?,
+                attempts: row.get(4)?,
+                next_attempt_at: (status == "pending").then_some(next_attempt_at),
+                status,
+                delivered_at: row.get(6)?,
+                log: Vec::new(),
+            })
+        })
+        .expect("expected to be able to get deliveries from WebhookDeliveries table");
+    let deliveries: Vec<Delivery> = rows
+        .map(|r| r.expect("problem getting delivery from database"))
+        .collect();
+    let deliveries = deliveries
+        .into_iter()
+        .map(|mut d| {
+            d.log = attempts(&db, d.id);
+            d
+        })
+        .collect();
+    Ok((deliveries, total))
+}
+
+pub fn retry(did: i64, actor: &str) -> Result<(), String> {
+    //! sends a failed delivery again with a fresh set of attempts
+    let db = connect();
+    let changed = db
+        .execute(
+            "UPDATE WebhookDeliveries SET status = 'pending', attempts = 0,
+                 nextAttemptAt = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND status = 'failed' AND subscriptionId IN
+                 (SELECT id FROM WebhookSubscriptions WHERE active = 1)",
+            [&did],
+        )
+        .expect("expected to be able to update WebhookDeliveries table");
+    if changed == 0 {
+        warn!(target: "warn", "retry of webhook delivery {} that isn't failed", did);
+        return Err(
+            "delivery does not exist, hasn't failed or its subscription is inactive".to_string(),
+        );
+    }
+    info!(target: "info", "webhook delivery {} queued again by {}", did, actor);
+    Ok(())
+}
+
+pub fn due(limit: i64) -> Vec<DueDelivery> {
+    //! pending deliveries whose next attempt is due, oldest event first
+    let db = connect();
+    let mut stmt = db
+        .prepare(
+            "SELECT d.id, e.id, e.eventType, e.payload, e.createdAt, s.url, s.secret
+             FROM WebhookDeliveries d JOIN WebhookEvents e ON e.id = d.eventId
+             JOIN WebhookSubscriptions s ON s.id = d.subscriptionId
+             WHERE d.status = 'pending' AND d.nextAttemptAt <= CURRENT_TIMESTAMP AND s.active = 1
+             ORDER BY e.id LIMIT ?1",
+        )
+        .expect("expected to be able to select from WebhookDeliveries table");
+    let rows = stmt
+        .query_map([&limit], |row| {
+            Ok(DueDelivery {
+                id: row.get(0)?,
+                event_id: row.get(1)?,
+                event_type: row.get(2)?,
+                payload: row.get(3)?,
+                created_at: row.get(4)?,
+                url: row.get(5)?,
+                secret: row.get(6)?,
+            })
+        })
+        .expect("expected to be able to get deliveries from WebhookDeliveries table");
+    rows.map(|r| r.expect("problem getting delivery from database"))
+        .collect()
+}
+
+pub fn record_attempt(did: i64, status_code: Option<u16>, error: Option<&str>, duration_ms: i64) {
+    //! logs an attempt. A 2xx response delivers the event, anything else is retried after
+    //! `webhook_backoff_seconds` doubled for each earlier failure, until the delivery has
+    //! failed `webhook_max_attempts` times
+    let delivered = status_code.is_some_and(|c| (200..300).contains(&c));
+    let mut db = connect();
+    let tx = db
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .unwrap_or_else(|e| {
+            error!(target: "error", "failed to start webhook transaction: {}", e);
+            panic!("connection with database failure")
+        });
+    tx.execute(
+        "INSERT INTO WebhookAttempts (deliveryId, statusCode, error, durationMs)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![did, status_code, error, duration_ms],
+    )
+    .expect("expected to be able to insert into WebhookAttempts table");
+    let attempts: i64 = tx
+        .query_row(
+            "UPDATE WebhookDeliveries SET attempts = attempts + 1 WHERE id = ?1 RETURNING attempts",
+            [&did],
+            |row| row.get(0),
+        )
+        .expect("expected to be able to update WebhookDeliveries table");
+    if delivered {
+        tx.execute(
+            "UPDATE WebhookDeliveries SET status = 'delivered', deliveredAt = CURRENT_TIMESTAMP
+             WHERE id = ?1",
+            [&did],
+        )
+        .expect("expected to be able to update WebhookDeliveries table");
+    } else if attempts >= config::webhook_max_attempts() {
+        tx.execute(
+            "UPDATE WebhookDeliveries SET status = 'failed' WHERE id = ?1",
+            [&did],
+        )
+        .expect("expected to be able to update WebhookDeliveries table");
+        warn!(target: "warn", "webhook delivery {} failed after {} attempts", did, attempts);
+    } else {
+        let wait = config::webhook_backoff_seconds() << (attempts - 1).min(20);
+        tx.execute(
+            "UPDATE WebhookDeliveries SET nextAttemptAt = datetime('now', ?1) WHERE id = ?2",
+            params![format!("+{} seconds", wait), did],
+        )
+        .expect("expected to be able to update WebhookDeliveries table");
+    }
+    tx.commit().unwrap_or_else(|e| {
+        error!(target: "error", "failed to commit webhook transaction: {}", e);
+        panic!("connection with database failure")
+    });
+}
+
+fn select_subscriptions<P: rusqlite::Params>(
+    conn: &Connection,
+    condition: &str,
+    params: P,
+) -> Vec<Subscription> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, url, active, createdBy, createdAt,
+                 (SELECT GROUP_CONCAT(eventType) FROM WebhookSubscriptionEvents
+                  WHERE subscriptionId = WebhookSubscriptions.id)
+             FROM WebhookSubscriptions WHERE {} ORDER BY id",
+            condition
+        ))
+        .expect("expected to be able to select from WebhookSubscriptions table");
+    let rows = stmt
+        .query_map(params, |row| {
+            let events: Option<String> = row.get(5)?;
+            let mut events: Vec<String> = events
+                .unwrap_or_default()
+                .split(',')
+                .filter(|e| !e.is_empty())
+                .map(str::to_string)
+                .collect();
+            events.sort();
+            Ok(Subscription {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                active: row.get(2)?,
+                created_by: row.get(3)?,
+                created_at: row.get(4)?,
+                events,
+            })
+        })
+        .expect("expected to be able to get subscriptions from WebhookSubscriptions table");
+    rows.map(|r| r.expect("problem getting subscription from database"))
+        .collect()
+}
+
+fn attempts(conn: &Connection, did: i64) -> Vec<Attempt> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT statusCode, error, durationMs, attemptedAt FROM WebhookAttempts
+             WHERE deliveryId = ?1 ORDER BY id",
+        )
+        .expect("expected to be able to select from WebhookAttempts table");
+    let rows = stmt
+        .query_map([&did], |row| {
+            Ok(Attempt {
+                status_code: row.get(0)?,
+                error: row.get(1)?,
+                duration_ms: row.get(2)?,
+                attempted_at: row.get(3)?,
+            })
+        })
+        .expect("expected to be able to get attempts from WebhookAttempts table");
+    rows.map(|r| r.expect("problem getting attempt from database"))
+        .collect()
+}
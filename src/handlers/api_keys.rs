@@ -0,0 +1,107 @@
+use log::{info, warn};
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Authorized, StaffAdmin};
+use crate::db::api_keys::{self, ApiKey, SCOPES};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Key {
+    id: Option<i64>,
+    name: Option<String>,
+    scopes: Option<Vec<String>>,
+    key: Option<String>,
+}
+
+#[post("/new", data = "<key>")]
+pub fn create_key(admin: Authorized<StaffAdmin>, key: Json<Key>) -> Result<Json<Key>, String> {
+    let name = validate_name(key.name.clone())?;
+    let scopes = validate_scopes(key.scopes.clone())?;
+
+    let (id, secret) = api_keys::create_key(name, scopes)?;
+    info!(target: "info", "api key {} issued by {}", id, admin.principal.name());
+    Ok(Json(Key {
+        id: Some(id),
+        name: None,
+        scopes: None,
+        key: Some(secret),
+    }))
+}
+
+#[put("/rotate", data = "<key>")]
+pub fn rotate_key(admin: Authorized<StaffAdmin>, key: Json<Key>) -> Result<Json<Key>, String> {
+    let id = validate_id(key.id)?;
+
+    let secret = api_keys::rotate_key(id)?;
+    info!(target: "info", "api key {} rotated by {}", id, admin.principal.name());
+    Ok(Json(Key {
+        id: Some(id),
+        name: None,
+        scopes: None,
+        key: Some(secret),
+    }))
+}
+
+#[put("/revoke", data = "<key>")]
+pub fn revoke_key(admin: Authorized<StaffAdmin>, key: Json<Key>) -> Result<(), String> {
+    let id = validate_id(key.id)?;
+
+    api_keys::revoke_key(id)?;
+    info!(target: "info", "api key {} revoked by {}", id, admin.principal.name());
+    Ok(())
+}
+
+#[get("/")]
+pub fn list_keys(_admin: Authorized<StaffAdmin>) -> Json<Vec<ApiKey>> {
+    Json(api_keys::list_keys())
+}
+
+fn validate_name(name: Option<String>) -> Result<String, String> {
+    //! a short label for the client the key belongs to
+    let name = match name {
+        Some(s) => s.trim().to_string(),
+        None => return Err("no name provided".to_string()),
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_')
+    {
+        warn!(target: "warn", "provided api key name is not alphanumeric");
+        Err("name should be alpha-numeric".to_string())
+    } else {
+        Ok(name)
+    }
+}
+
+fn validate_scopes(scopes: Option<Vec<String>>) -> Result<Vec<String>, String> {
+    //! every scope must name one of the mounts, duplicates are dropped
+    let scopes = match scopes {
+        Some(s) if !s.is_empty() => s,
+        _ => return Err("no scopes provided".to_string()),
+    };
+    let mut valid: Vec<String> = Vec::new();
+    for scope in scopes {
+        let scope = scope.trim().to_lowercase();
+        if !SCOPES.contains(&scope.as_str()) {
+            warn!(target: "warn", "unknown api key scope provided: {}", scope);
+            return Err(format!("scopes should be from {}", SCOPES.join(", ")));
+        }
+        if !valid.contains(&scope) {
+            valid.push(scope);
+        }
+    }
+    Ok(valid)
+}
+
+fn validate_id(id: Option<i64>) -> Result<i64, String> {
+    let id = match id {
+        Some(s) => s,
+        None => return Err("no id provided".to_string()),
+    };
+    if id <= 0 {
+        Err("id must be a value greater than 0".to_string())
+    } else {
+        Ok(id)
+    }
+}
@@ -1,5 +1,8 @@
+use crate::auth::{Authorized, CatalogWrite};
 use crate::db::books;
-use log::warn;
+use crate::db::tax::TaxCategory;
+use crate::idempotency::Idempotent;
+use log::{info, warn};
 use regex::Regex;
 use rocket::serde::json::Json;
 use serde::{Deserialize, Serialize};
@@ -15,12 +18,130 @@ pub struct Book {
 impl Book {}
 
 #[post("/new", data = "<book>")]
-pub fn create_book(book: Json<Book>) -> Result<(), String> {
+pub fn create_book(
+    auth: Authorized<CatalogWrite>,
+    book: Idempotent<Json<Book>>,
+) -> Result<(), String> {
     let title = validate_title(book.title.clone())?;
     let author = validate_auth(book.author.clone())?;
     let price = validate_price(book.price)?;
 
     books::create_book(title, author, price)?;
+    info!(target: "info", "book creation authorized by {}", auth.principal.name());
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Price {
+    price: Option<f64>,
+}
+
+#[put("/<id>/price", data = "<price>")]
+pub fn set_price(
+    auth: Authorized<CatalogWrite>,
+    id: i64,
+    price: Idempotent<Json<Price>>,
+) -> Result<(), String> {
+    if id <= 0 {
+        return Err("bid must be a value greater than 0".to_string());
+    }
+    let price = validate_price(price.price)?;
+
+    books::set_price(id, price)?;
+    info!(target: "info", "price change authorized by {}", auth.principal.name());
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Stock {
+    stock: Option<i64>,
+}
+
+#[put("/<id>/stock", data = "<stock>")]
+pub fn set_stock(
+    auth: Authorized<CatalogWrite>,
+    id: i64,
+    stock: Idempotent<Json<Stock>>,
+) -> Result<(), String> {
+    if id <= 0 {
+        return Err("bid must be a value greater than 0".to_string());
+    }
+    if stock.stock.is_some_and(|s| s < 0) {
+        warn!(target: "warn", "negative stock rejected for book {}", id);
+        return Err("stock must not be negative".to_string());
+    }
+
+    books::set_stock(id, stock.stock)?;
+    info!(target: "info", "stock change authorized by {}", auth.principal.name());
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Dimensions {
+    weight: Option<i64>,
+    length: Option<i64>,
+    width: Option<i64>,
+    height: Option<i64>,
+}
+
+#[put("/<id>/dimensions", data = "<dimensions>")]
+pub fn set_dimensions(
+    auth: Authorized<CatalogWrite>,
+    id: i64,
+    dimensions: Idempotent<Json<Dimensions>>,
+) -> Result<(), String> {
+    if id <= 0 {
+        return Err("bid must be a value greater than 0".to_string());
+    }
+    let dimensions = [
+        dimensions.weight,
+        dimensions.length,
+        dimensions.width,
+        dimensions.height,
+    ];
+    // up to 50kg and a metre a side
+    if dimensions
+        .iter()
+        .flatten()
+        .any(|d| !(1..=50_000).contains(d))
+        || dimensions[1..].iter().flatten().any(|d| *d > 1_000)
+    {
+        warn!(target: "warn", "book dimensions rejected for book {}: {:?}", id, dimensions);
+        return Err("weight must be 1 to 50000 grams and sides 1 to 1000 mm".to_string());
+    }
+
+    books::set_dimensions(id, dimensions)?;
+    info!(target: "info", "dimensions change authorized by {}", auth.principal.name());
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Category {
+    category: Option<String>,
+}
+
+#[put("/<id>/tax-category", data = "<category>")]
+pub fn set_tax_category(
+    auth: Authorized<CatalogWrite>,
+    id: i64,
+    category: Idempotent<Json<Category>>,
+) -> Result<(), String> {
+    if id <= 0 {
+        return Err("bid must be a value greater than 0".to_string());
+    }
+    let category = match category.category.as_deref().map(str::trim) {
+        Some(c) => match TaxCategory::parse(c) {
+            Some(c) => c,
+            None => {
+                warn!(target: "warn", "tax category rejected for book {}: {}", id, c);
+                return Err("category should be one of book, ebook, general".to_string());
+            }
+        },
+        None => return Err("no category provided".to_string()),
+    };
+
+    books::set_tax_category(id, category)?;
+    info!(target: "info", "tax category change authorized by {}", auth.principal.name());
     Ok(())
 }
 
@@ -0,0 +1,153 @@
+use log::warn;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    response::status::Custom,
+    serde::json::Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::db::carts::{self, Cart, CheckoutError, Owner};
+use crate::db::customers;
+use crate::db::purchaseOrders::{OrderError, OrderSummary, ShippingChoice};
+use crate::handlers::orders::{normalize_coupon, validate_id};
+use crate::idempotency::Idempotent;
+
+/// Request guard naming the cart: an anonymous session's `X-Cart-Token` header,
+/// or else the `customer_id` query parameter. Fails with 400 when neither is given.
+pub struct CartOwner(Owner);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CartOwner {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(token) = req.headers().get_one("X-Cart-Token") {
+            return Outcome::Success(CartOwner(Owner::Session(token.trim().to_string())));
+        }
+        match req.query_value::<i64>("customer_id") {
+            Some(Ok(cid)) if cid > 0 => {
+                Outcome::Success(CartOwner(Owner::Customer(customers::resolve_id(cid))))
+            }
+            Some(_) => Outcome::Error((
+                Status::BadRequest,
+                "cid must be a value greater than 0".to_string(),
+            )),
+            None => {
+                warn!(target: "warn", "cart request without an owner to {}", req.uri());
+                Outcome::Error((
+                    Status::BadRequest,
+                    "no customer_id or cart token provided".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Session {
+    token: String,
+}
+
+#[post("/new")]
+pub fn new_cart() -> Json<Session> {
+    Json(Session {
+        token: carts::new_session(),
+    })
+}
+
+#[get("/")]
+pub fn get_cart(owner: CartOwner) -> Result<Json<Cart>, String> {
+    Ok(Json(carts::view(&owner.0)?))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Item {
+    book_id: Option<i64>,
+    quantity: Option<i64>,
+}
+
+#[post("/items", data = "<item>")]
+pub fn add_item(owner: CartOwner, item: Idempotent<Json<Item>>) -> Result<Json<Cart>, String> {
+    let bid = validate_id(item.book_id, "bid")?;
+    let quantity = validate_quantity(item.quantity.or(Some(1)))?;
+
+    Ok(Json(carts::add(&owner.0, bid, quantity)?))
+}
+
+#[put("/items/<bid>", data = "<item>")]
+pub fn update_item(
+    owner: CartOwner,
+    bid: i64,
+    item: Idempotent<Json<Item>>,
+) -> Result<Json<Cart>, String> {
+    let bid = validate_id(Some(bid), "bid")?;
+    let quantity = validate_quantity(item.quantity)?;
+
+    Ok(Json(carts::set_quantity(&owner.0, bid, quantity)?))
+}
+
+#[delete("/items/<bid>")]
+pub fn remove_item(owner: CartOwner, bid: i64) -> Result<Json<Cart>, String> {
+    let bid = validate_id(Some(bid), "bid")?;
+
+    Ok(Json(carts::remove(&owner.0, bid)?))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Checkout {
+    customer_id: Option<i64>,
+    redeem_points: Option<i64>,
+    coupon_code: Option<String>,
+    carrier: Option<String>,
+    service_level: Option<String>,
+}
+
+#[post("/checkout", data = "<checkout>")]
+pub fn checkout(
+    owner: CartOwner,
+    checkout: Idempotent<Json<Checkout>>,
+) -> Result<Json<OrderSummary>, Custom<String>> {
+    // an anonymous cart is ordered by whoever checks it out
+    let cid = match (&owner.0, checkout.customer_id) {
+        (Owner::Customer(cid), _) => *cid,
+        (Owner::Session(_), cid) => customers::resolve_id(
+            validate_id(cid, "cid").map_err(|e| Custom(Status::BadRequest, e))?,
+        ),
+    };
+
+    let points = checkout.redeem_points.unwrap_or(0);
+    if points < 0 {
+        return Err(Custom(
+            Status::BadRequest,
+            "redeem_points must not be negative".to_string(),
+        ));
+    }
+
+    let choice = ShippingChoice {
+        carrier: checkout.carrier.as_ref().map(|c| c.trim().to_lowercase()),
+        service_level: checkout
+            .service_level
+            .as_ref()
+            .map(|s| s.trim().to_lowercase()),
+    };
+
+    let coupon = checkout.coupon_code.as_deref().map(normalize_coupon);
+
+    match carts::checkout(&owner.0, cid, points, coupon, choice) {
+        Ok(summary) => Ok(Json(summary)),
+        Err(CheckoutError::Changed(e)) => Err(Custom(Status::Conflict, e)),
+        Err(CheckoutError::Order(OrderError::PaymentRequired(e))) => {
+            Err(Custom(Status::PaymentRequired, e))
+        }
+        Err(CheckoutError::Order(OrderError::Rejected(e))) => Err(Custom(Status::BadRequest, e)),
+    }
+}
+
+fn validate_quantity(quantity: Option<i64>) -> Result<i64, String> {
+    match quantity {
+        Some(q) if (1..=100).contains(&q) => Ok(q),
+        Some(_) => Err("quantity must be between 1 and 100".to_string()),
+        None => Err("no quantity provided".to_string()),
+    }
+}
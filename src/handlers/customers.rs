@@ -1,34 +1,60 @@
-use log::warn;
+use log::{info, warn};
 use regex::Regex;
 use rocket::serde::json::Json;
 use serde::{Deserialize, Serialize};
 use titlecase::titlecase;
 
-use crate::db::customers;
+use crate::auth::{AccountLedger, Authorized, CustomerAddress, CustomerAdmin, CustomerMerge};
+use crate::db::customers::{self, MergeSummary};
+use crate::db::ledger::{self, EntryKind, LedgerEntry};
+use crate::db::shipping::Destination;
+use crate::handlers::orders::{order_history, validate_text, OrderHistory};
+use crate::handlers::shipping::{validate_country, validate_postal_code, validate_region};
+use crate::idempotency::Idempotent;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Customer {
     id: Option<i64>,
     name: Option<String>,
     shipping_address: Option<String>,
+    country: Option<String>,
+    region: Option<String>,
+    postal_code: Option<String>,
     account_balance: Option<f64>,
 }
 
 #[post("/new", data = "<customer>")]
-pub fn create_customer(customer: Json<Customer>) -> Result<(), String> {
+pub fn create_customer(customer: Idempotent<Json<Customer>>) -> Result<(), String> {
     let name = validate_name(customer.name.clone())?;
     let address = validate_addr(customer.shipping_address.clone())?;
+    let dest = Destination {
+        country: validate_country(customer.country.clone().or(Some("US".to_string())))?,
+        region: validate_region(customer.region.clone().unwrap_or_default())?,
+        postal_code: validate_postal_code(customer.postal_code.clone().unwrap_or_default())?,
+    };
 
-    customers::create_customer(name, address)?;
+    customers::create_customer(name, address, dest)?;
     Ok(())
 }
 
 #[post("/updateAddress", data = "<customer>")]
-pub fn update_address(customer: Json<Customer>) -> Result<(), String> {
-    let cid = validate_cid(customer.id)?;
+pub fn update_address(
+    auth: Authorized<CustomerAddress>,
+    customer: Idempotent<Json<Customer>>,
+) -> Result<(), String> {
+    let cid = customers::resolve_id(validate_cid(customer.id)?);
     let address = validate_addr(customer.shipping_address.clone())?;
 
     customers::update_customer_address(cid, address)?;
+    if customer.country.is_some() || customer.region.is_some() || customer.postal_code.is_some() {
+        let dest = Destination {
+            country: validate_country(customer.country.clone())?,
+            region: validate_region(customer.region.clone().unwrap_or_default())?,
+            postal_code: validate_postal_code(customer.postal_code.clone().unwrap_or_default())?,
+        };
+        customers::set_destination(cid, dest)?;
+    }
+    info!(target: "info", "address of customer {} updated by {}", cid, auth.principal.name());
     Ok(())
 }
 
@@ -37,16 +63,120 @@ pub fn get_balance(customer: Json<Customer>) -> Result<Json<Customer>, String> {
     let name = validate_name(customer.name.clone())?;
     let address = validate_addr(customer.shipping_address.clone())?;
 
-    let cid = customers::get_customer_id(name, address)?;
+    let cid = customers::resolve_id(customers::get_customer_id(name, address)?);
     let balance = customers::customer_balance(cid)?;
     Ok(Json(Customer {
         id: None,
         name: None,
         shipping_address: None,
+        country: None,
+        region: None,
+        postal_code: None,
         account_balance: Some(balance),
     }))
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    id: Option<i64>,
+    kind: Option<String>,
+    amount: Option<f64>,
+    order_id: Option<i64>,
+    memo: Option<String>,
+    balance: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Transactions {
+    entries: Vec<LedgerEntry>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+#[post("/<id>/ledger", data = "<entry>")]
+pub fn post_ledger_entry(
+    auth: Authorized<AccountLedger>,
+    id: i64,
+    entry: Idempotent<Json<Entry>>,
+) -> Result<Json<Entry>, String> {
+    let cid = customers::resolve_id(validate_cid(Some(id))?);
+    let kind = validate_kind(entry.kind.clone())?;
+    let amount = validate_amount(entry.amount, kind)?;
+    let memo = validate_text(entry.memo.clone(), "memo")?;
+
+    let eid = ledger::post_entry(cid, kind, amount, entry.order_id, memo)?;
+    info!(target: "info", "ledger entry {} posted by {}", eid, auth.principal.name());
+    let balance = customers::customer_balance(cid)?;
+    Ok(Json(Entry {
+        id: Some(eid),
+        kind: None,
+        amount: None,
+        order_id: None,
+        memo: None,
+        balance: Some(balance),
+    }))
+}
+
+#[get("/<id>/transactions?<page>&<per_page>")]
+pub fn get_transactions(
+    _auth: Authorized<CustomerAdmin>,
+    id: i64,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<Transactions>, String> {
+    let cid = customers::resolve_id(validate_cid(Some(id))?);
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(20).clamp(1, 100);
+
+    let (entries, total) = ledger::list_entries(cid, page, per_page)?;
+    Ok(Json(Transactions {
+        entries,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+#[get("/<id>/orders?<state>&<from>&<to>&<page>&<per_page>")]
+pub fn get_orders(
+    _auth: Authorized<CustomerAdmin>,
+    id: i64,
+    state: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<OrderHistory>, String> {
+    let cid = customers::resolve_id(validate_cid(Some(id))?);
+
+    order_history(Some(cid), state, from, to, page, per_page)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Merge {
+    source_id: Option<i64>,
+    target_id: Option<i64>,
+    reason: Option<String>,
+}
+
+#[post("/merge", data = "<merge>")]
+pub fn merge_customers(
+    auth: Authorized<CustomerMerge>,
+    merge: Idempotent<Json<Merge>>,
+) -> Result<Json<MergeSummary>, String> {
+    let source = validate_cid(merge.source_id)?;
+    let target = validate_cid(merge.target_id)?;
+    let reason = validate_text(merge.reason.clone(), "reason")?.ok_or("no reason provided")?;
+
+    Ok(Json(customers::merge_customers(
+        source,
+        target,
+        auth.principal.name(),
+        reason,
+    )?))
+}
+
 fn validate_name(name: Option<String>) -> Result<String, String> {
     //! validation function for name field (unwraps Option<String>)
     let name = match name {
@@ -103,3 +233,29 @@ fn validate_cid(cid: Option<i64>) -> Result<i64, String> {
         Ok(cid)
     }
 }
+
+fn validate_kind(kind: Option<String>) -> Result<EntryKind, String> {
+    let kind = match kind {
+        Some(s) => s,
+        None => return Err("no entry kind provided".to_string()),
+    };
+    EntryKind::parse(kind.trim()).ok_or_else(|| {
+        warn!(target: "warn", "unknown ledger entry kind provided: {}", kind);
+        "kind should be one of deposit, order_charge, refund, adjustment, store_credit".to_string()
+    })
+}
+
+fn validate_amount(amount: Option<f64>, kind: EntryKind) -> Result<f64, String> {
+    //! amounts are in whole cents, only adjustments may be negative
+    let amount = match amount {
+        Some(a) if a.is_finite() => a,
+        _ => return Err("no amount provided".to_string()),
+    };
+    let amount = (amount * 100.0).round() / 100.0;
+    if amount >= 0.01 || (kind == EntryKind::Adjustment && amount <= -0.01) {
+        Ok(amount)
+    } else {
+        warn!(target: "warn", "ledger amount rejected: {}", amount);
+        Err("amount must be greater than 0".to_string())
+    }
+}
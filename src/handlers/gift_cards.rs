@@ -0,0 +1,90 @@
+use log::{info, warn};
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{AccountLedger, Authorized, Reports};
+use crate::db::customers;
+use crate::db::gift_cards::{self, LiabilityReport};
+use crate::idempotency::Idempotent;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GiftCard {
+    id: Option<i64>,
+    code: Option<String>,
+    value: Option<f64>,
+    expires_in_days: Option<i64>,
+    amount: Option<f64>,
+    balance: Option<f64>,
+}
+
+#[post("/new", data = "<card>")]
+pub fn issue_card(
+    auth: Authorized<AccountLedger>,
+    card: Json<GiftCard>,
+) -> Result<Json<GiftCard>, String> {
+    let value = validate_value(card.value, "value")?;
+    let expires_in_days = validate_expiry(card.expires_in_days)?;
+
+    let (id, code) = gift_cards::issue_card(value, expires_in_days, auth.principal.name())?;
+    Ok(Json(GiftCard {
+        id: Some(id),
+        code: Some(code),
+        value: Some(value),
+        expires_in_days: None,
+        amount: None,
+        balance: None,
+    }))
+}
+
+#[post("/<id>/redeem", data = "<card>")]
+pub fn redeem_card(id: i64, card: Idempotent<Json<GiftCard>>) -> Result<Json<GiftCard>, String> {
+    if id <= 0 {
+        return Err("cid must be a value greater than 0".to_string());
+    }
+    let id = customers::resolve_id(id);
+    let code = card.code.clone().ok_or("no code provided")?;
+    let amount = match card.amount {
+        Some(a) => Some(validate_value(Some(a), "amount")?),
+        None => None,
+    };
+
+    let redeemed = gift_cards::redeem_card(id, code, amount)?;
+    let balance = customers::customer_balance(id)?;
+    Ok(Json(GiftCard {
+        id: None,
+        code: None,
+        value: None,
+        expires_in_days: None,
+        amount: Some(redeemed),
+        balance: Some(balance),
+    }))
+}
+
+#[get("/liability")]
+pub fn liability_report(auth: Authorized<Reports>) -> Json<LiabilityReport> {
+    info!(target: "info", "gift card liability report run by {}", auth.principal.name());
+    Json(gift_cards::liability_report())
+}
+
+fn validate_value(value: Option<f64>, label: &str) -> Result<f64, String> {
+    //! positive amount in whole cents
+    let value = match value {
+        Some(v) if v.is_finite() => (v * 100.0).round() / 100.0,
+        _ => return Err(format!("no {} provided", label)),
+    };
+    if value >= 0.01 {
+        Ok(value)
+    } else {
+        warn!(target: "warn", "gift card {} rejected: {}", label, value);
+        Err(format!("{} must be greater than 0", label))
+    }
+}
+
+fn validate_expiry(days: Option<i64>) -> Result<Option<i64>, String> {
+    //! cards without an expiry never expire
+    match days {
+        None => Ok(None),
+        Some(d) if (1..=3650).contains(&d) => Ok(Some(d)),
+        Some(_) => Err("expires_in_days must be between 1 and 3650".to_string()),
+    }
+}
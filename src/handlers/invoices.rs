@@ -0,0 +1,179 @@
+use askama::Template;
+use log::{error, info};
+use rocket::{
+    http::{Accept, ContentType},
+    response::content::RawHtml,
+    serde::json::Json,
+};
+
+use crate::auth::{Authorized, Reports};
+use crate::db::customers;
+use crate::db::invoices::{self, DocumentKind, Invoice};
+use crate::handlers::orders::validate_id;
+use crate::handlers::tax::validate_date;
+use crate::pdf::{Pdf, PAGE_HEIGHT, PAGE_WIDTH};
+
+/// rendered with askama, which HTML-escapes every value
+#[derive(Template)]
+#[template(path = "invoice.html")]
+struct InvoicePage<'a> {
+    invoice: &'a Invoice,
+}
+
+#[derive(Responder)]
+pub enum InvoiceResponse {
+    Json(Box<Json<Invoice>>),
+    Html(RawHtml<String>),
+}
+
+#[get("/<number>?<customer_id>")]
+pub fn get_invoice(
+    accept: Option<&Accept>,
+    number: &str,
+    customer_id: Option<i64>,
+) -> Result<InvoiceResponse, String> {
+    //! HTML when the client prefers it (browsers), JSON otherwise
+    let cid = customers::resolve_id(validate_id(customer_id, "cid")?);
+    let invoice = invoices::get(number, cid)?;
+    if accept.is_some_and(|a| a.preferred().media_type().is_html()) {
+        let page = InvoicePage { invoice: &invoice }.render().map_err(|e| {
+            error!(target: "error", "failed to render invoice page: {}", e);
+            "failed to render invoice".to_string()
+        })?;
+        Ok(InvoiceResponse::Html(RawHtml(page)))
+    } else {
+        Ok(InvoiceResponse::Json(Box::new(Json(invoice))))
+    }
+}
+
+#[get("/<number>/pdf?<customer_id>")]
+pub fn get_invoice_pdf(
+    number: &str,
+    customer_id: Option<i64>,
+) -> Result<(ContentType, Vec<u8>), String> {
+    let cid = customers::resolve_id(validate_id(customer_id, "cid")?);
+    let invoice = invoices::get(number, cid)?;
+
+    Ok((ContentType::PDF, render_pdf(&invoice)))
+}
+
+#[get("/?<from>&<to>")]
+pub fn list_invoices(
+    auth: Authorized<Reports>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Json<Vec<Invoice>>, String> {
+    let from = validate_date(from, "from")?;
+    let to = validate_date(to, "to")?;
+
+    let list = invoices::list(&from, &to)?;
+    info!(target: "info", "invoices for {} to {} listed by {}", from, to, auth.principal.name());
+    Ok(Json(list))
+}
+
+/// left edges of the line table's columns, amounts are right-aligned to the next one
+const COLUMNS: [f32; 4] = [50.0, 330.0, 380.0, 460.0];
+const RIGHT: f32 = PAGE_WIDTH - 50.0;
+
+fn render_pdf(invoice: &Invoice) -> Vec<u8> {
+    //! the same content as invoice.html, lines continue on a new page when one is full
+    let mut pdf = Pdf::default();
+    let title = match invoice.kind {
+        DocumentKind::Invoice => "Invoice",
+        DocumentKind::CreditNote => "Credit Note",
+    };
+    pdf.text(
+        50.0,
+        60.0,
+        20.0,
+        true,
+        &format!("{} {}", title, invoice.number),
+    );
+    let mut y = 90.0;
+    let mut details = vec![
+        format!("Issued: {}", invoice.issued_at),
+        format!("Order: {}", invoice.order_id),
+    ];
+    if let Some(credited) = &invoice.credited_invoice {
+        details.push(format!("Credits invoice: {}", credited));
+    }
+    if let Some(reason) = &invoice.reason {
+        details.push(format!("Reason: {}", reason));
+    }
+    details.push(String::new());
+    details.push("Bill to:".to_string());
+    details.push(invoice.billing_name.clone());
+    details.extend(invoice.billing_address.lines().map(str::to_string));
+    details.push(
+        [
+            invoice.postal_code.as_str(),
+            invoice.region.as_str(),
+            invoice.country.as_str(),
+        ]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" "),
+    );
+    for line in details {
+        pdf.text(50.0, y, 10.0, false, &line);
+        y += 14.0;
+    }
+
+    y += 20.0;
+    table_header(&mut pdf, y);
+    y += 18.0;
+    for line in &invoice.lines {
+        if y > PAGE_HEIGHT - 150.0 {
+            pdf.new_page();
+            y = 60.0;
+            table_header(&mut pdf, y);
+            y += 18.0;
+        }
+        let description: String = line.description.chars().take(50).collect();
+        pdf.text(COLUMNS[0], y, 10.0, false, &description);
+        pdf.text_right(
+            COLUMNS[2] - 10.0,
+            y,
+            10.0,
+            false,
+            &line.quantity.to_string(),
+        );
+        pdf.text_right(
+            COLUMNS[3] - 10.0,
+            y,
+            10.0,
+            false,
+            &format!("{:.2}", line.unit_price),
+        );
+        pdf.text_right(RIGHT, y, 10.0, false, &format!("{:.2}", line.line_total));
+        y += 14.0;
+    }
+
+    y += 20.0;
+    let tax_label = if invoice.tax_inclusive {
+        "Tax (included)"
+    } else {
+        "Tax"
+    };
+    for (label, amount, bold) in [
+        ("Subtotal", invoice.subtotal, false),
+        ("Discount", invoice.discount, false),
+        ("Shipping", invoice.shipping, false),
+        (tax_label, invoice.tax, false),
+        ("Total", invoice.total, true),
+    ] {
+        pdf.text(COLUMNS[2], y, 10.0, bold, label);
+        pdf.text_right(RIGHT, y, 10.0, bold, &format!("{:.2}", amount));
+        y += 14.0;
+    }
+    pdf.finish()
+}
+
+fn table_header(pdf: &mut Pdf, y: f32) {
+    pdf.text(COLUMNS[0], y, 10.0, true, "Description");
+    pdf.text_right(COLUMNS[2] - 10.0, y, 10.0, true, "Qty");
+    pdf.text_right(COLUMNS[3] - 10.0, y, 10.0, true, "Price");
+    pdf.text_right(RIGHT, y, 10.0, true, "Total");
+}
@@ -0,0 +1,52 @@
+use rocket::serde::json::Json;
+use serde::Serialize;
+
+use crate::auth::{Authorized, CustomerAdmin};
+use crate::db::customers;
+use crate::db::loyalty::{self, LoyaltySummary, LoyaltyTransaction};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LoyaltyHistory {
+    transactions: Vec<LoyaltyTransaction>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+#[get("/<id>/loyalty")]
+pub fn get_loyalty(
+    _auth: Authorized<CustomerAdmin>,
+    id: i64,
+) -> Result<Json<LoyaltySummary>, String> {
+    let cid = customers::resolve_id(validate_cid(id)?);
+
+    Ok(Json(loyalty::summary(cid)?))
+}
+
+#[get("/<id>/loyalty/history?<page>&<per_page>")]
+pub fn get_loyalty_history(
+    _auth: Authorized<CustomerAdmin>,
+    id: i64,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<LoyaltyHistory>, String> {
+    let cid = customers::resolve_id(validate_cid(id)?);
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(20).clamp(1, 100);
+
+    let (transactions, total) = loyalty::history(cid, page, per_page)?;
+    Ok(Json(LoyaltyHistory {
+        transactions,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+fn validate_cid(cid: i64) -> Result<i64, String> {
+    if cid <= 0 {
+        Err("cid must be a value greater than 0".to_string())
+    } else {
+        Ok(cid)
+    }
+}
@@ -1,3 +1,17 @@
+pub mod api_keys;
 pub mod books;
+pub mod carts;
 pub mod customers;
+pub mod gift_cards;
+pub mod invoices;
+pub mod loyalty;
 pub mod orders;
+pub mod picking;
+pub mod privacy;
+pub mod promotions;
+pub mod returns;
+pub mod shipments;
+pub mod shipping;
+pub mod staff;
+pub mod tax;
+pub mod webhooks;
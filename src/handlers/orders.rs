@@ -1,7 +1,22 @@
-use rocket::{response::content::RawHtml, serde::json::Json};
+use askama::Template;
+use log::{error, info, warn};
+use regex::Regex;
+use rocket::{
+    http::{Accept, Status},
+    response::{content::RawHtml, status::Custom},
+    serde::json::Json,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::auth::{AccountLedger, Authorized, CustomerAdmin, RecordShipment, Shipping};
+use crate::db::invoices::{self, Invoice};
+use crate::db::order_state::{self, OrderEvent, OrderState};
+use crate::db::purchaseOrders::{
+    Cancellation, OrderError, OrderFilter, OrderStatus, OrderSummary, ShippingChoice,
+};
 use crate::db::{customers, purchaseOrders};
+use crate::handlers::tax::validate_date;
+use crate::idempotency::Idempotent;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Order {
@@ -9,20 +24,87 @@ pub struct Order {
     customer_id: Option<i64>,
     book_id: Option<i64>,
     shipped: Option<i64>,
+    redeem_points: Option<i64>,
+    coupon_code: Option<String>,
 }
 
 #[post("/new", data = "<order>")]
-pub fn create_order(order: Json<Order>) -> Result<(), String> {
-    let cid = validate_id(order.customer_id, "cid")?;
-    let bid = validate_id(order.book_id, "bid")?;
+pub fn create_order(order: Idempotent<Json<Order>>) -> Result<(), Custom<String>> {
+    let cid = validate_id(order.customer_id, "cid").map_err(|e| Custom(Status::BadRequest, e))?;
+    let cid = customers::resolve_id(cid);
+    let bid = validate_id(order.book_id, "bid").map_err(|e| Custom(Status::BadRequest, e))?;
 
-    purchaseOrders::create_purchase_order(cid, bid)?;
-    Ok(())
+    let points = order.redeem_points.unwrap_or(0);
+    if points < 0 {
+        return Err(Custom(
+            Status::BadRequest,
+            "redeem_points must not be negative".to_string(),
+        ));
+    }
+
+    let coupon = order.coupon_code.as_deref().map(normalize_coupon);
+
+    match purchaseOrders::create_purchase_order(cid, bid, points, coupon) {
+        Ok(_) => Ok(()),
+        Err(OrderError::PaymentRequired(e)) => Err(Custom(Status::PaymentRequired, e)),
+        Err(OrderError::Rejected(e)) => Err(Custom(Status::BadRequest, e)),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Line {
+    book_id: Option<i64>,
+    quantity: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewOrder {
+    customer_id: Option<i64>,
+    lines: Option<Vec<Line>>,
+    redeem_points: Option<i64>,
+    coupon_code: Option<String>,
+    carrier: Option<String>,
+    service_level: Option<String>,
+}
+
+#[post("/", data = "<order>")]
+pub fn place_order(
+    order: Idempotent<Json<NewOrder>>,
+) -> Result<Json<OrderSummary>, Custom<String>> {
+    let cid = validate_id(order.customer_id, "cid").map_err(|e| Custom(Status::BadRequest, e))?;
+    let cid = customers::resolve_id(cid);
+    let lines =
+        validate_lines(order.lines.as_deref()).map_err(|e| Custom(Status::BadRequest, e))?;
+
+    let points = order.redeem_points.unwrap_or(0);
+    if points < 0 {
+        return Err(Custom(
+            Status::BadRequest,
+            "redeem_points must not be negative".to_string(),
+        ));
+    }
+
+    // unknown carriers or services are rejected when the order is priced
+    let choice = ShippingChoice {
+        carrier: order.carrier.as_ref().map(|c| c.trim().to_lowercase()),
+        service_level: order
+            .service_level
+            .as_ref()
+            .map(|s| s.trim().to_lowercase()),
+    };
+
+    let coupon = order.coupon_code.as_deref().map(normalize_coupon);
+
+    match purchaseOrders::create_order(cid, &lines, points, coupon, choice) {
+        Ok(summary) => Ok(Json(summary)),
+        Err(OrderError::PaymentRequired(e)) => Err(Custom(Status::PaymentRequired, e)),
+        Err(OrderError::Rejected(e)) => Err(Custom(Status::BadRequest, e)),
+    }
 }
 
 #[get("/shipped", format = "json", data = "<order>")]
 pub fn get_shipped(order: Json<Order>) -> Result<Json<Order>, String> {
-    let cid = validate_id(order.customer_id, "cid")?;
+    let cid = customers::resolve_id(validate_id(order.customer_id, "cid")?);
     let bid = validate_id(order.book_id, "bid")?;
 
     let oid = purchaseOrders::get_purchase_order_id(cid, bid)?;
@@ -32,49 +114,211 @@ pub fn get_shipped(order: Json<Order>) -> Result<Json<Order>, String> {
         customer_id: None,
         book_id: None,
         shipped: Some(shipped),
+        redeem_points: None,
+        coupon_code: None,
     }))
 }
 
 #[put("/ship", data = "<order>")]
-pub fn ship_order(order: Json<Order>) -> Result<(), String> {
+pub fn ship_order(
+    auth: Authorized<RecordShipment>,
+    order: Idempotent<Json<Order>>,
+) -> Result<(), String> {
     let oid = validate_id(order.id, "oid")?;
 
-    purchaseOrders::ship_po(oid)?;
+    purchaseOrders::ship_po(oid, &auth.principal.name())?;
+    info!(target: "info", "order {} shipped by {}", oid, auth.principal.name());
     Ok(())
 }
 
-#[get("/status", format = "json", data = "<order>")]
-pub fn get_status(order: Json<Order>) -> Result<RawHtml<String>, String> {
-    let oid = validate_id(order.id, "oid")?;
-    let cid = validate_id(order.id, "cid")?;
-    let bid = validate_id(order.id, "bid")?;
-
-    let addr = customers::get_customer_address(cid)?;
-
-    let response_html = format!(
-        "
-        <html>
-        <title>Order Status</title>
-        </head>
-        <body>
-        <h1>Order Status</h1>
-        <p>Order ID: {}</p>
-        <p>Book ID: {}</p>
-        <p>Customer ID: {}</p>
-        <p>Shipping Address: {}</p>
-        </body>
-        </html>
-    ",
+#[derive(Deserialize, Debug, Clone)]
+pub struct StateChange {
+    state: Option<String>,
+    note: Option<String>,
+}
+
+#[put("/<id>/state", data = "<change>")]
+pub fn set_state(
+    auth: Authorized<Shipping>,
+    id: i64,
+    change: Idempotent<Json<StateChange>>,
+) -> Result<(), String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let state = validate_state(change.state.clone())?;
+    let note = validate_text(change.note.clone(), "note")?;
+
+    purchaseOrders::set_state(oid, state, &auth.principal.name(), note)
+}
+
+#[get("/<id>/events")]
+pub fn get_events(
+    _auth: Authorized<CustomerAdmin>,
+    id: i64,
+) -> Result<Json<Vec<OrderEvent>>, String> {
+    let oid = validate_id(Some(id), "oid")?;
+
+    Ok(Json(order_state::events(oid)?))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Cancel {
+    customer_id: Option<i64>,
+    reason: Option<String>,
+    override_reason: Option<String>,
+}
+
+#[post("/<id>/cancel", data = "<cancel>")]
+pub fn cancel_order(
+    id: i64,
+    cancel: Idempotent<Json<Cancel>>,
+) -> Result<Json<Cancellation>, String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let cid = customers::resolve_id(validate_id(cancel.customer_id, "cid")?);
+    let reason = validate_text(cancel.reason.clone(), "reason")?.ok_or("no reason provided")?;
+
+    let actor = format!("customer {}", cid);
+    Ok(Json(purchaseOrders::cancel_order(
         oid,
-        bid,
-        cid,
-        &addr.as_str()
-    );
+        Some(cid),
+        &actor,
+        reason,
+        None,
+    )?))
+}
+
+#[post("/<id>/force-cancel", data = "<cancel>")]
+pub fn force_cancel_order(
+    auth: Authorized<AccountLedger>,
+    id: i64,
+    cancel: Idempotent<Json<Cancel>>,
+) -> Result<Json<Cancellation>, String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let reason = validate_text(cancel.reason.clone(), "reason")?.ok_or("no reason provided")?;
+    let override_reason = validate_text(cancel.override_reason.clone(), "override_reason")?
+        .ok_or("no override_reason provided")?;
+
+    let actor = auth.principal.name();
+    let cancellation =
+        purchaseOrders::cancel_order(oid, None, &actor, reason, Some(override_reason.clone()))?;
+    warn!(target: "warn", "order {} force-cancelled by {}: {}", oid, actor, override_reason);
+    Ok(Json(cancellation))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderHistory {
+    orders: Vec<OrderStatus>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+#[get("/?<customer_id>&<state>&<from>&<to>&<page>&<per_page>")]
+pub fn list_orders(
+    _auth: Authorized<CustomerAdmin>,
+    customer_id: Option<i64>,
+    state: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<OrderHistory>, String> {
+    let cid = match customer_id {
+        Some(cid) => Some(customers::resolve_id(validate_id(Some(cid), "cid")?)),
+        None => None,
+    };
+
+    order_history(cid, state, from, to, page, per_page)
+}
+
+pub(crate) fn order_history(
+    customer_id: Option<i64>,
+    state: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<OrderHistory>, String> {
+    //! the listing behind both the customer's and the staff order history
+    let filter = OrderFilter {
+        customer_id,
+        state: match state {
+            Some(s) => Some(OrderState::parse(s.trim()).ok_or_else(|| {
+                warn!(target: "warn", "order history state rejected: {}", s);
+                "state should be one of pending, paid, picking, shipped, delivered, cancelled, returned".to_string()
+            })?),
+            None => None,
+        },
+        from: from.map(|d| validate_date(Some(d), "from")).transpose()?,
+        to: to.map(|d| validate_date(Some(d), "to")).transpose()?,
+    };
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(20).clamp(1, 100);
+
+    let (orders, total) = purchaseOrders::list_orders(&filter, page, per_page)?;
+    Ok(Json(OrderHistory {
+        orders,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+/// rendered with askama, which HTML-escapes every value
+#[derive(Template)]
+#[template(path = "order_status.html")]
+struct StatusPage<'a> {
+    order: &'a OrderStatus,
+}
+
+#[derive(Responder)]
+pub enum StatusResponse {
+    Json(Json<OrderStatus>),
+    Html(RawHtml<String>),
+}
+
+#[get("/status", data = "<order>")]
+pub fn get_status(accept: Option<&Accept>, order: Json<Order>) -> Result<StatusResponse, String> {
+    let oid = validate_id(order.id, "oid")?;
+    let cid = customers::resolve_id(validate_id(order.customer_id, "cid")?);
+
+    render_status(accept, oid, cid)
+}
+
+#[get("/<id>/status?<customer_id>")]
+pub fn get_status_by_id(
+    accept: Option<&Accept>,
+    id: i64,
+    customer_id: Option<i64>,
+) -> Result<StatusResponse, String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let cid = customers::resolve_id(validate_id(customer_id, "cid")?);
+
+    render_status(accept, oid, cid)
+}
+
+#[get("/<id>/invoices?<customer_id>")]
+pub fn get_invoices(id: i64, customer_id: Option<i64>) -> Result<Json<Vec<Invoice>>, String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let cid = customers::resolve_id(validate_id(customer_id, "cid")?);
+
+    Ok(Json(invoices::for_order(oid, cid)?))
+}
 
-    Ok(RawHtml(response_html))
+fn render_status(accept: Option<&Accept>, oid: i64, cid: i64) -> Result<StatusResponse, String> {
+    //! HTML when the client prefers it (browsers), JSON otherwise
+    let status = purchaseOrders::order_status(oid, cid)?;
+    if accept.is_some_and(|a| a.preferred().media_type().is_html()) {
+        let page = StatusPage { order: &status }.render().map_err(|e| {
+            error!(target: "error", "failed to render order status page: {}", e);
+            "failed to render order status".to_string()
+        })?;
+        Ok(StatusResponse::Html(RawHtml(page)))
+    } else {
+        Ok(StatusResponse::Json(Json(status)))
+    }
 }
 
-fn validate_id(id: Option<i64>, label: &str) -> Result<i64, String> {
+pub(crate) fn validate_id(id: Option<i64>, label: &str) -> Result<i64, String> {
     //! makes sure a valid value is provided for cid/bid/oid
     let id = match id {
         Some(s) => s,
@@ -87,3 +331,320 @@ fn validate_id(id: Option<i64>, label: &str) -> Result<i64, String> {
         Ok(id)
     }
 }
+
+pub(crate) fn validate_lines(lines: Option<&[Line]>) -> Result<Vec<(i64, i64)>, String> {
+    //! (bid, quantity) pairs, lines for the same book are added together
+    let lines = match lines {
+        Some(l) if !l.is_empty() => l,
+        _ => return Err("no lines provided".to_string()),
+    };
+    if lines.len() > 50 {
+        return Err("an order can have at most 50 lines".to_string());
+    }
+    let mut merged: Vec<(i64, i64)> = Vec::new();
+    for line in lines {
+        let bid = validate_id(line.book_id, "bid")?;
+        let quantity = line.quantity.unwrap_or(1);
+        if !(1..=100).contains(&quantity) {
+            return Err("quantity must be between 1 and 100".to_string());
+        }
+        match merged.iter_mut().find(|(b, _)| *b == bid) {
+            Some((_, q)) if *q + quantity > 100 => {
+                return Err("quantity must be between 1 and 100".to_string())
+            }
+            Some((_, q)) => *q += quantity,
+            None => merged.push((bid, quantity)),
+        }
+    }
+    Ok(merged)
+}
+
+pub(crate) fn normalize_coupon(code: &str) -> String {
+    //! codes are stored uppercase, unknown codes are rejected when the order is priced
+    code.trim().to_uppercase()
+}
+
+fn validate_state(state: Option<String>) -> Result<OrderState, String> {
+    //! only the fulfilment steps can be set directly, the other states
+    //! are reached by placing, paying for, cancelling or returning an order
+    let state = match state {
+        Some(s) => s,
+        None => return Err("no state provided".to_string()),
+    };
+    match OrderState::parse(state.trim()) {
+        Some(s @ (OrderState::Picking | OrderState::Shipped | OrderState::Delivered)) => Ok(s),
+        _ => {
+            warn!(target: "warn", "order state rejected: {}", state);
+            Err("state should be one of picking, shipped, delivered".to_string())
+        }
+    }
+}
+
+pub(crate) fn validate_text(text: Option<String>, label: &str) -> Result<Option<String>, String> {
+    //! optional memos, notes and reasons, whitespace runs become one space and at most
+    //! 200 printable characters are kept
+    let text = match text {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let re = Regex::new(r"\s+").expect("regex creation failed");
+    let text = re.replace_all(text.trim(), " ").to_string();
+    if text.chars().count() > 200 || text.chars().any(|c| c.is_control()) {
+        warn!(target: "warn", "{} rejected", label);
+        Err(format!(
+            "{} should be at most 200 printable characters",
+            label
+        ))
+    } else {
+        Ok(Some(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(book_id: i64, quantity: Option<i64>) -> Line {
+        Line {
+            book_id: Some(book_id),
+            quantity,
+        }
+    }
+
+    #[test]
+    fn lines_for_the_same_book_are_added_together() {
+        let lines = [line(1, Some(2)), line(2, None), line(1, Some(3))];
+        assert_eq!(validate_lines(Some(&lines)), Ok(vec![(1, 5), (2, 1)]));
+    }
+
+    #[test]
+    fn quantities_stay_between_1_and_100() {
+        assert!(validate_lines(Some(&[line(1, Some(0))])).is_err());
+        assert!(validate_lines(Some(&[line(1, Some(101))])).is_err());
+        assert!(validate_lines(Some(&[line(1, Some(60)), line(1, Some(41))])).is_err());
+        assert!(validate_lines(Some(&[line(1, Some(100))])).is_ok());
+    }
+
+    #[test]
+    fn orders_need_between_1_and_50_lines() {
+        assert!(validate_lines(None).is_err());
+        assert!(validate_lines(Some(&[])).is_err());
+        let lines: Vec<Line> = (1..=51).map(|b| line(b, None)).collect();
+        assert!(validate_lines(Some(&lines)).is_err());
+        assert!(validate_lines(Some(&lines[..50])).is_ok());
+    }
+
+    #[test]
+    fn free_text_is_collapsed_and_kept_short() {
+        assert_eq!(validate_text(None, "note"), Ok(None));
+        assert_eq!(
+            validate_text(Some("  left at\n  the door ".to_string()), "note"),
+            Ok(Some("left at the door".to_string()))
+        );
+        assert_eq!(
+            validate_text(Some("x".repeat(201)), "reason"),
+            Err("reason should be at most 200 printable characters".to_string())
+        );
+        assert!(validate_text(Some("a\u{7}b".to_string()), "memo").is_err());
+    }
+
+    fn placed_order(name: &str) -> (i64, i64) {
+        use crate::db::purchaseOrders::{create_order, ShippingChoice};
+
+        let cid = crate::db::test_customer(name, 50.0);
+        let oid = create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id;
+        (oid, cid)
+    }
+
+    fn client() -> rocket::local::blocking::Client {
+        rocket::local::blocking::Client::untracked(
+            rocket::build()
+                .mount("/orders", routes![get_status_by_id, list_orders])
+                .mount(
+                    "/customers",
+                    routes![crate::handlers::customers::get_orders],
+                ),
+        )
+        .expect("valid rocket")
+    }
+
+    #[test]
+    fn status_is_json_unless_html_is_preferred() {
+        let _db = crate::db::test_db();
+        let (oid, cid) = placed_order("Ann Reader");
+        let client = client();
+        let uri = format!("/orders/{}/status?customer_id={}", oid, cid);
+
+        let json: rocket::serde::json::Value = client.get(&uri).dispatch().into_json().unwrap();
+        assert_eq!(json["state"], "paid");
+        assert_eq!(json["total"], 17.92);
+        assert_eq!(
+            json["lines"][0]["title"],
+            "The Hitchhikers Guide to the Galaxy"
+        );
+
+        let res = client.get(&uri).header(Accept::HTML).dispatch();
+        assert_eq!(res.content_type(), Some(rocket::http::ContentType::HTML));
+        assert!(res.into_string().unwrap().contains("Total: 17.92"));
+    }
+
+    #[test]
+    fn the_status_page_escapes_what_customers_typed() {
+        let _db = crate::db::test_db();
+        let (oid, cid) = placed_order("<script>alert(1)</script>");
+        let page = client()
+            .get(format!("/orders/{}/status?customer_id={}", oid, cid))
+            .header(Accept::HTML)
+            .dispatch()
+            .into_string()
+            .unwrap();
+
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn status_is_only_shown_to_the_orders_customer() {
+        let _db = crate::db::test_db();
+        let (oid, _) = placed_order("Ann Reader");
+        let (_, other) = placed_order("Bob Reader");
+
+        let body = client()
+            .get(format!("/orders/{}/status?customer_id={}", oid, other))
+            .dispatch()
+            .into_string()
+            .unwrap();
+        assert_eq!(body, "order does not exist in database");
+    }
+
+    fn another_order(cid: i64) -> i64 {
+        use crate::db::ledger::{self, EntryKind};
+        use crate::db::purchaseOrders::{create_order, ShippingChoice};
+
+        ledger::post_entry(cid, EntryKind::Deposit, 17.92, None, None).unwrap();
+        create_order(cid, &[(1, 1)], 0, None, ShippingChoice::default())
+            .ok()
+            .unwrap()
+            .id
+    }
+
+    fn support() -> String {
+        use crate::auth::Role;
+        use crate::db::staff;
+
+        let sid = staff::create_staff(
+            "sam".to_string(),
+            "correct horse".to_string(),
+            Role::Support,
+        )
+        .expect("new staff member");
+        staff::start_session(sid, false)
+    }
+
+    fn history(client: &rocket::local::blocking::Client, uri: &str, token: &str) -> String {
+        client
+            .get(uri.to_string())
+            .header(rocket::http::Header::new(
+                "Authorization",
+                format!("Bearer {}", token),
+            ))
+            .dispatch()
+            .into_string()
+            .unwrap()
+    }
+
+    fn ids(body: &str) -> (Vec<i64>, i64) {
+        let json: rocket::serde::json::Value =
+            rocket::serde::json::from_str(body).expect("an order history");
+        let ids = json["orders"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| o["id"].as_i64().unwrap())
+            .collect();
+        (ids, json["total"].as_i64().unwrap())
+    }
+
+    #[test]
+    fn a_customers_history_is_newest_first_and_paged() {
+        let _db = crate::db::test_db();
+        let (first, ann) = placed_order("Ann Reader");
+        let second = another_order(ann);
+        let third = another_order(ann);
+        placed_order("Bob Reader");
+        crate::db::purchaseOrders::cancel_order(
+            second,
+            None,
+            "clerk",
+            "changed mind".to_string(),
+            None,
+        )
+        .unwrap();
+        let (client, token) = (client(), support());
+
+        let uri = format!("/customers/{}/orders?per_page=2", ann);
+        assert_eq!(
+            ids(&history(&client, &uri, &token)),
+            (vec![third, second], 3)
+        );
+        let uri = format!("/customers/{}/orders?per_page=2&page=2", ann);
+        assert_eq!(ids(&history(&client, &uri, &token)), (vec![first], 3));
+        let uri = format!("/customers/{}/orders?state=cancelled", ann);
+        assert_eq!(ids(&history(&client, &uri, &token)), (vec![second], 1));
+    }
+
+    #[test]
+    fn every_order_can_be_listed_by_state_and_date() {
+        let _db = crate::db::test_db();
+        let (ann_order, _) = placed_order("Ann Reader");
+        let (bob_order, _) = placed_order("Bob Reader");
+        let (client, token) = (client(), support());
+
+        assert_eq!(
+            ids(&history(&client, "/orders?state=paid", &token)),
+            (vec![bob_order, ann_order], 2)
+        );
+        assert_eq!(
+            ids(&history(&client, "/orders?to=2000-01-31", &token)),
+            (vec![], 0)
+        );
+        assert_eq!(
+            history(&client, "/orders?from=2000-02-01&to=2000-01-31", &token),
+            "from must not be after to"
+        );
+        assert!(history(&client, "/orders?state=lost", &token).starts_with("state should be"));
+    }
+
+    #[test]
+    fn order_history_is_for_customer_administration() {
+        use crate::auth::Role;
+        use crate::db::staff;
+        use rocket::http::{Header, Status};
+
+        let _db = crate::db::test_db();
+        let (_, cid) = placed_order("Ann Reader");
+        let client = client();
+        let sid = staff::create_staff(
+            "cleo".to_string(),
+            "correct horse".to_string(),
+            Role::FulfillmentClerk,
+        )
+        .expect("new staff member");
+        let clerk = staff::start_session(sid, true);
+
+        for uri in ["/orders".to_string(), format!("/customers/{}/orders", cid)] {
+            assert_eq!(
+                client.get(uri.clone()).dispatch().status(),
+                Status::Unauthorized
+            );
+            let res = client
+                .get(uri)
+                .header(Header::new("Authorization", format!("Bearer {}", clerk)))
+                .dispatch();
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use askama::Template;
+use log::{error, info};
+use rocket::{
+    http::{Accept, ContentType, MediaType},
+    response::content::RawHtml,
+    serde::json::Json,
+};
+use serde::Deserialize;
+
+use crate::auth::{Authorized, Shipping};
+use crate::db::picking::{self, PackingSlip, PickBatch};
+use crate::handlers::orders::validate_id;
+use crate::idempotency::Idempotent;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewBatch {
+    order_ids: Option<Vec<i64>>,
+}
+
+#[post("/batches", data = "<batch>")]
+pub fn create_batch(
+    auth: Authorized<Shipping>,
+    batch: Idempotent<Json<NewBatch>>,
+) -> Result<Json<PickBatch>, String> {
+    let oids = match &batch.order_ids {
+        Some(ids) => Some(validate_order_ids(ids)?),
+        None => None,
+    };
+
+    Ok(Json(picking::create_batch(
+        oids.as_deref(),
+        &auth.principal.name(),
+    )?))
+}
+
+/// rendered with askama, which HTML-escapes every value
+#[derive(Template)]
+#[template(path = "pick_list.html")]
+struct PickListPage<'a> {
+    batch: &'a PickBatch,
+}
+
+#[derive(Template)]
+#[template(path = "packing_slips.html")]
+struct PackingSlipsPage<'a> {
+    slips: &'a [PackingSlip],
+}
+
+#[derive(Responder)]
+pub enum PickListResponse {
+    Json(Json<PickBatch>),
+    Html(RawHtml<String>),
+    Csv((ContentType, String)),
+}
+
+#[derive(Responder)]
+pub enum PackingSlipsResponse {
+    Json(Json<Vec<PackingSlip>>),
+    Html(RawHtml<String>),
+    Csv((ContentType, String)),
+}
+
+#[get("/batches/<id>")]
+pub fn get_pick_list(
+    auth: Authorized<Shipping>,
+    accept: Option<&Accept>,
+    id: i64,
+) -> Result<PickListResponse, String> {
+    //! HTML or CSV when the client asks for it, JSON otherwise
+    let bid = validate_id(Some(id), "batch id")?;
+    let batch = picking::get_batch(bid)?;
+    info!(target: "info", "pick list of batch {} printed by {}", bid, auth.principal.name());
+
+    match preferred(accept) {
+        Format::Html => Ok(PickListResponse::Html(RawHtml(render(
+            PickListPage { batch: &batch },
+            "pick list",
+        )?))),
+        Format::Csv => {
+            let mut rows = vec![vec![
+                "book_id".to_string(),
+                "title".to_string(),
+                "author".to_string(),
+                "quantity".to_string(),
+                "order_ids".to_string(),
+            ]];
+            for item in &batch.items {
+                rows.push(vec![
+                    item.book_id.to_string(),
+                    item.title.clone(),
+                    item.author.clone(),
+                    item.quantity.to_string(),
+                    item.order_ids
+                        .iter()
+                        .map(|o| o.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ]);
+            }
+            Ok(PickListResponse::Csv((ContentType::CSV, to_csv(&rows)?)))
+        }
+        Format::Json => Ok(PickListResponse::Json(Json(batch))),
+    }
+}
+
+#[get("/batches/<id>/slips")]
+pub fn get_packing_slips(
+    auth: Authorized<Shipping>,
+    accept: Option<&Accept>,
+    id: i64,
+) -> Result<PackingSlipsResponse, String> {
+    //! HTML prints one slip per page, CSV has a row per book of each order
+    let bid = validate_id(Some(id), "batch id")?;
+    let slips = picking::packing_slips(bid)?;
+    info!(target: "info", "packing slips of batch {} printed by {}", bid, auth.principal.name());
+
+    match preferred(accept) {
+        Format::Html => Ok(PackingSlipsResponse::Html(RawHtml(render(
+            PackingSlipsPage { slips: &slips },
+            "packing slips",
+        )?))),
+        Format::Csv => {
+            let mut rows = vec![vec![
+                "order_id".to_string(),
+                "name".to_string(),
+                "address".to_string(),
+                "postal_code".to_string(),
+                "region".to_string(),
+                "country".to_string(),
+                "carrier".to_string(),
+                "service_level".to_string(),
+                "book_id".to_string(),
+                "title".to_string(),
+                "quantity".to_string(),
+            ]];
+            for slip in &slips {
+                for line in &slip.lines {
+                    rows.push(vec![
+                        slip.order_id.to_string(),
+                        slip.name.clone(),
+                        slip.address.clone(),
+                        slip.postal_code.clone(),
+                        slip.region.clone(),
+                        slip.country.clone(),
+                        slip.carrier.clone().unwrap_or_default(),
+                        slip.service_level.clone().unwrap_or_default(),
+                        line.book_id.to_string(),
+                        line.title.clone(),
+                        line.quantity.to_string(),
+                    ]);
+                }
+            }
+            Ok(PackingSlipsResponse::Csv((
+                ContentType::CSV,
+                to_csv(&rows)?,
+            )))
+        }
+        Format::Json => Ok(PackingSlipsResponse::Json(Json(slips))),
+    }
+}
+
+enum Format {
+    Json,
+    Html,
+    Csv,
+}
+
+fn preferred(accept: Option<&Accept>) -> Format {
+    match accept.map(|a| a.preferred().media_type()) {
+        Some(m) if m.is_html() => Format::Html,
+        Some(m) if *m == MediaType::CSV => Format::Csv,
+        _ => Format::Json,
+    }
+}
+
+fn render<T: Template>(page: T, name: &str) -> Result<String, String> {
+    page.render().map_err(|e| {
+        error!(target: "error", "failed to render {}: {}", name, e);
+        format!("failed to render {}", name)
+    })
+}
+
+fn to_csv(rows: &[Vec<String>]) -> Result<String, String> {
+    //! cells that a spreadsheet would run as a formula are prefixed with '
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer
+            .write_record(row.iter().map(|cell| {
+                if cell.starts_with(['=', '+', '-', '@']) {
+                    format!("'{}", cell)
+                } else {
+                    cell.clone()
+                }
+            }))
+            .map_err(|e| {
+                error!(target: "error", "failed to write csv: {}", e);
+                "failed to write csv".to_string()
+            })?;
+    }
+    let bytes = writer.into_inner().map_err(|e| {
+        error!(target: "error", "failed to write csv: {}", e);
+        "failed to write csv".to_string()
+    })?;
+    String::from_utf8(bytes).map_err(|e| {
+        error!(target: "error", "csv is not utf-8: {}", e);
+        "failed to write csv".to_string()
+    })
+}
+
+fn validate_order_ids(ids: &[i64]) -> Result<Vec<i64>, String> {
+    if ids.is_empty() {
+        return Err(
+            "order_ids should not be empty, leave them out to pick every paid order".to_string(),
+        );
+    }
+    if ids.len() > 500 {
+        return Err("a batch can have at most 500 orders".to_string());
+    }
+    let mut oids: Vec<i64> = Vec::with_capacity(ids.len());
+    for &id in ids {
+        let oid = validate_id(Some(id), "oid")?;
+        if oids.contains(&oid) {
+            return Err(format!("oid {} is listed twice", oid));
+        }
+        oids.push(oid);
+    }
+    Ok(oids)
+}
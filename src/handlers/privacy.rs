@@ -0,0 +1,32 @@
+use log::info;
+use rocket::serde::json::Json;
+
+use crate::auth::{Authorized, CustomerAdmin, CustomerErasure};
+use crate::db::customers;
+use crate::db::privacy::{self, Erasure, Export};
+
+#[get("/<id>/export")]
+pub fn export_data(auth: Authorized<CustomerAdmin>, id: i64) -> Result<Json<Export>, String> {
+    let cid = customers::resolve_id(validate_cid(id)?);
+
+    let export = privacy::export(cid)?;
+    info!(target: "info", "data export of customer {} requested by {}", cid, auth.principal.name());
+    Ok(Json(export))
+}
+
+#[post("/<id>/erase")]
+pub fn erase_data(auth: Authorized<CustomerErasure>, id: i64) -> Result<Json<Erasure>, String> {
+    let cid = customers::resolve_id(validate_cid(id)?);
+
+    let erasure = privacy::erase(cid)?;
+    info!(target: "info", "customer {} erased by {}", cid, auth.principal.name());
+    Ok(Json(erasure))
+}
+
+fn validate_cid(cid: i64) -> Result<i64, String> {
+    if cid <= 0 {
+        Err("cid must be a value greater than 0".to_string())
+    } else {
+        Ok(cid)
+    }
+}
@@ -0,0 +1,161 @@
+use log::warn;
+use regex::Regex;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Authorized, Promotions};
+use crate::db::promotions::{self, CouponKind, Promotion, PromotionStats, Rules};
+use crate::handlers::tax::validate_date;
+use crate::idempotency::Idempotent;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PromotionRules {
+    code: Option<String>,
+    kind: Option<String>,
+    value: Option<f64>,
+    min_order: Option<f64>,
+    usage_limit: Option<i64>,
+    per_customer_limit: Option<i64>,
+    starts_on: Option<String>,
+    ends_on: Option<String>,
+    active: Option<bool>,
+    book_ids: Option<Vec<i64>>,
+    authors: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Deletion {
+    id: i64,
+    /// false when the promotion had been redeemed and was deactivated instead
+    deleted: bool,
+}
+
+#[post("/", data = "<rules>")]
+pub fn create_promotion(
+    auth: Authorized<Promotions>,
+    rules: Idempotent<Json<PromotionRules>>,
+) -> Result<Json<Promotion>, String> {
+    let rules = validate_rules(&rules)?;
+
+    Ok(Json(promotions::create(rules, &auth.principal.name())?))
+}
+
+#[get("/")]
+pub fn list_promotions(_auth: Authorized<Promotions>) -> Json<Vec<Promotion>> {
+    Json(promotions::list())
+}
+
+#[get("/<id>")]
+pub fn get_promotion(_auth: Authorized<Promotions>, id: i64) -> Result<Json<Promotion>, String> {
+    Ok(Json(promotions::get(validate_id(id)?)?))
+}
+
+#[put("/<id>", data = "<rules>")]
+pub fn update_promotion(
+    auth: Authorized<Promotions>,
+    id: i64,
+    rules: Idempotent<Json<PromotionRules>>,
+) -> Result<Json<Promotion>, String> {
+    let id = validate_id(id)?;
+    let rules = validate_rules(&rules)?;
+
+    Ok(Json(promotions::update(id, rules, &auth.principal.name())?))
+}
+
+#[delete("/<id>")]
+pub fn delete_promotion(auth: Authorized<Promotions>, id: i64) -> Result<Json<Deletion>, String> {
+    let id = validate_id(id)?;
+
+    let deleted = promotions::delete(id, &auth.principal.name())?;
+    Ok(Json(Deletion { id, deleted }))
+}
+
+#[get("/<id>/stats")]
+pub fn promotion_stats(
+    _auth: Authorized<Promotions>,
+    id: i64,
+) -> Result<Json<PromotionStats>, String> {
+    Ok(Json(promotions::stats(validate_id(id)?)?))
+}
+
+fn validate_id(id: i64) -> Result<i64, String> {
+    if id <= 0 {
+        Err("promotion id must be a value greater than 0".to_string())
+    } else {
+        Ok(id)
+    }
+}
+
+fn validate_rules(rules: &PromotionRules) -> Result<Rules, String> {
+    //! every rule at once, so the same checks apply to creating and replacing a promotion
+    let code = match &rules.code {
+        Some(c) => c.trim().to_uppercase(),
+        None => return Err("no code provided".to_string()),
+    };
+    let re = Regex::new(r"^[A-Z0-9_-]{3,32}$").expect("regex creation failed");
+    if !re.is_match(&code) {
+        warn!(target: "warn", "promotion code rejected: {}", code);
+        return Err("code should be 3 to 32 letters, digits, - or _".to_string());
+    }
+    let kind = match rules.kind.as_deref().map(str::trim).map(CouponKind::parse) {
+        Some(Some(k)) => k,
+        _ => return Err("kind should be percent or fixed".to_string()),
+    };
+    let value = match (rules.value, kind) {
+        (Some(v), CouponKind::Percent) if v > 0.0 && v <= 100.0 => v,
+        (Some(v), CouponKind::Fixed) if v >= 0.01 && v.is_finite() => (v * 100.0).round() / 100.0,
+        (_, CouponKind::Percent) => {
+            return Err("value must be a percentage from 0 to 100".to_string())
+        }
+        (_, CouponKind::Fixed) => return Err("value must be an amount greater than 0".to_string()),
+    };
+    if rules.min_order.is_some_and(|m| m < 0.0 || !m.is_finite()) {
+        return Err("min_order must not be negative".to_string());
+    }
+    if rules.usage_limit.is_some_and(|l| l < 1) || rules.per_customer_limit.is_some_and(|l| l < 1) {
+        return Err("limits must be at least 1".to_string());
+    }
+    let starts_on = rules
+        .starts_on
+        .clone()
+        .map(|d| validate_date(Some(d), "starts_on"))
+        .transpose()?;
+    let ends_on = rules
+        .ends_on
+        .clone()
+        .map(|d| validate_date(Some(d), "ends_on"))
+        .transpose()?;
+    if let (Some(s), Some(e)) = (&starts_on, &ends_on) {
+        if s > e {
+            return Err("starts_on must not be after ends_on".to_string());
+        }
+    }
+    let book_ids = rules.book_ids.clone().unwrap_or_default();
+    if book_ids.iter().any(|b| *b <= 0) {
+        return Err("bid must be a value greater than 0".to_string());
+    }
+    let authors: Vec<String> = rules
+        .authors
+        .clone()
+        .unwrap_or_default()
+        .iter()
+        .map(|a| a.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .collect();
+    if authors.iter().any(|a| a.is_empty() || a.len() > 100) {
+        return Err("authors should be 1 to 100 characters".to_string());
+    }
+
+    Ok(Rules {
+        code,
+        kind,
+        value,
+        min_order: rules.min_order,
+        usage_limit: rules.usage_limit,
+        per_customer_limit: rules.per_customer_limit,
+        starts_on,
+        ends_on,
+        active: rules.active.unwrap_or(true),
+        book_ids,
+        authors,
+    })
+}
@@ -0,0 +1,201 @@
+use log::{info, warn};
+use rocket::serde::json::Json;
+use serde::Deserialize;
+
+use crate::auth::{Authorized, CustomerAdmin, Shipping};
+use crate::db::customers;
+use crate::db::returns::{self, Condition, Disposition, Rma};
+use crate::handlers::orders::{validate_id, validate_text};
+use crate::idempotency::Idempotent;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReturnLine {
+    book_id: Option<i64>,
+    quantity: Option<i64>,
+    condition: Option<String>,
+    disposition: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewReturn {
+    order_id: Option<i64>,
+    customer_id: Option<i64>,
+    reason: Option<String>,
+    lines: Option<Vec<ReturnLine>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Decision {
+    approve: Option<bool>,
+    note: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Receipt {
+    lines: Option<Vec<ReturnLine>>,
+}
+
+#[post("/new", data = "<rma>")]
+pub fn open_return(rma: Idempotent<Json<NewReturn>>) -> Result<Json<Rma>, String> {
+    let oid = validate_id(rma.order_id, "oid")?;
+    let cid = customers::resolve_id(validate_id(rma.customer_id, "cid")?);
+    let reason = validate_text(rma.reason.clone(), "reason")?.ok_or("no reason provided")?;
+    let lines = validate_lines(rma.lines.as_deref())?;
+
+    let rid = returns::open_rma(oid, cid, reason, &lines)?;
+    Ok(Json(returns::get_rma(rid)?))
+}
+
+#[get("/<id>")]
+pub fn get_return(_auth: Authorized<CustomerAdmin>, id: i64) -> Result<Json<Rma>, String> {
+    let rid = validate_id(Some(id), "rma id")?;
+
+    Ok(Json(returns::get_rma(rid)?))
+}
+
+#[get("/queue?<status>")]
+pub fn return_queue(
+    _auth: Authorized<CustomerAdmin>,
+    status: Option<String>,
+) -> Result<Json<Vec<Rma>>, String> {
+    //! open rmas (requested and approved) unless a status is given
+    let statuses = match status.as_deref() {
+        None => vec!["requested", "approved"],
+        Some(s @ ("requested" | "approved" | "rejected" | "completed")) => vec![s],
+        Some(s) => {
+            warn!(target: "warn", "unknown rma status requested: {}", s);
+            return Err(
+                "status should be one of requested, approved, rejected, completed".to_string(),
+            );
+        }
+    };
+
+    Ok(Json(returns::queue(&statuses)))
+}
+
+#[put("/<id>/decision", data = "<decision>")]
+pub fn decide_return(
+    auth: Authorized<CustomerAdmin>,
+    id: i64,
+    decision: Idempotent<Json<Decision>>,
+) -> Result<Json<Rma>, String> {
+    let rid = validate_id(Some(id), "rma id")?;
+    let approve = decision.approve.ok_or("no decision provided")?;
+    let note = validate_text(decision.note.clone(), "note")?;
+
+    returns::decide(rid, approve, &auth.principal.name(), note)?;
+    Ok(Json(returns::get_rma(rid)?))
+}
+
+#[put("/<id>/receive", data = "<receipt>")]
+pub fn receive_return(
+    auth: Authorized<Shipping>,
+    id: i64,
+    receipt: Idempotent<Json<Receipt>>,
+) -> Result<Json<Rma>, String> {
+    let rid = validate_id(Some(id), "rma id")?;
+    let items = validate_items(receipt.lines.as_deref())?;
+
+    let rma = returns::receive(rid, &auth.principal.name(), &items)?;
+    info!(target: "info", "rma {} refund of {:.2} authorized by {}",
+        rid, rma.refunded.unwrap_or(0.0), auth.principal.name());
+    Ok(Json(rma))
+}
+
+fn validate_lines(lines: Option<&[ReturnLine]>) -> Result<Vec<(i64, i64)>, String> {
+    //! (bid, quantity) pairs, each book at most once
+    let lines = match lines {
+        Some(l) if !l.is_empty() => l,
+        _ => return Err("no lines provided".to_string()),
+    };
+    let mut pairs: Vec<(i64, i64)> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let bid = validate_id(line.book_id, "bid")?;
+        let quantity = line.quantity.unwrap_or(1);
+        if quantity < 1 {
+            return Err("quantity must be at least 1".to_string());
+        }
+        if pairs.iter().any(|(b, _)| *b == bid) {
+            return Err(format!("bid {} is listed twice", bid));
+        }
+        pairs.push((bid, quantity));
+    }
+    Ok(pairs)
+}
+
+fn validate_items(
+    lines: Option<&[ReturnLine]>,
+) -> Result<Vec<(i64, Condition, Disposition)>, String> {
+    //! each book at most once, unsellable copies can't be restocked
+    let lines = match lines {
+        Some(l) if !l.is_empty() => l,
+        _ => return Err("no lines provided".to_string()),
+    };
+    let mut items = Vec::with_capacity(lines.len());
+    for line in lines {
+        let bid = validate_id(line.book_id, "bid")?;
+        let condition = line
+            .condition
+            .as_deref()
+            .and_then(|c| Condition::parse(c.trim()))
+            .ok_or("condition should be one of as_new, damaged, unsellable")?;
+        let disposition = line
+            .disposition
+            .as_deref()
+            .and_then(|d| Disposition::parse(d.trim()))
+            .ok_or("disposition should be one of restock, write_off")?;
+        if condition == Condition::Unsellable && disposition == Disposition::Restock {
+            return Err(format!("bid {} is unsellable, it can't be restocked", bid));
+        }
+        if items.iter().any(|(b, _, _)| *b == bid) {
+            return Err(format!("bid {} is listed twice", bid));
+        }
+        items.push((bid, condition, disposition));
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(book_id: i64, condition: &str, disposition: &str) -> ReturnLine {
+        ReturnLine {
+            book_id: Some(book_id),
+            quantity: None,
+            condition: Some(condition.to_string()),
+            disposition: Some(disposition.to_string()),
+        }
+    }
+
+    #[test]
+    fn each_book_is_received_once() {
+        let lines = [
+            line(1, "as_new", "restock"),
+            line(1, "damaged", "write_off"),
+        ];
+        assert!(validate_items(Some(&lines)).is_err());
+    }
+
+    #[test]
+    fn unsellable_copies_are_not_restocked() {
+        assert!(validate_items(Some(&[line(1, "unsellable", "restock")])).is_err());
+        assert_eq!(
+            validate_items(Some(&[
+                line(1, "unsellable", "write_off"),
+                line(2, "damaged", "restock")
+            ])),
+            Ok(vec![
+                (1, Condition::Unsellable, Disposition::WriteOff),
+                (2, Condition::Damaged, Disposition::Restock)
+            ])
+        );
+    }
+
+    #[test]
+    fn conditions_and_dispositions_are_checked() {
+        assert!(validate_items(None).is_err());
+        assert!(validate_items(Some(&[line(1, "fine", "restock")])).is_err());
+        assert!(validate_items(Some(&[line(1, "as_new", "resell")])).is_err());
+    }
+}
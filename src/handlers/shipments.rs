@@ -0,0 +1,227 @@
+use log::warn;
+use regex::Regex;
+use rocket::serde::json::Json;
+use serde::Deserialize;
+
+use crate::auth::{Authorized, RecordShipment, Shipping};
+use crate::db::customers;
+use crate::db::shipments::{
+    self, BulkReport, BulkRow, Parcel, RowOutcome, RowResult, Shipment, Tracking,
+};
+use crate::handlers::orders::validate_id;
+use crate::handlers::tax;
+use crate::idempotency::Idempotent;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ParcelLine {
+    book_id: Option<i64>,
+    quantity: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewShipment {
+    carrier: Option<String>,
+    service_level: Option<String>,
+    tracking_number: Option<String>,
+    shipped_at: Option<String>,
+    lines: Option<Vec<ParcelLine>>,
+}
+
+#[post("/<id>/shipments", data = "<shipment>")]
+pub fn create_shipment(
+    auth: Authorized<RecordShipment>,
+    id: i64,
+    shipment: Idempotent<Json<NewShipment>>,
+) -> Result<Json<Shipment>, String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let parcel = Parcel {
+        carrier: validate_name(shipment.carrier.clone(), "carrier")?,
+        service_level: validate_name(shipment.service_level.clone(), "service_level")?,
+        tracking_number: validate_tracking(shipment.tracking_number.clone())?,
+        shipped_at: validate_date(shipment.shipped_at.clone())?,
+    };
+    let lines = match &shipment.lines {
+        Some(l) => Some(validate_lines(l)?),
+        None => None,
+    };
+
+    Ok(Json(shipments::create_shipment(
+        oid,
+        parcel,
+        lines.as_deref(),
+        &auth.principal.name(),
+    )?))
+}
+
+#[get("/<id>/shipments?<customer_id>")]
+pub fn get_tracking(id: i64, customer_id: Option<i64>) -> Result<Json<Tracking>, String> {
+    let oid = validate_id(Some(id), "oid")?;
+    let cid = customers::resolve_id(validate_id(customer_id, "cid")?);
+
+    Ok(Json(shipments::tracking(oid, cid)?))
+}
+
+/// a day's export from the warehouse is well below this
+const MAX_BULK_ROWS: usize = 5000;
+
+#[post("/ship/bulk?<mode>", data = "<file>")]
+pub fn ship_bulk(
+    auth: Authorized<Shipping>,
+    mode: Option<String>,
+    file: Idempotent<String>,
+) -> Result<Json<BulkReport>, String> {
+    //! a CSV of order id, carrier and optional tracking number per row, with or without
+    //! a header row. Rows that can't be read are reported as malformed
+    let atomic = match mode.as_deref().map(str::trim) {
+        None | Some("atomic") => true,
+        Some("per_row") => false,
+        Some(_) => return Err("mode should be atomic or per_row".to_string()),
+    };
+    let text = file.into_inner();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let mut rows: Vec<BulkRow> = Vec::new();
+    let mut malformed: Vec<RowResult> = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        if rows.len() + malformed.len() >= MAX_BULK_ROWS {
+            return Err(format!("file should have at most {} rows", MAX_BULK_ROWS));
+        }
+        let (line, parsed) = match record {
+            Ok(r) => {
+                let line = r.position().map_or(i as u64 + 1, |p| p.line());
+                if r.iter().all(str::is_empty)
+                    || (i == 0 && r.get(0).is_some_and(|f| f.eq_ignore_ascii_case("order_id")))
+                {
+                    continue;
+                }
+                (line, validate_bulk_row(line, &r))
+            }
+            Err(e) => (
+                e.position().map_or(i as u64 + 1, |p| p.line()),
+                Err((None, e.to_string())),
+            ),
+        };
+        match parsed {
+            Ok(row) => rows.push(row),
+            Err((order_id, message)) => malformed.push(RowResult {
+                line,
+                order_id,
+                outcome: RowOutcome::Malformed,
+                shipment_id: None,
+                message: Some(message),
+            }),
+        }
+    }
+    if rows.is_empty() && malformed.is_empty() {
+        return Err("file has no rows".to_string());
+    }
+
+    Ok(Json(shipments::ship_bulk(
+        &rows,
+        malformed,
+        atomic,
+        &auth.principal.name(),
+    )))
+}
+
+fn validate_bulk_row(
+    line: u64,
+    record: &csv::StringRecord,
+) -> Result<BulkRow, (Option<i64>, String)> {
+    //! order_id, carrier[, tracking_number]
+    if !(2..=3).contains(&record.len()) {
+        return Err((
+            None,
+            format!("row should have 2 or 3 fields, not {}", record.len()),
+        ));
+    }
+    let order_id = match record[0].parse::<i64>() {
+        Ok(id) if id > 0 => id,
+        _ => {
+            return Err((
+                None,
+                "order_id should be a number greater than 0".to_string(),
+            ))
+        }
+    };
+    let carrier =
+        validate_name(Some(record[1].to_string()), "carrier").map_err(|e| (Some(order_id), e))?;
+    let tracking_number = match record.get(2) {
+        Some(t) if !t.is_empty() => {
+            validate_tracking(Some(t.to_string())).map_err(|e| (Some(order_id), e))?
+        }
+        _ => None,
+    };
+    Ok(BulkRow {
+        line,
+        order_id,
+        carrier,
+        tracking_number,
+    })
+}
+
+fn validate_name(name: Option<String>, label: &str) -> Result<String, String> {
+    //! carriers and service levels are stored lowercase, e.g. "ups" and "next_day"
+    let name = match name {
+        Some(n) => n.trim().to_lowercase(),
+        None => return Err(format!("no {} provided", label)),
+    };
+    let re = Regex::new(r"^[a-z0-9][a-z0-9 _-]{0,39}$").expect("regex creation failed");
+    if re.is_match(&name) {
+        Ok(name)
+    } else {
+        warn!(target: "warn", "shipment {} rejected: {}", label, name);
+        Err(format!(
+            "{} should be at most 40 letters, digits, spaces, - or _",
+            label
+        ))
+    }
+}
+
+fn validate_tracking(tracking: Option<String>) -> Result<Option<String>, String> {
+    let tracking = match tracking {
+        Some(t) => t.trim().to_uppercase(),
+        None => return Ok(None),
+    };
+    let re = Regex::new(r"^[A-Z0-9]{4,40}$").expect("regex creation failed");
+    if re.is_match(&tracking) {
+        Ok(Some(tracking))
+    } else {
+        warn!(target: "warn", "tracking number rejected: {}", tracking);
+        Err("tracking_number should be 4 to 40 letters or digits".to_string())
+    }
+}
+
+fn validate_date(date: Option<String>) -> Result<Option<String>, String> {
+    //! YYYY-MM-DD, stored as midnight of that day
+    match date {
+        Some(d) => {
+            let date = tax::validate_date(Some(d), "shipped_at")?;
+            Ok(Some(format!("{} 00:00:00", date)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn validate_lines(lines: &[ParcelLine]) -> Result<Vec<(i64, i64)>, String> {
+    if lines.is_empty() {
+        return Err("lines should not be empty, leave them out to ship everything".to_string());
+    }
+    let mut pairs: Vec<(i64, i64)> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let bid = validate_id(line.book_id, "bid")?;
+        let quantity = line.quantity.unwrap_or(1);
+        if quantity < 1 {
+            return Err("quantity must be at least 1".to_string());
+        }
+        if pairs.iter().any(|(b, _)| *b == bid) {
+            return Err(format!("bid {} is listed twice", bid));
+        }
+        pairs.push((bid, quantity));
+    }
+    Ok(pairs)
+}
@@ -0,0 +1,176 @@
+use log::{info, warn};
+use regex::Regex;
+use rocket::serde::json::Json;
+use serde::Deserialize;
+
+use crate::auth::{Authorized, CatalogWrite};
+use crate::db::customers;
+use crate::db::shipping::{self, Area, Destination, Quote, Zone};
+use crate::handlers::orders::{validate_lines, Line};
+use crate::idempotency::Idempotent;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuoteRequest {
+    customer_id: Option<i64>,
+    country: Option<String>,
+    region: Option<String>,
+    postal_code: Option<String>,
+    lines: Option<Vec<Line>>,
+}
+
+#[post("/quote", data = "<request>")]
+pub fn get_quote(request: Idempotent<Json<QuoteRequest>>) -> Result<Json<Quote>, String> {
+    let lines = validate_lines(request.lines.as_deref())?;
+    // an explicit destination wins over the customer's own
+    let dest = match (request.customer_id, &request.country) {
+        (_, Some(_)) => Destination {
+            country: validate_country(request.country.clone())?,
+            region: validate_region(request.region.clone().unwrap_or_default())?,
+            postal_code: validate_postal_code(request.postal_code.clone().unwrap_or_default())?,
+        },
+        (Some(cid), None) => {
+            if cid <= 0 {
+                return Err("cid must be a value greater than 0".to_string());
+            }
+            shipping::customer_destination(customers::resolve_id(cid))?
+        }
+        (None, None) => return Err("no customer_id or country provided".to_string()),
+    };
+
+    Ok(Json(shipping::quote(&dest, &lines)?))
+}
+
+#[get("/zones")]
+pub fn get_zones(_auth: Authorized<CatalogWrite>) -> Json<Vec<Zone>> {
+    Json(shipping::zones())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewArea {
+    zone: Option<String>,
+    country: Option<String>,
+    postal_prefix: Option<String>,
+}
+
+#[post("/zones", data = "<area>")]
+pub fn add_area(
+    auth: Authorized<CatalogWrite>,
+    area: Idempotent<Json<NewArea>>,
+) -> Result<Json<i64>, String> {
+    let zone = validate_name(area.zone.clone())?;
+    let country = match area.country.as_deref().map(str::trim) {
+        Some("*") => "*".to_string(),
+        _ => validate_country(area.country.clone())?,
+    };
+    let postal_prefix = validate_postal_code(area.postal_prefix.clone().unwrap_or_default())?;
+
+    let zid = shipping::add_area(
+        &zone,
+        Area {
+            country,
+            postal_prefix,
+        },
+    )?;
+    info!(target: "info", "shipping area change authorized by {}", auth.principal.name());
+    Ok(Json(zid))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewRate {
+    carrier: Option<String>,
+    service_level: Option<String>,
+    max_weight: Option<i64>,
+    price: Option<f64>,
+    free_over: Option<f64>,
+}
+
+#[put("/zones/<id>/rates", data = "<rate>")]
+pub fn set_rate(
+    auth: Authorized<CatalogWrite>,
+    id: i64,
+    rate: Idempotent<Json<NewRate>>,
+) -> Result<Json<i64>, String> {
+    if id <= 0 {
+        return Err("zone id must be a value greater than 0".to_string());
+    }
+    let max_weight = match rate.max_weight {
+        Some(w) if (1..=100_000).contains(&w) => w,
+        _ => return Err("max_weight must be 1 to 100000 grams".to_string()),
+    };
+    let price = match rate.price {
+        Some(p) if p >= 0.0 && p.is_finite() => (p * 100.0).round() / 100.0,
+        _ => return Err("price must not be negative".to_string()),
+    };
+    if rate.free_over.is_some_and(|f| f <= 0.0 || !f.is_finite()) {
+        return Err("free_over must be a value greater than 0".to_string());
+    }
+
+    let rid = shipping::set_rate(
+        id,
+        &validate_name(rate.carrier.clone())?,
+        &validate_name(rate.service_level.clone())?,
+        max_weight,
+        price,
+        rate.free_over,
+    )?;
+    info!(target: "info", "shipping rate change authorized by {}", auth.principal.name());
+    Ok(Json(rid))
+}
+
+pub(crate) fn validate_country(country: Option<String>) -> Result<String, String> {
+    //! ISO 3166 alpha-2 codes, stored uppercase
+    let country = match country {
+        Some(c) => c.trim().to_uppercase(),
+        None => return Err("no country provided".to_string()),
+    };
+    let re = Regex::new(r"^[A-Z]{2}$").expect("regex creation failed");
+    if re.is_match(&country) {
+        Ok(country)
+    } else {
+        warn!(target: "warn", "country rejected: {}", country);
+        Err("country should be a two letter code".to_string())
+    }
+}
+
+pub(crate) fn validate_region(region: String) -> Result<String, String> {
+    //! the state or province part of an ISO 3166-2 code, e.g. "NY", may be empty
+    let region = region.trim().to_uppercase();
+    let re = Regex::new(r"^[A-Z0-9]{0,3}$").expect("regex creation failed");
+    if re.is_match(&region) {
+        Ok(region)
+    } else {
+        warn!(target: "warn", "region rejected: {}", region);
+        Err("region should be up to 3 letters and digits".to_string())
+    }
+}
+
+pub(crate) fn validate_postal_code(code: String) -> Result<String, String> {
+    //! stored uppercase without spaces so prefixes compare the same way, may be empty
+    let code: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    let re = Regex::new(r"^[A-Z0-9-]{0,10}$").expect("regex creation failed");
+    if re.is_match(&code) {
+        Ok(code)
+    } else {
+        warn!(target: "warn", "postal code rejected: {}", code);
+        Err("postal code should be up to 10 letters and digits".to_string())
+    }
+}
+
+fn validate_name(name: Option<String>) -> Result<String, String> {
+    //! zone, carrier and service names are stored lowercase, e.g. "us-remote" and "next_day"
+    let name = match name {
+        Some(n) => n.trim().to_lowercase(),
+        None => return Err("no name provided".to_string()),
+    };
+    let re = Regex::new(r"^[a-z0-9][a-z0-9 _-]{0,39}$").expect("regex creation failed");
+    if re.is_match(&name) {
+        Ok(name)
+    } else {
+        warn!(target: "warn", "shipping name rejected: {}", name);
+        Err("names should be up to 40 letters, digits, spaces, - or _".to_string())
+    }
+}
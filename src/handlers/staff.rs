@@ -0,0 +1,171 @@
+use log::{info, warn};
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Authorized, Principal, Role, StaffAdmin};
+use crate::db::staff::{self, SecondFactor, StaffMember};
+use crate::totp::SystemClock;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Staff {
+    id: Option<i64>,
+    username: Option<String>,
+    password: Option<String>,
+    role: Option<String>,
+    token: Option<String>,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TotpEnrollment {
+    secret: Option<String>,
+    provisioning_uri: Option<String>,
+    recovery_codes: Option<Vec<String>>,
+}
+
+#[post("/login", data = "<login>")]
+pub fn login(login: Json<Staff>) -> Result<Json<Staff>, String> {
+    let username = validate_username(login.username.clone())?;
+    let password = login.password.clone().ok_or("no password provided")?;
+    let second_factor = match (login.code.clone(), login.recovery_code.clone()) {
+        (Some(code), _) => Some(SecondFactor::Totp(code)),
+        (None, Some(code)) => Some(SecondFactor::RecoveryCode(code)),
+        (None, None) => None,
+    };
+
+    let token = staff::login(username, password, second_factor, &SystemClock)?;
+    Ok(Json(Staff {
+        id: None,
+        username: None,
+        password: None,
+        role: None,
+        token: Some(token),
+        code: None,
+        recovery_code: None,
+    }))
+}
+
+#[post("/logout")]
+pub fn logout(member: StaffMember) -> Result<(), String> {
+    //! ends the session of the bearer token the request was sent with
+    staff::end_session(&member)
+}
+
+#[post("/new", data = "<member>")]
+pub fn create_staff(
+    admin: Authorized<StaffAdmin>,
+    member: Json<Staff>,
+) -> Result<Json<Staff>, String> {
+    let username = validate_username(member.username.clone())?;
+    let password = validate_password(member.password.clone())?;
+    let role = validate_role(member.role.clone())?;
+
+    let sid = staff::create_staff(username, password, role)?;
+    info!(target: "info", "staff {} created by {}", sid, admin.principal.name());
+    Ok(Json(Staff {
+        id: Some(sid),
+        username: None,
+        password: None,
+        role: None,
+        token: None,
+        code: None,
+        recovery_code: None,
+    }))
+}
+
+#[put("/role", data = "<member>")]
+pub fn update_role(admin: Authorized<StaffAdmin>, member: Json<Staff>) -> Result<(), String> {
+    let sid = member
+        .id
+        .filter(|id| *id > 0)
+        .ok_or("sid must be a value greater than 0")?;
+    let role = validate_role(member.role.clone())?;
+    if let Principal::Staff(current) = &admin.principal {
+        if current.id == sid {
+            warn!(target: "warn", "staff {} attempted to change their own role", current.username);
+            return Err("cannot change your own role".to_string());
+        }
+    }
+
+    staff::set_role(sid, role)?;
+    Ok(())
+}
+
+#[post("/totp/enroll")]
+pub fn enroll_totp(member: StaffMember) -> Result<Json<TotpEnrollment>, String> {
+    let (secret, uri) = staff::begin_totp_enrollment(&member)?;
+    Ok(Json(TotpEnrollment {
+        secret: Some(secret),
+        provisioning_uri: Some(uri),
+        recovery_codes: None,
+    }))
+}
+
+#[post("/totp/confirm", data = "<confirm>")]
+pub fn confirm_totp(
+    member: StaffMember,
+    confirm: Json<Staff>,
+) -> Result<Json<TotpEnrollment>, String> {
+    let code = confirm.code.clone().ok_or("no code provided")?;
+
+    let codes = staff::confirm_totp_enrollment(&member, code, &SystemClock)?;
+    Ok(Json(TotpEnrollment {
+        secret: None,
+        provisioning_uri: None,
+        recovery_codes: Some(codes),
+    }))
+}
+
+#[put("/totp/reset", data = "<member>")]
+pub fn reset_totp(admin: Authorized<StaffAdmin>, member: Json<Staff>) -> Result<(), String> {
+    let sid = member
+        .id
+        .filter(|id| *id > 0)
+        .ok_or("sid must be a value greater than 0")?;
+
+    staff::reset_totp(sid)?;
+    info!(target: "info", "totp of staff {} reset by {}", sid, admin.principal.name());
+    Ok(())
+}
+
+fn validate_username(username: Option<String>) -> Result<String, String> {
+    //! usernames are lowercase alphanumeric (underscores allowed)
+    let username = match username {
+        Some(s) => s.trim().to_lowercase(),
+        None => {
+            warn!(target: "warn", "staff username validation failed: no username provided");
+            return Err("no username provided".to_string());
+        }
+    };
+    if username.is_empty() || !username.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        warn!(target: "warn", "provided staff username is not alphanumeric");
+        Err("username should be alpha-numeric".to_string())
+    } else {
+        Ok(username)
+    }
+}
+
+fn validate_password(password: Option<String>) -> Result<String, String> {
+    let password = match password {
+        Some(s) => s,
+        None => return Err("no password provided".to_string()),
+    };
+    if password.chars().count() < 12 {
+        warn!(target: "warn", "staff password rejected: too short");
+        Err("password must be at least 12 characters".to_string())
+    } else {
+        Ok(password)
+    }
+}
+
+fn validate_role(role: Option<String>) -> Result<Role, String> {
+    let role = match role {
+        Some(s) => s,
+        None => return Err("no role provided".to_string()),
+    };
+    Role::parse(role.trim()).ok_or_else(|| {
+        warn!(target: "warn", "unknown staff role provided: {}", role);
+        "role should be one of admin, catalog_manager, fulfillment_clerk, support".to_string()
+    })
+}
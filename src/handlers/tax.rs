@@ -0,0 +1,80 @@
+use log::info;
+use regex::Regex;
+use rocket::serde::json::Json;
+use serde::Deserialize;
+
+use crate::auth::{Authorized, Reports, TaxAdmin};
+use crate::db::tax::{self, TaxCategory, TaxRate, TaxReport};
+use crate::handlers::shipping::{validate_country, validate_region};
+use crate::idempotency::Idempotent;
+
+#[get("/rates")]
+pub fn get_rates(_auth: Authorized<TaxAdmin>) -> Json<Vec<TaxRate>> {
+    Json(tax::rates())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewRate {
+    country: Option<String>,
+    region: Option<String>,
+    category: Option<String>,
+    rate: Option<f64>,
+}
+
+#[put("/rates", data = "<rate>")]
+pub fn set_rate(auth: Authorized<TaxAdmin>, rate: Idempotent<Json<NewRate>>) -> Result<(), String> {
+    let country = validate_country(rate.country.clone())?;
+    let region = validate_region(rate.region.clone().unwrap_or_default())?;
+    let category = match rate
+        .category
+        .as_deref()
+        .map(str::trim)
+        .map(TaxCategory::parse)
+    {
+        Some(Some(c)) => c,
+        _ => return Err("category should be one of book, ebook, general".to_string()),
+    };
+    // a fraction, 0.2 for 20%
+    let value = match rate.rate {
+        Some(r) if (0.0..1.0).contains(&r) => (r * 1_000_000.0).round() / 1_000_000.0,
+        _ => return Err("rate must be at least 0 and below 1".to_string()),
+    };
+
+    tax::set_rate(TaxRate {
+        country,
+        region,
+        category,
+        rate: value,
+    });
+    info!(target: "info", "tax rate change authorized by {}", auth.principal.name());
+    Ok(())
+}
+
+#[get("/report?<from>&<to>")]
+pub fn tax_report(
+    auth: Authorized<Reports>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Json<TaxReport>, String> {
+    let from = validate_date(from, "from")?;
+    let to = validate_date(to, "to")?;
+
+    let report = tax::liability_report(&from, &to)?;
+    info!(target: "info", "tax liability report for {} to {} run by {}", from, to, auth.principal.name());
+    Ok(Json(report))
+}
+
+pub(crate) fn validate_date(date: Option<String>, label: &str) -> Result<String, String> {
+    //! YYYY-MM-DD
+    let date = match date {
+        Some(d) => d.trim().to_string(),
+        None => return Err(format!("no {} date provided", label)),
+    };
+    let re = Regex::new(r"^\d{4}-(0[1-9]|1[0-2])-(0[1-9]|[12]\d|3[01])$")
+        .expect("regex creation failed");
+    if re.is_match(&date) {
+        Ok(date)
+    } else {
+        Err(format!("{} should be a date like 2024-01-31", label))
+    }
+}
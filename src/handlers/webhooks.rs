@@ -0,0 +1,135 @@
+use log::warn;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Authorized, Webhooks};
+use crate::db::webhooks::{self, Delivery, EventType, NewSubscription, Subscription};
+use crate::handlers::orders::validate_id;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewWebhook {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+}
+
+#[post("/new", data = "<webhook>")]
+pub fn create_subscription(
+    auth: Authorized<Webhooks>,
+    webhook: Json<NewWebhook>,
+) -> Result<Json<NewSubscription>, String> {
+    let url = validate_url(webhook.url.clone())?;
+    let events = validate_events(webhook.events.as_deref())?;
+
+    Ok(Json(webhooks::create_subscription(
+        &url,
+        &events,
+        &auth.principal.name(),
+    )))
+}
+
+#[get("/")]
+pub fn get_subscriptions(_auth: Authorized<Webhooks>) -> Json<Vec<Subscription>> {
+    Json(webhooks::subscriptions())
+}
+
+#[delete("/<id>")]
+pub fn deactivate_subscription(auth: Authorized<Webhooks>, id: i64) -> Result<(), String> {
+    let sid = validate_id(Some(id), "subscription id")?;
+
+    webhooks::deactivate(sid, &auth.principal.name())
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Ping {
+    event_id: i64,
+}
+
+#[post("/<id>/ping")]
+pub fn ping(_auth: Authorized<Webhooks>, id: i64) -> Result<Json<Ping>, String> {
+    let sid = validate_id(Some(id), "subscription id")?;
+
+    Ok(Json(Ping {
+        event_id: webhooks::ping(sid)?,
+    }))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeliveryLog {
+    deliveries: Vec<Delivery>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+#[get("/<id>/deliveries?<page>&<per_page>")]
+pub fn get_deliveries(
+    _auth: Authorized<Webhooks>,
+    id: i64,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<DeliveryLog>, String> {
+    let sid = validate_id(Some(id), "subscription id")?;
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(20).clamp(1, 100);
+
+    let (deliveries, total) = webhooks::deliveries(sid, page, per_page)?;
+    Ok(Json(DeliveryLog {
+        deliveries,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+#[post("/deliveries/<id>/retry")]
+pub fn retry_delivery(auth: Authorized<Webhooks>, id: i64) -> Result<(), String> {
+    let did = validate_id(Some(id), "delivery id")?;
+
+    webhooks::retry(did, &auth.principal.name())
+}
+
+fn validate_url(url: Option<String>) -> Result<String, String> {
+    //! http or https, local listeners included so subscriptions can be tried out
+    let url = match url {
+        Some(u) => u.trim().to_string(),
+        None => return Err("no url provided".to_string()),
+    };
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"));
+    match rest {
+        Some(r) if url.len() <= 2000 && !r.is_empty() && !r.starts_with('/') => {
+            if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                warn!(target: "warn", "webhook url rejected: {}", url);
+                return Err("url should not contain spaces".to_string());
+            }
+            Ok(url)
+        }
+        _ => {
+            warn!(target: "warn", "webhook url rejected: {}", url);
+            Err("url should be an http or https address of at most 2000 characters".to_string())
+        }
+    }
+}
+
+fn validate_events(events: Option<&[String]>) -> Result<Vec<EventType>, String> {
+    let events = match events {
+        Some(e) if !e.is_empty() => e,
+        _ => return Err("no events provided".to_string()),
+    };
+    let mut parsed: Vec<EventType> = Vec::with_capacity(events.len());
+    for event in events {
+        match EventType::parse(event.trim()) {
+            Some(e) if !parsed.contains(&e) => parsed.push(e),
+            Some(_) => {}
+            None => {
+                return Err(format!(
+                    "unknown event {}, events are order.created, order.shipped, \
+                     order.cancelled, book.created and book.price_changed",
+                    event
+                ))
+            }
+        }
+    }
+    Ok(parsed)
+}
@@ -0,0 +1,432 @@
+//! The `Idempotency-Key` header on mutating requests. The first response for a key
+//! is stored and replayed for retries, so a storefront that retries after a timeout
+//! doesn't place or charge an order twice.
+use log::warn;
+use rocket::{
+    data::{self, ByteUnit, FromData, Limits},
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, ContentType, Method, Status},
+    serde::json::Json,
+    Data, Request, Response,
+};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::ops::Deref;
+use std::sync::Mutex;
+
+use crate::db::idempotency::{self, Claim, StoredResponse};
+
+/// responses of these routes hold a secret that is only shown once and isn't stored
+const SECRET_RESPONSES: [&str; 5] = [
+    "/staff/",
+    "/apikeys/",
+    "/giftcards/new",
+    "/carts/new",
+    "/webhooks/new",
+];
+
+/// headers that say who is asking, a key is only replayed to the same caller
+const PRINCIPAL_HEADERS: [&str; 3] = ["Authorization", "X-API-Key", "X-Cart-Token"];
+
+/// what the request's key came to, kept in the request's local cache
+enum Keyed {
+    Unkeyed,
+    /// the request has a body, the key is claimed by [`Idempotent`] once it is read
+    Deferred(String),
+    /// store the response under the key
+    Record(String),
+    /// answer with the stored response instead of running the handler
+    Replay(StoredResponse),
+    Reject(Status, &'static str),
+}
+
+pub struct Idempotency;
+
+#[rocket::async_trait]
+impl Fairing for Idempotency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency-Key",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, data: &mut Data<'_>) {
+        let key = match req.headers().get_one("Idempotency-Key") {
+            Some(k) if !matches!(req.method(), Method::Get | Method::Head | Method::Options) => {
+                k.trim().to_string()
+            }
+            _ => return,
+        };
+        let well_formed =
+            (1..=255).contains(&key.len()) && key.bytes().all(|b| b.is_ascii_graphic());
+        let keyed = if !well_formed {
+            warn!(target: "warn", "malformed idempotency key for {}", req.uri());
+            Keyed::Reject(
+                Status::BadRequest,
+                "Idempotency-Key should be 1 to 255 printable characters",
+            )
+        } else if SECRET_RESPONSES
+            .iter()
+            .any(|p| req.uri().path().as_str().starts_with(p))
+        {
+            warn!(target: "warn", "idempotency key sent to {}, which can't store its response", req.uri());
+            Keyed::Reject(
+                Status::BadRequest,
+                "Idempotency-Key isn't supported here, the response holds a secret",
+            )
+        } else if data.peek(1).await.is_empty() {
+            claim(scoped(req, &key), &fingerprint(req, &[]))
+        } else {
+            Keyed::Deferred(scoped(req, &key))
+        };
+        if matches!(keyed, Keyed::Replay(_) | Keyed::Reject(..)) {
+            // no route matches, so no handler runs and the response is swapped in below
+            req.set_uri(Origin::parse("/idempotency-replay").expect("valid origin"));
+        }
+        req.local_cache(|| Mutex::new(keyed));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let keyed = std::mem::replace(
+            &mut *req
+                .local_cache(|| Mutex::new(Keyed::Unkeyed))
+                .lock()
+                .expect("idempotency state lock"),
+            Keyed::Unkeyed,
+        );
+        match keyed {
+            // a body sent to a route that doesn't read it through Idempotent is not keyed
+            Keyed::Unkeyed | Keyed::Deferred(_) => {}
+            Keyed::Replay(stored) => {
+                res.remove_header("Content-Type");
+                res.set_status(Status::from_code(stored.status).unwrap_or(Status::Ok));
+                if let Some(ct) = stored
+                    .content_type
+                    .as_deref()
+                    .and_then(ContentType::parse_flexible)
+                {
+                    res.set_header(ct);
+                }
+                res.set_raw_header("Idempotent-Replayed", "true");
+                res.set_sized_body(stored.body.len(), Cursor::new(stored.body));
+            }
+            Keyed::Reject(status, message) => {
+                res.set_status(status);
+                res.set_header(ContentType::Plain);
+                res.set_sized_body(message.len(), Cursor::new(message));
+            }
+            Keyed::Record(key) => {
+                // failed requests change nothing, so they can be retried with the same key
+                if !res.status().class().is_success() {
+                    idempotency::release(&key);
+                    return;
+                }
+                let body = res.body_mut().to_bytes().await.unwrap_or_default();
+                idempotency::complete(
+                    &key,
+                    &StoredResponse {
+                        status: res.status().code,
+                        content_type: res.content_type().map(|ct| ct.to_string()),
+                        body: body.clone(),
+                    },
+                );
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
+fn scoped(req: &Request<'_>, key: &str) -> String {
+    //! prefixes the key with a hash of the caller's credentials, so one caller can't
+    //! get another's stored response by guessing its key
+    let mut hasher = Sha256::new();
+    for header in PRINCIPAL_HEADERS {
+        hasher.update(req.headers().get_one(header).unwrap_or_default());
+        hasher.update([0]);
+    }
+    format!("{}:{}", hex::encode(hasher.finalize()), key)
+}
+
+fn fingerprint(req: &Request<'_>, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(req.uri().to_string());
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+fn claim(key: String, fingerprint: &str) -> Keyed {
+    match idempotency::claim(&key, fingerprint) {
+        Claim::New => Keyed::Record(key),
+        Claim::Replay(response) => Keyed::Replay(response),
+        Claim::InProgress => Keyed::Reject(
+            Status::Conflict,
+            "a request with this Idempotency-Key is still being processed",
+        ),
+        Claim::Mismatch => Keyed::Reject(
+            Status::UnprocessableEntity,
+            "Idempotency-Key was already used for a different request",
+        ),
+    }
+}
+
+/// a body that can be fingerprinted, it is read in full before it is parsed
+pub trait FromBody: Sized {
+    /// the `limits` key in Rocket.toml, `LIMIT` applies when it isn't set
+    const LIMIT_NAME: &'static str;
+    const LIMIT: ByteUnit;
+
+    fn from_body(body: Vec<u8>) -> Result<Self, String>;
+}
+
+impl<T: DeserializeOwned> FromBody for Json<T> {
+    const LIMIT_NAME: &'static str = "json";
+    const LIMIT: ByteUnit = Limits::JSON;
+
+    fn from_body(body: Vec<u8>) -> Result<Self, String> {
+        rocket::serde::json::from_slice(&body)
+            .map(Json)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl FromBody for String {
+    const LIMIT_NAME: &'static str = "csv";
+    const LIMIT: ByteUnit = ByteUnit::Mebibyte(2);
+
+    fn from_body(body: Vec<u8>) -> Result<Self, String> {
+        String::from_utf8(body).map_err(|_| "body should be UTF-8 text".to_string())
+    }
+}
+
+/// data guard for the bodies of keyed routes: the whole body goes into the
+/// request's fingerprint, and a replayed or rejected key stops the handler
+pub struct Idempotent<T>(pub T);
+
+impl<T> Idempotent<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Idempotent<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromBody> FromData<'r> for Idempotent<T> {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req.limits().get(T::LIMIT_NAME).unwrap_or(T::LIMIT);
+        let body = match data.open(limit).into_bytes().await {
+            Ok(b) if b.is_complete() => b.into_inner(),
+            Ok(_) => {
+                let message = format!("body should be at most {}", limit);
+                return data::Outcome::Error((Status::PayloadTooLarge, message));
+            }
+            Err(e) => return data::Outcome::Error((Status::BadRequest, e.to_string())),
+        };
+
+        let mut keyed = req
+            .local_cache(|| Mutex::new(Keyed::Unkeyed))
+            .lock()
+            .expect("idempotency state lock");
+        if let Keyed::Deferred(key) = &*keyed {
+            *keyed = claim(key.clone(), &fingerprint(req, &body));
+        }
+        if let Keyed::Replay(_) | Keyed::Reject(..) = &*keyed {
+            // the handler doesn't run, the response is swapped in by the fairing
+            return data::Outcome::Error((Status::Conflict, "idempotent replay".to_string()));
+        }
+        drop(keyed);
+
+        match T::from_body(body) {
+            Ok(value) => data::Outcome::Success(Idempotent(value)),
+            Err(e) => {
+                warn!(target: "warn", "request body rejected for {}: {}", req.uri(), e);
+                data::Outcome::Error((Status::UnprocessableEntity, e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::{Client, LocalResponse};
+    use rocket::response::status::Conflict;
+    use rocket::serde::json::Value;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// how often the handlers below ran, and whether the next one fails
+        static RUNS: Cell<u32> = const { Cell::new(0) };
+        static FAIL: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[post("/orders", data = "<body>")]
+    fn place(body: Idempotent<Json<Value>>) -> Result<String, Conflict<&'static str>> {
+        RUNS.with(|r| r.set(r.get() + 1));
+        if FAIL.with(|f| f.replace(false)) {
+            return Err(Conflict("out of stock"));
+        }
+        Ok(format!(
+            "order {} for {}",
+            RUNS.with(Cell::get),
+            body["book"]
+        ))
+    }
+
+    #[post("/new")]
+    fn secret() -> &'static str {
+        "secret"
+    }
+
+    fn client() -> Client {
+        client_with(rocket::Config::figment())
+    }
+
+    fn client_with(figment: rocket::figment::Figment) -> Client {
+        let rocket = rocket::custom(figment)
+            .attach(Idempotency)
+            .mount("/", routes![place])
+            .mount("/carts", routes![secret]);
+        Client::untracked(rocket).expect("valid rocket")
+    }
+
+    fn post<'c>(client: &'c Client, key: &str, body: &str) -> LocalResponse<'c> {
+        client
+            .post("/orders")
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", key.to_string()))
+            .body(body)
+            .dispatch()
+    }
+
+    #[test]
+    fn retries_get_the_first_response_without_running_again() {
+        let _db = crate::db::test_db();
+        let client = client();
+
+        let first = post(&client, "abc", r#"{"book": 1}"#);
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(first.headers().get_one("Idempotent-Replayed"), None);
+        assert_eq!(first.into_string().unwrap(), "order 1 for 1");
+
+        let retry = post(&client, "abc", r#"{"book": 1}"#);
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+        assert_eq!(retry.into_string().unwrap(), "order 1 for 1");
+        assert_eq!(RUNS.with(Cell::get), 1);
+
+        // without a key every request runs
+        client
+            .post("/orders")
+            .header(ContentType::JSON)
+            .body(r#"{"book": 1}"#)
+            .dispatch();
+        assert_eq!(RUNS.with(Cell::get), 2);
+    }
+
+    #[test]
+    fn a_key_reused_with_another_body_is_refused() {
+        let _db = crate::db::test_db();
+        let client = client();
+        post(&client, "abc", r#"{"book": 1}"#);
+
+        let other = post(&client, "abc", r#"{"book": 2}"#);
+        assert_eq!(other.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            other.into_string().unwrap(),
+            "Idempotency-Key was already used for a different request"
+        );
+        assert_eq!(RUNS.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn a_key_in_progress_is_a_conflict() {
+        let _db = crate::db::test_db();
+        let client = client();
+        // what the fairing claims for a request without credentials
+        let req = client.post("/orders");
+        let key = scoped(req.inner(), "abc");
+        let fingerprint = fingerprint(req.inner(), br#"{"book": 1}"#);
+        assert!(matches!(idempotency::claim(&key, &fingerprint), Claim::New));
+
+        let retry = post(&client, "abc", r#"{"book": 1}"#);
+        assert_eq!(retry.status(), Status::Conflict);
+        assert_eq!(RUNS.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_caller() {
+        let _db = crate::db::test_db();
+        let client = client();
+        post(&client, "abc", r#"{"book": 1}"#);
+
+        let other_caller = client
+            .post("/orders")
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", "abc"))
+            .header(Header::new("X-API-Key", "someone else"))
+            .body(r#"{"book": 1}"#)
+            .dispatch();
+        assert_eq!(other_caller.headers().get_one("Idempotent-Replayed"), None);
+        assert_eq!(other_caller.into_string().unwrap(), "order 2 for 1");
+    }
+
+    #[test]
+    fn a_failed_request_can_be_retried_with_its_key() {
+        let _db = crate::db::test_db();
+        let client = client();
+        FAIL.with(|f| f.set(true));
+
+        assert_eq!(
+            post(&client, "abc", r#"{"book": 1}"#).status(),
+            Status::Conflict
+        );
+        let retry = post(&client, "abc", r#"{"book": 1}"#);
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(retry.into_string().unwrap(), "order 2 for 1");
+    }
+
+    #[test]
+    fn malformed_keys_and_secret_responses_are_refused() {
+        let _db = crate::db::test_db();
+        let client = client();
+
+        let long = "k".repeat(256);
+        assert_eq!(
+            post(&client, &long, r#"{"book": 1}"#).status(),
+            Status::BadRequest
+        );
+        let secret = client
+            .post("/carts/new")
+            .header(Header::new("Idempotency-Key", "abc"))
+            .dispatch();
+        assert_eq!(secret.status(), Status::BadRequest);
+        assert_eq!(RUNS.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn bodies_follow_the_configured_limits() {
+        let _db = crate::db::test_db();
+        let body = format!(r#"{{"book": 1, "note": "{}"}}"#, "x".repeat(100));
+
+        let small = client_with(rocket::Config::figment().merge(("limits.json", 64)));
+        let response = post(&small, "abc", &body);
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert_eq!(RUNS.with(Cell::get), 0);
+
+        assert_eq!(post(&client(), "abc", &body).status(), Status::Ok);
+        assert_eq!(RUNS.with(Cell::get), 1);
+    }
+}
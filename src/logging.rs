@@ -1,3 +1,7 @@
+use log::error;
+use regex::Regex;
+use std::fs;
+
 pub fn log_init() {
     log4rs::init_file("log4rs.yaml", Default::default()).unwrap_or_else(|error| {
         println!(
@@ -6,3 +10,51 @@ pub fn log_init() {
         )
     });
 }
+
+/// directories written by the appenders in `log4rs.yaml`, current and rolled files
+const LOG_DIRS: [&str; 2] = ["log", "logs"];
+
+pub fn scrub(terms: &[String], replacement: &str) -> usize {
+    //! replaces whole-word, case-insensitive occurrences of the terms in every log file,
+    //! returns the number of files that changed
+    let terms: Vec<String> = terms
+        .iter()
+        .map(|t| t.trim())
+        .filter(|t| t.chars().count() >= 3)
+        .map(regex::escape)
+        .collect();
+    if terms.is_empty() {
+        return 0;
+    }
+    let re =
+        Regex::new(&format!(r"(?i)\b(?:{})\b", terms.join("|"))).expect("regex creation failed");
+    let mut scrubbed = 0;
+    for dir in LOG_DIRS {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if !path.is_file() {
+                continue;
+            }
+            let contents = match fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!(target: "error", "failed to read log file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let replaced = re.replace_all(&contents, replacement);
+            if replaced != contents {
+                match fs::write(&path, replaced.as_bytes()) {
+                    Ok(_) => scrubbed += 1,
+                    Err(e) => {
+                        error!(target: "error", "failed to scrub log file {}: {}", path.display(), e)
+                    }
+                }
+            }
+        }
+    }
+    scrubbed
+}
@@ -5,22 +5,134 @@ use logging::log_init;
 extern crate rocket;
 extern crate serde;
 
+mod auth;
+mod config;
 mod db;
 mod handlers;
+mod idempotency;
 mod logging;
+mod pdf;
+mod totp;
+mod webhooks;
 
 #[launch]
 fn rocket() -> _ {
     log_init();
     info!(target: "info", "server started");
+    db::staff::bootstrap_admin();
+    webhooks::start_worker();
     rocket::build()
+        .attach(idempotency::Idempotency)
         .mount("/books", routes![handlers::books::create_book])
         .mount("/books", routes![handlers::books::get_price])
+        .mount("/books", routes![handlers::books::set_price])
+        .mount("/books", routes![handlers::books::set_stock])
+        .mount("/books", routes![handlers::books::set_dimensions])
+        .mount("/books", routes![handlers::books::set_tax_category])
         .mount("/customers", routes![handlers::customers::create_customer])
         .mount("/customers", routes![handlers::customers::get_balance])
         .mount("/customers", routes![handlers::customers::update_address])
+        .mount(
+            "/customers",
+            routes![handlers::customers::post_ledger_entry],
+        )
+        .mount("/customers", routes![handlers::customers::get_transactions])
+        .mount("/customers", routes![handlers::customers::get_orders])
+        .mount("/customers", routes![handlers::customers::merge_customers])
+        .mount("/customers", routes![handlers::privacy::export_data])
+        .mount("/customers", routes![handlers::privacy::erase_data])
+        .mount("/customers", routes![handlers::gift_cards::redeem_card])
+        .mount("/customers", routes![handlers::loyalty::get_loyalty])
+        .mount(
+            "/customers",
+            routes![handlers::loyalty::get_loyalty_history],
+        )
         .mount("/orders", routes![handlers::orders::create_order])
+        .mount("/orders", routes![handlers::orders::place_order])
+        .mount("/orders", routes![handlers::orders::list_orders])
         .mount("/orders", routes![handlers::orders::get_shipped])
         .mount("/orders", routes![handlers::orders::ship_order])
+        .mount("/orders", routes![handlers::orders::set_state])
+        .mount("/orders", routes![handlers::orders::get_events])
+        .mount("/orders", routes![handlers::shipments::create_shipment])
+        .mount("/orders", routes![handlers::shipments::get_tracking])
+        .mount("/orders", routes![handlers::shipments::ship_bulk])
+        .mount("/picking", routes![handlers::picking::create_batch])
+        .mount("/picking", routes![handlers::picking::get_pick_list])
+        .mount("/picking", routes![handlers::picking::get_packing_slips])
+        .mount("/orders", routes![handlers::orders::cancel_order])
+        .mount("/orders", routes![handlers::orders::force_cancel_order])
         .mount("/orders", routes![handlers::orders::get_status])
+        .mount("/orders", routes![handlers::orders::get_status_by_id])
+        .mount("/orders", routes![handlers::orders::get_invoices])
+        .mount("/carts", routes![handlers::carts::new_cart])
+        .mount("/carts", routes![handlers::carts::get_cart])
+        .mount("/carts", routes![handlers::carts::add_item])
+        .mount("/carts", routes![handlers::carts::update_item])
+        .mount("/carts", routes![handlers::carts::remove_item])
+        .mount("/carts", routes![handlers::carts::checkout])
+        .mount("/returns", routes![handlers::returns::open_return])
+        .mount("/returns", routes![handlers::returns::get_return])
+        .mount("/returns", routes![handlers::returns::return_queue])
+        .mount("/returns", routes![handlers::returns::decide_return])
+        .mount("/returns", routes![handlers::returns::receive_return])
+        .mount(
+            "/promotions",
+            routes![handlers::promotions::create_promotion],
+        )
+        .mount(
+            "/promotions",
+            routes![handlers::promotions::list_promotions],
+        )
+        .mount("/promotions", routes![handlers::promotions::get_promotion])
+        .mount(
+            "/promotions",
+            routes![handlers::promotions::update_promotion],
+        )
+        .mount(
+            "/promotions",
+            routes![handlers::promotions::delete_promotion],
+        )
+        .mount(
+            "/promotions",
+            routes![handlers::promotions::promotion_stats],
+        )
+        .mount("/shipping", routes![handlers::shipping::get_quote])
+        .mount("/shipping", routes![handlers::shipping::get_zones])
+        .mount("/shipping", routes![handlers::shipping::add_area])
+        .mount("/shipping", routes![handlers::shipping::set_rate])
+        .mount("/tax", routes![handlers::tax::get_rates])
+        .mount("/tax", routes![handlers::tax::set_rate])
+        .mount("/tax", routes![handlers::tax::tax_report])
+        .mount("/invoices", routes![handlers::invoices::get_invoice])
+        .mount("/invoices", routes![handlers::invoices::get_invoice_pdf])
+        .mount("/invoices", routes![handlers::invoices::list_invoices])
+        .mount(
+            "/webhooks",
+            routes![handlers::webhooks::create_subscription],
+        )
+        .mount("/webhooks", routes![handlers::webhooks::get_subscriptions])
+        .mount(
+            "/webhooks",
+            routes![handlers::webhooks::deactivate_subscription],
+        )
+        .mount("/webhooks", routes![handlers::webhooks::ping])
+        .mount("/webhooks", routes![handlers::webhooks::get_deliveries])
+        .mount("/webhooks", routes![handlers::webhooks::retry_delivery])
+        .mount("/giftcards", routes![handlers::gift_cards::issue_card])
+        .mount(
+            "/giftcards",
+            routes![handlers::gift_cards::liability_report],
+        )
+        .mount("/staff", routes![handlers::staff::login])
+        .mount("/staff", routes![handlers::staff::logout])
+        .mount("/staff", routes![handlers::staff::create_staff])
+        .mount("/staff", routes![handlers::staff::update_role])
+        .mount("/staff", routes![handlers::staff::enroll_totp])
+        .mount("/staff", routes![handlers::staff::confirm_totp])
+        .mount("/staff", routes![handlers::staff::reset_totp])
+        .mount("/apikeys", routes![handlers::api_keys::create_key])
+        .mount("/apikeys", routes![handlers::api_keys::rotate_key])
+        .mount("/apikeys", routes![handlers::api_keys::revoke_key])
+        .mount("/apikeys", routes![handlers::api_keys::list_keys])
 }
@@ -0,0 +1,140 @@
+//! A minimal PDF 1.4 writer for text documents such as invoices, so no external
+//! service or library is needed. Pages are A4 and text is set in the standard
+//! Helvetica fonts, which every PDF reader has built in.
+use std::fmt::Write;
+
+/// A4 in points
+pub const PAGE_WIDTH: f32 = 595.0;
+pub const PAGE_HEIGHT: f32 = 842.0;
+
+struct Text {
+    x: f32,
+    y: f32,
+    size: f32,
+    bold: bool,
+    content: String,
+}
+
+#[derive(Default)]
+pub struct Pdf {
+    pages: Vec<Vec<Text>>,
+}
+
+impl Pdf {
+    pub fn new_page(&mut self) {
+        self.pages.push(Vec::new());
+    }
+
+    pub fn text(&mut self, x: f32, y: f32, size: f32, bold: bool, content: &str) {
+        //! `y` counts from the top of the page, unlike PDF's own coordinates
+        if self.pages.is_empty() {
+            self.new_page();
+        }
+        self.pages
+            .last_mut()
+            .expect("a page was just added")
+            .push(Text {
+                x,
+                y: PAGE_HEIGHT - y,
+                size,
+                bold,
+                content: content.to_string(),
+            });
+    }
+
+    pub fn text_right(&mut self, right: f32, y: f32, size: f32, bold: bool, content: &str) {
+        //! right-aligned at `right`, for amounts in columns
+        let width = content.chars().count() as f32 * size * if bold { 0.58 } else { 0.55 };
+        self.text(right - width, y, size, bold, content);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        //! objects 1 and 2 are the catalog and the page tree, 3 and 4 the fonts,
+        //! then each page is followed by its content stream
+        if self.pages.is_empty() {
+            self.new_page();
+        }
+        let mut objects: Vec<String> = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            String::new(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+                .to_string(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>"
+                .to_string(),
+        ];
+        let mut kids = Vec::with_capacity(self.pages.len());
+        for page in &self.pages {
+            let mut content = String::new();
+            for text in page {
+                let _ = writeln!(
+                    content,
+                    "BT /{} {} Tf {:.2} {:.2} Td ({}) Tj ET",
+                    if text.bold { "F2" } else { "F1" },
+                    text.size,
+                    text.x,
+                    text.y,
+                    escape(&text.content)
+                );
+            }
+            let page_id = objects.len() + 1;
+            kids.push(format!("{} 0 R", page_id));
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                PAGE_WIDTH,
+                PAGE_HEIGHT,
+                page_id + 1
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}endstream",
+                content.len(),
+                content
+            ));
+        }
+        objects[1] = format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            kids.len()
+        );
+
+        let mut out: Vec<u8> = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::with_capacity(objects.len());
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+        }
+        let xref = out.len();
+        let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+        for offset in offsets {
+            let _ = writeln!(trailer, "{:010} 00000 n ", offset);
+        }
+        let _ = write!(
+            trailer,
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        );
+        out.extend_from_slice(trailer.as_bytes());
+        out
+    }
+}
+
+fn escape(text: &str) -> String {
+    //! a PDF string literal in WinAnsi, Latin-1 characters are written as octal
+    //! escapes and anything else becomes '?'
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            '\u{a0}'..='\u{ff}' => {
+                let _ = write!(out, "\\{:03o}", c as u32);
+            }
+            _ => out.push('?'),
+        }
+    }
+    out
+}
@@ -0,0 +1,160 @@
+//! RFC 6238 time based one-time passwords (SHA1, 6 digits, 30 second steps),
+//! the defaults that authenticator apps expect.
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// codes from one step either side of the current one are accepted to allow for clock drift
+const SKEW: u64 = 1;
+
+/// Source of the current unix time, so verification can be run against a fixed clock.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock is before the unix epoch")
+            .as_secs()
+    }
+}
+
+/// Clock pinned to a given unix time, for checking codes offline.
+#[cfg(test)]
+pub struct FixedClock(pub u64);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+pub fn generate_secret() -> String {
+    //! 160 bit random secret, base32 encoded
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+pub fn provisioning_uri(secret: &str, account: &str, issuer: &str) -> String {
+    //! `otpauth://` uri to be shown as a QR code by the client
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}"
+    )
+}
+
+pub fn code_at(secret: &str, step: u64) -> Result<String, String> {
+    let key = BASE32_NOPAD
+        .decode(secret.as_bytes())
+        .map_err(|_| "totp secret is not valid base32".to_string())?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).expect("hmac accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    Ok(format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    ))
+}
+
+pub fn verify(secret: &str, code: &str, clock: &dyn Clock, last_step: Option<u64>) -> Option<u64> {
+    //! returns the step the code matched, codes at or before `last_step` are
+    //! rejected so a code can't be replayed
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let current = clock.now() / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(secret, *step).is_ok_and(|c| c == code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the RFC 6238 SHA1 seed "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc_6238_sha1_vectors() {
+        // the RFC lists 8 digit codes, 6 digit codes are their last six digits
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(code_at(RFC_SECRET, time / STEP).unwrap(), code);
+            assert_eq!(
+                verify(RFC_SECRET, code, &FixedClock(time), None),
+                Some(time / STEP)
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_skew() {
+        let step = 1234567890 / STEP;
+        let code = code_at(RFC_SECRET, step).unwrap();
+        for now in [(step - 1) * STEP, step * STEP, (step + 1) * STEP + STEP - 1] {
+            assert_eq!(
+                verify(RFC_SECRET, &code, &FixedClock(now), None),
+                Some(step)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_codes_outside_the_window() {
+        let step = 1234567890 / STEP;
+        let code = code_at(RFC_SECRET, step).unwrap();
+        for now in [(step - 2) * STEP, (step + 2) * STEP] {
+            assert_eq!(verify(RFC_SECRET, &code, &FixedClock(now), None), None);
+        }
+    }
+
+    #[test]
+    fn rejects_a_replayed_step() {
+        let clock = FixedClock(1234567890);
+        let step = clock.0 / STEP;
+        let code = code_at(RFC_SECRET, step).unwrap();
+        assert_eq!(
+            verify(RFC_SECRET, &code, &clock, Some(step - 1)),
+            Some(step)
+        );
+        assert_eq!(verify(RFC_SECRET, &code, &clock, Some(step)), None);
+        let next = code_at(RFC_SECRET, step + 1).unwrap();
+        assert_eq!(
+            verify(RFC_SECRET, &next, &clock, Some(step)),
+            Some(step + 1)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let clock = FixedClock(59);
+        assert_eq!(verify(RFC_SECRET, "28708", &clock, None), None);
+        assert_eq!(verify(RFC_SECRET, "28708a", &clock, None), None);
+        assert!(code_at("not base32!", 1).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! The webhook delivery worker. Events are written to an outbox in the transaction
+//! of the change that raised them (see `db::webhooks::emit`); this thread sends them
+//! to their subscriptions afterwards, signed with the subscription's secret, and
+//! retries failed deliveries with exponential backoff.
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::Sha256;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::db::webhooks::{self, DueDelivery};
+
+/// deliveries sent per look at the outbox
+const BATCH: i64 = 50;
+
+pub fn start_worker() {
+    thread::Builder::new()
+        .name("webhooks".to_string())
+        .spawn(|| {
+            let agent = agent();
+            info!(target: "info", "webhook worker started");
+            loop {
+                if deliver_due(&agent) < BATCH as usize {
+                    thread::sleep(Duration::from_secs(config::webhook_poll_seconds()));
+                }
+            }
+        })
+        .expect("expected to be able to start the webhook worker");
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config::webhook_timeout_seconds()))
+        .redirects(0)
+        .user_agent("bookshop-rs-webhooks")
+        .build()
+}
+
+pub fn deliver_due(agent: &ureq::Agent) -> usize {
+    //! one look at the outbox: sends up to a batch of due deliveries and records each
+    //! attempt, returns how many were sent
+    let due = webhooks::due(BATCH);
+    for delivery in &due {
+        deliver(agent, delivery);
+    }
+    due.len()
+}
+
+fn deliver(agent: &ureq::Agent, delivery: &DueDelivery) {
+    let body = format!(
+        r#"{{"id":{},"type":"{}","created_at":"{}","data":{}}}"#,
+        delivery.event_id, delivery.event_type, delivery.created_at, delivery.payload
+    );
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the epoch")
+        .as_secs();
+    let started = Instant::now();
+    let result = agent
+        .post(&delivery.url)
+        .set("Content-Type", "application/json")
+        .set("X-Webhook-Id", &delivery.event_id.to_string())
+        .set("X-Webhook-Event", &delivery.event_type)
+        .set(
+            "X-Webhook-Signature",
+            &format!(
+                "t={},v1={}",
+                timestamp,
+                sign(&delivery.secret, timestamp, &body)
+            ),
+        )
+        .send_string(&body);
+    let duration_ms = started.elapsed().as_millis() as i64;
+    let (status_code, error) = match result {
+        Ok(response) => (Some(response.status()), None),
+        Err(ureq::Error::Status(code, _)) => (Some(code), None),
+        Err(ureq::Error::Transport(e)) => (None, Some(e.to_string())),
+    };
+    if status_code.is_none_or(|c| !(200..300).contains(&c)) {
+        warn!(target: "warn", "webhook delivery {} of event {} to {} failed: {}",
+            delivery.id, delivery.event_id, delivery.url,
+            error.clone().unwrap_or_else(|| format!("status {}", status_code.unwrap_or_default())));
+    }
+    webhooks::record_attempt(delivery.id, status_code, error.as_deref(), duration_ms);
+}
+
+fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    //! hex HMAC-SHA256 of "<timestamp>.<body>", the timestamp lets receivers reject replays
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::webhooks::EventType;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+
+    /// a request the listener received: its headers, lowercased, and its body
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    fn listen(statuses: Vec<u16>) -> (String, Receiver<Received>) {
+        //! answers one request per status, in order
+        let listener = TcpListener::bind("127.0.0.1:0").expect("a free port");
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().expect("a connection");
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = Vec::new();
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((n, v)) => headers.push((n.to_lowercase(), v.to_string())),
+                        None => break,
+                    }
+                }
+                let length: usize = headers
+                    .iter()
+                    .find(|(n, _)| n == "content-length")
+                    .map_or(0, |(_, v)| v.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                tx.send(Received {
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                })
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn delivery() -> (String, i64) {
+        //! (status, seconds until the next attempt) of the only delivery
+        crate::db::connect()
+            .query_row(
+                "SELECT status, CAST(round((julianday(nextAttemptAt) - julianday('now')) * 86400)
+                     AS INTEGER) FROM WebhookDeliveries",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+    }
+
+    fn make_due() {
+        crate::db::connect()
+            .execute(
+                "UPDATE WebhookDeliveries SET nextAttemptAt = datetime('now', '-1 seconds')",
+                [],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn deliveries_are_signed_over_the_timestamp_and_body() {
+        let _db = crate::db::test_db();
+        let (url, received) = listen(vec![204]);
+        let subscription = webhooks::create_subscription(&url, &[EventType::Ping], "admin");
+        let eid = webhooks::ping(subscription.subscription.id).unwrap();
+
+        assert_eq!(deliver_due(&agent()), 1);
+        let request = received.recv().unwrap();
+        assert_eq!(
+            request.header("x-webhook-id"),
+            Some(eid.to_string().as_str())
+        );
+        assert_eq!(request.header("x-webhook-event"), Some("ping"));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["type"], "ping");
+        assert_eq!(
+            body["data"]["subscription_id"],
+            subscription.subscription.id
+        );
+
+        let signature = request.header("x-webhook-signature").unwrap();
+        let (t, v1) = signature
+            .strip_prefix("t=")
+            .and_then(|s| s.split_once(",v1="))
+            .expect("t=<timestamp>,v1=<hmac>");
+        let mut mac = Hmac::<Sha256>::new_from_slice(subscription.secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", t, request.body).as_bytes());
+        mac.verify_slice(&hex::decode(v1).unwrap())
+            .expect("signature matches");
+    }
+
+    #[test]
+    fn failures_are_retried_with_doubling_backoff_until_delivered() {
+        let _db = crate::db::test_db();
+        let (url, received) = listen(vec![500, 500, 200]);
+        let subscription = webhooks::create_subscription(&url, &[EventType::Ping], "admin");
+        webhooks::ping(subscription.subscription.id).unwrap();
+        let agent = agent();
+        let backoff = config::webhook_backoff_seconds();
+
+        assert_eq!(deliver_due(&agent), 1);
+        received.recv().unwrap();
+        let (status, wait) = delivery();
+        assert_eq!(status, "pending");
+        assert!((backoff - 2..=backoff).contains(&wait));
+        // not due again until the backoff has passed
+        assert_eq!(deliver_due(&agent), 0);
+
+        make_due();
+        assert_eq!(deliver_due(&agent), 1);
+        received.recv().unwrap();
+        let (status, wait) = delivery();
+        assert_eq!(status, "pending");
+        assert!((backoff * 2 - 2..=backoff * 2).contains(&wait));
+
+        make_due();
+        assert_eq!(deliver_due(&agent), 1);
+        received.recv().unwrap();
+        let (deliveries, _) = webhooks::deliveries(subscription.subscription.id, 1, 10).unwrap();
+        let delivered = &deliveries[0];
+        assert_eq!(
+            (delivered.status.as_str(), delivered.attempts),
+            ("delivered", 3)
+        );
+        assert!(delivered.delivered_at.is_some());
+        let codes: Vec<Option<u16>> = delivered.log.iter().map(|a| a.status_code).collect();
+        assert_eq!(codes, vec![Some(500), Some(500), Some(200)]);
+        assert_eq!(deliver_due(&agent), 0);
+    }
+}